@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
+use crate::checkpoint::Checkpoint;
+use crate::commands::CommandRules;
+use crate::error::{self, ErrorKind, Failure};
+use crate::{config, gcode, report, OptimizedLayer, Optimizer};
+
+// Outcome of solving the layers with one configuration, times in seconds
+struct AbRun {
+    config_path: String,
+    base_time: f64,
+    optimized_time: f64,
+    travel: f64,
+    solve_time: f64,
+}
+
+// Solves the layers of a G-code file, parsed once, with each configuration and keeps the one with the
+// shortest estimated time. Its layers go to <input>.checkpoint so the optimization written with it
+// resumes from them instead of solving again. Returns the index of the winning configuration.
+pub fn run(gcode_path: &str, configs: &[(String, config::Config)], rules: &CommandRules) -> Result<usize, Failure> {
+    let file = File::open(gcode_path)
+        .map_err(|_| Failure::new(ErrorKind::Parse, format!("Unable to read file {}", gcode_path)))?;
+    let mut reader = gcode::GCodeReader::new(gcode_path, BufReader::new(file));
+    let rules = rules.clone();
+    reader.add_handler(Arc::new(move |command| rules.classify(command)));
+    // Layers are parsed once, in the modes the first configuration assumes
+    if let Some((_, config)) = configs.first() {
+        reader.assume_modes(config.input_modes());
+        if config.strict_modes {
+            reader.strict_modes();
+        }
+    }
+    let layers: Vec<gcode::GCodeLayer> = reader.by_ref().collect();
+    if let Some(e) = &reader.gcode().error {
+        return error::fail(ErrorKind::Parse, e.clone());
+    }
+    status!("Parsed {} layer-s of {}", layers.len(), gcode_path);
+
+    let mut runs: Vec<AbRun> = Vec::new();
+    let mut best: Option<(usize, Vec<OptimizedLayer>)> = None;
+    for (i, (config_path, config)) in configs.iter().enumerate() {
+        status!("\nTrying {}", config_path);
+        let start = Instant::now();
+        let optimized = Optimizer::optimize_layers(layers.clone(), config)?;
+        let run = AbRun {
+            config_path: config_path.clone(),
+            base_time: optimized.iter().map(|layer| layer.report.base_time).sum(),
+            optimized_time: optimized.iter().map(|layer| layer.report.optimized_time).sum(),
+            travel: optimized.iter().map(|layer| layer.report.optimized_travel).sum(),
+            solve_time: start.elapsed().as_secs_f64(),
+        };
+        info!("A/B {}: estimated time {:.2} s -> {:.2} s, travel {:.2}, solved in {:.3} s",
+            run.config_path, run.base_time, run.optimized_time, run.travel, run.solve_time);
+
+        if best.as_ref().is_none_or(|&(best, _)| run.optimized_time < runs[best].optimized_time) {
+            best = Some((i, optimized));
+        }
+        runs.push(run);
+    }
+
+    let Some((winner, optimized)) = best else {
+        return error::fail(ErrorKind::Usage, "No configuration to compare");
+    };
+    display(&runs, winner);
+
+    let (mut checkpoint, _) = Checkpoint::open(&format!("{}.checkpoint", gcode_path), false).map_err(Failure::error)?;
+    for (i, layer) in optimized.iter().enumerate() {
+        if layer.report.status == report::LayerStatus::Solved {
+            checkpoint.save(i as u32, layer.layer.nodes.len(), &layer.moves, layer.report.merged).map_err(Failure::error)?;
+        }
+    }
+
+    Ok(winner)
+}
+
+fn display(runs: &[AbRun], winner: usize) {
+    let width = runs.iter().map(|run| run.config_path.len()).max().unwrap_or(0).max(6);
+
+    status!("\n  {:<width$}  {:>12}  {:>12}  {:>12}  {:>10}", "Config", "Time (s)", "Saved (s)", "Travel", "Solve (s)", width = width);
+    for (i, run) in runs.iter().enumerate() {
+        let mark = if i == winner { "*" } else { " " };
+        status!("{} {:<width$}  {:>12.2}  {:>12.2}  {:>12.2}  {:>10.3}",
+            mark, run.config_path, run.optimized_time, run.base_time - run.optimized_time, run.travel, run.solve_time, width = width);
+    }
+    status!("\nWriting with {}", runs[winner].config_path);
+    info!("A/B winner: {}", runs[winner].config_path);
+}
@@ -0,0 +1,205 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::f64::consts::PI;
+use serde::Serialize;
+use crate::config;
+use crate::error::{self, ErrorKind, Failure};
+use crate::gcode;
+use crate::quick_math::distance_3d;
+use crate::segments::{self, Segment};
+use crate::simulator;
+
+// Distances and estimated time of a layer, times in seconds
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LayerStats {
+    pub layer: u32,
+    pub z: f64,
+    pub nodes: usize,
+    pub travel: f64,
+    pub extrusion: f64,
+    // Net filament pushed, retractions taking back what they pull, as opposed to the distance moved while extruding
+    pub filament: f64,
+    pub time: f64,
+}
+
+// Extrusion of one kind of feature named by the slicer, perimeters or infill for instance
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FeatureStats {
+    pub feature: String,
+    pub extrusion: f64,
+    // Filament pushed extruding the feature, retractions only counting for their layer
+    pub filament: f64,
+    pub time: f64,
+}
+
+// Material a length of filament amounts to, volume in cm³ and weight in g
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct FilamentUsage {
+    pub length: f64,
+    pub volume: f64,
+    pub weight: f64,
+    // In the currency of the configured price, 0 when it is not set
+    pub cost: f64,
+}
+
+impl FilamentUsage {
+    // Length in mm of filament of the configured diameter, density and price
+    pub fn new(length: f64, config: &config::Config) -> FilamentUsage {
+        let radius = config.filament_diameter / 2.0;
+        let volume = length * PI * radius * radius / 1000.0;
+        let weight = volume * config.filament_density;
+        FilamentUsage { length, volume, weight, cost: weight / 1000.0 * config.filament_price }
+    }
+
+    pub fn display(&self) {
+        if self.cost > 0.0 {
+            status!("Filament: {:.2} mm, {:.2} cm³, {:.2} g, cost {:.2}", self.length, self.volume, self.weight, self.cost);
+        } else {
+            status!("Filament: {:.2} mm, {:.2} cm³, {:.2} g", self.length, self.volume, self.weight);
+        }
+    }
+}
+
+// What a G-code file prints and how long it takes, without optimizing it
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Analysis {
+    pub layers: Vec<LayerStats>,
+    pub features: Vec<FeatureStats>,
+    pub travel_time: f64,
+    pub filament: FilamentUsage,
+}
+
+impl Analysis {
+    pub fn time(&self) -> f64 {
+        self.layers.iter().map(|layer| layer.time).sum()
+    }
+
+    pub fn display(&self) {
+        status!("{:>6}  {:>8}  {:>7}  {:>12}  {:>12}  {:>10}  {:>10}", "Layer", "Z", "Nodes", "Travel", "Extrusion", "Filament", "Time (s)");
+        for layer in &self.layers {
+            status!("{:>6}  {:>8.3}  {:>7}  {:>12.2}  {:>12.2}  {:>10.2}  {:>10.2}",
+                layer.layer, layer.z, layer.nodes, layer.travel, layer.extrusion, layer.filament, layer.time);
+        }
+
+        let time = self.time();
+        let width = self.features.iter().map(|feature| feature.feature.len()).max().unwrap_or(0).max(7);
+        status!("\n{:<width$}  {:>12}  {:>10}  {:>10}  {:>7}", "Feature", "Extrusion", "Filament", "Time (s)", "Time %", width = width);
+        for feature in &self.features {
+            status!("{:<width$}  {:>12.2}  {:>10.2}  {:>10.2}  {:>7.2}",
+                feature.feature, feature.extrusion, feature.filament, feature.time, percent(feature.time, time), width = width);
+        }
+        status!("{:<width$}  {:>12}  {:>10}  {:>10.2}  {:>7.2}", "Travel", "", "", self.travel_time, percent(self.travel_time, time), width = width);
+
+        let travel: f64 = self.layers.iter().map(|layer| layer.travel).sum();
+        let extrusion: f64 = self.layers.iter().map(|layer| layer.extrusion).sum();
+        let nodes: usize = self.layers.iter().map(|layer| layer.nodes).sum();
+        status!("\n{} layer-s, {} node-s", self.layers.len(), nodes);
+        status!("Extrusion distance: {:.2}", extrusion);
+        status!("Travel distance: {:.2}", travel);
+        status!("Estimated time: {:.2} min", time / 60.0);
+        self.filament.display();
+    }
+
+    // Layers as CSV, or the whole analysis as JSON when the path ends with .json
+    pub fn export(&self, path: &str) -> Result<(), String> {
+        let contents = if path.ends_with(".json") {
+            serde_json::to_string_pretty(self).unwrap()
+        } else {
+            let mut csv = String::from("Layer,Z,Nodes,Travel,Extrusion,Filament,Time\n");
+            for layer in &self.layers {
+                csv.push_str(&format!("{},{:.3},{},{:.3},{:.3},{:.5},{:.3}\n",
+                    layer.layer, layer.z, layer.nodes, layer.travel, layer.extrusion, layer.filament, layer.time));
+            }
+            csv
+        };
+
+        fs::write(path, contents)
+            .map_err(|e| format!("Unable to write file {}: {}", path, e))
+    }
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 { part / total * 100.0 } else { 0.0 }
+}
+
+// Reads a G-code file move by move. Layers change as the parser of the optimizer changes them,
+// on the first extrusion at a new height, so they are numbered the same way.
+// Filament is weighed and priced with the settings of the configuration.
+pub fn analyze(gcode_path: &str, config: &config::Config) -> Result<Analysis, Failure> {
+    let acceleration = config.acceleration;
+    let file = File::open(gcode_path)
+        .map_err(|_| Failure::new(ErrorKind::Parse, format!("Unable to read file {}", gcode_path)))?;
+    let mut reader = gcode::GCodeReader::new(gcode_path, BufReader::new(file));
+    reader.assume_modes(config.input_modes());
+    let nodes: Vec<usize> = reader.by_ref().map(|layer| layer.nodes.len()).collect();
+    if let Some(e) = &reader.gcode().error {
+        return error::fail(ErrorKind::Parse, e.clone());
+    }
+
+    let mut analysis = Analysis::default();
+    let mut layer = LayerStats { nodes: nodes.first().copied().unwrap_or(0), ..Default::default() };
+    let segments = segments::read_in(gcode_path, config.input_modes());
+    let mut starts = segments::layer_starts(&segments).into_iter().peekable();
+    for (i, segment) in segments.iter().enumerate() {
+        if starts.next_if_eq(&i).is_some() {
+            let number = layer.layer + 1;
+            analysis.layers.push(layer);
+            layer = LayerStats {
+                layer: number,
+                z: segment.to.2,
+                nodes: nodes.get(number as usize).copied().unwrap_or(0),
+                ..Default::default()
+            };
+        }
+        add(&mut analysis, &mut layer, segment, acceleration);
+    }
+    analysis.layers.push(layer);
+    analysis.filament = FilamentUsage::new(analysis.layers.iter().map(|layer| layer.filament).sum(), config);
+
+    Ok(analysis)
+}
+
+fn add(analysis: &mut Analysis, layer: &mut LayerStats, segment: &Segment, acceleration: f64) {
+    let distance = distance_3d(segment.from, segment.to);
+    let time = simulator::move_time(distance, segment.feedrate, acceleration);
+    layer.time += time;
+    layer.filament += segment.extrusion;
+
+    if !segment.extrudes() {
+        layer.travel += distance;
+        analysis.travel_time += time;
+        return;
+    }
+
+    layer.extrusion += distance;
+
+    let name = if segment.feature.is_empty() { "Unknown" } else { segment.feature.as_str() };
+    let feature = match analysis.features.iter().position(|feature| feature.feature == name) {
+        Some(i) => &mut analysis.features[i],
+        None => {
+            analysis.features.push(FeatureStats { feature: name.to_string(), ..Default::default() });
+            analysis.features.last_mut().unwrap()
+        }
+    };
+    feature.extrusion += distance;
+    feature.filament += segment.extrusion;
+    feature.time += time;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    #[test]
+    fn retractions_take_back_filament() {
+        let dir = TempDir::new().unwrap();
+        let gcode_path = dir.path().join("retracting.gcode").display().to_string();
+        fs::write(&gcode_path, "G90\nM83\nG1 Z0.2\nG0 X10 Y10\nG1 E0.8\nG1 X20 Y10 E1\nG1 E-0.8\nG0 X30 Y30\nG1 E0.8\nG1 X40 Y30 E1\nG1 E-0.8\n").unwrap();
+
+        let analysis = analyze(&gcode_path, &config::Config::default()).unwrap();
+        assert!((analysis.filament.length - 2.0).abs() < 1e-9);
+        assert!((analysis.layers.iter().map(|layer| layer.filament).sum::<f64>() - 2.0).abs() < 1e-9);
+        assert!((analysis.features[0].filament - 3.6).abs() < 1e-9);
+    }
+}
@@ -1,451 +1,704 @@
-mod config;
-mod gcode;
-mod quick_math;
-
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use std::{env, fs, thread};
+use std::fs;
+use std::io;
 use std::path::Path;
-use log::info;
-use quick_math::distance_3d;
-
-/*
-TODO (problems) :
-- Low feedrate on some g-codes : issue related to acceleration commands
-- PrusaSlicer related commands need to be treated
-*/
-
-/*
-TODO (optimizations) :
-- Usage of Z-hops only
-- Problems separation according to size
-- Multiple layers
-- Deletion of negligible movements
-- LKH parameters (Initial tour for LKH, other parameters, etc...)
-- Usage of LKH via source code instead of calling the program
-*/
-
-struct Optimizer {
-    config: config::Config,
-
-    base_gcode: gcode::GCode,
-    optimized_gcode: gcode::GCode,
-
-    last_position: (f64, f64, f64),
-    current_layer: u32,
-    last_extrusion: f64,
+use std::process;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use tracing::{info, warn, Level};
+use tracing_subscriber::fmt::{self, format::FmtSpan, format::Writer, time::FormatTime};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+use tsp_gcode_optimizer::error::{fail, ErrorKind, Failure};
+use tsp_gcode_optimizer::{ab, analysis, batch, bench, cancel, check_gcode_file, commands, concat, config, console, diff, events, gcode, printer, report, segments, simulator, status, verify, visualize, Optimizer};
+
+#[derive(Parser)]
+#[command(version, about = "Reorders the moves of each layer of a G-code file to shorten travels")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Optimizing is the default, without a subcommand
+    #[command(flatten)]
+    optimize: OptimizeArgs,
+
+    #[arg(long, global = true, value_enum, default_value_t = ErrorFormat::Text, help = "Format of the error printed on failure, its kind also giving the exit code")]
+    error_format: ErrorFormat,
+
+    #[arg(short, long, global = true, action = ArgAction::Count, help = "Log to stderr too, -vv for debug messages and layer timings, -vvv for everything")]
+    verbose: u8,
+    #[arg(short, long, global = true, conflicts_with = "verbose", help = "Print errors only")]
+    quiet: bool,
 }
 
-impl Optimizer {
-    fn set_units(&mut self) {
-        self.optimized_gcode.stats.units_mode = self.base_gcode.stats.units_mode;
-    }
-
-    fn optimize(&mut self, gcode_path: &str) {
-        // Start of file
-        self.optimized_gcode.contents.push_str(";Generated with TSP G-code optimizer V0.1\n");
-        self.optimized_gcode.contents.push_str(&format!(";Original file: {}\n", self.base_gcode.file_path));
-        self.optimized_gcode.contents.push_str("G28\n");
-        match self.optimized_gcode.stats.units_mode {
-            gcode::UnitsMode::Millimeters => self.optimized_gcode.contents.push_str("G21\n"),
-            gcode::UnitsMode::Inches => self.optimized_gcode.contents.push_str("G20\n"),
-            _ => (),
-        }
-        match self.optimized_gcode.position_mode {
-            gcode::CoordinatesMode::Absolute => self.optimized_gcode.contents.push_str("G90\n"),
-            gcode::CoordinatesMode::Relative => self.optimized_gcode.contents.push_str("G91\n"),
-            _ => (),
-        }
-        match self.optimized_gcode.extruder_mode {
-            gcode::CoordinatesMode::Absolute => self.optimized_gcode.contents.push_str("M82\n"),
-            gcode::CoordinatesMode::Relative => self.optimized_gcode.contents.push_str("M83\n"),
-            _ => (),
-        }
-        self.optimized_gcode.contents.push_str(&self.base_gcode.start_commands);
-        self.optimized_gcode.contents.push_str("G92 E0\n");
-
-        // Optimize G-code
-        let layers = self.base_gcode.layers.to_vec();
-        let layers: &'static [gcode::GCodeLayer] = Box::leak(layers.into_boxed_slice());
-        let merges: Arc<Mutex<HashMap<u32, HashMap<u32, u32>>>> = Arc::new(Mutex::new(HashMap::new()));
-        let mut threads: HashMap<u32, std::thread::JoinHandle<()>> = HashMap::new();
-        for layer in layers.iter() {
-
-            let current_layer = self.current_layer;
-            let base_gcode_size = self.base_gcode.layers.len() - 1;
-            let config = self.config.clone();
-            let mrg = Arc::clone(&merges);
-
-            let handle= thread::spawn(move || {
-                // Do something
-                if layer.nodes.len() > 3 {
-                    let parameters_path = format!("{}.par", current_layer);
-                    let tsp_path = format!("{}.tsp", current_layer);
-                    let result_path = format!("result_{}.tour", current_layer);
-
-                    // Write parameters file
-                    Optimizer::write_parameters_file(&parameters_path, &tsp_path, &result_path, &config);
-
-                    // Write TSP file
-                    let current_layer_merges = Optimizer::write_tsp_file(&tsp_path, layer, current_layer, &config, base_gcode_size);
-                    let count = current_layer_merges.len();
-
-                    // Store merges
-                    mrg.lock().unwrap().insert(current_layer, current_layer_merges);
-
-                    // Run TSP solver
-                    println!("Running TSP solver for layer {}/{} ({} nodes)", current_layer, base_gcode_size, count);
-                    std::process::Command::new(&config.program)
-                        .arg(&parameters_path)
-                        .output()
-                        .expect("Failed to run TSP solver");
-                } else {
-                    println!("Skipping layer {}/{} ({} node-s)", current_layer, base_gcode_size, layer.nodes.len());
-                }
-            });
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ErrorFormat {
+    Text,
+    Json,
+}
 
-            // Store thread
-            threads.insert(self.current_layer, handle);
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Optimize a G-code file, every file of a directory, or stdin to stdout with -")]
+    Optimize(Box<OptimizeArgs>),
+    #[command(about = "Join G-code files printing one object each into a sequential print and optimize it")]
+    Concat {
+        #[arg(required = true, help = "G-code files, the start script of the first one and the end script of the last one being kept")]
+        files: Vec<String>,
+        #[arg(short, long, help = "Concatenated G-code file, optimized to <output>_optimized.gcode")]
+        output: String,
+        #[arg(long, help = "Print the objects in the order given rather than the one shortening travels between them")]
+        keep_order: bool,
+        #[arg(short, long, help = "Configuration file [default: config.json next to the executable, or the builtin solver's defaults]")]
+        config: Option<String>,
+        #[arg(short, long, help = "Overwrite existing output files")]
+        force: bool,
+    },
+    #[command(about = "Compare solver configurations on a G-code file")]
+    Bench {
+        #[arg(help = "G-code file")]
+        gcode: String,
+        #[arg(required = true, help = "Configuration files to compare")]
+        configs: Vec<String>,
+    },
+    #[command(about = "Check that an optimized G-code extrudes every segment of the original exactly once")]
+    Verify {
+        #[arg(help = "Original G-code file")]
+        original: String,
+        #[arg(help = "Optimized G-code file")]
+        optimized: String,
+        #[arg(long, default_value_t = 0.0001, help = "Largest difference of extrusion between matching segments")]
+        tolerance: f64,
+        #[arg(long, default_value_t = 3, help = "Decimals the optimized G-code was written with, positions of the original being rounded to them")]
+        position_decimals: usize,
+    },
+    #[command(about = "Compare the extrusion segments of two G-code files")]
+    Diff {
+        #[arg(help = "First G-code file")]
+        first: String,
+        #[arg(help = "Second G-code file")]
+        second: String,
+    },
+    #[command(about = "Print the layers, features, distances and estimated time of a G-code file without optimizing it")]
+    Stats {
+        #[arg(help = "G-code file")]
+        gcode: String,
+        #[arg(short, long, help = "Configuration file giving the acceleration used to estimate times")]
+        config: Option<String>,
+        #[arg(short, long, help = "Write the layers to a CSV file, or everything to a JSON file if it ends with .json")]
+        export: Option<String>,
+    },
+    #[command(subcommand, about = "Manage configuration files")]
+    Config(ConfigCommand),
+    #[command(about = "Print the completion script of a shell")]
+    Completions {
+        #[arg(help = "Shell to complete in")]
+        shell: Shell,
+    },
+    #[cfg(feature = "server")]
+    #[command(about = "Optimize uploaded G-code files over HTTP, streaming their progress")]
+    Serve {
+        #[arg(short, long, default_value_t = 8080, help = "Port to listen on")]
+        port: u16,
+        #[arg(short, long, help = "Configuration file of the solver, jobs may change everything but its program and threads")]
+        config: Option<String>,
+    },
+}
 
-            // Update current position
-            self.current_layer += 1;
-        }
+#[derive(Subcommand)]
+enum ConfigCommand {
+    #[command(about = "Write a commented configuration with every default, used when no other is given")]
+    Init {
+        #[arg(help = "Configuration file [default: config.json next to the executable]")]
+        path: Option<String>,
+        #[arg(short, long, help = "Overwrite an existing configuration file")]
+        force: bool,
+    },
+}
 
-        // Reset position
-        self.current_layer = 0;
+#[derive(Args, Default)]
+struct OptimizeArgs {
+    #[arg(required = true, help = "G-code file, directory of G-code files, or - for stdin")]
+    gcode: Option<String>,
+    #[arg(short, long, help = "Configuration file [default: config.json next to the executable, or the builtin solver's defaults]")]
+    config: Option<String>,
+    #[arg(short, long, help = "Printer profile such as prusa-mk4, from tsp-gcode-optimizer/printers/<name>.toml of the user's configuration directory, or a TOML file")]
+    printer: Option<String>,
+    #[arg(short, long, help = "Optimized G-code file [default: <input>_optimized.gcode, stdout for stdin]")]
+    output: Option<String>,
+    #[arg(long, conflicts_with = "output", help = "Replace the input file once optimized")]
+    in_place: bool,
+    #[arg(short, long, help = "Overwrite existing output files")]
+    force: bool,
+    #[arg(long, conflicts_with_all = ["output", "in_place"], help = "Print the projected savings of each layer without writing anything")]
+    dry_run: bool,
+    #[arg(long, value_name = "FIELD=VALUE", help = "Set a field of the configuration, such as num_runs=5, overriding the file and TSP_GCODE_<FIELD> environment variables")]
+    set: Vec<String>,
+    #[arg(short, long, help = "Solver threads, overriding the configuration")]
+    threads: Option<usize>,
+    #[arg(long, help = "Layers to optimize, such as 2-10,15,20- (others keep their order)")]
+    layers: Option<String>,
+    #[arg(long, value_enum, help = "Solver, overriding the configuration")]
+    solver: Option<SolverArg>,
+    #[arg(long, value_name = "DIR", help = "Keep the files of each LKH run and every solved problem and tour in TSPLIB format, in DIR/layer_<n>")]
+    keep_intermediates: Option<String>,
+    #[arg(long, help = "Write the smallest file the printer reads the same way, without comments, repeated modes or repeated values")]
+    minify: bool,
+    #[arg(long, help = "Number every line and add its checksum, starting with an M110 reset, for printers streamed over serial")]
+    line_numbers: bool,
+    #[arg(long, help = "Reject G-code moving before setting its position and extruder modes, instead of assuming them")]
+    strict_modes: bool,
+    #[arg(long, value_name = "DIR", help = "Write which original nodes each TSP node stands for and the length of each merged chain, to DIR/layer_<n>.merges.csv")]
+    merge_report: Option<String>,
+    #[arg(long, value_name = "FILE", help = "Rules classifying unknown commands, one \"<command> barrier|passthrough|start|end\" per line")]
+    command_rules: Option<String>,
+    #[arg(long, help = "Reuse the layers solved by an interrupted run")]
+    resume: bool,
+    #[arg(long, value_name = "CONFIG", num_args = 2.., conflicts_with_all = ["config", "resume", "dry_run"], help = "Solve with each configuration file and write the output of the one with the shortest estimated time")]
+    ab: Option<Vec<String>>,
+    #[arg(long, value_name = "FILE", help = "CSV of the travels and solve time of each layer [default: <input>.csv], the layers kept in their original order going to <name>.skipped.csv")]
+    csv: Option<String>,
+    #[arg(long, conflicts_with = "csv", help = "Write no CSV of the layers")]
+    no_csv: bool,
+    #[arg(long, num_args = 2, value_names = ["FORMAT", "FILE"], help = "Write a report of the optimization as json or as an html page, such as --report html report.html")]
+    report: Option<Vec<String>>,
+    #[arg(long, value_enum, conflicts_with_all = ["dry_run", "in_place"], help = "Draw the original and optimized paths of each layer side by side, to <input>.layer<n>.svg, or the optimized path move by move to <input>.layer<n>.animated.svg")]
+    visualize: Option<VisualizeFormat>,
+    #[arg(long, requires = "visualize", help = "Layers to draw, such as 2-10,15,20- [default: the layers optimized]")]
+    visualize_layers: Option<String>,
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dry_run", "in_place"], help = "Draw where travels pass over the bed before (left) and after (right) optimization, as a PNG heatmap")]
+    heatmap: Option<String>,
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run", help = "Export the optimized toolpath as lines to a .obj or .gltf file, for Blender or three.js viewers")]
+    export_toolpath: Option<String>,
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Text, help = "Progress as status messages, as JSON lines on stdout with messages moved to stderr, or as a progress bar")]
+    progress: ProgressFormat,
+}
 
-        for layer in layers.iter() {
-            let _ = threads.remove(&self.current_layer).unwrap().join();
-            println!("Processing result of layer {}/{}", self.current_layer, self.base_gcode.layers.len() - 1);
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+enum ProgressFormat {
+    #[default]
+    Text,
+    Json,
+    #[cfg(feature = "tui")]
+    Bar,
+}
 
-            if layer.nodes.len() > 3 {
-                let parameters_path = format!("{}.par", self.current_layer);
-                let tsp_path = format!("{}.tsp", self.current_layer);
-                let result_path = format!("result_{}.tour", self.current_layer);
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Json,
+    Html,
+}
 
-                // Read result file
-                let result = fs::read_to_string(&result_path)
-                    .unwrap_or_else(|_| panic!("Unable to read file {}", result_path));
+#[derive(Clone, Copy, ValueEnum)]
+enum VisualizeFormat {
+    Svg,
+    // Optimized path alone, drawn move by move
+    Animated,
+}
 
-                self.read_optimized_tour(&result, layer, merges.lock().unwrap().clone());
+#[derive(Clone, Copy, ValueEnum)]
+enum SolverArg {
+    Lkh,
+    Builtin,
+}
 
-                // Clean up
-                fs::remove_file(&parameters_path).unwrap();
-                fs::remove_file(&tsp_path).unwrap();
-                fs::remove_file(&result_path).unwrap();
-            } else {
-                self.add_line(layer, 1, 1);
-                for i in 2..=layer.nodes.len() as i32 {
-                    self.add_line(layer, i - 1, i);
-                }
-            }
+// Exit codes: 1 error, 2 usage, 3 G-code file, 4 configuration, 5 solver missing, 6 solver failed,
+// 7 verification failed, 130 interrupted twice
+fn main() {
+    let cli = Cli::parse();
+    let error_format = cli.error_format;
+    init_logging(cli.verbose);
+    console::set_quiet(cli.quiet);
+
+    // Errors stop the tool, their kind giving the exit code
+    if let Err(failure) = run(cli) {
+        match error_format {
+            ErrorFormat::Text => eprintln!("Error: {}", failure.message),
+            ErrorFormat::Json => eprintln!("{}", serde_json::json!({ "error": failure, "code": failure.kind.code() })),
+        }
+        process::exit(failure.kind.code());
+    }
+}
 
-            // Write buffer
-            self.optimized_gcode.contents.push_str(&layer.end_commands);
+fn run(cli: Cli) -> Result<(), Failure> {
+    match cli.command {
+        Some(Command::Optimize(args)) => optimize(*args),
+        Some(Command::Bench { gcode, configs }) => {
+            // Compare solver configurations on a file
+            check_gcode_file(&gcode).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+            cancel::handle_interrupts();
+            set_log_file(&format!("{}.bench.log", gcode))?;
+            bench::run(&gcode, &configs)
+        }
+        Some(Command::Concat { files, output, keep_order, config, force }) => {
+            for file in &files {
+                check_gcode_file(file).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+            }
+            if !force && Path::new(&output).exists() {
+                return fail(ErrorKind::Usage, format!("Output file {} already exists, use --force to overwrite it", output));
+            }
+            let gcode = concat::concat(&files, keep_order)?;
+            fs::write(&output, gcode).map_err(|_| Failure::new(ErrorKind::Error, format!("Unable to write file {}", output)))?;
+            status!("Concatenated {} file-s into {}\n", files.len(), output);
+            optimize(OptimizeArgs { gcode: Some(output), config, force, ..Default::default() })
+        }
+        Some(Command::Verify { original, optimized, tolerance, position_decimals }) => verify(&original, &optimized, tolerance, position_decimals),
+        Some(Command::Diff { first, second }) => {
+            check_gcode_file(&first).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+            check_gcode_file(&second).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+            diff::diff(&first, &second).display();
+            Ok(())
+        }
+        Some(Command::Stats { gcode, config, export }) => stats(&gcode, config.or_else(local_config).as_deref(), export.as_deref()),
+        Some(Command::Config(ConfigCommand::Init { path, force })) => config_init(path, force),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Cli::command(), env!("CARGO_BIN_NAME"), &mut io::stdout());
+            Ok(())
+        }
+        #[cfg(feature = "server")]
+        Some(Command::Serve { port, config }) => {
+            let config = config.or_else(local_config).as_deref().map_or_else(|| config::complete(config::defaults()), config::read_config)?;
+            set_log_file("serve.log")?;
+            tsp_gcode_optimizer::server::serve(port, config)
+        }
+        None => optimize(cli.optimize),
+    }
+}
 
-            // Update current position
-            self.current_layer += 1;
+fn optimize(args: OptimizeArgs) -> Result<(), Failure> {
+    let now = Instant::now();
+    let gcode_path = &args.gcode.ok_or_else(|| Failure::new(ErrorKind::Usage, "No G-code file given"))?;
+
+    // Read the configuration file, options applied before it is completed
+    let profile = args.printer.as_deref().map(|name| {
+        let profile = printer::load(name).map_err(|e| Failure::new(ErrorKind::Config, e))?;
+        status!("Printer: {}", profile.name);
+        Ok(profile)
+    }).transpose()?;
+    let layered = |path: Option<&str>| {
+        let mut config = config::parse_layered(path, profile.clone(), &args.set)?;
+        if let Some(threads) = args.threads {
+            config.threads = threads;
+        }
+        if let Some(layers) = &args.layers {
+            config.layers = config::parse_layers(layers).map_err(|e| Failure::new(ErrorKind::Usage, e))?;
+        }
+        match args.solver {
+            Some(SolverArg::Lkh) => config.solver = config::Solver::Lkh,
+            Some(SolverArg::Builtin) => config.solver = config::Solver::Builtin,
+            None => (),
+        }
+        if let Some(dir) = &args.keep_intermediates {
+            config.keep_intermediates = dir.clone();
+        }
+        if args.minify {
+            config.minify = true;
+        }
+        if args.line_numbers {
+            config.line_numbers = true;
+        }
+        if args.strict_modes {
+            config.strict_modes = true;
+        }
+        if let Some(dir) = &args.merge_report {
+            config.merge_report = dir.clone();
+        }
+        config::complete(config)
+    };
+    let ab_configs: Option<Vec<(String, config::Config)>> = args.ab.as_ref()
+        .map(|paths| paths.iter().map(|path| Ok((path.clone(), layered(Some(path))?))).collect())
+        .transpose()?;
+    let mut config = match &ab_configs {
+        Some(configs) => configs[0].1.clone(),
+        None => layered(args.config.clone().or_else(local_config).as_deref())?,
+    };
+    let mut effective_config = serde_json::to_string(&config).unwrap();
+    let visualized_layers = args.visualize_layers.as_deref()
+        .map(|layers| config::parse_layers(layers).map_err(|e| Failure::new(ErrorKind::Usage, e)))
+        .transpose()?;
+    let report_file = args.report.as_deref().map(|report| {
+        let format = ReportFormat::from_str(&report[0], true)
+            .map_err(|_| Failure::new(ErrorKind::Usage, format!("Unknown report format {}, expected json or html", report[0])))?;
+        Ok((format, report[1].clone()))
+    }).transpose()?;
+    let rules = args.command_rules.as_deref()
+        .map_or(Ok(commands::CommandRules::default()), commands::CommandRules::read)
+        .map_err(|e| Failure::new(ErrorKind::Config, e))?;
+
+    // Ctrl-C finishes the output unoptimized, never leaving temporary files behind
+    cancel::handle_interrupts();
+
+    // Optimize every file of a directory
+    if Path::new(gcode_path).is_dir() {
+        if args.output.is_some() {
+            return fail(ErrorKind::Usage, format!("No output file can be given for directory {}", gcode_path));
+        }
+        if args.dry_run {
+            return fail(ErrorKind::Usage, format!("Dry runs take a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.progress != ProgressFormat::Text {
+            return fail(ErrorKind::Usage, format!("Progress events take a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.csv.is_some() {
+            return fail(ErrorKind::Usage, format!("No CSV file can be given for directory {}, each file gets its own", gcode_path));
+        }
+        if report_file.is_some() {
+            return fail(ErrorKind::Usage, format!("Reports take a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.keep_intermediates.is_some() {
+            return fail(ErrorKind::Usage, format!("Solver files are kept for a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.merge_report.is_some() {
+            return fail(ErrorKind::Usage, format!("Merge reports are written for a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.ab.is_some() {
+            return fail(ErrorKind::Usage, format!("Configurations are compared on a single G-code file, {} is a directory", gcode_path));
+        }
+        if args.visualize.is_some() || args.heatmap.is_some() || args.export_toolpath.is_some() {
+            return fail(ErrorKind::Usage, format!("Drawings take a single G-code file, {} is a directory", gcode_path));
         }
 
-        // End of file
-        self.optimized_gcode.contents.push_str("M107\n");
-        self.optimized_gcode.contents.push_str(&self.base_gcode.end_commands);
+        set_log_file(&format!("{}/batch.log", gcode_path.trim_end_matches('/')))?;
+        info!("Configuration: {}", effective_config);
+        let failed = batch::run(&config, gcode_path, &rules, args.resume, args.force, args.in_place, !args.no_csv)?;
 
-        // Store nodes and merges sizes into a CSV file
-        let csv_path = format!("{}.csv", gcode_path);
-        let mut csv = String::new();
-        csv.push_str("Layer,Nodes,Merged\n");
-        for (layer, merges) in merges.lock().unwrap().iter() {
-            csv.push_str(&format!("{},{},{}\n", layer, self.base_gcode.layers[*layer as usize].nodes.len(), merges.len()));
+        let time = elapsed_time(now);
+        status!("\nBatch completed in {}", time);
+        info!("Batch completed in {}", time);
+        if failed > 0 {
+            return fail(ErrorKind::Error, format!("{} file-s of {} failed", failed, gcode_path));
         }
-        fs::write(&csv_path, csv)
-            .unwrap_or_else(|_| panic!("Unable to write file {}", csv_path));
+        return Ok(());
     }
 
-    fn write_parameters_file(path: &str, tsp_path: &str, result_path: &str, config: &config::Config) {
-        let parameters = format!(
-            "PROBLEM_FILE = {}\n\
-            TOUR_FILE = {}\n\
-            PRECISION = {}\n\
-            RUNS = {}\n\
-            CANDIDATE_SET_TYPE = POPMUSIC\n",
-            tsp_path, 
-            result_path, 
-            config.precision, 
-            config.num_runs
-        );
-
-        fs::write(path, parameters)
-            .unwrap_or_else(|_| panic!("Unable to write file {}", path));
+    // Setup optimizer, the input file is checked before anything is written
+    if gcode_path != gcode::STDIO {
+        check_gcode_file(gcode_path).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
     }
 
-    fn write_tsp_file(path: &str, layer: &gcode::GCodeLayer, current_layer: u32,
-        config: &config::Config, base_gcode_size: usize) -> HashMap<u32, u32> {
-
-        let mut merges: HashMap<u32, u32> = HashMap::new();
-
-        let mut tsp = "EDGE_WEIGHT_TYPE: EUC_3D\nNODE_COORD_SECTION\n".to_string();
-
-        let mut keys: Vec<u32> = Vec::new();
-
-        // Write nodes
-        let mut count = 0;
-        let mut extruded = false;
-        let mut last_position = (0.0, 0.0, 0.0);
-        let mut current_distance = 0.0;
-        for (i, node) in layer.nodes.iter().enumerate() {
-            let extrude = layer.extrusions.contains_key(&(i as u32 + 1));
+    // The winner's layers are left in the checkpoint, writing its output resumes from them
+    if let Some(configs) = &ab_configs {
+        if gcode_path == gcode::STDIO {
+            return fail(ErrorKind::Usage, "Configurations are compared on a G-code file, not on stdin");
+        }
+        config = configs[ab::run(gcode_path, configs, &rules)?].1.clone();
+        effective_config = serde_json::to_string(&config).unwrap();
+    }
+    let mut builder = Optimizer::builder()
+        .input(gcode_path)
+        .config(config)
+        .resume(args.resume || ab_configs.is_some())
+        .overwrite(args.force)
+        .in_place(args.in_place)
+        .dry_run(args.dry_run)
+        .command_handler(move |command| rules.classify(command));
+    if let Some(output) = &args.output {
+        builder = builder.output(output);
+    }
+    if args.no_csv {
+        builder = builder.csv(None);
+    } else if let Some(csv) = &args.csv {
+        builder = builder.csv(Some(csv));
+    }
 
-            if !extrude || !extruded {
-                count += 1;
-                tsp.push_str(&format!("{} {:.3} {:.3} {:.3}\n", count, node.0, node.1, node.2));
-                merges.insert(count, i as u32 + 1);
-                if extrude {
-                    keys.push(count);
-                } else {
-                    current_distance = 0.0;
-                }
-            } else {
-                current_distance += distance_3d(last_position, *node);
-                if current_distance > config.max_merge_length {
-                    count += 1;
-                    tsp.push_str(&format!("{} {:.3} {:.3} {:.3}\n", count, node.0, node.1, node.2));
-                    merges.insert(count, i as u32 + 1);
-                    current_distance = 0.0;
-                    count += 1;
-                    tsp.push_str(&format!("{} {:.3} {:.3} {:.3}\n", count, node.0, node.1, node.2));
-                    merges.insert(count, i as u32 + 1);
-                    keys.push(count);
+    // One JSON object per line on stdout, as long as it does not carry the G-code
+    let mut progress = None;
+    #[cfg(feature = "tui")]
+    if args.progress == ProgressFormat::Bar {
+        console::set_quiet(true);
+        let (sender, receiver) = mpsc::channel();
+        builder = builder.events(sender);
+        progress = Some(thread::spawn(move || tsp_gcode_optimizer::tui::show(receiver)));
+    }
+    if args.progress == ProgressFormat::Json {
+        if args.output.as_deref().unwrap_or(gcode_path) == gcode::STDIO {
+            return fail(ErrorKind::Usage, "JSON progress is written to stdout, which carries the optimized G-code");
+        }
+        console::set_stderr(true);
+        let (sender, receiver) = mpsc::channel();
+        builder = builder.events(sender);
+        progress = Some(thread::spawn(move || {
+            for event in receiver {
+                println!("{}", serde_json::to_string(&event).unwrap());
+                if matches!(event, events::ProgressEvent::Finished { .. }) {
+                    break;
                 }
             }
-            extruded = extrude;
-            last_position = *node;
-        }
-        if extruded {
-            count += 1;
-            tsp.push_str(&format!("{} {:.3} {:.3} {:.3}\n", count, layer.nodes[layer.nodes.len() - 1].0, layer.nodes[layer.nodes.len() - 1].1, layer.nodes[layer.nodes.len() - 1].2));
-            merges.insert(count, layer.nodes.len() as u32);
+        }));
+    }
+    let mut optimizer = builder.build()
+        .map_err(|e| Failure::new(ErrorKind::Usage, e))?;
+    if (args.visualize.is_some() || args.heatmap.is_some()) && (gcode_path == gcode::STDIO || optimizer.output_path() == gcode::STDIO) {
+        return fail(ErrorKind::Usage, "Drawings need the original and optimized G-code in files");
+    }
+    if let Some(export_path) = &args.export_toolpath {
+        if optimizer.output_path() == gcode::STDIO {
+            return fail(ErrorKind::Usage, "Toolpath exports need the optimized G-code in a file");
         }
+        visualize::toolpath_format(export_path).map_err(|e| Failure::new(ErrorKind::Usage, e))?;
+    }
 
-        // Write mandatory edges
-        tsp.push_str("FIXED_EDGES_SECTION\n");
-        for key in keys.iter() {
-            tsp.push_str(&format!("{} {}\n", key, key + 1));
+    // Streams have no file to log along with, dry runs leave no file behind
+    if gcode_path != gcode::STDIO && !args.dry_run {
+        set_log_file(&format!("{}.log", gcode_path))?;
+    }
+    info!("Configuration: {}", effective_config);
+
+    let report = match optimizer.optimize() {
+        Ok(report) => report,
+        Err(failure) => {
+            // Progress of a failed optimization ends without finishing, once its events are dropped
+            drop(optimizer);
+            if let Some(progress) = progress {
+                let _ = progress.join();
+                console::set_quiet(false);
+            }
+            return Err(failure);
         }
-        tsp.push_str(&format!("{} {}\n", count, 1));
-        tsp.push_str("-1\nEOF\n");
-
-        tsp = format!(
-            "NAME: {}\n\
-            COMMENT: {}\n\
-            TYPE: TSP\n\
-            DIMENSION: {}\n\
-            {}",
-            format_args!("Layer {}", current_layer),
-            format_args!("Print optimization for current_layer {}", current_layer),
-            count, 
-            tsp
-        );
-
-        println!("Merging layer {}/{} ({} -> {} nodes)", current_layer, base_gcode_size, layer.nodes.len(), count);
-        info!("Merged {} nodes into {} for layer {}", layer.nodes.len(), count, current_layer);
-
-        fs::write(path, tsp)
-            .unwrap_or_else(|_| panic!("Unable to write file {}", path));
-
-        merges
+    };
+    if let Some(progress) = progress {
+        let _ = progress.join();
+        console::set_quiet(false);
     }
 
-    fn read_optimized_tour(&mut self, result: &str, layer: &gcode::GCodeLayer, merges: HashMap<u32, HashMap<u32, u32>>) {
-        let mut process = false;
-        let mut prev_node: i32 = 1;
-
-        for line in result.lines() {
-            if process {
+    // Display stats
+    status!("\nBase G-code stats:");
+    optimizer.base_stats().display();
+    optimizer.base_stats().log("Base G-code".to_string());
+    status!("\nOptimized G-code stats:");
+    optimizer.optimized_stats().display();
+    optimizer.optimized_stats().log("Optimized G-code".to_string());
+
+    // Reordering moves must leave the material used as it was
+    let filament = analysis::FilamentUsage::new(report.base_filament, optimizer.config());
+    filament.display();
+    info!("Filament: {:.2} mm, {:.2} g", filament.length, filament.weight);
+    if (report.base_filament - report.optimized_filament).abs() > 1e-6 * report.base_filament.max(1.0) {
+        status!("Warning: optimized G-code pushes {:.5} mm of filament, the original {:.5} mm", report.optimized_filament, report.base_filament);
+        warn!("Filament changed from {:.5} mm to {:.5} mm", report.base_filament, report.optimized_filament);
+    }
 
-                // Gather next node position
-                let node = line.parse::<i32>().unwrap();
-                if node == -1 {
-                    break;
-                }
+    if report.flow_limited > 0 {
+        status!("Warning: {} extrusion-s slowed down to stay under {} mm³/s", report.flow_limited, optimizer.config().max_volumetric_flow);
+        warn!("{} extrusion-s slowed down to the maximum volumetric flow", report.flow_limited);
+    }
+    if report.feedrate_capped > 0 {
+        status!("Warning: {} move-s slowed down to the maximum feedrates of {}", report.feedrate_capped, optimizer.config().printer.as_ref().map_or("", |printer| printer.name.as_str()));
+        warn!("{} move-s slowed down to the maximum feedrates of the printer", report.feedrate_capped);
+    }
 
-                let from = merges.get(&self.current_layer).unwrap().get(&(prev_node as u32)).unwrap();
-                let to = merges.get(&self.current_layer).unwrap().get(&(node as u32)).unwrap();
-
-                if node - prev_node == 1 {
-                    for i in *from..*to {
-                        self.add_line(layer, i as i32, i as i32 + 1);
-                    }
-                } else if node - prev_node == -1 {
-                    for i in (*to..*from).rev() {
-                        self.add_line(layer, i as i32 + 1, i as i32);
-                    }
-                } else {
-                    self.add_line(layer, *from as i32, *to as i32);
-                }
+    if optimizer.config().objective == config::Objective::Time {
+        status!("\nEstimated time saved: {:.2} min", optimizer.time_saved());
+        info!("Estimated time saved: {:.2} min", optimizer.time_saved());
+    }
 
-                // Update previous node
-                prev_node = node;
+    // Both files planned move by move, streams and dry runs leave nothing to read back
+    let mut comparison = report::Comparison::new(&report);
+    if !args.dry_run && !args.in_place && gcode_path != gcode::STDIO && optimizer.output_path() != gcode::STDIO {
+        let limits = simulator::Limits::new(optimizer.config());
+        let base_time = simulator::print_time(&segments::read_in(gcode_path, optimizer.config().input_modes()), &limits);
+        let optimized_time = simulator::print_time(&segments::read(optimizer.output_path()), &limits);
+        comparison.time = report::Change::new(base_time, optimized_time);
+        status!("\nEstimated print time: {:.2} min -> {:.2} min ({:.2} min saved)",
+            base_time / 60.0, optimized_time / 60.0, (base_time - optimized_time) / 60.0);
+        info!("Estimated print time: {:.2} min -> {:.2} min", base_time / 60.0, optimized_time / 60.0);
+    }
+    comparison.display();
+    comparison.log();
+    report::display_features(&report.features);
+    report::display_tools(&report.tools);
+    report::display_top_layers(&report.per_layer, 10);
+
+    if args.dry_run {
+        report::display_layers(&report.per_layer);
+    }
 
-            } else {
-                process = line.starts_with("TOUR_SECTION");
-            }
+    match report_file {
+        Some((ReportFormat::Json, path)) => {
+            report::write_json(&report, &comparison, gcode_path, optimizer.output_path(), &path).map_err(Failure::error)?;
+            status!("Report written to {}", path);
+        }
+        Some((ReportFormat::Html, path)) => {
+            report::write_html(&report, &comparison, gcode_path, optimizer.output_path(), &path).map_err(Failure::error)?;
+            status!("Report written to {}", path);
         }
+        None => (),
     }
 
-    fn add_line(&mut self, layer: &gcode::GCodeLayer, origin: i32, destination: i32) {
-        let pno = origin as u32;
-        let no = destination as u32;
-        
-        let n = layer.nodes[destination as usize - 1];
-
-        let mut x = n.0;
-        let mut y = n.1;
-        let mut z = n.2;
+    if let Some(format) = args.visualize {
+        let selected = |layer| match &visualized_layers {
+            Some(layers) => layers.iter().any(|&(first, last)| first <= layer && layer <= last),
+            None => optimizer.config().optimizes_layer(layer),
+        };
+        let paths = match format {
+            VisualizeFormat::Svg => visualize::write_svgs(gcode_path, optimizer.output_path(), selected),
+            VisualizeFormat::Animated => visualize::write_animations(gcode_path, optimizer.output_path(), selected),
+        }.map_err(Failure::error)?;
+        status!("{} layer drawing-s written next to {}", paths.len(), gcode_path);
+    }
+    if let Some(heatmap_path) = &args.heatmap {
+        visualize::write_heatmap(gcode_path, optimizer.output_path(), heatmap_path).map_err(Failure::error)?;
+        status!("Travel heatmap written to {}", heatmap_path);
+    }
+    if let Some(export_path) = &args.export_toolpath {
+        visualize::export_toolpath(optimizer.output_path(), export_path)
+            .map_err(|e| Failure::new(ErrorKind::Usage, e))?;
+        status!("Toolpath exported to {}", export_path);
+    }
 
-        if self.optimized_gcode.position_mode == gcode::CoordinatesMode::Relative {
-            let p = layer.nodes[origin as usize - 1];
+    report.warnings.display();
 
-            x -= p.0;
-            y -= p.1;
-            z -= p.2;
-        }
+    status!("\nTime per phase (summed over threads):");
+    optimizer.times().display();
+    optimizer.times().log("Total times".to_string());
 
-        // Prepare new g-code line
-        let mut text = format!("X{} Y{} Z{}", x, y, z);
+    // Time
+    let time = elapsed_time(now);
+    if cancel::is_cancelled() {
+        status!("\nOptimization cancelled after {}", time);
+        info!("Cancelled after {}", time);
+    } else {
+        status!("\nOptimization completed in {}", time);
+        info!("Completed in {}", time);
+    }
 
-        if (destination - origin == 1 && layer.extrusions.contains_key(&pno)) ||
-            (destination - origin == -1 && layer.extrusions.contains_key(&no)) {
-            
-            // Take a change of direction into account
-            let mut e = layer.extrusions.get(
-                if destination - origin == 1 { &pno }
-                else { &no }
-            ).unwrap();
-            
-            let extr = e + self.last_extrusion;
-            if self.optimized_gcode.extruder_mode == gcode::CoordinatesMode::Absolute {
-                e = &extr;
-            }
-            
-            self.last_extrusion = *e;
+    // The output is complete all the same, failed layers keeping their order
+    if optimizer.solver_failures() > 0 {
+        return fail(ErrorKind::SolverFailed, format!("TSP solver failed on {} problem-s, kept in their original order", optimizer.solver_failures()));
+    }
+    Ok(())
+}
 
-            text = format!("G1 {} E{:.5}", text, e);
-            self.optimized_gcode.stats.increment_extrusion(distance_3d(self.last_position, n));
-        } else {
-            text = format!("G0 {}", text);
-            self.optimized_gcode.stats.increment_travel(distance_3d(self.last_position, n));
-        }
+// Fails if the optimized G-code does not extrude what the original does
+fn verify(gcode_path: &str, optimized_path: &str, tolerance: f64, position_decimals: usize) -> Result<(), Failure> {
+    check_gcode_file(gcode_path).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+    check_gcode_file(optimized_path).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
 
-        // Add feedrate if needed
-        let f = layer.feedrates.get(
-            if destination - origin == 1 { &pno }
-            else if destination - origin == -1 { &no }
-            else { &0 } // Will give default travel feedrate, this is used for new travel movements
-        );
+    let verification = verify::verify(gcode_path, optimized_path, tolerance, position_decimals, commands::Modes::default());
+    verification.display();
+    if !verification.is_ok() {
+        return fail(ErrorKind::Verification, format!("{} does not extrude what {} does", optimized_path, gcode_path));
+    }
+    Ok(())
+}
 
-        if f > Some(&0.0) {
-            text = format!("{} F{:.3}", text, f.unwrap());
-        }
+// Reads a G-code file for its layers and features, nothing is solved nor written but the export
+fn stats(gcode_path: &str, config_path: Option<&str>, export_path: Option<&str>) -> Result<(), Failure> {
+    check_gcode_file(gcode_path).map_err(|e| Failure::new(ErrorKind::Parse, e))?;
+    let config = config_path.map_or_else(|| Ok(config::Config::default()), config::read_config)?;
 
-        // Add new line to optimized G-code
-        self.optimized_gcode.contents.push_str(&text);
-        self.optimized_gcode.contents.push('\n');
+    let analysis = analysis::analyze(gcode_path, &config)?;
+    analysis.display();
 
-        // Update previous node
-        self.last_position = n;
+    if let Some(export_path) = export_path {
+        analysis.export(export_path).map_err(Failure::error)?;
+        status!("\nStats written to {}", export_path);
     }
+    Ok(())
 }
 
-fn main() {
-    let now = Instant::now();
-
-    // Get both file paths from command line arguments
-    let args: Vec<String> = env::args().collect();
+// Configuration written by config init next to the executable, used when none is given
+fn local_config() -> Option<String> {
+    let path = std::env::current_exe().ok()?.with_file_name("config.json");
+    path.is_file().then(|| {
+        status!("Using configuration {}", path.display());
+        path.display().to_string()
+    })
+}
 
-    if args.len() != 3 {
-        panic!("Usage: {} <config file> <G-code file>", args[0]);
+fn config_init(path: Option<String>, force: bool) -> Result<(), Failure> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let exe = std::env::current_exe()
+                .map_err(|_| Failure::new(ErrorKind::Error, "Unable to locate the executable"))?;
+            exe.with_file_name("config.json").display().to_string()
+        }
+    };
+    if Path::new(&path).exists() && !force {
+        return fail(ErrorKind::Usage, format!("Configuration file {} already exists, use --force to overwrite it", path));
     }
 
-    let config_path = &args[1];
-    let gcode_path = &args[2];
+    fs::write(&path, config::TEMPLATE)
+        .map_err(|_| Failure::new(ErrorKind::Error, format!("Unable to write file {}", path)))?;
+    status!("Configuration written to {}", path);
+    Ok(())
+}
 
-    // Read the configuration file
-    let config = config::read_config(config_path);
+// Log file of the run, set once its path is known
+static LOG_FILE: Mutex<Option<fs::File>> = Mutex::new(None);
 
-    let path_gcode = Path::new(gcode_path);
+// Writes to the log file, or nowhere until there is one
+struct LogFile;
 
-    // Check that the G-code file exists
-    if !path_gcode.exists() {
-        panic!("File {} does not exist", gcode_path);
+impl io::Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
     }
 
-    // Check that file has a .gcode extension
-    if path_gcode.extension().unwrap_or_default() != "gcode" {
-        panic!("File {} does not have a .gcode extension", gcode_path);
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
     }
+}
 
-    // Read contents of G-code file
-    let contents = fs::read_to_string(gcode_path)
-        .unwrap_or_else(|_| panic!("Unable to read file {}", gcode_path));
-
-    // Check that G-code file is not empty
-    if contents.is_empty() {
-        panic!("File {} is empty", gcode_path);
-    }
+struct LocalTime;
 
-    // Set log file
-    let log_path = format!("{}.log", gcode_path);
-    if Path::new(&log_path).exists() {
-        fs::remove_file(&log_path)
-            .unwrap_or_else(|_| panic!("Unable to replace {}", log_path));
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"))
     }
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                message
-            ))
-        })
-        .chain(fern::log_file(&log_path).unwrap())
-        .apply()
-        .unwrap_or_else(|_| panic!("Unable to set log file {}", log_path));
-
-    // Setup optimizer
-    let optimized_file = format!("{}_optimized.gcode", gcode_path);
+}
 
-    let mut optimizer = Optimizer {
-        config,
-        base_gcode: gcode::GCode::read(gcode_path),
-        optimized_gcode: gcode::GCode::new(&optimized_file,
-            gcode::CoordinatesMode::Absolute,
-            gcode::CoordinatesMode::Relative),
-        last_position: (0.0, 0.0, 0.0),
-        current_layer: 0,
-        last_extrusion: 0.0,
+// The log file gets info messages and up, stderr only gets messages when verbose.
+// From -vv, debug messages go to both along with the time spent in each layer span.
+// RUST_LOG overrides the level of both.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 | 1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
     };
+    let filter = || EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level.as_str()));
+    let span_events = if verbose >= 2 { FmtSpan::CLOSE } else { FmtSpan::NONE };
+
+    let file = fmt::layer()
+        .with_writer(|| LogFile)
+        .with_ansi(false)
+        .with_timer(LocalTime)
+        .with_target(false)
+        .with_span_events(span_events.clone())
+        .with_filter(filter());
+    let console = (verbose > 0).then(|| fmt::layer()
+        .with_writer(io::stderr)
+        .with_timer(LocalTime)
+        .with_target(false)
+        .with_span_events(span_events)
+        .with_filter(filter()));
+
+    tracing_subscriber::registry()
+        .with(file)
+        .with(console)
+        .init();
+}
 
-    optimizer.set_units();
-
-    optimizer.optimize(gcode_path);
-
-    optimizer.optimized_gcode.write();
-
-    // Display stats
-    println!("\nBase G-code stats:");
-    optimizer.base_gcode.stats.display();
-    optimizer.base_gcode.stats.log("Base G-code".to_string());
-    println!("\nOptimized G-code stats:");
-    optimizer.optimized_gcode.stats.display();
-    optimizer.optimized_gcode.stats.log("Optimized G-code".to_string());
-
-    // Time
-    let time = elapsed_time(now);
-    println!("\nOptimization completed in {}", time);
-    info!("Completed in {}", time);
+fn set_log_file(log_path: &str) -> Result<(), Failure> {
+    if Path::new(log_path).exists() {
+        fs::remove_file(log_path)
+            .map_err(|e| Failure::error(format!("Unable to replace {}: {}", log_path, e)))?;
+    }
+    let file = fs::File::create(log_path)
+        .map_err(|e| Failure::error(format!("Unable to set log file {}: {}", log_path, e)))?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
 }
 
 fn elapsed_time(now: Instant) -> String {
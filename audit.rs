@@ -0,0 +1,90 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use serde::Serialize;
+use crate::commands::Commands;
+
+// Commands the optimizer drops on purpose: modes and positions written again for the new order,
+// progress regenerated for it and the ones it does not handle yet
+pub const DROPPED: &[&str] = &["G20", "G21", "G90", "G91", "G92", "M82", "M83", "M73", "M74", "M201", "M204"];
+// Discrepancies listed when some are found
+const LISTED: usize = 10;
+
+// Command written a different number of times than the input has it
+#[derive(Clone, Debug, Serialize)]
+pub struct Discrepancy {
+    // Command and its parameters, without comment
+    pub command: String,
+    pub input: usize,
+    pub output: usize,
+    // First line of the file having it more often, the input for missing commands
+    pub line_num: u32,
+}
+
+// Outcome of checking that every command other than a move of the input is written once
+pub struct Audit {
+    pub commands: usize,
+    pub missing: Vec<Discrepancy>,
+    pub extra: Vec<Discrepancy>,
+}
+
+impl Audit {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+
+    pub fn display(&self) {
+        for (name, discrepancies) in [("missing", &self.missing), ("extra", &self.extra)] {
+            for discrepancy in discrepancies.iter().take(LISTED) {
+                status!("  {} {}: {} in the input, {} in the output (line {})",
+                    name, discrepancy.command, discrepancy.input, discrepancy.output, discrepancy.line_num);
+            }
+            if discrepancies.len() > LISTED {
+                status!("  and {} more {}", discrepancies.len() - LISTED, name);
+            }
+        }
+    }
+}
+
+// Commands other than moves and comments, with how many times each one is found and its first line
+fn count(gcode_path: &str, skipped: &HashSet<&str>) -> BTreeMap<String, (usize, u32)> {
+    let file = File::open(gcode_path)
+        .unwrap_or_else(|_| panic!("Unable to read file {}", gcode_path));
+    let mut commands = BTreeMap::new();
+    for (line_num, command) in Commands::new(BufReader::new(file)) {
+        if matches!(command.name.as_str(), "" | "G0" | "G1" | "G28") || skipped.contains(command.name.as_str()) {
+            continue;
+        }
+        let mut key = command.name;
+        for param in &command.params {
+            key.push(' ');
+            key.push_str(param);
+        }
+        commands.entry(key).or_insert((0, line_num)).0 += 1;
+    }
+    commands
+}
+
+// Compares the commands of the input and the optimized G-code, leaving out the ones dropped by
+// policy and the unknown ones the parser dropped. Commands the optimizer wrote on its own are given once each.
+pub fn audit(gcode_path: &str, optimized_path: &str, unknown: &[&str], added: &[&str]) -> Audit {
+    let skipped: HashSet<&str> = DROPPED.iter().chain(unknown).copied().collect();
+    let base = count(gcode_path, &skipped);
+    let optimized = count(optimized_path, &skipped);
+
+    let mut audit = Audit { commands: base.values().map(|(count, _)| count).sum(), missing: Vec::new(), extra: Vec::new() };
+    for (command, &(input, line_num)) in &base {
+        let output = optimized.get(command).map_or(0, |(count, _)| *count);
+        if output < input {
+            audit.missing.push(Discrepancy { command: command.clone(), input, output, line_num });
+        }
+    }
+    for (command, &(output, line_num)) in &optimized {
+        let input = base.get(command).map_or(0, |(count, _)| *count);
+        let added = added.iter().filter(|&&added| added == command).count();
+        if output > input + added {
+            audit.extra.push(Discrepancy { command: command.clone(), input, output, line_num });
+        }
+    }
+    audit
+}
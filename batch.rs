@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+use tracing::{info, warn};
+use crate::error::Failure;
+use crate::{cancel, commands, config, pool, Optimizer};
+
+// Outcome of one file of a batch, travel before and after or the reason it failed
+struct BatchResult {
+    file: String,
+    travel: Result<(f64, f64), String>,
+    time: f64,
+}
+
+// Optimizes every G-code file of a directory, several files at a time.
+// Solver processes stay bounded by the configuration for the whole batch.
+// Existing outputs fail their file unless overwriting is forced, files may be replaced in place.
+// Unknown commands are classified by the rules given, each file gets the CSV of its layers unless told otherwise.
+// Returns the number of files that failed.
+pub fn run(config: &config::Config, dir: &str, rules: &commands::CommandRules, resume: bool, force: bool, in_place: bool, csv: bool) -> Result<usize, Failure> {
+    let files = gcode_files(dir)?;
+    status!("Optimizing {} file-s of {}, {} at a time", files.len(), dir, config.parallel_files);
+    info!("Batch of {} file-s in {}", files.len(), dir);
+
+    let results: Vec<BatchResult> = thread::scope(|scope| {
+        let (mut pool, jobs) = pool::WorkerPool::run(scope, config.parallel_files, |_, file: String| {
+            optimize_file(config, &file, rules, resume, force, in_place, csv)
+        });
+        for (i, file) in files.iter().enumerate() {
+            jobs.send(i as u32, file.clone());
+        }
+        drop(jobs);
+
+        (0..files.len()).map(|i| pool.wait(i as u32)).collect()
+    });
+
+    display(&results);
+    write_summary(&format!("{}/summary.csv", dir.trim_end_matches('/')), &results)?;
+    Ok(results.iter().filter(|result| result.travel.is_err()).count())
+}
+
+// G-code files of a directory in name order, leaving out outputs of previous runs
+fn gcode_files(dir: &str) -> Result<Vec<String>, Failure> {
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| Failure::error(format!("Unable to read directory {}: {}", dir, e)))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().unwrap_or_default() == "gcode")
+        .map(|path| path.display().to_string())
+        .filter(|path| !path.ends_with("_optimized.gcode"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// Optimizes a file, a failing file is reported without stopping the batch
+fn optimize_file(config: &config::Config, file: &str, rules: &commands::CommandRules, resume: bool, force: bool, in_place: bool, csv: bool) -> BatchResult {
+    let start = Instant::now();
+    let name = Path::new(file).file_name().map_or(file.to_string(), |name| name.to_string_lossy().to_string());
+
+    // Files not started yet are left alone once cancelled
+    if cancel::is_cancelled() {
+        return BatchResult { file: name, travel: Err(String::from("cancelled")), time: 0.0 };
+    }
+
+    let optimize = || {
+        let mut builder = Optimizer::builder()
+            .input(file)
+            .config(config.clone())
+            .resume(resume)
+            .overwrite(force)
+            .in_place(in_place)
+            .command_handler({
+                let rules = rules.clone();
+                move |command| rules.classify(command)
+            });
+        if !csv {
+            builder = builder.csv(None);
+        }
+        let mut optimizer = builder.build()?;
+        optimizer.optimize().map_err(|e| e.message)?;
+        Ok((optimizer.base_gcode.stats.travel_distance(), optimizer.optimized_gcode.stats.travel_distance()))
+    };
+    let travel = optimize().inspect_err(|reason| warn!("Optimization of {} failed: {}", file, reason));
+
+    BatchResult { file: name, travel, time: start.elapsed().as_secs_f64() }
+}
+
+fn display(results: &[BatchResult]) {
+    let width = results.iter().map(|result| result.file.len()).max().unwrap_or(0).max(4);
+
+    status!("\n{:<width$}  {:>12}  {:>12}  {:>8}  {:>10}", "File", "Travel", "Saved", "Saved %", "Time (s)", width = width);
+    for result in results {
+        match &result.travel {
+            Ok((base, optimized)) => {
+                let saved = base - optimized;
+                let percent = if *base > 0.0 { saved / base * 100.0 } else { 0.0 };
+                status!("{:<width$}  {:>12.2}  {:>12.2}  {:>8.2}  {:>10.3}",
+                    result.file, optimized, saved, percent, result.time, width = width);
+            }
+            Err(reason) => status!("{:<width$}  failed: {}", result.file, reason, width = width),
+        }
+    }
+
+    let failed = results.iter().filter(|result| result.travel.is_err()).count();
+    status!("\n{} file-s optimized, {} failed", results.len() - failed, failed);
+    info!("Batch done, {} file-s optimized, {} failed", results.len() - failed, failed);
+}
+
+fn write_summary(path: &str, results: &[BatchResult]) -> Result<(), Failure> {
+    let mut csv = String::from("File,Status,Base travel,Optimized travel,Time\n");
+    for result in results {
+        match &result.travel {
+            Ok((base, optimized)) => csv.push_str(&format!("{},ok,{:.3},{:.3},{:.3}\n", result.file, base, optimized, result.time)),
+            Err(_) => csv.push_str(&format!("{},failed,,,{:.3}\n", result.file, result.time)),
+        }
+    }
+
+    fs::write(path, csv)
+        .map_err(|e| Failure::error(format!("Unable to write file {}: {}", path, e)))
+}
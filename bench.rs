@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use tracing::info;
+use crate::error::{ErrorKind, Failure};
+use crate::{config, temp, Optimizer};
+
+// Outcome of optimizing the file with one configuration
+struct BenchRun {
+    config_path: String,
+    solver: String,
+    max_merge_length: f64,
+    base_travel: f64,
+    travel: f64,
+    time: f64,
+}
+
+// Optimizes the same G-code with each configuration and compares travel saved and wall time.
+// Every run works on a copy of the file in a temp directory so no output is left behind.
+// Each run is bounded by its own max_solver_processes.
+pub fn run(gcode_path: &str, config_paths: &[String]) -> Result<(), Failure> {
+    let file_name = Path::new(gcode_path).file_name()
+        .ok_or_else(|| Failure::new(ErrorKind::Usage, format!("Invalid G-code path {}", gcode_path)))?;
+    let mut runs: Vec<BenchRun> = Vec::new();
+
+    for config_path in config_paths {
+        let config = config::read_config(config_path)?;
+        let solver = match config.solver {
+            config::Solver::Lkh => format!("lkh, {} run-s", config.num_runs),
+            config::Solver::Builtin => String::from("builtin"),
+        };
+        let max_merge_length = config.max_merge_length;
+
+        let work_dir = temp::TempDir::new().map_err(Failure::error)?;
+        let copy = work_dir.path().join(file_name).display().to_string();
+        fs::copy(gcode_path, &copy)
+            .map_err(|e| Failure::error(format!("Unable to copy {} to {}: {}", gcode_path, copy, e)))?;
+
+        status!("\nBenchmarking {}", config_path);
+        let mut optimizer = Optimizer::builder()
+            .input(&copy)
+            .config(config)
+            .build()
+            .map_err(Failure::error)?;
+        let start = Instant::now();
+        optimizer.optimize()?;
+        let time = start.elapsed().as_secs_f64();
+
+        let run = BenchRun {
+            config_path: config_path.clone(),
+            solver,
+            max_merge_length,
+            base_travel: optimizer.base_gcode.stats.travel_distance(),
+            travel: optimizer.optimized_gcode.stats.travel_distance(),
+            time,
+        };
+        info!("Benchmark {}: travel {:.2} -> {:.2} in {:.3} s", run.config_path, run.base_travel, run.travel, run.time);
+        runs.push(run);
+    }
+
+    display(&runs);
+    Ok(())
+}
+
+fn display(runs: &[BenchRun]) {
+    let width = runs.iter().map(|run| run.config_path.len()).max().unwrap_or(0).max(6);
+
+    status!("\n{:<width$}  {:<16}  {:>10}  {:>12}  {:>12}  {:>8}  {:>10}",
+        "Config", "Solver", "Max merge", "Travel", "Saved", "Saved %", "Time (s)", width = width);
+    for run in runs {
+        let saved = run.base_travel - run.travel;
+        let percent = if run.base_travel > 0.0 { saved / run.base_travel * 100.0 } else { 0.0 };
+        let max_merge = if run.max_merge_length.is_finite() {
+            format!("{:.2}", run.max_merge_length)
+        } else {
+            String::from("none")
+        };
+
+        status!("{:<width$}  {:<16}  {:>10}  {:>12.2}  {:>12.2}  {:>8.2}  {:>10.3}",
+            run.config_path, run.solver, max_merge, run.travel, saved, percent, run.time, width = width);
+    }
+}
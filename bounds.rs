@@ -0,0 +1,53 @@
+use std::collections::HashSet;
+use crate::commands::Modes;
+use crate::printer::PrinterProfile;
+use crate::segments::{self, round, Segment, POSITION_STEP};
+
+// Moves listed when some leave the bed
+const LISTED: usize = 10;
+
+// Outcome of checking that the moves of an optimized G-code stay on the bed of the printer
+pub struct BoundsCheck {
+    pub moves: usize,
+    // Moves ending off the bed at a position the original never goes to
+    pub outside: Vec<Segment>,
+    // Lines of the moves ending off the bed where the original already goes, purge lines in front of the bed for instance
+    pub inherited: Vec<u32>,
+}
+
+impl BoundsCheck {
+    pub fn is_ok(&self) -> bool {
+        self.outside.is_empty()
+    }
+
+    pub fn display(&self) {
+        for segment in self.outside.iter().take(LISTED) {
+            status!("  line {}: {}", segment.line_num, segment);
+        }
+        if self.outside.len() > LISTED {
+            status!("  and {} more", self.outside.len() - LISTED);
+        }
+    }
+}
+
+// Checks the end of every move of the optimized G-code against the bed of the printer.
+// Positions off the bed the original also goes to were chosen by the slicer and are let through,
+// the original being read in the modes given until it sets its own.
+pub fn check(gcode_path: &str, optimized_path: &str, profile: &PrinterProfile, modes: Modes) -> BoundsCheck {
+    let off_bed = |segment: &Segment| !profile.on_bed(segment.to.0, segment.to.1, POSITION_STEP);
+    let original: HashSet<(i64, i64, i64)> = segments::read_in(gcode_path, modes).iter()
+        .filter(|segment| off_bed(segment))
+        .map(|segment| round(segment.to, POSITION_STEP))
+        .collect();
+
+    let optimized = segments::read(optimized_path);
+    let mut check = BoundsCheck { moves: optimized.len(), outside: Vec::new(), inherited: Vec::new() };
+    for segment in optimized.into_iter().filter(off_bed) {
+        if original.contains(&round(segment.to, POSITION_STEP)) {
+            check.inherited.push(segment.line_num);
+        } else {
+            check.outside.push(segment);
+        }
+    }
+    check
+}
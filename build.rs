@@ -0,0 +1,21 @@
+// Regenerates the C header of the FFI, only built along with it
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=ffi.rs");
+        let config = cbindgen::Config {
+            language: cbindgen::Language::C,
+            include_guard: Some(String::from("TSP_GCODE_OPTIMIZER_H")),
+            header: Some(String::from("/* Generated from ffi.rs with cbindgen, do not edit */")),
+            usize_is_size_t: true,
+            ..Default::default()
+        };
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src("ffi.rs")
+            .generate()
+            .expect("Unable to generate the C header")
+            .write_to_file("include/tsp_gcode_optimizer.h");
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::temp;
+
+// Set by the first Ctrl-C: no more layers are solved and running solvers are stopped
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+// Cancels a single optimization from another thread, a GUI's stop button for instance.
+// Clones share the same state and Ctrl-C cancels every token.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || is_cancelled()
+    }
+}
+
+// The first Ctrl-C cancels the optimization, remaining layers are still written in their
+// original order so the output stays printable. A second one quits at once.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn handle_interrupts() {
+    ctrlc::set_handler(|| {
+        if !CANCELLED.swap(true, Ordering::SeqCst) {
+            eprintln!("\nInterrupted, writing remaining layers unoptimized (press Ctrl-C again to quit)");
+            return;
+        }
+
+        temp::remove_active_dirs();
+        eprintln!("\nInterrupted, temporary files removed");
+        std::process::exit(130);
+    })
+    .unwrap_or_else(|_| panic!("Unable to set Ctrl-C handler"));
+}
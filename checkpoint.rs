@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use tracing::{info, warn};
+
+// Layer as saved in a checkpoint, only reused if the G-code layer still has as many nodes
+pub struct SolvedLayer {
+    pub nodes: usize,
+    pub moves: Vec<(i32, i32)>,
+    pub merged: usize,
+}
+
+// Solved layers of an optimization, appended as they complete so an interrupted run can be resumed.
+// Each line holds a layer index, its node count, its merged node count and its moves.
+// Layers of a resumed run stay on disk, only where they start in the file is kept in memory.
+pub struct Checkpoint {
+    path: String,
+    file: File,
+    reader: File,
+    offsets: HashMap<u32, u64>,
+}
+
+impl Checkpoint {
+    // Starts a checkpoint file, keeping the valid layers of a previous one when resuming.
+    // Node counts of the kept layers are returned, their moves are read with solved.
+    pub fn open(path: &str, resume: bool) -> Result<(Checkpoint, HashMap<u32, usize>), String> {
+        // The previous checkpoint is moved aside and copied back one line at a time
+        let previous_path = format!("{}.previous", path);
+        let previous = if resume {
+            Checkpoint::previous(path, &previous_path)?
+        } else {
+            None
+        };
+
+        let mut file = File::create(path)
+            .map_err(|e| format!("Unable to create file {}: {}", path, e))?;
+        let mut offsets = HashMap::new();
+        let mut solved = HashMap::new();
+
+        if let Some(previous) = previous {
+            // Keep complete layers, dropping any line cut short by the interruption
+            let mut offset = 0;
+            for line in BufReader::new(previous).lines().map_while(Result::ok) {
+                match Checkpoint::parse(&line) {
+                    Some((layer, solution)) => {
+                        file.write_all(format!("{}\n", line).as_bytes())
+                            .map_err(|e| format!("Unable to write file {}: {}", path, e))?;
+                        offsets.insert(layer, offset);
+                        solved.insert(layer, solution.nodes);
+                        offset += line.len() as u64 + 1;
+                    }
+                    None => warn!("Ignoring invalid checkpoint line: {}", line),
+                }
+            }
+
+            if let Err(e) = fs::remove_file(&previous_path) {
+                warn!("Unable to remove checkpoint {}: {}", previous_path, e);
+            }
+            status!("Resuming with {} solved layer-s from {}", solved.len(), path);
+            info!("Resuming with {} solved layer-s from {}", solved.len(), path);
+        }
+
+        let reader = File::open(path)
+            .map_err(|e| format!("Unable to read file {}: {}", path, e))?;
+
+        Ok((Checkpoint { path: path.to_string(), file, reader, offsets }, solved))
+    }
+
+    // Moves of a layer kept from the previous run, read back when the layer is written
+    pub fn solved(&mut self, layer: u32) -> Result<SolvedLayer, String> {
+        let offset = self.offsets.remove(&layer)
+            .ok_or_else(|| format!("Layer {} is not in checkpoint {}", layer, self.path))?;
+
+        let mut line = String::new();
+        self.reader.seek(SeekFrom::Start(offset))
+            .and_then(|_| BufReader::new(&self.reader).read_line(&mut line))
+            .map_err(|e| format!("Unable to read file {}: {}", self.path, e))?;
+
+        Checkpoint::parse(&line)
+            .map(|(_, solution)| solution)
+            .ok_or_else(|| format!("Invalid checkpoint line for layer {} in {}", layer, self.path))
+    }
+
+    pub fn save(&mut self, layer: u32, nodes: usize, moves: &[(i32, i32)], merged: usize) -> Result<(), String> {
+        // A single write per layer, a crash leaves at most one partial line
+        self.file.write_all(Checkpoint::line(layer, nodes, moves, merged).as_bytes())
+            .map_err(|e| format!("Unable to write file {}: {}", self.path, e))
+    }
+
+    // Removes the checkpoint once the optimization is complete
+    pub fn finish(self) {
+        drop(self.file);
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Unable to remove checkpoint {}: {}", self.path, e);
+        }
+    }
+
+    fn line(layer: u32, nodes: usize, moves: &[(i32, i32)], merged: usize) -> String {
+        let mut line = format!("{} {} {}", layer, nodes, merged);
+        for (origin, destination) in moves {
+            line.push_str(&format!(" {}:{}", origin, destination));
+        }
+        line.push('\n');
+        line
+    }
+
+    // Previous checkpoint moved aside, none if there is nothing to resume
+    fn previous(path: &str, previous_path: &str) -> Result<Option<File>, String> {
+        if fs::rename(path, previous_path).is_err() {
+            status!("No checkpoint found at {}, starting from scratch", path);
+            return Ok(None);
+        }
+
+        File::open(previous_path)
+            .map(Some)
+            .map_err(|e| format!("Unable to read file {}: {}", previous_path, e))
+    }
+
+    fn parse(line: &str) -> Option<(u32, SolvedLayer)> {
+        let mut fields = line.split_whitespace();
+        let layer = fields.next()?.parse::<u32>().ok()?;
+        let nodes = fields.next()?.parse::<usize>().ok()?;
+        let merged = fields.next()?.parse::<usize>().ok()?;
+
+        let mut moves = Vec::new();
+        for field in fields {
+            let (origin, destination) = field.split_once(':')?;
+            let origin = origin.parse::<i32>().ok()?;
+            let destination = destination.parse::<i32>().ok()?;
+            if origin < 1 || destination < 1 || origin as usize > nodes || destination as usize > nodes {
+                return None;
+            }
+            moves.push((origin, destination));
+        }
+
+        // Every node is visited once, a truncated line misses some
+        if moves.len() != nodes {
+            return None;
+        }
+
+        Some((layer, SolvedLayer { nodes, moves, merged }))
+    }
+}
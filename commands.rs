@@ -0,0 +1,216 @@
+use std::fs;
+use std::io::BufRead;
+use std::sync::Arc;
+use serde::Serialize;
+
+// Line of G-code split into its command, parameters and comment
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Command {
+    // G1, M104, T0... empty on a line holding only a comment
+    pub name: String,
+    // Words following the name, X10.5 or S215 for instance
+    pub params: Vec<String>,
+    // Text after the first ';'
+    pub comment: String,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Command {
+        let (line, comment) = line.split_once(';').unwrap_or((line, ""));
+        let mut words = line.split_whitespace().map(|word| word.to_string());
+
+        Command {
+            name: words.next().unwrap_or_default(),
+            params: words.collect(),
+            comment: comment.trim().to_string(),
+        }
+    }
+
+    // Value of the parameter with the given letter, none if missing or not a number
+    pub fn value(&self, letter: char) -> Option<f64> {
+        self.params.iter()
+            .find(|param| param.starts_with(letter))
+            .and_then(|param| param[1..].parse().ok())
+    }
+
+    // Position given by the X, Y and Z parameters, the missing ones taken from another position
+    pub fn position(&self, position: (f64, f64, f64)) -> (f64, f64, f64) {
+        (
+            self.value('X').unwrap_or(position.0),
+            self.value('Y').unwrap_or(position.1),
+            self.value('Z').unwrap_or(position.2),
+        )
+    }
+}
+
+// What to do with a command the parser does not know
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandClass {
+    // Replayed where it was, the moves before and after it keep their order
+    Barrier,
+    // Replayed after the move it followed, wherever that move ends up
+    Passthrough,
+    // Moved to the start of the file
+    Start,
+    // Moved to the end of the file
+    End,
+}
+
+// Classifies unknown commands, none leaving them to the next handler and finally dropping them
+pub type CommandHandler = Arc<dyn Fn(&Command) -> Option<CommandClass> + Send + Sync>;
+
+// Classes of unknown commands read from a rule file, one "<command> <class>" per line.
+// A command ending with * stands for every command starting with it, # starts a comment.
+//   M600 barrier
+//   M117 passthrough
+//   M862* start
+#[derive(Clone, Debug, Default)]
+pub struct CommandRules {
+    rules: Vec<(String, bool, CommandClass)>,
+}
+
+impl CommandRules {
+    pub fn read(path: &str) -> Result<CommandRules, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|_| format!("Unable to read file {}", path))?;
+        CommandRules::parse(&contents).map_err(|e| format!("{} in {}", e, path))
+    }
+
+    pub fn parse(contents: &str) -> Result<CommandRules, String> {
+        let mut rules = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [command, class] = words[..] else {
+                return Err(format!("Expected \"<command> <class>\" on line {}, found \"{}\"", i + 1, line));
+            };
+            let class = match class.to_lowercase().as_str() {
+                "barrier" => CommandClass::Barrier,
+                "passthrough" => CommandClass::Passthrough,
+                "start" => CommandClass::Start,
+                "end" => CommandClass::End,
+                _ => return Err(format!("Unknown class {} on line {}, expected barrier, passthrough, start or end", class, i + 1)),
+            };
+            let (command, prefix) = command.strip_suffix('*').map_or((command, false), |command| (command, true));
+            rules.push((command.to_uppercase(), prefix, class));
+        }
+
+        Ok(CommandRules { rules })
+    }
+
+    // Class of the first rule matching the command
+    pub fn classify(&self, command: &Command) -> Option<CommandClass> {
+        let name = command.name.to_uppercase();
+        self.rules.iter()
+            .find(|(rule, prefix, _)| if *prefix { name.starts_with(rule.as_str()) } else { name == *rule })
+            .map(|(_, _, class)| *class)
+    }
+}
+
+// Commands of a G-code stream with their line number, counted from 1. Blank lines are skipped.
+pub struct Commands<R: BufRead> {
+    input: R,
+    buffer: String,
+    line_num: u32,
+}
+
+impl<R: BufRead> Commands<R> {
+    pub fn new(input: R) -> Commands<R> {
+        Commands { input, buffer: String::new(), line_num: 0 }
+    }
+}
+
+impl<R: BufRead> Iterator for Commands<R> {
+    type Item = (u32, Command);
+
+    fn next(&mut self) -> Option<(u32, Command)> {
+        loop {
+            self.buffer.clear();
+            // A read error ends the stream like its end does
+            if self.input.read_line(&mut self.buffer).unwrap_or(0) == 0 {
+                return None;
+            }
+            self.line_num += 1;
+
+            let command = Command::parse(self.buffer.trim_end_matches(['\n', '\r']));
+            if !command.name.is_empty() || !command.comment.is_empty() {
+                return Some((self.line_num, command));
+            }
+        }
+    }
+}
+
+// Callbacks for the commands of a G-code stream, every one of them ignored unless implemented
+pub trait Visitor {
+    // Moves (G0, G1, G28) with the absolute positions they go from and to
+    fn visit_move(&mut self, _line_num: u32, _command: &Command, _from: (f64, f64, f64), _to: (f64, f64, f64)) {}
+
+    // Any other command
+    fn visit_command(&mut self, _line_num: u32, _command: &Command) {}
+
+    // Lines holding only a comment
+    fn visit_comment(&mut self, _line_num: u32, _comment: &str) {}
+}
+
+// Modes a G-code stream is read in until it sets its own with G90/G91 and M82/M83
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Modes {
+    pub relative: bool,
+    pub relative_extrusion: bool,
+}
+
+// Walks every command of a G-code stream, following the position mode so moves come with
+// absolute positions
+pub fn visit<R: BufRead>(input: R, visitor: &mut impl Visitor) {
+    visit_in(input, Modes::default(), visitor);
+}
+
+// Same as visit, the stream starting in the position mode given
+pub fn visit_in<R: BufRead>(input: R, modes: Modes, visitor: &mut impl Visitor) {
+    let mut position = (0.0, 0.0, 0.0);
+    let mut relative = modes.relative;
+
+    for (line_num, command) in Commands::new(input) {
+        match command.name.as_str() {
+            "G0" | "G1" => {
+                let to = if relative {
+                    let offset = command.position((0.0, 0.0, 0.0));
+                    (position.0 + offset.0, position.1 + offset.1, position.2 + offset.2)
+                } else {
+                    command.position(position)
+                };
+                visitor.visit_move(line_num, &command, position, to);
+                position = to;
+            },
+            // Home, the axes given or all of them
+            "G28" => {
+                let homed = (command.value('X').is_some(), command.value('Y').is_some(), command.value('Z').is_some());
+                let to = if homed == (false, false, false) {
+                    (0.0, 0.0, 0.0)
+                } else {
+                    (
+                        if homed.0 { 0.0 } else { position.0 },
+                        if homed.1 { 0.0 } else { position.1 },
+                        if homed.2 { 0.0 } else { position.2 },
+                    )
+                };
+                visitor.visit_move(line_num, &command, position, to);
+                position = to;
+            },
+            "" => visitor.visit_comment(line_num, &command.comment),
+            name => {
+                match name {
+                    "G90" => relative = false,
+                    "G91" => relative = true,
+                    "G92" => position = command.position(position),
+                    _ => (),
+                }
+                visitor.visit_command(line_num, &command);
+            },
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+use crate::commands::{self, Command, Visitor};
+use crate::error::{fail, ErrorKind, Failure};
+use crate::quick_math::distance_3d;
+
+// Height the nozzle is lifted to above the tallest object printed so far before moving to the next one
+const CLEARANCE: f64 = 1.0;
+
+// Objects up to this many are ordered exhaustively, more by nearest neighbour
+const EXACT_MAX_OBJECTS: usize = 8;
+
+// G-code of one object split into the start script, the moves printing the object and the end script
+struct Part {
+    name: String,
+    start_script: String,
+    body: String,
+    end_script: String,
+    // Where the object starts and ends being printed, how tall it is
+    first: (f64, f64, f64),
+    last: (f64, f64, f64),
+    top: f64,
+    // Where the start script leaves the nozzle and the extruder
+    start_position: (f64, f64, f64),
+    start_extrusion: f64,
+    relative_extrusion: bool,
+}
+
+// Finds where the object starts and ends. It starts at the first layer change the slicer marks,
+// or after the last command before the first extrusion without marks.
+#[derive(Default)]
+struct PartReader {
+    relative_extrusion: bool,
+    relative_position: bool,
+    extruder_position: f64,
+    position: (f64, f64, f64),
+
+    marker: Option<(u32, (f64, f64, f64), f64)>,
+    setup: (u32, (f64, f64, f64), f64),
+    first: Option<(f64, f64, f64)>,
+    last: Option<(u32, (f64, f64, f64))>,
+    top: f64,
+}
+
+impl Visitor for PartReader {
+    fn visit_move(&mut self, line_num: u32, command: &Command, from: (f64, f64, f64), to: (f64, f64, f64)) {
+        let extrusion = match command.value('E') {
+            Some(e) if self.relative_extrusion => e,
+            Some(e) => {
+                let extrusion = e - self.extruder_position;
+                self.extruder_position = e;
+                extrusion
+            },
+            None => 0.0,
+        };
+        if command.name != "G28" && extrusion > 0.0 {
+            self.first.get_or_insert(from);
+            self.last = Some((line_num, to));
+            self.top = self.top.max(to.2);
+        }
+        self.position = to;
+    }
+
+    fn visit_command(&mut self, line_num: u32, command: &Command) {
+        match command.name.as_str() {
+            "M82" => self.relative_extrusion = false,
+            "M83" => self.relative_extrusion = true,
+            "G91" => self.relative_position = true,
+            "G92" => if let Some(e) = command.value('E') {
+                self.extruder_position = e;
+            },
+            _ => (),
+        }
+        if self.first.is_none() {
+            self.setup = (line_num, self.position, self.extruder_position);
+        }
+    }
+
+    fn visit_comment(&mut self, line_num: u32, comment: &str) {
+        // Extrusions before the first layer, a purge line for instance, belong to the start script
+        if self.marker.is_none() && (comment.starts_with("LAYER_CHANGE") || comment.starts_with("LAYER:")) {
+            self.marker = Some((line_num, self.position, self.extruder_position));
+            self.first = None;
+            self.top = 0.0;
+        }
+    }
+}
+
+fn read_part(gcode_path: &str) -> Result<Part, Failure> {
+    let contents = fs::read_to_string(gcode_path)
+        .map_err(|_| Failure::new(ErrorKind::Parse, format!("Unable to read file {}", gcode_path)))?;
+    let mut reader = PartReader::default();
+    commands::visit(contents.as_bytes(), &mut reader);
+
+    if reader.relative_position {
+        return fail(ErrorKind::Parse, format!("File {} uses relative positioning (G91), only absolute positions can be concatenated", gcode_path));
+    }
+    let (Some(first), Some((last_line, last))) = (reader.first, reader.last) else {
+        return fail(ErrorKind::Parse, format!("File {} extrudes nothing", gcode_path));
+    };
+
+    // The body starts at the marker line, or on the line after the last setup command
+    let (start_line, start_position, start_extrusion) = match reader.marker {
+        Some(marker) => marker,
+        None => (reader.setup.0 + 1, reader.setup.1, reader.setup.2),
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = (start_line as usize - 1).min(lines.len());
+    let end = (last_line as usize).max(start).min(lines.len());
+    let join = |lines: &[&str]| lines.iter().map(|line| format!("{}\n", line)).collect::<String>();
+
+    Ok(Part {
+        name: Path::new(gcode_path).file_name().map_or(gcode_path.to_string(), |name| name.to_string_lossy().to_string()),
+        start_script: join(&lines[..start]),
+        body: join(&lines[start..end]),
+        end_script: join(&lines[end..]),
+        first,
+        last,
+        top: reader.top,
+        start_position,
+        start_extrusion,
+        relative_extrusion: reader.relative_extrusion,
+    })
+}
+
+// Joins G-code files printing one object each into a single sequential print. The start script
+// of the first file and the end script of the last one are kept, the others are left out.
+// Unless the order is kept, objects are ordered to shorten the travels between them.
+pub fn concat(gcode_paths: &[String], keep_order: bool) -> Result<String, Failure> {
+    let parts: Vec<Part> = gcode_paths.iter().map(|path| read_part(path)).collect::<Result<_, _>>()?;
+    let (Some(head), Some(tail)) = (parts.first(), parts.last()) else {
+        return fail(ErrorKind::Usage, "No G-code file to concatenate");
+    };
+
+    for part in &parts[1..] {
+        if part.relative_extrusion != head.relative_extrusion {
+            return fail(ErrorKind::Parse, format!("Files {} and {} use different extruder modes", head.name, part.name));
+        }
+        if part.start_script.trim() != head.start_script.trim() {
+            warn!("Start script of {} differs from the one of {}, which is kept", part.name, head.name);
+        }
+    }
+
+    let order = if keep_order {
+        (0..parts.len()).collect()
+    } else {
+        order(&parts, head.start_position)
+    };
+    let names: Vec<&str> = order.iter().map(|&i| parts[i].name.as_str()).collect();
+    status!("Printing {} object-s in order: {}", parts.len(), names.join(", "));
+    info!("Concatenated {} in order {}", gcode_paths.join(", "), names.join(", "));
+
+    let mut gcode = head.start_script.clone();
+    let mut top: f64 = 0.0;
+    for i in order {
+        let part = &parts[i];
+
+        // Lift above everything printed, move over the object and down to where it starts
+        gcode.push_str(&format!(";Object {}\n", part.name));
+        gcode.push_str(&format!("G0 Z{:.3}\n", top.max(part.first.2) + CLEARANCE));
+        gcode.push_str(&format!("G0 X{:.3} Y{:.3}\n", part.first.0, part.first.1));
+        gcode.push_str(&format!("G0 Z{:.3}\n", part.first.2));
+        if !part.relative_extrusion {
+            gcode.push_str(&format!("G92 E{:.5}\n", part.start_extrusion));
+        }
+
+        gcode.push_str(&part.body);
+        top = top.max(part.top);
+    }
+    gcode.push_str(&tail.end_script);
+
+    Ok(gcode)
+}
+
+// Order of the objects shortening the travels from the end of each one to the start of the next,
+// starting from where the start script leaves the nozzle
+fn order(parts: &[Part], start: (f64, f64, f64)) -> Vec<usize> {
+    let travel = |from: (f64, f64, f64), to: usize| {
+        distance_3d((from.0, from.1, 0.0), (parts[to].first.0, parts[to].first.1, 0.0))
+    };
+
+    if parts.len() <= EXACT_MAX_OBJECTS {
+        let mut best = (f64::INFINITY, Vec::new());
+        let mut order = Vec::with_capacity(parts.len());
+        permutations(parts.len(), &mut order, &mut |order| {
+            let mut position = start;
+            let length: f64 = order.iter().map(|&i| {
+                let length = travel(position, i);
+                position = parts[i].last;
+                length
+            }).sum();
+            if length < best.0 {
+                best = (length, order.to_vec());
+            }
+        });
+        return best.1;
+    }
+
+    let mut left: Vec<usize> = (0..parts.len()).collect();
+    let mut order = Vec::with_capacity(parts.len());
+    let mut position = start;
+    while !left.is_empty() {
+        let nearest = (0..left.len())
+            .min_by(|&a, &b| travel(position, left[a]).total_cmp(&travel(position, left[b])))
+            .unwrap();
+        let i = left.remove(nearest);
+        position = parts[i].last;
+        order.push(i);
+    }
+    order
+}
+
+// Calls visit with every order of n items
+fn permutations(n: usize, order: &mut Vec<usize>, visit: &mut dyn FnMut(&[usize])) {
+    if order.len() == n {
+        visit(order);
+        return;
+    }
+    for i in 0..n {
+        if !order.contains(&i) {
+            order.push(i);
+            permutations(n, order, visit);
+            order.pop();
+        }
+    }
+}
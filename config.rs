@@ -1,40 +1,671 @@
-use std::{fs::File, io::BufReader, path::Path};
-use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
+use crate::commands;
+use crate::error::{fail, ErrorKind, Failure};
+use crate::printer;
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Objective {
+    Distance,
+    Time,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizationLevel {
+    Full,
+    IslandOnly,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Solver {
+    Lkh,
+    Builtin,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SolverIo {
+    Files,
+    Pipe,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Printer {
+    OctoPrint,
+    Moonraker,
+}
+
+// Printer host the optimized G-code is sent to
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Upload {
+    pub printer: Printer,
+    // Address of the host such as http://octopi.local
+    pub url: String,
+    #[serde(default, skip_serializing)]
+    pub api_key: String,
+    // Start printing the file once uploaded, instead of only adding it to the files of the host
+    #[serde(default)]
+    pub print: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
+    #[serde(default = "default_solver")]
+    pub solver: Solver,
+    #[serde(default)]
     pub program: String,
+    // Solve with the builtin solver when the program cannot be run, instead of stopping
+    #[serde(default = "default_solver_fallback")]
+    pub solver_fallback: bool,
+    #[serde(default = "default_precision")]
     pub precision: u32,
+    #[serde(default = "default_num_runs")]
     pub num_runs: u32,
     pub max_merge_length: f64,
+
+    #[serde(default = "default_objective")]
+    pub objective: Objective,
+    #[serde(default = "default_acceleration")]
+    pub acceleration: f64,
+
+    // Filament diameter in mm, density in g/cm³ and price of a kg, 0 when unknown
+    #[serde(default = "default_filament_diameter")]
+    pub filament_diameter: f64,
+    #[serde(default = "default_filament_density")]
+    pub filament_density: f64,
+    #[serde(default)]
+    pub filament_price: f64,
+    // Modes read for moves coming before any G90/G91 or M82/M83, strict files being rejected instead
+    #[serde(default = "default_assume_absolute_position")]
+    pub assume_absolute_position: bool,
+    #[serde(default)]
+    pub assume_relative_extrusion: bool,
+    #[serde(default)]
+    pub strict_modes: bool,
+    // Copy what comes before the first extrusion and after the last one as it is, instead of writing a header
+    #[serde(default)]
+    pub keep_scripts: bool,
+    // Write the comments of the input after the move they followed, or at the start of the layer when it moved
+    #[serde(default)]
+    pub keep_comments: bool,
+    // Write ;LAYER_CHANGE, ;Z: and ;TYPE: comments where G-code viewers expect them, to color the preview
+    #[serde(default)]
+    pub viewer_markers: bool,
+    // Leave out comments, blank lines, modes already set and values already written, for slow serial links and small SD cards
+    #[serde(default)]
+    pub minify: bool,
+    // Number every line and end it with its checksum, after an M110 reset, for hosts streaming over serial
+    #[serde(default)]
+    pub line_numbers: bool,
+    // Decimals written for X, Y and Z, for E and for F, trailing zeros being left out
+    #[serde(default = "default_position_decimals")]
+    pub position_decimals: usize,
+    #[serde(default = "default_extrusion_decimals")]
+    pub extrusion_decimals: usize,
+    #[serde(default = "default_feedrate_decimals")]
+    pub feedrate_decimals: usize,
+    // Write E values as running totals (M82), reset at each layer, instead of relative amounts (M83)
+    #[serde(default)]
+    pub absolute_extrusion: bool,
+    // Largest volume of filament melted in mm³/s, extrusions asking for more being slowed down, 0 for no limit
+    #[serde(default)]
+    pub max_volumetric_flow: f64,
+    // Largest difference of extrusion between a segment of the input and its copy in the output
+    #[serde(default = "default_extrusion_tolerance")]
+    pub extrusion_tolerance: f64,
+
+    #[serde(default)]
+    pub time_limit: f64,
+    #[serde(default = "default_annealing_time")]
+    pub annealing_time: f64,
+    #[serde(default = "default_exact_max_nodes")]
+    pub exact_max_nodes: usize,
+    #[serde(default)]
+    pub gap_target: f64,
+    #[serde(default)]
+    pub skip_gap: f64,
+
+    #[serde(default = "default_optimization_level")]
+    pub optimization_level: OptimizationLevel,
+    #[serde(default = "default_island_max_travel")]
+    pub island_max_travel: f64,
+
+    #[serde(default)]
+    pub threads: usize,
+    #[serde(default)]
+    pub max_solver_processes: usize,
+    #[serde(default)]
+    pub parallel_files: usize,
+    #[serde(default)]
+    pub lookahead_layers: usize,
+    #[serde(default)]
+    pub solver_timeout: f64,
+    #[serde(default)]
+    pub solver_memory_limit: u64,
+    #[serde(default = "default_solver_io")]
+    pub solver_io: SolverIo,
+
+    // Directory the files of every LKH run are copied to and every solved problem and tour written to, none when empty
+    #[serde(default)]
+    pub keep_intermediates: String,
+    // Directory a CSV of the nodes merged into each TSP node is written to for every solved layer, none when empty
+    #[serde(default)]
+    pub merge_report: String,
+
+    // Ranges of layers to optimize such as "2-10,15,20-", every layer when empty
+    #[serde(default, deserialize_with = "deserialize_layers", serialize_with = "serialize_layers")]
+    pub layers: Vec<(u32, u32)>,
+
+    #[serde(default)]
+    pub upload: Option<Upload>,
+
+    // Machine the G-code is printed on, given apart from the configuration file
+    #[serde(skip)]
+    pub printer: Option<printer::PrinterProfile>,
 }
 
-pub fn read_config(path: &str) -> Config {
-    let file = File::open(path)
-        .unwrap_or_else(|_| panic!("Unable to open file {}", path));
-    let reader = BufReader::new(file);
+impl Config {
+    // Whether a layer is among the ones to optimize
+    pub fn optimizes_layer(&self, layer: u32) -> bool {
+        self.layers.is_empty() || self.layers.iter().any(|&(first, last)| first <= layer && layer <= last)
+    }
 
-    // Check that file contains JSON
-    let mut config: Config = serde_json::from_reader(reader)
-        .unwrap_or_else(|_| panic!("Unable to parse JSON in file {}", path));
+    // Modes the input is read in until it sets its own
+    pub fn input_modes(&self) -> commands::Modes {
+        commands::Modes { relative: !self.assume_absolute_position, relative_extrusion: self.assume_relative_extrusion }
+    }
+}
+
+// Parses ranges of layers: single layers, closed ranges and ranges open to the last layer
+pub fn parse_layers(spec: &str) -> Result<Vec<(u32, u32)>, String> {
+    let parse = |value: &str| value.trim().parse::<u32>()
+        .map_err(|_| format!("Invalid layer {} in {}", value.trim(), spec));
 
-    // Check that program is set and exists
-    if config.program.is_empty() {
-        panic!("Program not set in configuration file");
+    let mut layers = Vec::new();
+    for range in spec.split(',').filter(|range| !range.trim().is_empty()) {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) if last.trim().is_empty() => (parse(first)?, u32::MAX),
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(range)?, parse(range)?),
+        };
+        if first > last {
+            return Err(format!("Invalid layer range {} in {}", range.trim(), spec));
+        }
+        layers.push((first, last));
     }
-    
-    if !Path::new(&config.program).exists() {
-        panic!("Program {} does not exist", config.program);
+
+    Ok(layers)
+}
+
+fn serialize_layers<S: Serializer>(layers: &[(u32, u32)], serializer: S) -> Result<S::Ok, S::Error> {
+    let ranges: Vec<String> = layers.iter().map(|&(first, last)| match last {
+        u32::MAX => format!("{}-", first),
+        _ if first == last => first.to_string(),
+        _ => format!("{}-{}", first, last),
+    }).collect();
+    serializer.serialize_str(&ranges.join(","))
+}
+
+fn deserialize_layers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u32, u32)>, D::Error> {
+    let spec = String::deserialize(deserializer)?;
+    parse_layers(&spec).map_err(de::Error::custom)
+}
+
+// Builtin solver with every default, it needs no external program and so cannot fail to complete
+impl Default for Config {
+    fn default() -> Config {
+        complete(defaults()).unwrap()
     }
+}
 
-    if config.max_merge_length == 0.0 {
-        config = Config {
-            program: config.program,
-            precision: config.precision,
-            num_runs: config.num_runs,
-            max_merge_length: f64::INFINITY,
+// Fields of the default configuration, the others taking their own defaults
+const DEFAULTS: &str = r#"{"solver": "builtin", "max_merge_length": 0}"#;
+
+// Same as the default configuration, before it is completed
+pub fn defaults() -> Config {
+    serde_json::from_str(DEFAULTS).unwrap()
+}
+
+// LKH unless the build has no external solver
+fn default_solver() -> Solver {
+    if cfg!(feature = "lkh") { Solver::Lkh } else { Solver::Builtin }
+}
+
+fn default_solver_fallback() -> bool {
+    true
+}
+
+fn default_assume_absolute_position() -> bool {
+    true
+}
+
+fn default_precision() -> u32 {
+    100
+}
+
+fn default_num_runs() -> u32 {
+    1
+}
+
+fn default_objective() -> Objective {
+    Objective::Distance
+}
+
+fn default_acceleration() -> f64 {
+    1000.0 // mm/s², conservative value for most printers
+}
+
+fn default_filament_diameter() -> f64 {
+    1.75 // mm
+}
+
+fn default_filament_density() -> f64 {
+    1.24 // g/cm³, PLA
+}
+
+fn default_position_decimals() -> usize {
+    3 // µm, finer than any printer moves
+}
+
+fn default_extrusion_decimals() -> usize {
+    5
+}
+
+fn default_feedrate_decimals() -> usize {
+    1
+}
+
+fn default_extrusion_tolerance() -> f64 {
+    0.0001 // mm of filament, below the 5 decimals E values are written with
+}
+
+fn default_annealing_time() -> f64 {
+    1.0 // seconds
+}
+
+fn default_exact_max_nodes() -> usize {
+    12 // Held-Karp stays well under a millisecond at this size
+}
+
+fn default_optimization_level() -> OptimizationLevel {
+    OptimizationLevel::Full
+}
+
+fn default_island_max_travel() -> f64 {
+    5.0 // mm, longer travels are considered to leave the island
+}
+
+fn default_solver_io() -> SolverIo {
+    SolverIo::Files
+}
+
+pub fn read_config(path: &str) -> Result<Config, Failure> {
+    complete(parse_config(path)?)
+}
+
+// Configuration file as written, to be completed once changed
+pub fn parse_config(path: &str) -> Result<Config, Failure> {
+    serde_json::from_value(read_value(path)?)
+        .map_err(|e| Failure::new(ErrorKind::Config, format!("Invalid configuration file {}: {}", path, e)))
+}
+
+// Prefix of the environment variables setting configuration fields, TSP_GCODE_NUM_RUNS for num_runs
+pub const ENV_PREFIX: &str = "TSP_GCODE_";
+
+// Configuration built up in layers, each one replacing the fields set by the ones before it:
+// the defaults, the printer profile, the configuration file, environment variables
+// and finally overrides given as field=value
+pub fn parse_layered(path: Option<&str>, profile: Option<printer::PrinterProfile>, overrides: &[String]) -> Result<Config, Failure> {
+    let mut value = match path {
+        Some(path) => read_value(path)?,
+        None => serde_json::from_str(DEFAULTS).unwrap(),
+    };
+    let serde_json::Value::Object(map) = &mut value else {
+        unreachable!("configurations are checked to be objects");
+    };
+
+    // The profile only fills in what the file leaves out
+    if let Some(profile) = &profile {
+        for (name, setting) in profile.settings() {
+            map.entry(name).or_insert(setting);
+        }
+    }
+
+    for (variable, setting) in env::vars().filter(|(variable, _)| variable.starts_with(ENV_PREFIX)) {
+        let name = variable[ENV_PREFIX.len()..].to_lowercase();
+        match FIELDS.iter().find(|(known, field, ..)| *known == name && !matches!(field, Field::Table(_))) {
+            Some(&(name, field, ..)) => {
+                map.insert(name.to_string(), parse_setting(field, &setting));
+            }
+            None => warn!("Environment variable {} sets no configuration field, ignored", variable),
+        }
+    }
+
+    for set in overrides {
+        let Some((name, setting)) = set.split_once('=') else {
+            return fail(ErrorKind::Usage, format!("Expected field=value to set, such as num_runs=5, found {}", set));
+        };
+        let name = name.trim();
+        let Some(&(name, field, ..)) = FIELDS.iter().find(|(known, field, ..)| *known == name && !matches!(field, Field::Table(_))) else {
+            let closest = FIELDS.iter().map(|(known, ..)| (edit_distance(name, known), known)).min().filter(|(distance, _)| *distance <= 2);
+            return match closest {
+                Some((_, known)) => fail(ErrorKind::Usage, format!("Unknown configuration field `{}` to set, did you mean `{}`?", name, known)),
+                None => fail(ErrorKind::Usage, format!("Unknown configuration field `{}` to set", name)),
+            };
+        };
+        map.insert(name.to_string(), parse_setting(field, setting.trim()));
+    }
+
+    validate(&value, FIELDS, "")
+        .map_err(|e| Failure::new(ErrorKind::Config, format!("Invalid configuration: {}", e)))?;
+    let mut config: Config = serde_json::from_value(value)
+        .map_err(|e| Failure::new(ErrorKind::Config, format!("Invalid configuration: {}", e)))?;
+    config.printer = profile;
+    Ok(config)
+}
+
+// Value of a field given as text, numbers and flags being parsed as JSON
+fn parse_setting(field: Field, setting: &str) -> serde_json::Value {
+    match field {
+        Field::Text | Field::Choice(_) | Field::Layers => serde_json::Value::String(setting.to_string()),
+        _ => serde_json::from_str(setting).unwrap_or_else(|_| serde_json::Value::String(setting.to_string())),
+    }
+}
+
+// Fields of a configuration file, checked to hold what they should
+fn read_value(path: &str) -> Result<serde_json::Value, Failure> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|_| Failure::new(ErrorKind::Config, format!("Unable to open file {}", path)))?;
+
+    // Check that file contains JSON, then that every field holds what it should
+    let value: serde_json::Value = serde_json::from_str(&strip_comments(&json))
+        .map_err(|e| Failure::new(ErrorKind::Config, format!("Unable to parse JSON in file {}: {}", path, e)))?;
+    validate(&value, FIELDS, "")
+        .map_err(|e| Failure::new(ErrorKind::Config, format!("Invalid configuration file {}: {}", path, e)))?;
+    Ok(value)
+}
+
+// Configuration given as JSON text, to be completed once changed
+pub fn parse_config_str(json: &str) -> Result<Config, String> {
+    let value: serde_json::Value = serde_json::from_str(&strip_comments(json))
+        .map_err(|e| format!("Unable to parse JSON configuration: {}", e))?;
+    validate(&value, FIELDS, "")
+        .map_err(|e| format!("Invalid configuration: {}", e))?;
+    serde_json::from_value(value)
+        .map_err(|e| format!("Invalid configuration: {}", e))
+}
+
+// Commented configuration with every default, as written by config init
+pub const TEMPLATE: &str = include_str!("config.template.json");
+
+// Blanks out // comments outside of strings, lines being kept so errors point at the right one
+fn strip_comments(json: &str) -> String {
+    let mut stripped = String::with_capacity(json.len());
+    for line in json.lines() {
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut end = line.len();
+        for (i, c) in line.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '/' if !in_string && line[i + 1..].starts_with('/') => {
+                    end = i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        stripped.push_str(&line[..end]);
+        stripped.push('\n');
+    }
+    stripped
+}
+
+// What a field of the configuration holds
+#[derive(Clone, Copy)]
+enum Field {
+    Number(f64),
+    Positive,
+    Integer(u64, u64),
+    Text,
+    Flag,
+    Choice(&'static [&'static str]),
+    Layers,
+    Table(&'static [FieldSpec]),
+}
+
+// Name, content, example of a valid value and whether the field is required
+type FieldSpec = (&'static str, Field, &'static str, bool);
+
+const FIELDS: &[FieldSpec] = &[
+    ("solver", Field::Choice(&["lkh", "builtin"]), "\"builtin\"", false),
+    ("program", Field::Text, "\"/usr/local/bin/LKH\"", false),
+    ("solver_fallback", Field::Flag, "true", false),
+    ("precision", Field::Integer(1, u32::MAX as u64), "100", false),
+    ("num_runs", Field::Integer(1, u32::MAX as u64), "1", false),
+    ("max_merge_length", Field::Number(0.0), "0 (no limit)", true),
+    ("objective", Field::Choice(&["distance", "time"]), "\"distance\"", false),
+    ("acceleration", Field::Positive, "1000", false),
+    ("filament_diameter", Field::Positive, "1.75", false),
+    ("filament_density", Field::Positive, "1.24", false),
+    ("filament_price", Field::Number(0.0), "25", false),
+    ("assume_absolute_position", Field::Flag, "true", false),
+    ("assume_relative_extrusion", Field::Flag, "false", false),
+    ("strict_modes", Field::Flag, "false", false),
+    ("keep_scripts", Field::Flag, "false", false),
+    ("keep_comments", Field::Flag, "false", false),
+    ("viewer_markers", Field::Flag, "false", false),
+    ("minify", Field::Flag, "false", false),
+    ("line_numbers", Field::Flag, "false", false),
+    ("position_decimals", Field::Integer(0, 10), "3", false),
+    ("extrusion_decimals", Field::Integer(0, 10), "5", false),
+    ("feedrate_decimals", Field::Integer(0, 10), "1", false),
+    ("absolute_extrusion", Field::Flag, "false", false),
+    ("max_volumetric_flow", Field::Number(0.0), "15", false),
+    ("extrusion_tolerance", Field::Positive, "0.0001", false),
+    ("time_limit", Field::Number(0.0), "10", false),
+    ("annealing_time", Field::Number(0.0), "1", false),
+    // Held-Karp takes memory in 2^n
+    ("exact_max_nodes", Field::Integer(0, 20), "12", false),
+    ("gap_target", Field::Number(0.0), "2", false),
+    ("skip_gap", Field::Number(0.0), "1", false),
+    ("optimization_level", Field::Choice(&["full", "island_only"]), "\"full\"", false),
+    ("island_max_travel", Field::Number(0.0), "5", false),
+    ("threads", Field::Integer(0, u32::MAX as u64), "0 (every core)", false),
+    ("max_solver_processes", Field::Integer(0, u32::MAX as u64), "0 (one per thread)", false),
+    ("parallel_files", Field::Integer(0, u32::MAX as u64), "2", false),
+    ("lookahead_layers", Field::Integer(0, u32::MAX as u64), "0 (twice the threads)", false),
+    ("solver_timeout", Field::Number(0.0), "60", false),
+    ("solver_memory_limit", Field::Integer(0, u64::MAX), "2147483648", false),
+    ("solver_io", Field::Choice(&["files", "pipe"]), "\"pipe\"", false),
+    ("keep_intermediates", Field::Text, "\"tsp-files\"", false),
+    ("merge_report", Field::Text, "\"merges\"", false),
+    ("layers", Field::Layers, "\"2-10,15,20-\"", false),
+    ("upload", Field::Table(UPLOAD_FIELDS), "{\"printer\": \"octoprint\", \"url\": \"http://octopi.local\"}", false),
+];
+
+const UPLOAD_FIELDS: &[FieldSpec] = &[
+    ("printer", Field::Choice(&["octoprint", "moonraker"]), "\"octoprint\"", true),
+    ("url", Field::Text, "\"http://octopi.local\"", true),
+    ("api_key", Field::Text, "\"0123456789ABCDEF\"", false),
+    ("print", Field::Flag, "false", false),
+];
+
+// Points at the first field that is missing or holds the wrong thing, with a valid example.
+// Unknown fields are only warned about, along with the closest known one.
+fn validate(value: &serde_json::Value, fields: &[FieldSpec], prefix: &str) -> Result<(), String> {
+    let serde_json::Value::Object(map) = value else {
+        return Err(String::from("expected a JSON object, such as {\"solver\": \"builtin\", \"max_merge_length\": 0}"));
+    };
+
+    for &(name, _, example, required) in fields {
+        if required && !map.contains_key(name) {
+            return Err(format!("missing field `{}{}`, such as \"{}\": {}", prefix, name, name, example));
+        }
+    }
+
+    for (name, value) in map {
+        let Some(&(_, field, example, _)) = fields.iter().find(|(known, ..)| known == name) else {
+            let closest = fields.iter()
+                .map(|(known, ..)| (edit_distance(name, known), known))
+                .min()
+                .filter(|(distance, _)| *distance <= 2);
+            match closest {
+                Some((_, known)) => status!("Unknown configuration field `{}{}` ignored, did you mean `{}{}`?", prefix, name, prefix, known),
+                None => status!("Unknown configuration field `{}{}` ignored", prefix, name),
+            }
+            continue;
+        };
+
+        let expected = match field {
+            Field::Table(fields) => {
+                validate(value, fields, &format!("{}{}.", prefix, name))?;
+                continue;
+            }
+            Field::Number(min) if value.as_f64().is_some_and(|value| value >= min) => continue,
+            Field::Number(min) => format!("a number of at least {}", min),
+            Field::Positive if value.as_f64().is_some_and(|value| value > 0.0) => continue,
+            Field::Positive => String::from("a number above 0"),
+            Field::Integer(min, max) if value.as_u64().is_some_and(|value| min <= value && value <= max) => continue,
+            Field::Integer(min, max) if max == u32::MAX as u64 || max == u64::MAX => format!("a whole number of at least {}", min),
+            Field::Integer(min, max) => format!("a whole number from {} to {}", min, max),
+            Field::Text if value.is_string() => continue,
+            Field::Text => String::from("a string"),
+            Field::Flag if value.is_boolean() => continue,
+            Field::Flag => String::from("true or false"),
+            Field::Choice(choices) if value.as_str().is_some_and(|value| choices.contains(&value)) => continue,
+            Field::Choice(choices) => format!("one of {}", choices.iter().map(|choice| format!("\"{}\"", choice)).collect::<Vec<_>>().join(", ")),
+            Field::Layers => match value.as_str().map(parse_layers) {
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => format!("ranges of layers ({})", e),
+                None => String::from("a string of layer ranges"),
+            },
+        };
+        return Err(format!("field `{}{}` is {}, it must be {}, such as {}", prefix, name, value, expected, example));
+    }
+
+    Ok(())
+}
+
+// Single character insertions, deletions and substitutions turning one name into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Executable a solver program is run from: the path itself when it names a directory,
+// else the first one found in the directories of PATH, as processes are started
+fn find_program(program: &str) -> Result<PathBuf, String> {
+    let candidates: Vec<PathBuf> = if program.contains(['/', std::path::MAIN_SEPARATOR]) {
+        vec![env::current_dir().map_or_else(|_| PathBuf::from(program), |dir| dir.join(program.strip_prefix("./").unwrap_or(program)))]
+    } else {
+        env::var_os("PATH").map_or_else(Vec::new, |path| env::split_paths(&path).map(|dir| dir.join(program)).collect())
+    };
+
+    let Some(found) = candidates.iter().find(|candidate| candidate.exists()) else {
+        let looked: Vec<String> = candidates.iter()
+            .map(|candidate| candidate.parent().map_or_else(String::new, |dir| dir.display().to_string()))
+            .collect();
+        return Err(match looked.len() {
+            0 => format!("Program {} not found, PATH is not set", program),
+            1 => format!("Program {} does not exist, looked for {}", program, candidates[0].display()),
+            _ => format!("Program {} not found in PATH, looked in {}", program, looked.join(", ")),
+        });
+    };
+
+    if !found.is_file() {
+        return Err(format!("Program {} is not a file ({})", program, found.display()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = found.metadata().map_or(0, |metadata| metadata.permissions().mode());
+        if mode & 0o111 == 0 {
+            return Err(format!("Program {} is not executable ({}), allow it with chmod +x", program, found.display()));
+        }
+    }
+
+    Ok(found.clone())
+}
+
+// Checks the solver program and fills in the settings left to 0
+pub fn complete(mut config: Config) -> Result<Config, Failure> {
+    // Check that program is set and can be run before any layer needs it, the builtin solver does without it
+    if config.solver == Solver::Lkh {
+        let missing = if !cfg!(feature = "lkh") {
+            Some(String::from("This build cannot run LKH (lkh feature)"))
+        } else if config.program.is_empty() {
+            Some(String::from("Program not set in configuration file, set `program` to the LKH executable such as \"program\": \"/usr/local/bin/LKH\""))
+        } else {
+            find_program(&config.program).err()
+                .map(|e| format!("{}, `program` must be the path of the LKH executable such as \"/usr/local/bin/LKH\"", e))
         };
+
+        if let Some(reason) = missing {
+            if !config.solver_fallback {
+                return fail(ErrorKind::SolverMissing, format!("{}, or use \"solver\": \"builtin\"", reason));
+            }
+            status!("{}, using the builtin solver", reason);
+            warn!("{}, using the builtin solver", reason);
+            config.solver = Solver::Builtin;
+        }
+    }
+
+    if let Some(upload) = &mut config.upload {
+        if !cfg!(feature = "upload") {
+            return fail(ErrorKind::Config, "Uploading to a printer needs the upload feature");
+        }
+        if upload.url.is_empty() {
+            return fail(ErrorKind::Config, "Upload URL not set in configuration file");
+        }
+        upload.url = upload.url.trim_end_matches('/').to_string();
+    }
+
+    if config.max_merge_length == 0.0 {
+        config.max_merge_length = f64::INFINITY;
+    }
+
+    // Use every core unless told otherwise
+    if config.threads == 0 {
+        config.threads = std::thread::available_parallelism().map_or(1, |n| n.get());
     }
 
-    config
-}
\ No newline at end of file
+    if config.max_solver_processes == 0 {
+        config.max_solver_processes = config.threads;
+    }
+
+    // Layers parsed ahead of the writer, the biggest of them are solved first
+    if config.lookahead_layers == 0 {
+        config.lookahead_layers = config.threads * 2;
+    }
+
+    // Files of a directory are optimized two at a time, so one can parse and write while the other solves
+    if config.parallel_files == 0 {
+        config.parallel_files = 2;
+    }
+
+    // Piping relies on /dev/stdin and /dev/stdout
+    if config.solver_io != SolverIo::Files && !cfg!(unix) {
+        status!("Solver pipes are not supported on this platform, using files");
+        config.solver_io = SolverIo::Files;
+    }
+
+    Ok(config)
+}
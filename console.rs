@@ -0,0 +1,34 @@
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once stdout carries the optimized G-code, status messages then go to stderr
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_stderr(to_stderr: bool) {
+    TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+pub fn to_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+// Set while a progress bar stands in for the status messages
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// Text in green when better or red when worse, left plain unless status messages go to a terminal
+// and NO_COLOR is unset
+pub fn highlight(text: &str, better: bool) -> String {
+    let terminal = if to_stderr() { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+    if !terminal || std::env::var_os("NO_COLOR").is_some() {
+        return text.to_string();
+    }
+    format!("\x1b[{}m{}\x1b[0m", if better { 32 } else { 31 }, text)
+}
@@ -0,0 +1,47 @@
+use crate::quick_math::distance_3d;
+use crate::simulator;
+
+type Point = (f64, f64, f64);
+
+// Cost of travelling between two nodes of a layer, which every solver minimizes over the tour.
+// Costs are taken as symmetric and must not be negative. Any Fn(from, to) -> f64 is one.
+pub trait CostModel: Send + Sync {
+    fn cost(&self, from: Point, to: Point) -> f64;
+
+    // Euclidean costs are computed by LKH from the coordinates, others are written out as a matrix
+    fn is_euclidean(&self) -> bool {
+        false
+    }
+}
+
+// Length of the travel, the default
+pub struct Euclidean;
+
+impl CostModel for Euclidean {
+    fn cost(&self, from: Point, to: Point) -> f64 {
+        distance_3d(from, to)
+    }
+
+    fn is_euclidean(&self) -> bool {
+        true
+    }
+}
+
+// Seconds the travel takes at a feedrate in mm/min, starting and ending at rest,
+// so many short travels may cost more than a single long one
+pub struct TravelTime {
+    pub feedrate: f64,
+    pub acceleration: f64,
+}
+
+impl CostModel for TravelTime {
+    fn cost(&self, from: Point, to: Point) -> f64 {
+        simulator::move_time(distance_3d(from, to), self.feedrate, self.acceleration)
+    }
+}
+
+impl<F: Fn(Point, Point) -> f64 + Send + Sync> CostModel for F {
+    fn cost(&self, from: Point, to: Point) -> f64 {
+        self(from, to)
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use tracing::info;
+use crate::quick_math::distance_3d;
+use crate::segments::{self, Segment, SegmentKey, POSITION_STEP};
+
+// Segments listed for each kind of difference
+const LISTED: usize = 10;
+// Amounts and feedrates closer than this are unchanged
+const TOLERANCE: f64 = 0.0001;
+
+// Differences between the extrusion segments of two G-code files, segments being matched
+// by their endpoints whichever way they are run
+pub struct Diff {
+    pub segments: (usize, usize),
+    // Only in the first file
+    pub removed: Vec<Segment>,
+    // Only in the second file
+    pub added: Vec<Segment>,
+    // Matching segments printed out of the order of the first file
+    pub reordered: usize,
+    // Matching segments run the other way
+    pub reversed: usize,
+    pub changed_feedrates: Vec<(Segment, Segment)>,
+    pub changed_extrusions: Vec<(Segment, Segment)>,
+    // Number and length of the moves that do not extrude
+    pub travels: ((usize, f64), (usize, f64)),
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty() && self.reordered == 0 && self.reversed == 0
+            && self.changed_feedrates.is_empty() && self.changed_extrusions.is_empty()
+    }
+
+    pub fn display(&self) {
+        status!("Extrusion segments: {} -> {}", self.segments.0, self.segments.1);
+
+        status!("Removed: {}", self.removed.len());
+        for segment in self.removed.iter().take(LISTED) {
+            status!("  - line {}: {}", segment.line_num, segment);
+        }
+        status!("Added: {}", self.added.len());
+        for segment in self.added.iter().take(LISTED) {
+            status!("  + line {}: {}", segment.line_num, segment);
+        }
+        status!("Reordered: {}", self.reordered);
+        status!("Reversed: {}", self.reversed);
+        status!("Changed feedrate: {}", self.changed_feedrates.len());
+        for (first, second) in self.changed_feedrates.iter().take(LISTED) {
+            status!("  lines {} and {}: F{:.3} -> F{:.3}", first.line_num, second.line_num, first.feedrate, second.feedrate);
+        }
+        status!("Changed extrusion: {}", self.changed_extrusions.len());
+        for (first, second) in self.changed_extrusions.iter().take(LISTED) {
+            status!("  lines {} and {}: E{:.5} -> E{:.5}", first.line_num, second.line_num, first.extrusion, second.extrusion);
+        }
+
+        let ((first_travels, first_travel), (second_travels, second_travel)) = self.travels;
+        status!("Travels: {} -> {}, {:.2} -> {:.2}", first_travels, second_travels, first_travel, second_travel);
+
+        if self.is_empty() {
+            status!("\nSame extrusion segments in the same order");
+        }
+    }
+}
+
+// Compares two G-code files segment by segment
+pub fn diff(first_path: &str, second_path: &str) -> Diff {
+    let (first, first_travels) = split(segments::read(first_path));
+    let (second, second_travels) = split(segments::read(second_path));
+
+    let mut diff = Diff {
+        segments: (first.len(), second.len()),
+        removed: Vec::new(),
+        added: Vec::new(),
+        reordered: 0,
+        reversed: 0,
+        changed_feedrates: Vec::new(),
+        changed_extrusions: Vec::new(),
+        travels: (first_travels, second_travels),
+    };
+
+    // Positions in the second file of each segment, earliest first
+    let mut remaining: HashMap<SegmentKey, Vec<usize>> = HashMap::new();
+    for (i, segment) in second.iter().enumerate().rev() {
+        remaining.entry(segment.key(POSITION_STEP)).or_default().push(i);
+    }
+
+    let mut matched = vec![false; second.len()];
+    // Positions in the second file of the matched segments, in the order of the first one
+    let mut order = Vec::new();
+    for segment in first {
+        let Some(i) = remaining.get_mut(&segment.key(POSITION_STEP)).and_then(|positions| positions.pop()) else {
+            diff.removed.push(segment);
+            continue;
+        };
+        matched[i] = true;
+        order.push(i);
+
+        let other = &second[i];
+        if segments::round(other.from, POSITION_STEP) != segments::round(segment.from, POSITION_STEP) {
+            diff.reversed += 1;
+        }
+        if (other.feedrate - segment.feedrate).abs() > TOLERANCE {
+            diff.changed_feedrates.push((segment.clone(), other.clone()));
+        }
+        if (other.extrusion - segment.extrusion).abs() > TOLERANCE {
+            diff.changed_extrusions.push((segment, other.clone()));
+        }
+    }
+
+    diff.added = second.into_iter().zip(matched).filter(|(_, matched)| !matched).map(|(segment, _)| segment).collect();
+    diff.reordered = order.len() - longest_increasing(&order);
+
+    info!("Diff of {} and {}: {} removed, {} added, {} reordered segment-s",
+        first_path, second_path, diff.removed.len(), diff.added.len(), diff.reordered);
+    diff
+}
+
+// Extrusion segments, and the number and length of the other moves
+fn split(segments: Vec<Segment>) -> (Vec<Segment>, (usize, f64)) {
+    let (extrusions, travels): (Vec<Segment>, Vec<Segment>) = segments.into_iter().partition(Segment::extrudes);
+    let length = travels.iter().map(|segment| distance_3d(segment.from, segment.to)).sum();
+    (extrusions, (travels.len(), length))
+}
+
+// Length of the longest increasing subsequence, the segments left out of it being those that moved
+fn longest_increasing(values: &[usize]) -> usize {
+    // Smallest tail of the increasing subsequences of each length
+    let mut tails: Vec<usize> = Vec::new();
+    for &value in values {
+        let i = tails.partition_point(|&tail| tail < value);
+        if i == tails.len() {
+            tails.push(value);
+        } else {
+            tails[i] = value;
+        }
+    }
+    tails.len()
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use serde::Serialize;
+
+// Why the tool stopped, each kind exiting with its own code so wrappers can react to it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // Anything else, files that cannot be read or written for instance
+    Error,
+    // Options that do not go together, as for clap's own errors
+    Usage,
+    // G-code file missing, empty or not a G-code file
+    Parse,
+    Config,
+    SolverMissing,
+    // Solver runs that failed, their layers being written in their original order
+    SolverFailed,
+    // Optimized G-code not extruding what the original does
+    Verification,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorKind::Error => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::Parse => 3,
+            ErrorKind::Config => 4,
+            ErrorKind::SolverMissing => 5,
+            ErrorKind::SolverFailed => 6,
+            ErrorKind::Verification => 7,
+        }
+    }
+}
+
+// Error returned by the optimization and the commands, telling what went wrong along with the message
+#[derive(Clone, Debug, Serialize)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Failure {
+        Failure { kind, message: message.into() }
+    }
+
+    // Failure of any other kind, a file that cannot be written for instance
+    pub fn error(message: impl Into<String>) -> Failure {
+        Failure::new(ErrorKind::Error, message)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// Stops like any other error, the kind being kept for the exit code
+pub fn fail<T>(kind: ErrorKind, message: impl Into<String>) -> Result<T, Failure> {
+    Err(Failure::new(kind, message))
+}
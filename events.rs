@@ -0,0 +1,40 @@
+use std::sync::mpsc::Sender;
+use serde::Serialize;
+use crate::timing::Instant;
+
+// Progress of an optimization as layers go through the pipeline.
+// Layers are numbered from 0 and elapsed times are seconds since the optimization started.
+// Layers are solved out of order, largest first, but always written in order.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    Parsed { layer: u32, nodes: usize, elapsed: f64 },
+    Solving { layer: u32, nodes: usize, elapsed: f64 },
+    Solved { layer: u32, nodes: usize, merged: usize, solve_time: f64, elapsed: f64 },
+    // Travel saved on the layer, in the units of the G-code
+    Written { layer: u32, nodes: usize, saved: f64, elapsed: f64 },
+    Finished { layers: u32, cancelled: bool, elapsed: f64 },
+}
+
+// Sends progress events to the consumer, if there is one, from every thread of the pipeline
+pub(crate) struct Progress {
+    sender: Option<Sender<ProgressEvent>>,
+    start: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(sender: Option<Sender<ProgressEvent>>) -> Progress {
+        Progress { sender, start: Instant::now() }
+    }
+
+    pub(crate) fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    // A consumer gone away only stops the events, never the optimization
+    pub(crate) fn send(&self, event: ProgressEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
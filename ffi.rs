@@ -0,0 +1,127 @@
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+use crate::{config, OptimizationReport, Optimizer};
+
+/// Totals of an optimization, distances in the units of the G-code
+#[repr(C)]
+pub struct TspStats {
+    pub layers: u32,
+    pub base_travel: f64,
+    pub optimized_travel: f64,
+    pub base_extrusion: f64,
+    pub optimized_extrusion: f64,
+    /// Estimated minutes saved, only computed with the time objective
+    pub time_saved: f64,
+}
+
+impl From<&OptimizationReport> for TspStats {
+    fn from(report: &OptimizationReport) -> TspStats {
+        TspStats {
+            layers: report.layers,
+            base_travel: report.base_travel,
+            optimized_travel: report.optimized_travel,
+            base_extrusion: report.base_extrusion,
+            optimized_extrusion: report.optimized_extrusion,
+            time_saved: report.time_saved,
+        }
+    }
+}
+
+/// Outcome of tsp_optimize, either the optimized G-code and its stats or an error.
+/// Opaque to C, freed with tsp_result_free.
+pub struct TspResult {
+    gcode: Option<CString>,
+    stats: Option<OptimizationReport>,
+    error: Option<CString>,
+}
+
+impl TspResult {
+    fn failed(error: String) -> *mut TspResult {
+        let error = CString::new(error.replace('\0', " ")).unwrap();
+        Box::into_raw(Box::new(TspResult { gcode: None, stats: None, error: Some(error) }))
+    }
+}
+
+/// Optimizes `length` bytes of UTF-8 G-code with a JSON configuration, the builtin solver's
+/// defaults when it is null. Never returns null, the result must be given to tsp_result_free.
+///
+/// # Safety
+/// `gcode` must point to `length` readable bytes and `config` be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn tsp_optimize(gcode: *const c_char, length: usize, config: *const c_char) -> *mut TspResult {
+    if gcode.is_null() {
+        return TspResult::failed(String::from("No G-code given"));
+    }
+    let bytes = std::slice::from_raw_parts(gcode as *const u8, length);
+    let Ok(gcode) = std::str::from_utf8(bytes) else {
+        return TspResult::failed(String::from("G-code is not valid UTF-8"));
+    };
+
+    let config = if config.is_null() {
+        config::defaults()
+    } else {
+        match CStr::from_ptr(config).to_str().map_err(|e| e.to_string()).and_then(config::parse_config_str) {
+            Ok(config) => config,
+            Err(e) => return TspResult::failed(e),
+        }
+    };
+
+    let optimized = config::complete(config).and_then(|config| Optimizer::optimize_str(gcode, &config));
+
+    match optimized {
+        Ok((gcode, report)) => match CString::new(gcode) {
+            Ok(gcode) => Box::into_raw(Box::new(TspResult { gcode: Some(gcode), stats: Some(report), error: None })),
+            Err(_) => TspResult::failed(String::from("Optimized G-code holds a NUL byte")),
+        },
+        Err(e) => TspResult::failed(e.message),
+    }
+}
+
+/// Error message of a failed optimization, null if it succeeded. Valid until the result is freed.
+///
+/// # Safety
+/// `result` must come from tsp_optimize and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn tsp_result_error(result: *const TspResult) -> *const c_char {
+    result.as_ref()
+        .and_then(|result| result.error.as_ref())
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+/// Optimized G-code as a NUL-terminated string, null if the optimization failed.
+/// Valid until the result is freed.
+///
+/// # Safety
+/// `result` must come from tsp_optimize and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn tsp_result_gcode(result: *const TspResult) -> *const c_char {
+    result.as_ref()
+        .and_then(|result| result.gcode.as_ref())
+        .map_or(ptr::null(), |gcode| gcode.as_ptr())
+}
+
+/// Stats of the optimization, copied into `stats`. Returns false if it failed.
+///
+/// # Safety
+/// `result` must come from tsp_optimize and not be freed yet, `stats` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn tsp_result_stats(result: *const TspResult, stats: *mut TspStats) -> bool {
+    match (result.as_ref().and_then(|result| result.stats.as_ref()), stats.as_mut()) {
+        (Some(report), Some(stats)) => {
+            *stats = TspStats::from(report);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Frees a result and the strings it handed out, null is ignored
+///
+/// # Safety
+/// `result` must come from tsp_optimize and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn tsp_result_free(result: *mut TspResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
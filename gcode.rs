@@ -1,21 +1,30 @@
-use std::collections::HashMap;
-use log::{info, warn};
-use crate::quick_math::{get_position, distance_3d, distance_to_origin};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use tracing::{info, warn};
+use serde::Serialize;
+use crate::commands::{Command, CommandClass, CommandHandler, Commands, Modes};
+use crate::minify::Minifier;
+use crate::serial::LineNumbers;
+use crate::quick_math::{get_position, distance_3d, distance_to_origin, number};
+use crate::warnings::{WarningKind, Warnings};
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
 pub enum CoordinatesMode {
     Absolute,
     Relative,
     NotSet
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Serialize)]
 pub enum UnitsMode {
     Millimeters,
     Inches,
     NotSet
 }
 
+#[derive(Serialize)]
 pub struct GCode {
     pub file_path: String,
     pub contents: String,
@@ -25,305 +34,900 @@ pub struct GCode {
 
     pub start_commands: String,
     pub end_commands: String,
+    // Lines before the first extrusion and after the last one as they were read, when scripts are kept
+    pub start_script: String,
+    pub end_script: String,
+    // Absolute position of the extruder the end script starts from
+    pub end_script_extrusion: f64,
+    // Net filament pushed by the start and end scripts, written as they are
+    pub script_filament: (f64, f64),
+    // M73 progress commands were found, to be regenerated for the optimized order
+    pub has_progress_commands: bool,
+    // Why the file could not be read to its end, the layers from there on being left out
+    pub error: Option<String>,
+    // The last M106 or M107 read leaves the fan running
+    pub fan_on: bool,
 
-    pub layers: Vec<GCodeLayer>,
-
-    travel_count: u32,
-    extrude_count: u32,
     pub stats: GCodeStats,
+    pub warnings: Warnings,
+    // Commands no handler knew, in the order first met
+    pub unknown_commands: Vec<UnknownCommand>,
+    // Times each tool was selected
+    pub tool_changes: BTreeMap<u32, usize>,
+
+    #[serde(skip)]
+    output: Option<Output>,
+    #[serde(skip)]
+    minifier: Option<Minifier>,
+    #[serde(skip)]
+    line_numbers: Option<LineNumbers>,
+}
+
+// Command the parser dropped, with the line it was first met on as it was read
+#[derive(Clone, Debug, Serialize)]
+pub struct UnknownCommand {
+    pub command: String,
+    pub count: usize,
+    pub first_line: u32,
+    pub line: String,
+}
+
+// Path standing for stdin as input and stdout as output
+pub const STDIO: &str = "-";
+
+// Where contents go when flushed
+enum Output {
+    Stream(Box<dyn Write + Send>),
+    Memory(String),
 }
 
+#[derive(Serialize)]
 pub struct GCodeStats {
     extrusion_distance: f64,
     travel_distance: f64,
+    // Net filament pushed, retractions taking back what they pull, in the units of the G-code
+    filament: f64,
+    travel_moves: usize,
+    extrusion_moves: usize,
     pub units_mode: UnitsMode,
 }
 
-#[derive(Clone)]
+// Nodes are numbered from 1 and index parallel arrays with their number minus one,
+// the extrusion and feedrate of a node being those of the move leaving it (0 when none)
+#[derive(Clone, Default, Serialize)]
 pub struct GCodeLayer {
     pub nodes: Vec<(f64, f64, f64)>,
-    pub extrusions: HashMap<u32, f64>,
-    pub feedrates: HashMap<u32, f64>,
+    pub extrusions: Vec<f64>,
+    pub feedrates: Vec<f64>,
+    // Feedrate of travels added by the optimization
+    pub travel_feedrate: f64,
     pub end_commands: String,
+
+    // Commands to replay once the given node is reached
+    pub commands: HashMap<u32, String>,
+    // Fan commands (M106, M107) to write before the extrusion leaving the given node, whichever way it is run
+    pub fans: HashMap<u32, String>,
+    // Filament retracted once the given node is reached, before travelling away from it, and the feedrate retracting it
+    pub retractions: HashMap<u32, (f64, f64)>,
+    // Node ranges kept in their original order (wipe tower visits, tool changes)
+    pub fixed_sections: Vec<(u32, u32)>,
+    // Features named by ;TYPE: comments, from the first node extruding them
+    pub features: Vec<(u32, String)>,
+    // Tools selected by T<n> commands, from the first node extruding with them
+    pub tools: Vec<(u32, u32)>,
+    // Comments read once the given node is reached, when comments are kept
+    pub comments: HashMap<u32, String>,
+}
+
+impl GCodeLayer {
+    // Whether any move of the layer extrudes, a layer of travels only having nothing to print
+    pub fn extrudes(&self) -> bool {
+        self.extrusions.iter().any(|e| *e > 0.0)
+    }
+
+    // Extrusion of the move leaving a node, if it extrudes
+    pub fn extrusion(&self, node: u32) -> Option<f64> {
+        self.extrusions.get(node as usize - 1).copied().filter(|e| *e > 0.0)
+    }
+
+    // Feedrate of the move leaving a node, node 0 giving the travel feedrate
+    pub fn feedrate(&self, node: u32) -> Option<f64> {
+        if node == 0 {
+            return Some(self.travel_feedrate).filter(|f| *f > 0.0);
+        }
+        self.feedrates.get(node as usize - 1).copied().filter(|f| *f > 0.0)
+    }
+
+    // Feature of the move leaving a node, empty before the first ;TYPE: comment
+    pub fn feature(&self, node: u32) -> &str {
+        match self.features.partition_point(|(first, _)| *first <= node) {
+            0 => "",
+            i => &self.features[i - 1].1,
+        }
+    }
+
+    // Tool of the move leaving a node, T0 before the first tool change
+    pub fn tool(&self, node: u32) -> u32 {
+        match self.tools.partition_point(|(first, _)| *first <= node) {
+            0 => 0,
+            i => self.tools[i - 1].1,
+        }
+    }
+
+    // Moves the nodes from the given one on to a layer of their own, numbered from 1 there, along with
+    // the moves leaving them and what is attached to them. This layer ends on the node before.
+    pub fn split_off(&mut self, node: u32) -> GCodeLayer {
+        let offset = node - 1;
+        let index = offset as usize;
+        let feature = self.feature(node).to_string();
+        let tool = self.tool(node);
+
+        let mut rest = GCodeLayer {
+            nodes: self.nodes.split_off(index),
+            extrusions: self.extrusions.split_off(index.min(self.extrusions.len())),
+            feedrates: self.feedrates.split_off(index.min(self.feedrates.len())),
+            travel_feedrate: self.travel_feedrate,
+            ..Default::default()
+        };
+        // The move into the first moved node is no longer part of this layer
+        self.extrusions.truncate(index.saturating_sub(1));
+
+        for (attached, moved) in [(&mut self.commands, &mut rest.commands), (&mut self.fans, &mut rest.fans), (&mut self.comments, &mut rest.comments)] {
+            move_attached(attached, moved, node);
+        }
+        move_attached(&mut self.retractions, &mut rest.retractions, node);
+
+        let first = self.features.partition_point(|(first, _)| *first < node);
+        rest.set_feature(1, &feature);
+        for (first, feature) in self.features.split_off(first) {
+            rest.set_feature(first - offset, &feature);
+        }
+        let first = self.tools.partition_point(|(first, _)| *first < node);
+        rest.set_tool(1, tool);
+        for (first, tool) in self.tools.split_off(first) {
+            rest.set_tool(first - offset, tool);
+        }
+
+        for (first, last) in std::mem::take(&mut self.fixed_sections) {
+            if last < node {
+                self.fixed_sections.push((first, last));
+            } else if first >= node {
+                rest.fixed_sections.push((first - offset, last - offset));
+            } else {
+                self.fixed_sections.push((first, offset));
+                rest.fixed_sections.push((1, last - offset));
+            }
+        }
+
+        rest
+    }
+
+    pub fn set_extrusion(&mut self, node: u32, extrusion: f64) {
+        set_value(&mut self.extrusions, node, extrusion);
+    }
+
+    // Starts a new feature at a node unless it continues the last one
+    pub fn set_feature(&mut self, node: u32, feature: &str) {
+        if self.features.last().is_none_or(|(_, last)| last != feature) {
+            self.features.push((node, feature.to_string()));
+        }
+    }
+
+    pub fn set_tool(&mut self, node: u32, tool: u32) {
+        if self.tools.last().map_or(tool != 0, |&(_, last)| last != tool) {
+            self.tools.push((node, tool));
+        }
+    }
+
+    pub fn set_feedrate(&mut self, node: u32, feedrate: f64) {
+        set_value(&mut self.feedrates, node, feedrate);
+    }
+}
+
+// Moves what is attached to the nodes from the given one on to a layer starting there
+fn move_attached<T>(attached: &mut HashMap<u32, T>, moved: &mut HashMap<u32, T>, node: u32) {
+    let keys: Vec<u32> = attached.keys().filter(|&&key| key >= node).copied().collect();
+    for key in keys {
+        moved.insert(key - node + 1, attached.remove(&key).unwrap());
+    }
+}
+
+// Sets the value of a node, growing the array as needed
+fn set_value(values: &mut Vec<f64>, node: u32, value: f64) {
+    if values.len() < node as usize {
+        values.resize(node as usize, 0.0);
+    }
+    values[node as usize - 1] = value;
 }
 
 impl GCode {
-    // Reads a G-code file
-    pub fn read(file_path: &str) -> GCode {
-        let mut gcode = GCode {
+    // Creates a new G-code file without content
+    pub fn new(file_path: &str, 
+            position_mode: CoordinatesMode, 
+            extruder_mode: CoordinatesMode) -> GCode {
+
+        GCode {
             file_path: file_path.to_string(),
-            contents: std::fs::read_to_string(file_path)
-                .unwrap_or_else(|_| panic!("Unable to read file {}", file_path)),
+            contents: String::new(),
 
-            position_mode: CoordinatesMode::NotSet,
-            extruder_mode: CoordinatesMode::NotSet,
+            position_mode,
+            extruder_mode,
 
             start_commands: String::new(),
             end_commands: String::new(),
+            start_script: String::new(),
+            end_script: String::new(),
+            end_script_extrusion: 0.0,
+            script_filament: (0.0, 0.0),
+            has_progress_commands: false,
+            error: None,
+            fan_on: false,
 
-            layers: Vec::new(),
-
-            travel_count: 0,
-            extrude_count: 0,
             stats: GCodeStats {
                 extrusion_distance: 0.0,
                 travel_distance: 0.0,
+                filament: 0.0,
+                travel_moves: 0,
+                extrusion_moves: 0,
                 units_mode: UnitsMode::NotSet,
             },
-        };
+            warnings: Warnings::default(),
+            unknown_commands: Vec::new(),
+            tool_changes: BTreeMap::new(),
 
-        gcode.layers.push(GCodeLayer {
-            nodes: Vec::new(),
-            extrusions: HashMap::new(),
-            feedrates: HashMap::new(),
-            end_commands: String::new(),
-        });
-
-        // Processing variables
-        let mut line_num = 0;
-        let mut last_position = (0.0, 0.0, 0.0);
-        let mut current_position: (f64, f64, f64);
-        let mut current_layer: u32 = 0;
-        let mut current_z = 0.0;
-        let mut current_feedrate = 1500.0; // Default feedrate (1500 = 25 mm/s, safe value)
-        let mut last_extrusion = 0.0;
-        let mut last_travel_position = (0.0, 0.0, 0.0);
-        let mut last_loop_travel = false;
-
-        for line in gcode.contents.lines() {
-            line_num += 1;
-            let line = line.split(';').next().unwrap();
-            
-            match line.split_whitespace().next() {
-                Some("G0") | Some("G1") => {
-                    current_position = get_position(line, last_position);
-                    
-                    // Process extrusion and feed rate
-                    let mut extrudes = false;
-                    let mut extrusion = 0.0;
-                    let mut feedrate: f64 = 0.0;
-
-                    for part in line.split_whitespace() {
-                        match part.chars().next() {
-                            Some('E') => {
-                                extrusion = part[1..].parse().unwrap();
-
-                                if gcode.extruder_mode != CoordinatesMode::Relative {
-                                    extrusion -= last_extrusion;
-                                }
-
-                                extrudes = extrusion > 0.0;
-                            },
-                            Some('F') => feedrate = part[1..].parse().unwrap(),
-                            _ => (),
-                        }
-                    }
+            output: None,
+            minifier: None,
+            line_numbers: None,
+        }
+    }
 
-                    // Process stats
-                    let distance = if gcode.position_mode != CoordinatesMode::Relative {
-                        distance_3d(current_position, last_position)
-                    } else {
-                        distance_to_origin(current_position)
-                    };
-
-                    if extrudes {
-                        gcode.extrude_count += 1;
-                        gcode.stats.increment_extrusion(distance);
-                    } else {
-                        gcode.travel_count += 1;
-                        gcode.stats.increment_travel(distance);
-                    }
+    // Creates the G-code file, contents are then written to it each time they are flushed.
+    // They are written to stdout if the path is STDIO.
+    pub fn create(&mut self) -> Result<(), String> {
+        if self.file_path == STDIO {
+            self.output = Some(Output::Stream(Box::new(BufWriter::new(io::stdout()))));
+            return Ok(());
+        }
 
-                    // Process a change of layer
-                    if current_position.2 != current_z && extrudes {
-                        if last_loop_travel {
-                            last_loop_travel = false;
-                        }
-                        current_layer += 1;
-                        current_z = current_position.2;
-
-                        gcode.layers.push(GCodeLayer {
-                            nodes: Vec::new(),
-                            extrusions: HashMap::new(),
-                            feedrates: HashMap::new(),
-                            end_commands: String::new(),
-                        });
-
-                        gcode.layers[current_layer as usize].nodes.push(last_position);
-                        gcode.layers[current_layer as usize].feedrates.insert(0, 9000.0); // Default travel feedrate (150 mm/s)
-                    }
+        let file = File::create(&self.file_path)
+            .map_err(|e| format!("Unable to write to file {}: {}", self.file_path, e))?;
+        self.output = Some(Output::Stream(Box::new(BufWriter::new(file))));
+        Ok(())
+    }
 
-                    // nodes
-                    let layer = &mut gcode.layers[current_layer as usize];
-                    if extrudes {
-                        if last_loop_travel {
-                            layer.nodes.push(last_travel_position);
-                            last_loop_travel = false;
-                        }
-                        layer.nodes.push(current_position);
-                    } else if gcode.position_mode != CoordinatesMode::Relative {
-                        last_travel_position = current_position;
-                    } else {
-                        last_travel_position = 
-                            (last_travel_position.0 + current_position.0, 
-                            last_travel_position.1 + current_position.1, 
-                            last_travel_position.2 + current_position.2);
-                    }
+    // Same as create, contents being kept in memory until taken back with take_output
+    pub fn create_in_memory(&mut self) {
+        self.output = Some(Output::Memory(String::new()));
+    }
 
-                    // extrusions
-                    if extrudes {
-                        layer.extrusions.insert(layer.nodes.len() as u32 - 1, extrusion);
-                    } else {
-                        last_loop_travel = true;
-                    }
+    // Same as create, contents being dropped when flushed
+    pub fn create_sink(&mut self) {
+        self.output = Some(Output::Stream(Box::new(io::sink())));
+    }
 
-                    // feedrates
-                    let n = layer.nodes.len() as u32 - if last_loop_travel { 0 } else { 1 };
-                    if feedrate > 0.0 {
-                        layer.feedrates.insert(n, feedrate);
-                        current_feedrate = feedrate;
-                    } else {
-                        layer.feedrates.insert(n, current_feedrate);
-                    }
+    // Contents flushed to memory so far
+    pub fn take_output(&mut self) -> String {
+        match self.output.as_mut() {
+            Some(Output::Memory(output)) => std::mem::take(output),
+            _ => String::new(),
+        }
+    }
 
-                    // Update last position, extrusion and feedrate
-                    if gcode.position_mode != CoordinatesMode::Relative {
-                        last_position = current_position;
-                    }
+    // Closes the file, anything not flushed is dropped
+    pub fn close(&mut self) {
+        self.output = None;
+    }
 
-                    if gcode.extruder_mode != CoordinatesMode::Relative {
-                        last_extrusion += extrusion;
-                    } else {
-                        last_extrusion = extrusion;
-                    }
-                },
-                // Units mode: inches
-                Some("G20") => {
-                    if gcode.stats.units_mode != UnitsMode::NotSet {
-                        warn!("G20 command at line {} after units mode was already set", line_num);
+    // Commands of the G-code file with their line number, read as they are iterated
+    pub fn commands(&self) -> Commands<BufReader<File>> {
+        let file = File::open(&self.file_path)
+            .unwrap_or_else(|_| panic!("Unable to read file {}", self.file_path));
+        Commands::new(BufReader::new(file))
+    }
+
+    // Contents are minified as they are flushed from now on
+    pub fn minify(&mut self) {
+        self.minifier = Some(Minifier::default());
+    }
+
+    // Lines are numbered with checksums as they are flushed from now on
+    pub fn number_lines(&mut self) {
+        self.line_numbers = Some(LineNumbers::default());
+    }
+
+    // Appends pending contents to the G-code file
+    pub fn flush(&mut self) -> Result<(), String> {
+        let output = self.output.as_mut()
+            .unwrap_or_else(|| panic!("File {} was not created", self.file_path));
+        if let Some(minifier) = self.minifier.as_mut() {
+            self.contents = minifier.minify(&self.contents);
+        }
+        if let Some(line_numbers) = self.line_numbers.as_mut() {
+            self.contents = line_numbers.number(&self.contents);
+        }
+
+        match output {
+            Output::Stream(stream) => stream.write_all(self.contents.as_bytes())
+                .and_then(|_| stream.flush())
+                .map_err(|e| format!("Unable to write to file {}: {}", self.file_path, e))?,
+            Output::Memory(output) => output.push_str(&self.contents),
+        }
+        self.contents.clear();
+        Ok(())
+    }
+}
+
+// Parses a G-code stream one layer at a time, only the layer being read is kept in memory
+pub struct GCodeReader<R: BufRead> {
+    input: R,
+    buffer: String,
+    gcode: GCode,
+    layer: GCodeLayer,
+    ready: Option<GCodeLayer>,
+    done: bool,
+
+    // Processing variables
+    line_num: u32,
+    last_position: (f64, f64, f64),
+    current_layer: u32,
+    current_z: f64,
+    current_feedrate: f64,
+    last_extrusion: f64,
+    last_travel_position: (f64, f64, f64),
+    last_loop_travel: bool,
+    // First node and height of the primes in place since the last extrusion moving in XY, when at
+    // another height than the layer
+    prime: Option<(u32, f64)>,
+    in_tower: bool,
+    tower: Option<(u32, u32)>,
+    feature: String,
+    tool: u32,
+    // Fan commands read since the last extrusion, waiting for the next one
+    fans: String,
+    keep_comments: bool,
+    drop_markers: bool,
+    keep_scripts: bool,
+    // Modes of the moves coming before the file sets them, unless they are rejected,
+    // and whether the position and extruder modes were assumed
+    assumed_modes: Modes,
+    strict_modes: bool,
+    assumed: (bool, bool),
+    last_extruding_line: u32,
+    // Lengths of the end commands, layer end commands and comments of the last node at the last extrusion
+    tail: (usize, usize, usize),
+    handlers: Vec<CommandHandler>,
+}
+
+impl<R: BufRead> GCodeReader<R> {
+    pub fn new(file_path: &str, input: R) -> GCodeReader<R> {
+        GCodeReader {
+            input,
+            buffer: String::new(),
+            gcode: GCode::new(file_path, CoordinatesMode::NotSet, CoordinatesMode::NotSet),
+            layer: GCodeLayer::default(),
+            ready: None,
+            done: false,
+
+            line_num: 0,
+            last_position: (0.0, 0.0, 0.0),
+            current_layer: 0,
+            current_z: 0.0,
+            current_feedrate: 1500.0, // Default feedrate (1500 = 25 mm/s, safe value)
+            last_extrusion: 0.0,
+            last_travel_position: (0.0, 0.0, 0.0),
+            last_loop_travel: false,
+            prime: None,
+            in_tower: false,
+            tower: None,
+            feature: String::new(),
+            tool: 0,
+            fans: String::new(),
+            keep_comments: false,
+            drop_markers: false,
+            keep_scripts: false,
+            assumed_modes: Modes::default(),
+            strict_modes: false,
+            assumed: (false, false),
+            last_extruding_line: 0,
+            tail: (0, 0, 0),
+            handlers: Vec::new(),
+        }
+    }
+
+    // Keeps the comments of each layer along with the node they follow, instead of dropping them
+    pub fn keep_comments(&mut self) {
+        self.keep_comments = true;
+    }
+
+    // Leaves the viewer markers out of the comments kept, for output writing its own
+    pub fn drop_markers(&mut self) {
+        self.drop_markers = true;
+    }
+
+    // Keeps the lines before the first extrusion and after the last one as they are, the first layer
+    // coming out empty and nothing after the last extrusion being queued with the layers
+    pub fn keep_scripts(&mut self) {
+        self.keep_scripts = true;
+    }
+
+    // Reads the moves coming before G90/G91 or M82/M83 in these modes, absolute ones by default
+    pub fn assume_modes(&mut self, modes: Modes) {
+        self.assumed_modes = modes;
+    }
+
+    // Stops on moves coming before G90/G91 or M82/M83 instead of assuming their modes
+    pub fn strict_modes(&mut self) {
+        self.strict_modes = true;
+    }
+
+    // Asks the handler about unknown commands, after the ones added before it
+    pub fn add_handler(&mut self, handler: CommandHandler) {
+        self.handlers.push(handler);
+    }
+
+    // Modes, stats and start commands gathered so far
+    pub fn gcode(&self) -> &GCode {
+        &self.gcode
+    }
+
+    // Modes, stats and start/end commands gathered while reading
+    pub fn into_gcode(self) -> GCode {
+        self.gcode
+    }
+
+    // Sets the modes a move needs and the file left unset to the ones assumed, or stops in strict mode
+    fn check_modes(&mut self, line: &str) -> Result<(), String> {
+        let extrudes = line.split_whitespace().any(|part| part.starts_with('E'));
+        let unset = [
+            (self.gcode.position_mode == CoordinatesMode::NotSet, "position", "G90 or G91", self.assumed_modes.relative),
+            (extrudes && self.gcode.extruder_mode == CoordinatesMode::NotSet, "extruder", "M82 or M83", self.assumed_modes.relative_extrusion),
+        ];
+        for (i, (_, name, commands, relative)) in unset.into_iter().enumerate().filter(|(_, (unset, ..))| *unset) {
+            if self.strict_modes {
+                return Err(format!("Move before {} sets the {} mode", commands, name));
+            }
+            let mode = if relative { CoordinatesMode::Relative } else { CoordinatesMode::Absolute };
+            warn!("Move at line {} before {} sets the {} mode, read as {:?}", self.line_num, commands, name, mode);
+            self.gcode.warnings.add(WarningKind::AssumedMode, self.line_num);
+            if i == 0 {
+                self.gcode.position_mode = mode;
+                self.assumed.0 = true;
+            } else {
+                self.gcode.extruder_mode = mode;
+                self.assumed.1 = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn process_line(&mut self, line: &str) -> Result<(), String> {
+        let current_position: (f64, f64, f64);
+        self.line_num += 1;
+        let raw = line;
+        let (line, comment) = line.split_once(';').unwrap_or((line, ""));
+
+        // Feature type changes, wipe tower visits must keep their place in the layer
+        if let Some(feature) = comment.trim().strip_prefix("TYPE:") {
+            self.feature = feature.trim().to_string();
+            self.in_tower = is_tower(feature);
+            if !self.in_tower {
+                if let Some(range) = self.tower.take() {
+                    self.layer.fixed_sections.push(range);
+                }
+            }
+        }
+        
+        match line.split_whitespace().next() {
+            Some("G0") | Some("G1") => {
+                self.check_modes(line)?;
+                current_position = get_position(line, self.last_position)?;
+                
+                // Process extrusion and feed rate
+                let mut extrudes = false;
+                let mut extrusion = 0.0;
+                let mut feedrate: f64 = 0.0;
+
+                for part in line.split_whitespace() {
+                    match part.chars().next() {
+                        Some('E') => {
+                            extrusion = number(part)?;
+
+                            if self.gcode.extruder_mode != CoordinatesMode::Relative {
+                                extrusion -= self.last_extrusion;
+                            }
+
+                            extrudes = extrusion > 0.0;
+                        },
+                        Some('F') => feedrate = number(part)?,
+                        _ => (),
                     }
-                    gcode.stats.units_mode = UnitsMode::Inches;
-                },
-                // Units mode: millimeters
-                Some("G21") => {
-                    if gcode.stats.units_mode != UnitsMode::NotSet {
-                        warn!("G21 command at line {} after units mode was already set", line_num);
+                }
+
+                // Process stats
+                let distance = if self.gcode.position_mode != CoordinatesMode::Relative {
+                    distance_3d(current_position, self.last_position)
+                } else {
+                    distance_to_origin(current_position)
+                };
+
+                if extrudes {
+                    self.gcode.stats.increment_extrusion(distance);
+                } else {
+                    self.gcode.stats.increment_travel(distance);
+                }
+                self.gcode.stats.increment_filament(extrusion);
+
+                // Process a change of layer, on the height of extrusions moving in XY only. Primes in place
+                // at that height right before join the new layer, the ones at the top of a z-hop stay.
+                let in_place = if self.gcode.position_mode != CoordinatesMode::Relative {
+                    (current_position.0, current_position.1) == (self.last_position.0, self.last_position.1)
+                } else {
+                    (current_position.0, current_position.1) == (0.0, 0.0)
+                };
+                if extrudes && in_place {
+                    // From the travel leading to the prime, the node the new layer starts on
+                    if current_position.2 != self.current_z && self.prime.is_none() && self.last_loop_travel {
+                        self.prime = Some((self.layer.nodes.len() as u32 + 1, current_position.2));
                     }
-                    gcode.stats.units_mode = UnitsMode::Millimeters;
-                },
-                // Home all axes
-                Some("G28") => {
-                    current_position = get_position(line, (0.0, 0.0, 0.0));
-                    gcode.stats.increment_travel(distance_3d(current_position, last_position));
-                    last_position = current_position;
-
-                    gcode.layers[current_layer as usize].nodes.push(current_position);
-                },
-                // Position mode: absolute
-                Some("G90") => {
-                    if gcode.position_mode != CoordinatesMode::NotSet {
-                        warn!("G90 command at line {} after position mode was already set", line_num);
+                } else if extrudes {
+                    let prime = self.prime.take()
+                        .filter(|&(_, z)| z == current_position.2)
+                        // Primes of the start script are copied with it
+                        .filter(|_| !(self.keep_scripts && self.current_layer == 0));
+                    if current_position.2 != self.current_z {
+                        if let Some(range) = self.tower.take() {
+                            self.layer.fixed_sections.push(range);
+                        }
+                        self.current_layer += 1;
+                        self.current_z = current_position.2;
+
+                        let next = match prime {
+                            Some((node, _)) => self.layer.split_off(node),
+                            None => {
+                                self.last_loop_travel = false;
+                                GCodeLayer { nodes: vec![self.last_position], ..Default::default() }
+                            },
+                        };
+                        let layer = std::mem::replace(&mut self.layer, next);
+                        // What comes before the first layer is the start script, copied instead
+                        self.ready = Some(if self.keep_scripts && self.current_layer == 1 { GCodeLayer::default() } else { layer });
+                        self.layer.travel_feedrate = 9000.0; // Default travel feedrate (150 mm/s)
                     }
-                    gcode.position_mode = CoordinatesMode::Absolute;
-                },
-                // Position mode: relative
-                Some("G91") => {
-                    if gcode.position_mode != CoordinatesMode::NotSet {
-                        warn!("G91 command at line {} after position mode was already set", line_num);
+                }
+
+                // nodes
+                let layer = &mut self.layer;
+                let first_pushed = layer.nodes.len() as u32 + 1;
+                if extrudes {
+                    if self.last_loop_travel {
+                        layer.nodes.push(self.last_travel_position);
+                        self.last_loop_travel = false;
+                    } else if layer.nodes.is_empty() {
+                        // Extruding before any travel, from where the head starts
+                        layer.nodes.push(self.last_position);
                     }
-                    gcode.position_mode = CoordinatesMode::Relative;
-                },
-                // Set current position
-                Some("G92") => {
-                    last_position = get_position(line, last_position);
-                },
-                // Extruder mode: absolute
-                Some("M82") => {
-                    if gcode.extruder_mode != CoordinatesMode::NotSet {
-                        warn!("M82 command at line {} after extruder mode was already set", line_num);
+                    layer.nodes.push(current_position);
+                    if !self.fans.is_empty() {
+                        layer.fans.insert(layer.nodes.len() as u32 - 1, std::mem::take(&mut self.fans));
                     }
-                    gcode.extruder_mode = CoordinatesMode::Absolute;
-                },
-                // Extruder mode: relative
-                Some("M83") => {
-                    if gcode.extruder_mode != CoordinatesMode::NotSet {
-                        warn!("M83 command at line {} after extruder mode was already set", line_num);
+
+                    if self.in_tower {
+                        self.tower = Some((self.tower.map_or(first_pushed, |range| range.0), layer.nodes.len() as u32));
                     }
-                    gcode.extruder_mode = CoordinatesMode::Relative;
-                },
-                // Bed temperature and other configuration commands
-                Some("M84") | Some("M104") | Some("M107") | Some("M109") | Some("M140") | Some("M190") | Some("T0")
-                | Some("G4") | Some("M593") | Some("M572") | Some("M142") | Some("M900") | Some("M221") | Some("M569")
-                | Some("G29") | Some("M302") | Some("M555") | Some("M115") | Some("M17") | Some("M203") | Some("M205")
-                | Some("M862.1") | Some("M862.3") | Some("M862.5") | Some("M862.6") => {
-                    if current_layer == 0 {
-                        gcode.start_commands.push_str(&format!("{}\n", line));
-                    } else {
-                        gcode.end_commands.push_str(&format!("{}\n", line));
+                } else if self.gcode.position_mode != CoordinatesMode::Relative {
+                    self.last_travel_position = current_position;
+                } else {
+                    self.last_travel_position = 
+                        (self.last_travel_position.0 + current_position.0, 
+                        self.last_travel_position.1 + current_position.1, 
+                        self.last_travel_position.2 + current_position.2);
+                }
+
+                // extrusions
+                if extrudes {
+                    self.last_extruding_line = self.line_num;
+                    layer.set_extrusion(layer.nodes.len() as u32 - 1, extrusion);
+                    layer.set_feature(layer.nodes.len() as u32 - 1, &self.feature);
+                    layer.set_tool(layer.nodes.len() as u32 - 1, self.tool);
+                } else {
+                    self.last_loop_travel = true;
+                }
+
+                // feedrates
+                let n = layer.nodes.len() as u32 - if self.last_loop_travel { 0 } else { 1 };
+                if feedrate > 0.0 {
+                    self.current_feedrate = feedrate;
+                }
+                // Travels before the first node of a layer, with nothing homed yet, leave no node
+                if n > 0 {
+                    layer.set_feedrate(n, self.current_feedrate);
+                }
+
+                // Retractions wait on the last node reached for the travel leaving it
+                if extrusion < 0.0 {
+                    if layer.nodes.is_empty() {
+                        // Retracting before any move, from where the head starts
+                        layer.nodes.push(self.last_position);
                     }
-                },
-                // M106 : Turn on fan
-                Some("M106") => {
-                    // TODO : Find a better solution to handle fan commands
-                    gcode.layers[current_layer as usize].end_commands.push_str(&format!("{}\n", line));
-                },
-                // Ignore for now, TODO : Find a solution to handle these commands
-                // M73 : Set/Get build percentage
-                // M74 : Set weight on print bed
-                // M201 : Set max acceleration
-                // M204 : Set default acceleration / Set PID values (Repetier)
-                Some("M73") | Some("M74") | Some("M201") | Some("M204") => {
-                    info!("Command {} not treated yet", line);
-                },
-                // Unknown commands
-                Some(command) => {
-                    if !command.starts_with(';') {
-                        println!("Unknown command {}", command);
-                        warn!("Unknown command {} at line {}", command, line_num);
+                    let retraction = layer.retractions.entry(layer.nodes.len() as u32).or_default();
+                    retraction.0 -= extrusion;
+                    retraction.1 = self.current_feedrate;
+                }
+
+                // Update last position, extrusion and feedrate
+                if self.gcode.position_mode != CoordinatesMode::Relative {
+                    self.last_position = current_position;
+                }
+
+                if self.gcode.extruder_mode != CoordinatesMode::Relative {
+                    self.last_extrusion += extrusion;
+                } else {
+                    self.last_extrusion = extrusion;
+                }
+            },
+            // Units mode: inches
+            Some("G20") => {
+                if self.gcode.stats.units_mode != UnitsMode::NotSet {
+                    warn!("G20 command at line {} after units mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.stats.units_mode = UnitsMode::Inches;
+            },
+            // Units mode: millimeters
+            Some("G21") => {
+                if self.gcode.stats.units_mode != UnitsMode::NotSet {
+                    warn!("G21 command at line {} after units mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.stats.units_mode = UnitsMode::Millimeters;
+            },
+            // Home all axes
+            Some("G28") => {
+                current_position = get_position(line, (0.0, 0.0, 0.0))?;
+                self.gcode.stats.increment_travel(distance_3d(current_position, self.last_position));
+                self.last_position = current_position;
+
+                self.layer.nodes.push(current_position);
+            },
+            // Position mode: absolute
+            Some("G90") => {
+                if self.gcode.position_mode != CoordinatesMode::NotSet && !std::mem::take(&mut self.assumed.0) {
+                    warn!("G90 command at line {} after position mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.position_mode = CoordinatesMode::Absolute;
+            },
+            // Position mode: relative
+            Some("G91") => {
+                if self.gcode.position_mode != CoordinatesMode::NotSet && !std::mem::take(&mut self.assumed.0) {
+                    warn!("G91 command at line {} after position mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.position_mode = CoordinatesMode::Relative;
+            },
+            // Set current position, and the extruder's which absolute extrusions are counted from
+            Some("G92") => {
+                self.last_position = get_position(line, self.last_position)?;
+                if self.gcode.extruder_mode != CoordinatesMode::Relative {
+                    if let Some(e) = line.split_whitespace().find(|part| part.starts_with('E')) {
+                        self.last_extrusion = number(e)?;
                     }
-                },
-                // Empty line
-                _ => (),
-            }
+                }
+            },
+            // Extruder mode: absolute
+            Some("M82") => {
+                if self.gcode.extruder_mode != CoordinatesMode::NotSet && !std::mem::take(&mut self.assumed.1) {
+                    warn!("M82 command at line {} after extruder mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.extruder_mode = CoordinatesMode::Absolute;
+            },
+            // Extruder mode: relative
+            Some("M83") => {
+                if self.gcode.extruder_mode != CoordinatesMode::NotSet && !std::mem::take(&mut self.assumed.1) {
+                    warn!("M83 command at line {} after extruder mode was already set", self.line_num);
+                    self.gcode.warnings.add(WarningKind::RepeatedMode, self.line_num);
+                }
+                self.gcode.extruder_mode = CoordinatesMode::Relative;
+            },
+            // Bed temperature and other configuration commands
+            Some("M84") | Some("M104") | Some("M109") | Some("M140") | Some("M190")
+            | Some("G4") | Some("M593") | Some("M572") | Some("M142") | Some("M900") | Some("M221") | Some("M569")
+            | Some("G29") | Some("M302") | Some("M555") | Some("M115") | Some("M17") | Some("M203") | Some("M205")
+            | Some("M862.1") | Some("M862.3") | Some("M862.5") | Some("M862.6") => {
+                if self.current_layer == 0 {
+                    self.gcode.start_commands.push_str(&format!("{}\n", line));
+                } else {
+                    self.gcode.end_commands.push_str(&format!("{}\n", line));
+                }
+            },
+            // M106 : Turn on fan, M107 : Turn off fan, kept with the extrusion they come before
+            Some(command @ ("M106" | "M107")) => {
+                self.gcode.fan_on = command == "M106" && Command::parse(line).value('S') != Some(0.0);
+                // Fans of the start script are copied with it
+                if !(self.keep_scripts && self.current_layer == 0) {
+                    self.fans.push_str(&format!("{}\n", line));
+                }
+            },
+            // Tool change
+            Some(command) if is_tool_change(command) => {
+                self.tool = command[1..].parse().unwrap();
+                *self.gcode.tool_changes.entry(self.tool).or_default() += 1;
+                self.replay(line, true);
+            },
+            // M73 : Set/Get build percentage, dropped and regenerated once the moves are reordered
+            Some("M73") => self.gcode.has_progress_commands = true,
+            // Ignore for now, TODO : Find a solution to handle these commands
+            // M74 : Set weight on print bed
+            // M201 : Set max acceleration
+            // M204 : Set default acceleration / Set PID values (Repetier)
+            Some("M74") | Some("M201") | Some("M204") => {
+                info!("Command {} not treated yet", line);
+            },
+            // Unknown commands, unless a handler knows what to do with them
+            Some(command) if !command.starts_with(';') => {
+                let parsed = Command::parse(line);
+                match self.handlers.iter().find_map(|handler| handler(&parsed)) {
+                    Some(CommandClass::Barrier) => self.replay(line, true),
+                    Some(CommandClass::Passthrough) => self.replay(line, false),
+                    Some(CommandClass::Start) => self.gcode.start_commands.push_str(&format!("{}\n", line)),
+                    Some(CommandClass::End) => self.gcode.end_commands.push_str(&format!("{}\n", line)),
+                    None => {
+                        status!("Unknown command {}", command);
+                        warn!("Unknown command {} at line {}", command, self.line_num);
+                        self.gcode.warnings.add(WarningKind::UnknownCommand, self.line_num);
+                        match self.gcode.unknown_commands.iter_mut().find(|unknown| unknown.command == command) {
+                            Some(unknown) => unknown.count += 1,
+                            None => self.gcode.unknown_commands.push(UnknownCommand {
+                                command: command.to_string(),
+                                count: 1,
+                                first_line: self.line_num,
+                                line: raw.trim_end().to_string(),
+                            }),
+                        }
+                    },
+                }
+            },
+            // Empty line
+            _ => (),
         }
 
-        gcode
+        // Once the line is handled, the comment follows the node it led to or the last one reached
+        if self.keep_comments && !comment.trim().is_empty() && !(self.drop_markers && is_viewer_marker(comment)) {
+            let node = self.layer.nodes.len() as u32;
+            let comments = self.layer.comments.entry(node).or_default();
+            comments.push(';');
+            comments.push_str(comment.trim_end());
+            comments.push('\n');
+        }
+
+        // Anything read so far belongs to the body, the end script starts after this extrusion
+        if self.keep_scripts && self.last_extruding_line == self.line_num {
+            let node = self.layer.nodes.len() as u32;
+            let comments = self.layer.comments.get(&node).map_or(0, String::len);
+            self.tail = (self.gcode.end_commands.len(), self.layer.end_commands.len(), comments);
+            self.gcode.end_script.clear();
+            self.gcode.end_script_extrusion = self.last_extrusion;
+            self.gcode.script_filament.1 = 0.0;
+        }
+        Ok(())
     }
 
-    // Creates a new G-code file without content
-    pub fn new(file_path: &str, 
-            position_mode: CoordinatesMode, 
-            extruder_mode: CoordinatesMode) -> GCode {
+    // Leaves out of the last layer what follows its last extrusion, the end script holding it
+    fn cut_end_script(&mut self) {
+        let (end_commands, layer_end_commands, comments) = self.tail;
+        let node = self.layer.nodes.len() as u32;
+        self.gcode.end_commands.truncate(end_commands);
+        self.layer.end_commands.truncate(layer_end_commands);
+        self.layer.commands.remove(&node);
+        self.layer.retractions.remove(&node);
+        if let Some(node_comments) = self.layer.comments.get_mut(&node) {
+            node_comments.truncate(comments);
+        }
+    }
+}
 
-        GCode {
-            file_path: file_path.to_string(),
-            contents: String::new(),
+impl<R: BufRead> GCodeReader<R> {
+    // Replays a command once the last node read is reached, before the first layer it joins the start commands.
+    // Fixed, that node stays in place unless it is part of the wipe tower.
+    fn replay(&mut self, line: &str, fixed: bool) {
+        if self.current_layer == 0 {
+            self.gcode.start_commands.push_str(&format!("{}\n", line));
+            return;
+        }
 
-            position_mode,
-            extruder_mode,
+        let layer = &mut self.layer;
+        let node = layer.nodes.len() as u32;
+        layer.commands.entry(node).or_default().push_str(&format!("{}\n", line));
+        if fixed && !self.in_tower {
+            layer.fixed_sections.push((node, node));
+        }
+    }
+}
 
-            start_commands: String::new(),
-            end_commands: String::new(),
+impl<R: BufRead> Iterator for GCodeReader<R> {
+    type Item = GCodeLayer;
 
-            layers: Vec::new(),
+    fn next(&mut self) -> Option<GCodeLayer> {
+        while self.ready.is_none() && !self.done {
+            self.buffer.clear();
+            let Ok(read) = self.input.read_line(&mut self.buffer) else {
+                self.gcode.error = Some(format!("Unable to read line {} of {}", self.line_num + 1, self.gcode.file_path));
+                self.done = true;
+                return None;
+            };
 
-            travel_count: 0,
-            extrude_count: 0,
-            stats: GCodeStats {
-                extrusion_distance: 0.0,
-                travel_distance: 0.0,
-                units_mode: UnitsMode::NotSet,
-            },
+            if read == 0 {
+                // End of file, the last layer is complete
+                self.done = true;
+                if self.keep_scripts && self.current_layer > 0 {
+                    self.cut_end_script();
+                }
+                // Fans after the last extrusion end the layer, unless the end script holds them
+                if !self.keep_scripts {
+                    self.layer.end_commands.push_str(&std::mem::take(&mut self.fans));
+                }
+                if let Some(range) = self.tower.take() {
+                    self.layer.fixed_sections.push(range);
+                }
+                self.ready = Some(std::mem::take(&mut self.layer));
+            } else {
+                let line = std::mem::take(&mut self.buffer);
+                let filament = self.gcode.stats.filament;
+                // Reading stops on the first line that cannot be understood
+                if let Err(e) = self.process_line(line.trim_end_matches(['\n', '\r'])) {
+                    self.gcode.error = Some(format!("{} at line {} of {}", e, self.line_num, self.gcode.file_path));
+                    self.done = true;
+                    return None;
+                }
+                if self.keep_scripts && self.current_layer == 0 {
+                    self.gcode.start_script.push_str(&line);
+                    self.gcode.script_filament.0 += self.gcode.stats.filament - filament;
+                } else if self.keep_scripts && self.last_extruding_line != self.line_num {
+                    self.gcode.end_script.push_str(&line);
+                    self.gcode.script_filament.1 += self.gcode.stats.filament - filament;
+                }
+                self.buffer = line;
+            }
         }
+
+        self.ready.take()
     }
+}
 
-    // Writes contents to G-code file
-    pub fn write(&self) {
-        std::fs::write(&self.file_path, &self.contents)
-            .unwrap_or_else(|_| panic!("Unable to write to file {}", self.file_path));
+// Writes a parameter such as " X12.5", rounded to the given decimals without trailing zeros
+pub fn write_value(contents: &mut String, prefix: &str, value: f64, decimals: usize) {
+    let start = contents.len();
+    contents.push_str(prefix);
+    write!(contents, "{:.*}", decimals, value).unwrap();
+    if decimals > 0 {
+        let trimmed = contents.trim_end_matches('0').trim_end_matches('.').len();
+        contents.truncate(trimmed);
+    }
+    // Values rounding to zero from below
+    if contents[start + prefix.len()..] == *"-0" {
+        contents.truncate(start + prefix.len());
+        contents.push('0');
     }
 }
 
+// Wipe tower (PrusaSlicer) or prime tower (Cura) feature
+// Comments G-code viewers split layers and color features by
+pub fn is_viewer_marker(comment: &str) -> bool {
+    let comment = comment.trim();
+    comment == "LAYER_CHANGE" || comment.starts_with("Z:") || comment.starts_with("TYPE:")
+}
+
+fn is_tower(feature: &str) -> bool {
+    let feature = feature.trim().to_lowercase().replace('-', " ");
+    feature == "wipe tower" || feature == "prime tower"
+}
+
+// T0, T1, ... commands
+fn is_tool_change(command: &str) -> bool {
+    command.len() > 1 && command.starts_with('T') && command[1..].parse::<u32>().is_ok()
+}
+
 impl GCodeStats {
     pub fn display(&self) {
         let units = match self.units_mode {
@@ -331,8 +935,8 @@ impl GCodeStats {
             UnitsMode::Inches => "in",
             UnitsMode::NotSet => "units",
         };
-        println!("Extrusion distance: {:.2} {}", self.extrusion_distance, units);
-        println!("Travel distance: {:.2} {}", self.travel_distance, units);
+        status!("Extrusion distance: {:.2} {}", self.extrusion_distance, units);
+        status!("Travel distance: {:.2} {}", self.travel_distance, units);
     }
 
     pub fn log(&self, info: String) {
@@ -345,11 +949,89 @@ impl GCodeStats {
         info!("{}, travel distance: {:.2} {}", info, self.travel_distance, units);
     }
 
+    pub fn travel_distance(&self) -> f64 {
+        self.travel_distance
+    }
+
+    pub fn extrusion_distance(&self) -> f64 {
+        self.extrusion_distance
+    }
+
+    pub fn filament(&self) -> f64 {
+        self.filament
+    }
+
+    pub fn increment_filament(&mut self, length: f64) {
+        self.filament += length;
+    }
+
+    pub fn travel_moves(&self) -> usize {
+        self.travel_moves
+    }
+
+    pub fn extrusion_moves(&self) -> usize {
+        self.extrusion_moves
+    }
+
+    // Adds an extruding move of the given length
     pub fn increment_extrusion(&mut self, distance: f64) {
         self.extrusion_distance += distance;
+        self.extrusion_moves += 1;
     }
 
+    // Adds a travel of the given length
     pub fn increment_travel(&mut self, distance: f64) {
         self.travel_distance += distance;
+        self.travel_moves += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_starting_by_extruding() {
+        let gcode = "G90\nM82\nG1 X10 Y10 E1 F1200\nG1 X20 Y10 E2\nG1 X20 Y20 E3\n";
+        let layers: Vec<GCodeLayer> = GCodeReader::new("test.gcode", gcode.as_bytes()).collect();
+
+        assert_eq!(layers.len(), 1);
+        let layer = &layers[0];
+        assert_eq!(layer.nodes, vec![(0.0, 0.0, 0.0), (10.0, 10.0, 0.0), (20.0, 10.0, 0.0), (20.0, 20.0, 0.0)]);
+        assert_eq!(layer.extrusion(1), Some(1.0));
+        assert_eq!(layer.extrusion(3), Some(1.0));
+        assert_eq!(layer.feedrate(1), Some(1200.0));
+    }
+
+    #[test]
+    fn retractions_take_back_filament() {
+        let gcode = "G90\nM82\nG0 X10 Y10\nG1 E0.8\nG1 X20 Y10 E1.8\nG1 E1\nG0 X30 Y30\nG1 E1.8\nG1 X40 Y30 E2.8\nG1 E2\n";
+        let mut reader = GCodeReader::new("test.gcode", gcode.as_bytes());
+        reader.by_ref().for_each(drop);
+
+        assert!((reader.gcode().stats.filament() - 2.0).abs() < 1e-9);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn scripts_kept_with_their_filament() {
+        let gcode = "G90\nM83\nG1 X5 Y5 E2\nG1 E-0.8\nG1 Z0.2\nG0 X10 Y10\nG1 E0.8\nG1 X20 Y10 Z0.2 E1\nG1 E-0.8\nG0 Z10\n";
+        let mut reader = GCodeReader::new("test.gcode", gcode.as_bytes());
+        reader.keep_scripts();
+        reader.by_ref().for_each(drop);
+
+        let (start, end) = reader.gcode().script_filament;
+        // The prime before the first layer belongs to the start script
+        assert!((start - 2.0).abs() < 1e-9 && (end + 0.8).abs() < 1e-9);
+        assert!((reader.gcode().stats.filament() - 2.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_number_stops_the_parser() {
+        let gcode = "G90\nM82\nG1 X10 Y10 E1\nG1 X2O Y10 E2\nG1 X20 Y20 E3\n";
+        let mut reader = GCodeReader::new("test.gcode", gcode.as_bytes());
+        let layers: Vec<GCodeLayer> = reader.by_ref().collect();
+
+        assert!(layers.is_empty());
+        assert_eq!(reader.gcode().error.as_deref(), Some("Invalid number X2O at line 4 of test.gcode"));
+    }
+}
@@ -0,0 +1,75 @@
+/* Generated from ffi.rs with cbindgen, do not edit */
+
+#ifndef TSP_GCODE_OPTIMIZER_H
+#define TSP_GCODE_OPTIMIZER_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Outcome of tsp_optimize, either the optimized G-code and its stats or an error.
+ * Opaque to C, freed with tsp_result_free.
+ */
+typedef struct TspResult TspResult;
+
+/**
+ * Totals of an optimization, distances in the units of the G-code
+ */
+typedef struct TspStats {
+  uint32_t layers;
+  double base_travel;
+  double optimized_travel;
+  double base_extrusion;
+  double optimized_extrusion;
+  /**
+   * Estimated minutes saved, only computed with the time objective
+   */
+  double time_saved;
+} TspStats;
+
+/**
+ * Optimizes `length` bytes of UTF-8 G-code with a JSON configuration, the builtin solver's
+ * defaults when it is null. Never returns null, the result must be given to tsp_result_free.
+ *
+ * # Safety
+ * `gcode` must point to `length` readable bytes and `config` be null or a NUL-terminated string.
+ */
+struct TspResult *tsp_optimize(const char *gcode, size_t length, const char *config);
+
+/**
+ * Error message of a failed optimization, null if it succeeded. Valid until the result is freed.
+ *
+ * # Safety
+ * `result` must come from tsp_optimize and not be freed yet.
+ */
+const char *tsp_result_error(const struct TspResult *result);
+
+/**
+ * Optimized G-code as a NUL-terminated string, null if the optimization failed.
+ * Valid until the result is freed.
+ *
+ * # Safety
+ * `result` must come from tsp_optimize and not be freed yet.
+ */
+const char *tsp_result_gcode(const struct TspResult *result);
+
+/**
+ * Stats of the optimization, copied into `stats`. Returns false if it failed.
+ *
+ * # Safety
+ * `result` must come from tsp_optimize and not be freed yet, `stats` must be writable.
+ */
+bool tsp_result_stats(const struct TspResult *result, struct TspStats *stats);
+
+/**
+ * Frees a result and the strings it handed out, null is ignored
+ *
+ * # Safety
+ * `result` must come from tsp_optimize and not be freed yet.
+ */
+void tsp_result_free(struct TspResult *result);
+
+#endif  /* TSP_GCODE_OPTIMIZER_H */
@@ -0,0 +1,2383 @@
+// Prints a status message, on stderr when stdout carries the optimized G-code,
+// nowhere while a progress bar is shown
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::console::is_quiet() {
+        } else if $crate::console::to_stderr() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub mod ab;
+pub mod analysis;
+pub mod audit;
+pub mod batch;
+pub mod bench;
+pub mod bounds;
+pub mod cancel;
+mod checkpoint;
+pub mod commands;
+pub mod concat;
+pub mod config;
+pub mod console;
+pub mod cost;
+pub mod diff;
+pub mod error;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod gcode;
+pub mod lint;
+mod minify;
+mod pool;
+pub mod printer;
+mod progress;
+mod quick_math;
+pub mod report;
+pub mod segments;
+mod serial;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "async")]
+pub mod service;
+pub mod simulator;
+mod solver;
+mod temp;
+pub mod timing;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "upload")]
+pub mod upload;
+pub mod verify;
+pub mod visualize;
+pub mod warnings;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+#[cfg(feature = "lkh")]
+use std::io::{Read, Write};
+use std::panic;
+#[cfg(feature = "lkh")]
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+#[cfg(feature = "lkh")]
+use std::time::Duration;
+use std::{fs, thread};
+use std::path::Path;
+use tracing::{debug, info, info_span, warn};
+use quick_math::{distance_3d, segments_cross};
+use timing::Instant;
+use error::{ErrorKind, Failure};
+pub use report::OptimizationReport;
+
+/*
+TODO (problems) :
+- Low feedrate on some g-codes : issue related to acceleration commands
+- PrusaSlicer related commands need to be treated
+*/
+
+/*
+TODO (optimizations) :
+- Usage of Z-hops only
+- Problems separation according to size
+- Multiple layers
+- Deletion of negligible movements
+- LKH parameters (Initial tour for LKH, other parameters, etc...)
+- Usage of LKH via source code instead of calling the program
+*/
+
+// Solver processes running in the whole process; each run starts one only while fewer than its own
+// max_solver_processes are running
+#[cfg(feature = "lkh")]
+static SOLVER_SLOTS: pool::Semaphore = pool::Semaphore::new();
+
+// Shared by every solver thread of an optimization
+struct SolveContext<'a> {
+    config: &'a config::Config,
+    // Temp directory of the external solver's files, none with the builtin solver
+    #[cfg(feature = "lkh")]
+    work_dir: Option<&'a Path>,
+    cost: Arc<dyn cost::CostModel>,
+    // Runtime of the async service the optimization was started from
+    #[cfg(feature = "async")]
+    runtime: Option<tokio::runtime::Handle>,
+    // Problems whose solver failed, kept in their original order, and their layers
+    failures: AtomicUsize,
+    failed_layers: Mutex<Vec<u32>>,
+    cancel: cancel::CancellationToken,
+}
+
+// Moves of a solved layer, the number of nodes its problems were merged into and the time it took
+struct LayerSolution {
+    moves: Vec<(i32, i32)>,
+    merged: usize,
+    times: timing::PhaseTimes,
+    // Why the original order was kept, for the whole layer or one of its sections
+    kept: Option<report::SkipReason>,
+    // Chains its problems were merged into, when merges are reported
+    chains: Vec<MergedChain>,
+}
+
+// Original nodes a TSP node stands for, an extrusion chain being two TSP nodes joined by a fixed edge
+struct MergedChain {
+    // Free section of the layer the problem was built from, numbered from 0
+    section: usize,
+    tsp_nodes: (u32, u32),
+    nodes: (u32, u32),
+    length: f64,
+}
+
+// Layer given to Optimizer::optimize_layers along with the order to print it in
+pub struct OptimizedLayer {
+    pub layer: gcode::GCodeLayer,
+    // Moves between nodes of the layer, starting on its first node with (1, 1).
+    // A move to the next or previous node follows the original move between them, any other one is a travel.
+    pub moves: Vec<(i32, i32)>,
+    pub report: report::LayerReport,
+}
+
+impl OptimizedLayer {
+    // Positions in the order they are reached, with the extrusion of the move reaching them if it extrudes
+    pub fn path(&self) -> Vec<((f64, f64, f64), Option<f64>)> {
+        self.moves.iter().map(|&(origin, destination)| {
+            let extrusion = if destination - origin == 1 { self.layer.extrusion(origin as u32) }
+                else if destination - origin == -1 { self.layer.extrusion(destination as u32) }
+                else { None };
+            (self.layer.nodes[destination as usize - 1], extrusion)
+        }).collect()
+    }
+}
+
+pub struct Optimizer {
+    config: config::Config,
+    resume: bool,
+    in_place: bool,
+    dry_run: bool,
+    // CSV of the layers written along with the output, if any
+    csv_path: Option<String>,
+    events: Option<mpsc::Sender<events::ProgressEvent>>,
+    cost: Arc<dyn cost::CostModel>,
+    handlers: Vec<commands::CommandHandler>,
+    cancel: cancel::CancellationToken,
+
+    base_gcode: gcode::GCode,
+    optimized_gcode: gcode::GCode,
+
+    last_position: (f64, f64, f64),
+    current_layer: u32,
+    last_extrusion: f64,
+    time_saved: f64,
+    solver_failures: usize,
+    flow_limited: usize,
+    feedrate_capped: usize,
+    // Feature of the last ;TYPE: marker written in the layer
+    marked_feature: String,
+    times: timing::PhaseTimes,
+    layers: Vec<report::LayerReport>,
+    features: Vec<report::FeatureReport>,
+    tools: Vec<report::ToolReport>,
+    warnings: warnings::Warnings,
+}
+
+// Builds an optimizer, checking its input first. The output defaults to the input path
+// followed by _optimized.gcode and the configuration to the builtin solver's defaults.
+// An existing output file is only replaced when overwriting is allowed.
+#[derive(Default)]
+pub struct OptimizerBuilder {
+    input: Option<String>,
+    output: Option<String>,
+    config: Option<config::Config>,
+    resume: bool,
+    overwrite: bool,
+    in_place: bool,
+    dry_run: bool,
+    csv_path: Option<Option<String>>,
+    events: Option<mpsc::Sender<events::ProgressEvent>>,
+    cost: Option<Arc<dyn cost::CostModel>>,
+    handlers: Vec<commands::CommandHandler>,
+    cancel: Option<cancel::CancellationToken>,
+}
+
+impl OptimizerBuilder {
+    pub fn input(mut self, gcode_path: &str) -> OptimizerBuilder {
+        self.input = Some(gcode_path.to_string());
+        self
+    }
+
+    pub fn output(mut self, optimized_path: &str) -> OptimizerBuilder {
+        self.output = Some(optimized_path.to_string());
+        self
+    }
+
+    pub fn config(mut self, config: config::Config) -> OptimizerBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    // Reuses the layers solved by an interrupted run of the same file
+    pub fn resume(mut self, resume: bool) -> OptimizerBuilder {
+        self.resume = resume;
+        self
+    }
+
+    // Allows replacing an existing output file
+    pub fn overwrite(mut self, overwrite: bool) -> OptimizerBuilder {
+        self.overwrite = overwrite;
+        self
+    }
+
+    // Replaces the input file with the optimized G-code once the optimization completes
+    pub fn in_place(mut self, in_place: bool) -> OptimizerBuilder {
+        self.in_place = in_place;
+        self
+    }
+
+    // Solves every layer without writing anything, only the reports are kept
+    pub fn dry_run(mut self, dry_run: bool) -> OptimizerBuilder {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // CSV of the distances and timings of each layer, <input>.csv by default and none when not given.
+    // Streams never have one.
+    pub fn csv(mut self, csv_path: Option<&str>) -> OptimizerBuilder {
+        self.csv_path = Some(csv_path.map(str::to_string));
+        self
+    }
+
+    // Progress of the optimization is sent there, from any thread
+    pub fn events(mut self, sender: mpsc::Sender<events::ProgressEvent>) -> OptimizerBuilder {
+        self.events = Some(sender);
+        self
+    }
+
+    // Cost of the travels the solvers minimize, their length by default. Reported travels and
+    // savings stay distances whatever the model.
+    pub fn cost_model(mut self, cost: impl cost::CostModel + 'static) -> OptimizerBuilder {
+        self.cost = Some(Arc::new(cost));
+        self
+    }
+
+    // Classifies the commands the parser does not know, which are dropped otherwise.
+    // Handlers are asked in the order they were added until one of them knows the command.
+    pub fn command_handler(mut self, handler: impl Fn(&commands::Command) -> Option<commands::CommandClass> + Send + Sync + 'static) -> OptimizerBuilder {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    // Stops the optimization once cancelled: layers not solved yet are written in their original order
+    // and running solvers are stopped, the report telling it was cancelled
+    pub fn cancellation(mut self, token: cancel::CancellationToken) -> OptimizerBuilder {
+        self.cancel = Some(token);
+        self
+    }
+
+    // The input may be STDIO to read stdin, the output then defaulting to stdout
+    pub fn build(self) -> Result<Optimizer, String> {
+        let input = self.input.ok_or("No input G-code file given")?;
+        if input != gcode::STDIO {
+            check_gcode_file(&input)?;
+        }
+
+        // In place, the output is written next to the input until it replaces it
+        if self.in_place && (input == gcode::STDIO || self.output.is_some()) {
+            return Err(String::from("Only an input file without output file can be optimized in place"));
+        }
+        let output = match self.output {
+            Some(output) => output,
+            None if self.in_place => format!("{}.optimizing", input),
+            None if input == gcode::STDIO => input.clone(),
+            None => format!("{}_optimized.gcode", input),
+        };
+        if output == input && input != gcode::STDIO {
+            return Err(format!("Output {} would overwrite the input file", output));
+        }
+        if !self.overwrite && !self.in_place && !self.dry_run && output != gcode::STDIO && Path::new(&output).exists() {
+            return Err(format!("Output file {} already exists, use --force to overwrite it", output));
+        }
+
+        let mut optimizer = Optimizer::new(self.config.unwrap_or_default(), &input, &output, self.resume);
+        optimizer.in_place = self.in_place;
+        optimizer.dry_run = self.dry_run;
+        optimizer.csv_path = self.csv_path.unwrap_or_else(|| Some(format!("{}.csv", input)));
+        optimizer.events = self.events;
+        optimizer.handlers = self.handlers;
+        if let Some(cancel) = self.cancel {
+            optimizer.cancel = cancel;
+        }
+        if let Some(cost) = self.cost {
+            optimizer.cost = cost;
+        }
+        Ok(optimizer)
+    }
+}
+
+impl Optimizer {
+    pub fn builder() -> OptimizerBuilder {
+        OptimizerBuilder::default()
+    }
+
+    fn new(config: config::Config, gcode_path: &str, optimized_path: &str, resume: bool) -> Optimizer {
+        let extruder_mode = if config.absolute_extrusion { gcode::CoordinatesMode::Absolute } else { gcode::CoordinatesMode::Relative };
+        let mut optimized_gcode = gcode::GCode::new(optimized_path, gcode::CoordinatesMode::Absolute, extruder_mode);
+        if config.minify {
+            optimized_gcode.minify();
+        }
+        Optimizer {
+            config,
+            resume,
+            in_place: false,
+            dry_run: false,
+            csv_path: None,
+            events: None,
+            cost: Arc::new(cost::Euclidean),
+            handlers: Vec::new(),
+            cancel: cancel::CancellationToken::new(),
+            base_gcode: gcode::GCode::new(gcode_path,
+                gcode::CoordinatesMode::NotSet,
+                gcode::CoordinatesMode::NotSet),
+            optimized_gcode,
+            last_position: (0.0, 0.0, 0.0),
+            current_layer: 0,
+            last_extrusion: 0.0,
+            time_saved: 0.0,
+            solver_failures: 0,
+            flow_limited: 0,
+            feedrate_capped: 0,
+            marked_feature: String::new(),
+            times: timing::PhaseTimes::default(),
+            layers: Vec::new(),
+            features: Vec::new(),
+            tools: Vec::new(),
+            warnings: warnings::Warnings::default(),
+        }
+    }
+
+    pub fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    // File the optimized G-code is written to, STDIO for stdout
+    pub fn output_path(&self) -> &str {
+        &self.optimized_gcode.file_path
+    }
+
+    pub fn base_stats(&self) -> &gcode::GCodeStats {
+        &self.base_gcode.stats
+    }
+
+    pub fn optimized_stats(&self) -> &gcode::GCodeStats {
+        &self.optimized_gcode.stats
+    }
+
+    // Problems the solver failed on, written in their original order
+    pub fn solver_failures(&self) -> usize {
+        self.solver_failures
+    }
+
+    // Estimated minutes saved, only computed with the time objective
+    pub fn time_saved(&self) -> f64 {
+        self.time_saved
+    }
+
+    pub fn times(&self) -> &timing::PhaseTimes {
+        &self.times
+    }
+
+    // Travel and time of each layer written so far
+    pub fn layer_reports(&self) -> &[report::LayerReport] {
+        &self.layers
+    }
+
+    fn set_units(&mut self) {
+        self.optimized_gcode.stats.units_mode = self.base_gcode.stats.units_mode;
+    }
+
+    // Optimizes the input to the output, returning what the optimization did
+    pub fn optimize(&mut self) -> Result<OptimizationReport, Failure> {
+        let gcode_path = self.base_gcode.file_path.clone();
+
+        // A dry run solves every layer but writes nothing, not even a checkpoint
+        if self.dry_run {
+            self.optimized_gcode.create_sink();
+            if gcode_path == gcode::STDIO {
+                self.run(BufReader::new(io::stdin()), None, HashMap::new())?;
+            } else {
+                let file = File::open(&gcode_path)
+                    .map_err(|_| Failure::new(ErrorKind::Parse, format!("Unable to read file {}", gcode_path)))?;
+                self.run(BufReader::new(file), None, HashMap::new())?;
+            }
+            return Ok(self.report());
+        }
+
+        // Status messages make way for the G-code on stdout, numbered as it goes since nothing is rewritten
+        if self.optimized_gcode.file_path == gcode::STDIO {
+            console::set_stderr(true);
+            if self.config.line_numbers {
+                self.optimized_gcode.number_lines();
+            }
+        }
+        self.optimized_gcode.create().map_err(Failure::error)?;
+
+        // A stream has no checkpoint nor CSV going along with it
+        if gcode_path == gcode::STDIO {
+            self.run(BufReader::new(io::stdin()), None, HashMap::new())?;
+            return Ok(self.report());
+        }
+
+        let file = File::open(&gcode_path)
+            .map_err(|_| Failure::new(ErrorKind::Parse, format!("Unable to read file {}", gcode_path)))?;
+
+        // Layers solved by an interrupted run are taken from its checkpoint
+        let checkpoint_path = format!("{}.checkpoint", gcode_path);
+        let (mut checkpoint, solved) = checkpoint::Checkpoint::open(&checkpoint_path, self.resume).map_err(Failure::error)?;
+
+        self.run(BufReader::new(file), Some(&mut checkpoint), solved)?;
+
+        if self.cancel.is_cancelled() {
+            status!("Optimization cancelled, solved layers are kept in {} for --resume", checkpoint_path);
+        } else {
+            checkpoint.finish();
+        }
+
+        // Progress commands of the original order no longer hold, they are worked out again for the new one
+        if self.base_gcode.has_progress_commands && self.optimized_gcode.file_path != gcode::STDIO {
+            self.optimized_gcode.close();
+            progress::add_progress(&self.optimized_gcode.file_path, &simulator::Limits::new(&self.config)).map_err(Failure::error)?;
+        }
+        // A stream is gone once written, nothing can be read back from it
+        if self.optimized_gcode.file_path != gcode::STDIO {
+            self.optimized_gcode.close();
+            self.check_extrusion()?;
+            self.lint();
+            self.audit_commands();
+            if let Some(profile) = self.config.printer.clone() {
+                self.check_bounds(&profile)?;
+            }
+            // Numbered once checked, the checks reading commands without numbers
+            if self.config.line_numbers {
+                serial::number_file(&self.optimized_gcode.file_path).map_err(Failure::error)?;
+            }
+        }
+        if self.in_place {
+            self.replace_input()?;
+        }
+        #[cfg(feature = "upload")]
+        if let Some(target) = &self.config.upload {
+            // Half optimized files never reach the printer
+            if !self.cancel.is_cancelled() {
+                self.optimized_gcode.close();
+                upload::upload(target, &self.optimized_gcode.file_path).map_err(Failure::error)?;
+            }
+        }
+
+        // Store the distances and timings of each layer into a CSV file
+        if let Some(csv_path) = &self.csv_path {
+            report::write_csv(&self.layers, csv_path).map_err(Failure::error)?;
+            report::write_skipped_csv(&self.layers, &report::skipped_csv_path(csv_path)).map_err(Failure::error)?;
+        }
+
+        Ok(self.report())
+    }
+
+    // Stops before the optimized G-code replaces the input or reaches the printer if it dropped or
+    // duplicated material, every extrusion segment of the input being written exactly once and each
+    // layer pushing the same net filament, retractions included
+    fn check_extrusion(&self) -> Result<(), Failure> {
+        let optimized_path = &self.optimized_gcode.file_path;
+        let verification = verify::verify(&self.base_gcode.file_path, optimized_path, self.config.extrusion_tolerance, self.config.position_decimals, self.config.input_modes());
+        if !verification.is_ok() {
+            status!("\nExtrusion of {} differs from {}:", optimized_path, self.base_gcode.file_path);
+            verification.display();
+            return error::fail(ErrorKind::Verification,
+                format!("Optimized G-code {} does not extrude what {} does, do not print it", optimized_path, self.base_gcode.file_path));
+        }
+        Ok(())
+    }
+
+    // Warns about structural problems of the optimized G-code, which still prints
+    fn lint(&mut self) {
+        let optimized_path = &self.optimized_gcode.file_path;
+        let issues = lint::lint(&self.base_gcode.file_path, optimized_path);
+        if issues.is_empty() {
+            return;
+        }
+        status!("\nWarning: {} lint issue-s in {}:", issues.len(), optimized_path);
+        lint::display(&issues);
+        for issue in &issues {
+            warn!("Line {} of {}: {}, {}", issue.line_num, optimized_path, issue.kind.name(), issue.message);
+            self.warnings.add(warnings::WarningKind::Lint, issue.line_num);
+        }
+    }
+
+    // Whether the end of the output turns off the fan the input leaves running, end scripts being copied as they are
+    fn turns_fan_off(&self) -> bool {
+        !self.config.keep_scripts && self.base_gcode.fan_on
+    }
+
+    // Warns about commands of the input written more or less than once, besides the ones dropped by policy
+    fn audit_commands(&mut self) {
+        let optimized_path = &self.optimized_gcode.file_path;
+        let unknown: Vec<&str> = self.base_gcode.unknown_commands.iter().map(|unknown| unknown.command.as_str()).collect();
+        let added: &[&str] = if self.turns_fan_off() { &["M107"] } else { &[] };
+        let audit = audit::audit(&self.base_gcode.file_path, optimized_path, &unknown, added);
+        if audit.is_ok() {
+            info!("Every one of the {} command-s of {} is written once", audit.commands, self.base_gcode.file_path);
+            return;
+        }
+        status!("\nWarning: commands of {} differ from {}:", optimized_path, self.base_gcode.file_path);
+        audit.display();
+        for discrepancy in &audit.missing {
+            warn!("{} written {} time-s instead of {}", discrepancy.command, discrepancy.output, discrepancy.input);
+            self.warnings.add(warnings::WarningKind::LostCommand, discrepancy.line_num);
+        }
+        for discrepancy in &audit.extra {
+            warn!("{} written {} time-s instead of {}", discrepancy.command, discrepancy.output, discrepancy.input);
+            self.warnings.add(warnings::WarningKind::AddedCommand, discrepancy.line_num);
+        }
+    }
+
+    // Stops before the optimized G-code replaces the input or reaches the printer if it leaves the bed
+    fn check_bounds(&mut self, profile: &printer::PrinterProfile) -> Result<(), Failure> {
+        let optimized_path = &self.optimized_gcode.file_path;
+        let check = bounds::check(&self.base_gcode.file_path, optimized_path, profile, self.config.input_modes());
+        if !check.inherited.is_empty() {
+            status!("Warning: {} move-s leave the bed of {} as they do in the original", check.inherited.len(), profile.name);
+            warn!("{} move-s off the bed of {}, as in the original", check.inherited.len(), profile.name);
+            for &line in &check.inherited {
+                self.warnings.add(warnings::WarningKind::OffBed, line);
+            }
+        }
+        if !check.is_ok() {
+            status!("\n{} of {} move-s of {} leave the bed of {}:", check.outside.len(), check.moves, optimized_path, profile.name);
+            check.display();
+            return error::fail(ErrorKind::Verification,
+                format!("Optimized G-code {} leaves the bed of {}, do not print it", optimized_path, profile.name));
+        }
+        info!("Every move of {} stays on the bed of {}", optimized_path, profile.name);
+        Ok(())
+    }
+
+    // Moves the optimized G-code over the input file, which is left unchanged if cancelled
+    fn replace_input(&mut self) -> Result<(), Failure> {
+        let gcode_path = self.base_gcode.file_path.clone();
+        let optimized_path = self.optimized_gcode.file_path.clone();
+        self.optimized_gcode.close();
+
+        if self.cancel.is_cancelled() {
+            fs::remove_file(&optimized_path)
+                .map_err(|e| Failure::new(ErrorKind::Error, format!("Unable to remove file {}: {}", optimized_path, e)))?;
+            status!("File {} left unchanged", gcode_path);
+            return Ok(());
+        }
+
+        fs::rename(&optimized_path, &gcode_path)
+            .map_err(|e| Failure::new(ErrorKind::Error, format!("Unable to replace file {} with {}: {}", gcode_path, optimized_path, e)))?;
+        self.optimized_gcode.file_path = gcode_path;
+        info!("Replaced {} with its optimized G-code", self.optimized_gcode.file_path);
+        Ok(())
+    }
+
+    // Optimizes G-code held in memory, returning the optimized G-code and its report.
+    // The G-code never touches the filesystem, only solver runs may go through temp files.
+    pub fn optimize_str(input: &str, config: &config::Config) -> Result<(String, OptimizationReport), Failure> {
+        Optimizer::optimize_str_with_events(input, config, None)
+    }
+
+    // Same as optimize_str, sending the progress of the optimization to a consumer
+    pub fn optimize_str_with_events(input: &str, config: &config::Config, events: Option<mpsc::Sender<events::ProgressEvent>>) -> Result<(String, OptimizationReport), Failure> {
+        if input.trim().is_empty() {
+            return error::fail(ErrorKind::Parse, "G-code is empty");
+        }
+
+        let mut optimizer = Optimizer::new(config.clone(), "", "", false);
+        optimizer.events = events;
+        optimizer.optimized_gcode.create_in_memory();
+        if config.line_numbers {
+            optimizer.optimized_gcode.number_lines();
+        }
+        optimizer.run(input.as_bytes(), None, HashMap::new())?;
+        Ok((optimizer.optimized_gcode.take_output(), optimizer.report()))
+    }
+
+    // Optimizes layers built by the caller rather than parsed from G-code, such as generated toolpaths.
+    // Layers are numbered from 0 in the given order, each one starting where the previous one ends,
+    // and come back in that order with their moves. Nothing is written anywhere.
+    pub fn optimize_layers(layers: Vec<gcode::GCodeLayer>, config: &config::Config) -> Result<Vec<OptimizedLayer>, Failure> {
+        let mut optimizer = Optimizer::new(config.clone(), "", "", false);
+        Optimizer::create_report_dirs(config)?;
+        #[cfg(feature = "lkh")]
+        let work_dir = Optimizer::work_dir(config)?;
+        let context = SolveContext {
+            config,
+            #[cfg(feature = "lkh")]
+            work_dir: work_dir.as_ref().map(|work_dir| work_dir.path()),
+            cost: Arc::clone(&optimizer.cost),
+            #[cfg(feature = "async")]
+            runtime: tokio::runtime::Handle::try_current().ok(),
+            failures: AtomicUsize::new(0),
+            failed_layers: Mutex::new(Vec::new()),
+            cancel: optimizer.cancel.clone(),
+        };
+        let solvable = |i: u32, layer: &gcode::GCodeLayer| layer.nodes.len() > 3 && layer.extrudes() && config.optimizes_layer(i);
+
+        // Every layer is known upfront, all of them are queued before waiting for the first one
+        let solutions: Vec<Option<LayerSolution>> = thread::scope(|scope| {
+            let (mut pool, jobs) = pool::WorkerPool::run(scope, config.threads, |current_layer, layer: &gcode::GCodeLayer| {
+                let _span = info_span!("solve", layer = current_layer).entered();
+                Optimizer::solve_layer(layer, current_layer, &context)
+            });
+            for (i, layer) in layers.iter().enumerate() {
+                if solvable(i as u32, layer) {
+                    jobs.send_with_priority(i as u32, layer, layer.nodes.len());
+                }
+            }
+            drop(jobs);
+
+            (0..layers.len() as u32)
+                .map(|i| solvable(i, &layers[i as usize]).then(|| pool.wait(i)))
+                .collect()
+        });
+
+        let mut base_position = (0.0, 0.0, 0.0);
+        let mut position = (0.0, 0.0, 0.0);
+        Ok(layers.into_iter().zip(solutions).enumerate().map(|(i, (layer, solution))| {
+            optimizer.current_layer = i as u32;
+            // Empty layers have no node to start from
+            let original = if layer.nodes.is_empty() { Vec::new() } else { Optimizer::original_moves(&layer) };
+            let (moves, merged, status, solve_time, skip_reason) = match solution {
+                Some(solution) => {
+                    let (moves, slower) = optimizer.select_moves(&layer, solution.moves);
+                    (moves, solution.merged, report::LayerStatus::Solved, solution.times.solve, slower.or(solution.kept))
+                }
+                None => (original.clone(), 0, report::LayerStatus::Skipped, 0.0, Some(report::SkipReason::unsolved(&layer, i as u32, config))),
+            };
+
+            let report = report::LayerReport {
+                layer: i as u32,
+                nodes: layer.nodes.len(),
+                merged,
+                status,
+                solve_time,
+                base_travel: Optimizer::travel(&layer, base_position, &original),
+                optimized_travel: Optimizer::travel(&layer, position, &moves),
+                base_extrusion: Optimizer::extrusion(&layer, &original),
+                optimized_extrusion: Optimizer::extrusion(&layer, &moves),
+                base_time: Optimizer::layer_time(&layer, &original, config),
+                optimized_time: Optimizer::layer_time(&layer, &moves, config),
+                base_crossings: Optimizer::crossings(&layer, base_position, &original),
+                optimized_crossings: Optimizer::crossings(&layer, position, &moves),
+                skip_reason,
+            };
+            base_position = layer.nodes.last().copied().unwrap_or(base_position);
+            position = moves.last().map_or(position, |&(_, destination)| layer.nodes[destination as usize - 1]);
+
+            OptimizedLayer { layer, moves, report }
+        }).collect())
+    }
+
+    // Tools of the last optimization with the times they were selected
+    fn tools(&self) -> Vec<report::ToolReport> {
+        let mut tools = self.tools.clone();
+        for (&tool, &changes) in &self.base_gcode.tool_changes {
+            report::tool(&mut tools, tool).tool_changes = changes;
+        }
+        tools
+    }
+
+    // Warnings of the last optimization, those of the parser first
+    pub fn warnings(&self) -> warnings::Warnings {
+        let mut warnings = self.base_gcode.warnings.clone();
+        warnings.merge(&self.warnings);
+        warnings
+    }
+
+    // Totals of the last optimization
+    pub fn report(&self) -> OptimizationReport {
+        OptimizationReport {
+            layers: self.current_layer,
+            base_travel: self.base_gcode.stats.travel_distance(),
+            optimized_travel: self.optimized_gcode.stats.travel_distance(),
+            base_extrusion: self.base_gcode.stats.extrusion_distance(),
+            optimized_extrusion: self.optimized_gcode.stats.extrusion_distance(),
+            base_travel_moves: self.base_gcode.stats.travel_moves(),
+            optimized_travel_moves: self.optimized_gcode.stats.travel_moves(),
+            base_extrusion_moves: self.base_gcode.stats.extrusion_moves(),
+            optimized_extrusion_moves: self.optimized_gcode.stats.extrusion_moves(),
+            base_filament: self.base_gcode.stats.filament(),
+            optimized_filament: self.optimized_gcode.stats.filament(),
+            time_saved: self.time_saved,
+            solver_failures: self.solver_failures,
+            flow_limited: self.flow_limited,
+            feedrate_capped: self.feedrate_capped,
+            solve_time: self.times.solve,
+            cancelled: self.cancel.is_cancelled(),
+            per_layer: self.layers.clone(),
+            features: self.features.clone(),
+            tools: self.tools(),
+            warnings: self.warnings(),
+            unknown_commands: self.base_gcode.unknown_commands.clone(),
+        }
+    }
+
+    // Parses, solves and writes every layer of the input to the optimized G-code, reusing the layers
+    // of the checkpoint found solved. Fails on the first line of the input that cannot be read,
+    // or when the output, the checkpoint or the directories of the reports cannot be written.
+    fn run<R: BufRead + Send>(&mut self, input: R, mut checkpoint: Option<&mut checkpoint::Checkpoint>, mut solved: HashMap<u32, usize>) -> Result<(), Failure> {
+        let gcode_path = self.base_gcode.file_path.clone();
+        let progress = events::Progress::new(self.events.clone());
+
+        // The first layer is read ahead, it holds the start commands and modes needed by the header
+        let mut reader = gcode::GCodeReader::new(&gcode_path, input);
+        for handler in &self.handlers {
+            reader.add_handler(Arc::clone(handler));
+        }
+        if self.config.keep_comments {
+            reader.keep_comments();
+            if self.config.viewer_markers {
+                reader.drop_markers();
+            }
+        }
+        if self.config.keep_scripts {
+            reader.keep_scripts();
+        }
+        reader.assume_modes(self.config.input_modes());
+        if self.config.strict_modes {
+            reader.strict_modes();
+        }
+        let start = Instant::now();
+        let first = reader.next().map(|layer| (layer, timing::seconds_since(start)));
+        self.optimized_gcode.stats.units_mode = reader.gcode().stats.units_mode;
+
+        // Start of file
+        let start = Instant::now();
+        if self.config.keep_scripts {
+            self.write_start_script(reader.gcode());
+        } else {
+            self.write_header(&gcode_path, reader.gcode());
+        }
+        self.optimized_gcode.flush().map_err(Failure::error)?;
+        self.times.write_output += timing::seconds_since(start);
+
+        let config = self.config.clone();
+        // Directories of the reports are checked once, before any layer is solved
+        Optimizer::create_report_dirs(&config)?;
+        #[cfg(feature = "lkh")]
+        let work_dir = Optimizer::work_dir(&config)?;
+        let context = SolveContext {
+            config: &config,
+            #[cfg(feature = "lkh")]
+            work_dir: work_dir.as_ref().map(|work_dir| work_dir.path()),
+            cost: Arc::clone(&self.cost),
+            #[cfg(feature = "async")]
+            runtime: tokio::runtime::Handle::try_current().ok(),
+            failures: AtomicUsize::new(0),
+            failed_layers: Mutex::new(Vec::new()),
+            cancel: self.cancel.clone(),
+        };
+        // Layers too small to gain anything, with nothing to print or left out by the configuration keep their order
+        let solvable = |i: u32, layer: &gcode::GCodeLayer| layer.nodes.len() > 3 && layer.extrudes() && config.optimizes_layer(i);
+
+        // Parsing, solving and writing overlap: layers flow from the parser to the solver threads
+        // and to the writer, which puts them back in order
+        let base_gcode = thread::scope(|scope| {
+            // Solve layers on a bounded number of threads
+            let (mut pool, jobs) = pool::WorkerPool::run(scope, config.threads, |current_layer, layer: Arc<gcode::GCodeLayer>| {
+                let _span = info_span!("solve", layer = current_layer).entered();
+                let nodes = layer.nodes.len();
+                progress.send(events::ProgressEvent::Solving { layer: current_layer, nodes, elapsed: progress.elapsed() });
+                let solution = Optimizer::solve_layer(&layer, current_layer, &context);
+                progress.send(events::ProgressEvent::Solved {
+                    layer: current_layer,
+                    nodes,
+                    merged: solution.merged,
+                    solve_time: solution.times.solve,
+                    elapsed: progress.elapsed(),
+                });
+                solution
+            });
+
+            // Parser handing each layer over as soon as it is read, until the writer no longer takes them
+            let parser_progress = &progress;
+            let parse = move |emit: &mut dyn FnMut((Arc<gcode::GCodeLayer>, bool, f64)) -> bool| {
+                let mut next = first;
+                for i in 0.. {
+                    let Some((layer, parse_time)) = next.take() else {
+                        break;
+                    };
+                    let layer = Arc::new(layer);
+                    parser_progress.send(events::ProgressEvent::Parsed { layer: i, nodes: layer.nodes.len(), elapsed: parser_progress.elapsed() });
+
+                    // Checkpointed layers are reused as long as their nodes are unchanged
+                    let reused = solved.remove(&i).is_some_and(|nodes| nodes == layer.nodes.len());
+                    // Largest layers take longest, starting them first shortens the run
+                    if solvable(i, &layer) && !reused {
+                        jobs.send_with_priority(i, Arc::clone(&layer), layer.nodes.len());
+                    }
+
+                    if !emit((layer, reused, parse_time)) {
+                        break;
+                    }
+
+                    let start = Instant::now();
+                    next = reader.next().map(|layer| (layer, timing::seconds_since(start)));
+                }
+
+                reader.into_gcode()
+            };
+
+            // Reset position
+            self.current_layer = 0;
+            self.layers.clear();
+            self.features.clear();
+            self.tools.clear();
+            self.flow_limited = 0;
+            self.feedrate_capped = 0;
+            self.warnings = warnings::Warnings::default();
+            self.last_extrusion = 0.0;
+            // Where the original G-code left the previous layer
+            let mut base_position = (0.0, 0.0, 0.0);
+
+            let mut write_layer = |(layer, reused, parse_time): (Arc<gcode::GCodeLayer>, bool, f64)| -> Result<(), Failure> {
+                let _span = info_span!("write", layer = self.current_layer).entered();
+                let mut times = timing::PhaseTimes { parse: parse_time, ..Default::default() };
+                let written_travel = self.optimized_gcode.stats.travel_distance();
+                let written_extrusion = self.optimized_gcode.stats.extrusion_distance();
+                // Empty layers have no node to start from
+                // Nodes of layers without extrusion are homing positions and travels the first move of the
+                // next layer makes up for, no move is made up for them
+                let original = if layer.extrudes() { Optimizer::original_moves(&layer) } else { Vec::new() };
+                let position = self.last_position;
+                Optimizer::add_features(&mut self.features, &layer, base_position, &original, false);
+                Optimizer::add_tools(&mut self.tools, &layer, base_position, &original, false);
+                let base_travel = Optimizer::travel(&layer, base_position, &original);
+                let base_crossings = Optimizer::crossings(&layer, base_position, &original);
+                let base_extrusion = Optimizer::extrusion(&layer, &original);
+                let base_time = Optimizer::layer_time(&layer, &original, &self.config);
+                base_position = layer.nodes.last().copied().unwrap_or(base_position);
+                let mut optimized_time = base_time;
+                let mut optimized_crossings = base_crossings;
+                let mut merged = 0;
+                let mut status = report::LayerStatus::Skipped;
+                let skip_reason;
+
+                // Room for a move line per node, around 48 bytes each
+                self.optimized_gcode.contents.reserve(layer.nodes.len() * 48);
+                if self.config.viewer_markers {
+                    self.add_layer_markers(&layer);
+                }
+
+                if solvable(self.current_layer, &layer) {
+                    let solution = if reused {
+                        status!("Reusing checkpoint of layer {}", self.current_layer);
+                        status = report::LayerStatus::Reused;
+                        let solved = checkpoint.as_mut().unwrap().solved(self.current_layer).map_err(Failure::error)?;
+                        LayerSolution { moves: solved.moves, merged: solved.merged, times: timing::PhaseTimes::default(), kept: None, chains: Vec::new() }
+                    } else {
+                        let solution = pool.wait(self.current_layer);
+                        status!("Processing result of layer {}", self.current_layer);
+                        status = report::LayerStatus::Solved;
+                        // Once cancelled, layers may come back unsolved and must be solved on resume
+                        if let Some(checkpoint) = checkpoint.as_mut().filter(|_| !context.cancel.is_cancelled()) {
+                            checkpoint.save(self.current_layer, layer.nodes.len(), &solution.moves, solution.merged).map_err(Failure::error)?;
+                        }
+                        solution
+                    };
+                    merged = solution.merged;
+                    times.add(&solution.times);
+                    let (moves, slower) = self.select_moves(&layer, solution.moves);
+                    skip_reason = slower.or(solution.kept);
+                    optimized_time = Optimizer::layer_time(&layer, &moves, &self.config);
+                    optimized_crossings = Optimizer::crossings(&layer, position, &moves);
+                    Optimizer::add_features(&mut self.features, &layer, position, &moves, true);
+                    Optimizer::add_tools(&mut self.tools, &layer, position, &moves, true);
+                    let start = Instant::now();
+                    self.add_moved_comments(&layer, &moves);
+                    self.add_moves(&layer, &moves);
+                    times.write_output += timing::seconds_since(start);
+                } else {
+                    if !layer.extrudes() {
+                        status!("Skipping layer {} (no extrusion)", self.current_layer);
+                    } else if layer.nodes.len() > 3 {
+                        status!("Skipping layer {} (not selected)", self.current_layer);
+                    } else {
+                        status!("Skipping layer {} ({} node-s)", self.current_layer, layer.nodes.len());
+                    }
+                    skip_reason = Some(report::SkipReason::unsolved(&layer, self.current_layer, &self.config));
+                    Optimizer::add_features(&mut self.features, &layer, position, &original, true);
+                    Optimizer::add_tools(&mut self.tools, &layer, position, &original, true);
+                    let start = Instant::now();
+                    self.add_moved_comments(&layer, &original);
+                    self.add_moves(&layer, &original);
+                    // Commands and retractions of a layer without moves are written as they came
+                    if !layer.extrudes() {
+                        let mut nodes: Vec<u32> = layer.commands.keys().chain(layer.retractions.keys()).copied().collect();
+                        nodes.sort();
+                        nodes.dedup();
+                        for node in nodes {
+                            if let Some(commands) = layer.commands.get(&node) {
+                                self.optimized_gcode.contents.push_str(commands);
+                            }
+                            self.add_retraction(&layer, node);
+                        }
+                    }
+                    times.write_output += timing::seconds_since(start);
+                }
+                let optimized_travel = self.optimized_gcode.stats.travel_distance() - written_travel;
+                let optimized_extrusion = self.optimized_gcode.stats.extrusion_distance() - written_extrusion;
+                info!("Layer {}: travel {:.2} -> {:.2} ({:.2} saved), {} in {:.3} s",
+                    self.current_layer, base_travel, optimized_travel, base_travel - optimized_travel, status.name(), times.solve);
+                self.layers.push(report::LayerReport {
+                    layer: self.current_layer,
+                    nodes: layer.nodes.len(),
+                    merged,
+                    status,
+                    solve_time: times.solve,
+                    base_travel,
+                    optimized_travel,
+                    base_extrusion,
+                    optimized_extrusion,
+                    base_time,
+                    optimized_time,
+                    base_crossings,
+                    optimized_crossings,
+                    skip_reason,
+                });
+
+                // Write buffer
+                let start = Instant::now();
+                self.optimized_gcode.contents.push_str(&layer.end_commands);
+                // Running totals restart at each layer, keeping E values small enough for any firmware
+                if self.optimized_gcode.extruder_mode == gcode::CoordinatesMode::Absolute {
+                    self.optimized_gcode.contents.push_str("G92 E0\n");
+                    self.last_extrusion = 0.0;
+                }
+                self.optimized_gcode.flush().map_err(Failure::error)?;
+                times.write_output += timing::seconds_since(start);
+                progress.send(events::ProgressEvent::Written {
+                    layer: self.current_layer,
+                    nodes: layer.nodes.len(),
+                    saved: base_travel - optimized_travel,
+                    elapsed: progress.elapsed(),
+                });
+
+                times.log(format!("Layer {} times", self.current_layer));
+                self.times.add(&times);
+
+                // Update current position
+                self.current_layer += 1;
+                Ok(())
+            };
+
+            // The parser stops as soon as a layer cannot be written
+            if pool::THREADED {
+                // Parser stays a few layers ahead of the writer, so the file is never fully held in memory
+                let (sender, receiver) = mpsc::sync_channel(config.lookahead_layers);
+                let parser = scope.spawn(move || parse(&mut |layer| sender.send(layer).is_ok()));
+                let written = receiver.into_iter().try_for_each(&mut write_layer);
+                let base_gcode = parser.join().unwrap_or_else(|e| panic::resume_unwind(e));
+                written.map(|_| base_gcode)
+            } else {
+                // Without threads, each layer is solved and written as soon as it is parsed
+                let mut written = Ok(());
+                let base_gcode = parse(&mut |layer| {
+                    written = write_layer(layer);
+                    written.is_ok()
+                });
+                written.map(|_| base_gcode)
+            }
+        })?;
+
+        // Modes, stats and end commands are complete once the whole file is parsed
+        self.base_gcode = base_gcode;
+        if let Some(e) = &self.base_gcode.error {
+            return error::fail(ErrorKind::Parse, e.clone());
+        }
+        self.set_units();
+        if self.cancel.is_cancelled() {
+            warn!("Optimization cancelled on layer {}", self.current_layer);
+        }
+        self.solver_failures = context.failures.load(Ordering::Relaxed);
+        for &layer in context.failed_layers.lock().unwrap().iter() {
+            self.warnings.add(warnings::WarningKind::SolverFailure, layer);
+        }
+
+        // End of file
+        let start = Instant::now();
+        if self.turns_fan_off() {
+            self.optimized_gcode.contents.push_str("M107\n");
+        }
+        self.optimized_gcode.contents.push_str(&self.base_gcode.end_commands);
+        if self.config.keep_scripts {
+            self.write_end_script();
+        }
+        self.optimized_gcode.flush().map_err(Failure::error)?;
+        self.times.write_output += timing::seconds_since(start);
+
+        progress.send(events::ProgressEvent::Finished {
+            layers: self.current_layer,
+            cancelled: self.cancel.is_cancelled(),
+            elapsed: progress.elapsed(),
+        });
+        Ok(())
+    }
+
+    // Header setting the machine up the way the layers are written
+    fn write_header(&mut self, gcode_path: &str, base_gcode: &gcode::GCode) {
+        self.optimized_gcode.contents.push_str(";Generated with TSP G-code optimizer V0.1\n");
+        if !gcode_path.is_empty() && gcode_path != gcode::STDIO {
+            self.optimized_gcode.contents.push_str(&format!(";Original file: {}\n", gcode_path));
+        }
+        self.optimized_gcode.contents.push_str("G28\n");
+        match self.optimized_gcode.stats.units_mode {
+            gcode::UnitsMode::Millimeters => self.optimized_gcode.contents.push_str("G21\n"),
+            gcode::UnitsMode::Inches => self.optimized_gcode.contents.push_str("G20\n"),
+            _ => (),
+        }
+        match self.optimized_gcode.position_mode {
+            gcode::CoordinatesMode::Absolute => self.optimized_gcode.contents.push_str("G90\n"),
+            gcode::CoordinatesMode::Relative => self.optimized_gcode.contents.push_str("G91\n"),
+            _ => (),
+        }
+        match self.optimized_gcode.extruder_mode {
+            gcode::CoordinatesMode::Absolute => self.optimized_gcode.contents.push_str("M82\n"),
+            gcode::CoordinatesMode::Relative => self.optimized_gcode.contents.push_str("M83\n"),
+            _ => (),
+        }
+        self.optimized_gcode.contents.push_str(&base_gcode.start_commands);
+        self.optimized_gcode.contents.push_str("G92 E0\n");
+    }
+
+    // Start script of the slicer as it was, followed by the modes the layers are written in
+    // when the script leaves the machine in others
+    fn write_start_script(&mut self, base_gcode: &gcode::GCode) {
+        self.optimized_gcode.contents.push_str(&base_gcode.start_script);
+        self.optimized_gcode.stats.increment_filament(base_gcode.script_filament.0);
+        self.optimized_gcode.contents.push_str(";Generated with TSP G-code optimizer V0.1\n");
+        if base_gcode.position_mode != self.optimized_gcode.position_mode {
+            self.optimized_gcode.contents.push_str("G90\n");
+        }
+        if base_gcode.extruder_mode != self.optimized_gcode.extruder_mode {
+            match self.optimized_gcode.extruder_mode {
+                gcode::CoordinatesMode::Absolute => self.optimized_gcode.contents.push_str("M82\n"),
+                _ => self.optimized_gcode.contents.push_str("M83\n"),
+            }
+        }
+        if self.optimized_gcode.extruder_mode == gcode::CoordinatesMode::Absolute {
+            self.optimized_gcode.contents.push_str("G92 E0\n");
+        }
+    }
+
+    // End script of the slicer as it was, the extruder being put back in the mode and position it expects
+    fn write_end_script(&mut self) {
+        let contents = &mut self.optimized_gcode.contents;
+        if self.base_gcode.extruder_mode == gcode::CoordinatesMode::Relative {
+            if self.optimized_gcode.extruder_mode != gcode::CoordinatesMode::Relative {
+                contents.push_str("M83\n");
+            }
+        } else {
+            // Without M82 nor M83 the extruder was read as absolute, as firmware defaults to
+            if self.optimized_gcode.extruder_mode != gcode::CoordinatesMode::Absolute {
+                contents.push_str("M82\n");
+            }
+            gcode::write_value(contents, "G92 E", self.base_gcode.end_script_extrusion, self.config.extrusion_decimals);
+            contents.push('\n');
+        }
+        contents.push_str(&self.base_gcode.end_script);
+        self.optimized_gcode.stats.increment_filament(self.base_gcode.script_filament.1);
+    }
+
+    // Temp directory of the solver runs. Piped runs only leave the parameter file behind,
+    // kept in memory when possible. The builtin solver needs no files at all.
+    #[cfg(feature = "lkh")]
+    fn work_dir(config: &config::Config) -> Result<Option<temp::TempDir>, Failure> {
+        let shm = Path::new("/dev/shm");
+        match config.solver {
+            config::Solver::Builtin => Ok(None),
+            config::Solver::Lkh if config.solver_io != config::SolverIo::Files && shm.is_dir() => temp::TempDir::new_in(shm).map(Some),
+            config::Solver::Lkh => temp::TempDir::new().map(Some),
+        }.map_err(Failure::error)
+    }
+
+    // Creates the directories the merge report and the kept solver files are written to, failing before
+    // any layer is solved if they cannot be written. Files then failing to be written are only warned about.
+    fn create_report_dirs(config: &config::Config) -> Result<(), Failure> {
+        for dir in [&config.merge_report, &config.keep_intermediates] {
+            if dir.is_empty() {
+                continue;
+            }
+            fs::create_dir_all(dir)
+                .map_err(|e| Failure::new(ErrorKind::Error, format!("Unable to create directory {}: {}", dir, e)))?;
+            if fs::metadata(dir).is_ok_and(|metadata| metadata.permissions().readonly()) {
+                return error::fail(ErrorKind::Error, format!("Unable to write to directory {}, it is read-only", dir));
+            }
+        }
+        Ok(())
+    }
+
+    // Solves a layer section by section, fixed sections like wipe tower visits stay untouched in between
+    fn solve_layer(layer: &gcode::GCodeLayer, current_layer: u32, context: &SolveContext) -> LayerSolution {
+        let solution = if layer.fixed_sections.is_empty() {
+            Optimizer::solve_section(layer, current_layer, context)
+        } else {
+            Optimizer::solve_sections(layer, current_layer, context)
+        };
+        if !context.config.merge_report.is_empty() {
+            if let Err(e) = Optimizer::write_merge_report(&context.config.merge_report, current_layer, &solution.chains) {
+                warn!("Merge report of layer {} not written: {}", current_layer, e);
+            }
+        }
+
+        solution
+    }
+
+    fn solve_sections(layer: &gcode::GCodeLayer, current_layer: u32, context: &SolveContext) -> LayerSolution {
+        let mut solution = LayerSolution { moves: vec![(1, 1)], merged: 0, times: timing::PhaseTimes::default(), kept: None, chains: Vec::new() };
+        let mut section_count = 0;
+        let last_node = layer.nodes.len() as u32;
+        let mut start = 1;
+
+        for &(first, last) in layer.fixed_sections.iter().chain(std::iter::once(&(last_node, last_node))) {
+            // Free section leading to the fixed one, its start and end stay in place
+            if first > start + 2 {
+                let section = Optimizer::sub_layer(layer, start, first);
+                let part = Optimizer::solve_section(&section, current_layer, context);
+                let offset = start as i32 - 1;
+
+                solution.merged += part.merged;
+                solution.times.add(&part.times);
+                solution.kept = solution.kept.or(part.kept);
+                solution.chains.extend(part.chains.into_iter().map(|chain| MergedChain {
+                    section: section_count,
+                    nodes: (chain.nodes.0 + start - 1, chain.nodes.1 + start - 1),
+                    ..chain
+                }));
+                section_count += 1;
+                solution.moves.extend(part.moves.iter()
+                    .filter(|(origin, destination)| origin != destination)
+                    .map(|(origin, destination)| (origin + offset, destination + offset)));
+            } else {
+                solution.moves.extend((start as i32 + 1..=first as i32).map(|i| (i - 1, i)));
+            }
+
+            // Fixed section, in its original order
+            solution.moves.extend((first as i32 + 1..=last as i32).map(|i| (i - 1, i)));
+            start = last;
+        }
+
+        solution
+    }
+
+    // Nodes first..=last of a layer, renumbered from 1
+    fn sub_layer(layer: &gcode::GCodeLayer, first: u32, last: u32) -> gcode::GCodeLayer {
+        let mut section = gcode::GCodeLayer {
+            nodes: layer.nodes[first as usize - 1..last as usize].to_vec(),
+            ..Default::default()
+        };
+
+        for key in first..last {
+            if let Some(e) = layer.extrusion(key) {
+                section.set_extrusion(key - first + 1, e);
+            }
+            if let Some(f) = layer.feedrate(key) {
+                section.set_feedrate(key - first + 1, f);
+            }
+        }
+        section.travel_feedrate = layer.travel_feedrate;
+
+        section
+    }
+
+    // Solves tiny problems exactly, bigger ones with the configured solver
+    fn solve_section(layer: &gcode::GCodeLayer, current_layer: u32, context: &SolveContext) -> LayerSolution {
+        // Layers left once cancelled are written as they are
+        if context.cancel.is_cancelled() {
+            return LayerSolution { moves: Optimizer::original_moves(layer), merged: 0, times: timing::PhaseTimes::default(), kept: Some(report::SkipReason::Cancelled), chains: Vec::new() };
+        }
+
+        let mut times = timing::PhaseTimes::default();
+        let start = Instant::now();
+        let (problem, merges) = Optimizer::build_problem(layer, current_layer, context);
+        times.merge = timing::seconds_since(start);
+        debug!("{} nodes merged into {} in {:.3} s", layer.nodes.len(), merges.len(), times.merge);
+
+        let start = Instant::now();
+        let mut kept = None;
+        // Tours of the builtin solvers are checked as the ones of LKH are when read
+        let checked = |tour: Vec<i32>, method: &str| problem.check_tour(&tour)
+            .map_err(|e| warn!("Invalid {} tour for layer {}: {}", method, current_layer, e))
+            .map(|_| tour)
+            .ok();
+        let (tour, method) = if merges.len() <= context.config.exact_max_nodes {
+            status!("Solving layer {} exactly ({} nodes)", current_layer, merges.len());
+            let tour = solver::held_karp(&problem);
+            times.solve = timing::seconds_since(start);
+            (checked(tour, "exact"), "exact")
+        } else if let Some(tour) = Optimizer::near_bound_tour(&problem, current_layer, context.config) {
+            times.solve = timing::seconds_since(start);
+            (Some(tour), "original order near the lower bound")
+        } else if context.config.solver == config::Solver::Builtin {
+            status!("Solving layer {} with the builtin solver ({} nodes)", current_layer, merges.len());
+            let target = Optimizer::target_length(&problem, current_layer, context.config);
+            let tour = solver::simulated_annealing(&problem, solver::nearest_neighbour(&problem), context.config.annealing_time, target, &context.cancel);
+            times.solve = timing::seconds_since(start);
+            (checked(tour, "builtin"), "builtin")
+        } else {
+            // Files and solver process are timed separately
+            let tours = (1..=Optimizer::solver_runs(context.config))
+                .filter_map(|run| Optimizer::run_solver(&problem, current_layer, run, context, &mut times))
+                .collect();
+            (Optimizer::fastest_tour(layer, &merges, tours, current_layer, context.config), "LKH")
+        };
+        // A failed solver run keeps the original order
+        let (tour, method) = match tour {
+            Some(tour) => (tour, method),
+            None => {
+                status!("TSP solver failed on layer {}, keeping original order", current_layer);
+                context.failures.fetch_add(1, Ordering::Relaxed);
+                context.failed_layers.lock().unwrap().push(current_layer);
+                kept = Some(report::SkipReason::SolverFailed);
+                ((1..=problem.nodes.len() as i32).collect(), "original order after a solver failure")
+            },
+        };
+
+        if !context.config.keep_intermediates.is_empty() {
+            let comment = format!("Layer {}, {} nodes merged into {}, solved by {} in {:.3} s",
+                current_layer, layer.nodes.len(), merges.len(), method, times.solve);
+            if let Err(e) = Optimizer::export_tour(&context.config.keep_intermediates, current_layer, &problem, &tour, &comment) {
+                warn!("Tour of layer {} not kept: {}", current_layer, e);
+            }
+        }
+
+        let chains = if context.config.merge_report.is_empty() {
+            Vec::new()
+        } else {
+            Optimizer::merged_chains(layer, &problem, &merges)
+        };
+
+        LayerSolution {
+            moves: Optimizer::tour_moves(&tour, &merges),
+            merged: merges.len(),
+            times,
+            kept,
+            chains,
+        }
+    }
+
+    // Writes the files of a solver run to <dir>/layer_<n>/run_<k>.tsp, .par and .tour, runs of a layer
+    // being numbered from 0. The parameters point at the copies, so LKH can be run again from there.
+    #[cfg(feature = "lkh")]
+    fn keep_intermediates(dir: &str, current_layer: u32, tsp_path: &str, tsp: &str, parameters: &str, result_path: &str, result: Option<&str>) -> Result<(), String> {
+        let layer_dir = Path::new(dir).join(format!("layer_{}", current_layer));
+        fs::create_dir_all(&layer_dir)
+            .map_err(|e| format!("Unable to create directory {}: {}", layer_dir.display(), e))?;
+        let run = (0..).find(|run| !layer_dir.join(format!("run_{}.tsp", run)).exists()).unwrap();
+
+        let name = format!("run_{}", run);
+        let kept_tsp = layer_dir.join(format!("{}.tsp", name));
+        fs::write(&kept_tsp, tsp)
+            .map_err(|e| format!("Unable to write file {}: {}", kept_tsp.display(), e))?;
+        let parameters = parameters.replace(tsp_path, &format!("{}.tsp", name)).replace(result_path, &format!("{}.tour", name));
+        let kept_parameters = layer_dir.join(format!("{}.par", name));
+        fs::write(&kept_parameters, parameters)
+            .map_err(|e| format!("Unable to write file {}: {}", kept_parameters.display(), e))?;
+        if let Some(result) = result {
+            let kept_tour = layer_dir.join(format!("{}.tour", name));
+            fs::write(&kept_tour, result)
+                .map_err(|e| format!("Unable to write file {}: {}", kept_tour.display(), e))?;
+        }
+        debug!("Kept the solver files of layer {} as {}", current_layer, layer_dir.join(name).display());
+        Ok(())
+    }
+
+    // Runs the external TSP solver on a problem and reads back its tour, if it gave a complete one
+    #[cfg(feature = "lkh")]
+    fn run_solver(problem: &solver::TspProblem, current_layer: u32, run: u32, context: &SolveContext, times: &mut timing::PhaseTimes) -> Option<Vec<i32>> {
+        let config = context.config;
+
+        // Files of the run's own temp directory, the problem is written to the solver's stdin
+        // and its tour read from its stdout when piping
+        let pipe = config.solver_io == config::SolverIo::Pipe;
+        let Some(work_dir) = context.work_dir else {
+            warn!("No work directory for the TSP solver on layer {}", current_layer);
+            return None;
+        };
+        let parameters_path = work_dir.join(format!("{}.par", current_layer)).display().to_string();
+        let (tsp_path, result_path) = if pipe {
+            (String::from("/dev/stdin"), String::from("/dev/stdout"))
+        } else {
+            (work_dir.join(format!("{}.tsp", current_layer)).display().to_string(),
+                work_dir.join(format!("result_{}.tour", current_layer)).display().to_string())
+        };
+
+        let target = Optimizer::target_length(problem, current_layer, config);
+
+        // Write parameters and TSP files
+        let start = Instant::now();
+        let parameters = Optimizer::parameters(&tsp_path, &result_path, config, run, target * problem.weight_scale());
+        let tsp = Optimizer::tsp_problem(problem, current_layer);
+        let written = fs::write(&parameters_path, &parameters)
+            .map_err(|e| format!("Unable to write file {}: {}", parameters_path, e))
+            .and_then(|_| if pipe {
+                Ok(())
+            } else {
+                fs::write(&tsp_path, &tsp).map_err(|e| format!("Unable to write file {}: {}", tsp_path, e))
+            });
+        if let Err(e) = written {
+            warn!("TSP solver not run on layer {}: {}", current_layer, e);
+            return None;
+        }
+        times.write_problem += timing::seconds_since(start);
+
+        // Run TSP solver once a process slot is free
+        let slot = SOLVER_SLOTS.acquire(config.max_solver_processes);
+        status!("Running TSP solver for layer {} ({} nodes)", current_layer, problem.nodes.len());
+        let start = Instant::now();
+        let output = Optimizer::run_process(&parameters_path, pipe.then_some(tsp.as_str()), current_layer, context);
+        let solve_time = start.elapsed().as_secs_f64();
+        drop(slot);
+
+        // Read result, the tour section is picked out of the solver output when piping
+        let start = Instant::now();
+        let result = output.and_then(|output| if pipe {
+            Some(output)
+        } else {
+            fs::read_to_string(&result_path)
+                .map_err(|_| warn!("Missing tour file {} for layer {}", result_path, current_layer))
+                .ok()
+        });
+        if !config.keep_intermediates.is_empty() {
+            if let Err(e) = Optimizer::keep_intermediates(&config.keep_intermediates, current_layer, &tsp_path, &tsp, &parameters, &result_path, result.as_deref()) {
+                warn!("Solver files of layer {} not kept: {}", current_layer, e);
+            }
+        }
+        let tour = result.and_then(|result| {
+            let tour = Optimizer::read_tour(&result);
+            if tour.is_none() {
+                warn!("Incomplete tour for layer {}", current_layer);
+            }
+            tour
+        }).filter(|tour| problem.check_tour(tour)
+            .map_err(|e| warn!("Invalid tour for layer {}: {}", current_layer, e))
+            .is_ok());
+        times.read_tour += timing::seconds_since(start);
+        times.solve += solve_time;
+
+        // Clean up, files the solver removed being already gone
+        let _ = fs::remove_file(&parameters_path);
+        if !pipe {
+            let _ = fs::remove_file(&tsp_path);
+            let _ = fs::remove_file(&result_path);
+        }
+
+        // Moves are made from node 1, where LKH starts its tours but other solvers may not
+        let mut tour = tour?;
+        let first = tour.iter().position(|&node| node == 1).unwrap_or(0);
+        tour.rotate_left(first);
+
+        // Keep improving the tour if the solver ran out of time before reaching the target
+        if config.time_limit > 0.0 && config.annealing_time > 0.0
+            && solve_time >= Optimizer::run_time_limit(config)
+            && problem.tour_length(&tour) > target {
+
+            let start = Instant::now();
+            let before = problem.tour_length(&tour);
+            tour = solver::simulated_annealing(problem, tour, config.annealing_time, target, &context.cancel);
+            let after = problem.tour_length(&tour);
+            times.solve += timing::seconds_since(start);
+
+            status!("Annealing layer {} ({:.2} -> {:.2})", current_layer, before, after);
+            info!("Simulated annealing improved layer {} from {:.3} to {:.3}", current_layer, before, after);
+        }
+
+        Some(tour)
+    }
+
+    // Builds without LKH only have the builtin solver, configurations asking for LKH are refused when completed
+    // and the layers of any other one kept in their order
+    #[cfg(not(feature = "lkh"))]
+    fn run_solver(_problem: &solver::TspProblem, current_layer: u32, _run: u32, _context: &SolveContext, _times: &mut timing::PhaseTimes) -> Option<Vec<i32>> {
+        warn!("This build cannot run LKH (lkh feature) on layer {}, use the builtin solver", current_layer);
+        None
+    }
+
+    // Original order of a problem, if it is already within the skip gap of the lower bound
+    fn near_bound_tour(problem: &solver::TspProblem, current_layer: u32, config: &config::Config) -> Option<Vec<i32>> {
+        if config.skip_gap <= 0.0 {
+            return None;
+        }
+
+        // Merged nodes keep the order of the layer
+        let tour: Vec<i32> = (1..=problem.nodes.len() as i32).collect();
+        let length = problem.tour_length(&tour);
+        let bound = solver::one_tree_bound(problem);
+        if length > bound * (1.0 + config.skip_gap / 100.0) {
+            return None;
+        }
+
+        status!("Skipping layer {} ({:.2}% above lower bound)", current_layer, (length / bound - 1.0) * 100.0);
+        info!("Layer {}: original tour {:.3} within {}% of lower bound {:.3}, not solved", current_layer, length, config.skip_gap, bound);
+        Some(tour)
+    }
+
+    // Tour length that is good enough to stop optimizing
+    fn target_length(problem: &solver::TspProblem, current_layer: u32, config: &config::Config) -> f64 {
+        if config.gap_target > 0.0 {
+            let bound = solver::one_tree_bound(problem);
+            info!("Layer {}: lower bound {:.3}, target {:.3}", current_layer, bound, bound * (1.0 + config.gap_target / 100.0));
+            bound * (1.0 + config.gap_target / 100.0)
+        } else {
+            0.0
+        }
+    }
+
+    // Solver program, run under the configured memory cap
+    #[cfg(feature = "lkh")]
+    fn solver_command(config: &config::Config) -> Command {
+        if config.solver_memory_limit > 0 && cfg!(unix) {
+            // Virtual memory limit in KB, applied by the shell before starting the solver
+            let mut command = Command::new("sh");
+            command.arg("-c")
+                .arg(format!("ulimit -v {} && exec \"$0\" \"$@\"", config.solver_memory_limit * 1024))
+                .arg(&config.program);
+            command
+        } else {
+            if config.solver_memory_limit > 0 {
+                warn!("Solver memory limit is not supported on this platform");
+            }
+            Command::new(&config.program)
+        }
+    }
+
+    // Runs the solver program within the configured time and memory caps,
+    // input is written to its stdin and its output returned when given.
+    // Nothing is returned if the solver could not start, crashed or ran out of time.
+    #[cfg(feature = "lkh")]
+    fn run_process(parameters_path: &str, input: Option<&str>, current_layer: u32, context: &SolveContext) -> Option<String> {
+        let config = context.config;
+        let mut command = Optimizer::solver_command(config);
+        command.arg(parameters_path);
+
+        // Started from an async service, the solver thread waits on the runtime's process handling
+        #[cfg(feature = "async")]
+        if let Some(runtime) = &context.runtime {
+            return runtime.block_on(service::run_process(command.into(), input, current_layer, config.solver_timeout, &context.cancel));
+        }
+
+        let piped = || if input.is_some() { Stdio::piped() } else { Stdio::null() };
+        let mut child = command
+            .stdin(piped())
+            .stdout(piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| warn!("Failed to run TSP solver on layer {}: {}", current_layer, e))
+            .ok()?;
+
+        // Output is drained on its own thread so a full pipe never stalls the solver
+        let reader = child.stdout.take().map(|mut stdout| thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        }));
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            // A solver dying early shows in its exit status
+            let _ = stdin.write_all(input.as_bytes());
+        }
+        let output = || reader.map_or(String::new(), |reader| reader.join().unwrap_or_default());
+
+        // Poll the solver until it finishes or runs out of time
+        let start = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => (),
+                Err(e) => {
+                    warn!("Lost TSP solver on layer {}: {}", current_layer, e);
+                    let _ = child.kill();
+                    return None;
+                }
+            }
+
+            if config.solver_timeout > 0.0 && start.elapsed().as_secs_f64() >= config.solver_timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                warn!("TSP solver killed after {} s on layer {}", config.solver_timeout, current_layer);
+                return None;
+            }
+            if context.cancel.is_cancelled() {
+                let _ = child.kill();
+                let _ = child.wait();
+                warn!("TSP solver stopped on layer {}, optimization cancelled", current_layer);
+                return None;
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+
+        // Crashed or killed, by the memory limit for instance
+        if !status.success() {
+            warn!("TSP solver failed on layer {} ({})", current_layer, status);
+            return None;
+        }
+
+        Some(output())
+    }
+
+    // Solver processes run on each problem. LKH keeps the shortest tour of its runs, so with the time
+    // objective each run is a process of its own and every tour is compared on estimated time.
+    fn solver_runs(config: &config::Config) -> u32 {
+        if config.objective == config::Objective::Time { config.num_runs } else { 1 }
+    }
+
+    // Seconds given to each solver process, the time limit being shared by the runs of a problem
+    #[cfg(feature = "lkh")]
+    fn run_time_limit(config: &config::Config) -> f64 {
+        config.time_limit / Optimizer::solver_runs(config) as f64
+    }
+
+    // Parameters of a solver process, its run numbered from 1 seeding it when runs are separate processes
+    #[cfg(feature = "lkh")]
+    fn parameters(tsp_path: &str, result_path: &str, config: &config::Config, run: u32, target: f64) -> String {
+        let separate = Optimizer::solver_runs(config) > 1;
+        let mut parameters = format!(
+            "PROBLEM_FILE = {}\n\
+            TOUR_FILE = {}\n\
+            PRECISION = {}\n\
+            RUNS = {}\n\
+            CANDIDATE_SET_TYPE = POPMUSIC\n",
+            tsp_path, 
+            result_path, 
+            config.precision, 
+            if separate { 1 } else { config.num_runs }
+        );
+
+        if separate {
+            parameters.push_str(&format!("SEED = {}\n", run));
+        }
+        if config.time_limit > 0.0 {
+            parameters.push_str(&format!("TIME_LIMIT = {}\n", Optimizer::run_time_limit(config)));
+        }
+
+        // Let the solver stop as soon as the target is reached
+        if target > 0.0 {
+            parameters.push_str(&format!("OPTIMUM = {}\nSTOP_AT_OPTIMUM = YES\n", target.ceil()));
+        }
+
+        // Keep the tour alone on stdout
+        if result_path == "/dev/stdout" {
+            parameters.push_str("TRACE_LEVEL = 0\n");
+        }
+
+        parameters
+    }
+
+    // Merges extrusion chains of a layer into a TSP problem
+    fn build_problem(layer: &gcode::GCodeLayer, current_layer: u32,
+        context: &SolveContext) -> (solver::TspProblem, HashMap<u32, u32>) {
+
+        let config = context.config;
+        let mut merges: HashMap<u32, u32> = HashMap::new();
+
+        let mut nodes: Vec<(f64, f64, f64)> = Vec::new();
+
+        let mut keys: Vec<u32> = Vec::new();
+
+        // Whole islands become single chains, including the short travels inside them
+        let island_only = config.optimization_level == config::OptimizationLevel::IslandOnly;
+
+        // Gather nodes
+        let mut count = 0;
+        let mut extruded = false;
+        let mut last_position = (0.0, 0.0, 0.0);
+        let mut current_distance = 0.0;
+        for (i, node) in layer.nodes.iter().enumerate() {
+            let extrude = layer.extrusion(i as u32 + 1).is_some()
+                || (island_only && i + 1 < layer.nodes.len()
+                    && distance_3d(*node, layer.nodes[i + 1]) <= config.island_max_travel);
+
+            if !extrude || !extruded {
+                count += 1;
+                nodes.push(*node);
+                merges.insert(count, i as u32 + 1);
+                if extrude {
+                    keys.push(count);
+                } else {
+                    current_distance = 0.0;
+                }
+            } else {
+                current_distance += distance_3d(last_position, *node);
+                if !island_only && current_distance > config.max_merge_length {
+                    count += 1;
+                    nodes.push(*node);
+                    merges.insert(count, i as u32 + 1);
+                    current_distance = 0.0;
+                    count += 1;
+                    nodes.push(*node);
+                    merges.insert(count, i as u32 + 1);
+                    keys.push(count);
+                }
+            }
+            extruded = extrude;
+            last_position = *node;
+        }
+        if extruded {
+            count += 1;
+            nodes.push(layer.nodes[layer.nodes.len() - 1]);
+            merges.insert(count, layer.nodes.len() as u32);
+        }
+
+        // Mandatory edges
+        let mut fixed_edges: Vec<(u32, u32)> = keys.iter().map(|key| (*key, key + 1)).collect();
+        fixed_edges.push((count, 1));
+
+        status!("Merging layer {} ({} -> {} nodes)", current_layer, layer.nodes.len(), count);
+        info!("Merged {} nodes into {} for layer {}", layer.nodes.len(), count, current_layer);
+
+        (solver::TspProblem { nodes, fixed_edges, cost: Arc::clone(&context.cost) }, merges)
+    }
+
+    // Walks the TSP nodes of a problem, a node opening a fixed edge to the next one standing for the
+    // extrusion chain up to it and any other node for a single original node
+    fn merged_chains(layer: &gcode::GCodeLayer, problem: &solver::TspProblem, merges: &HashMap<u32, u32>) -> Vec<MergedChain> {
+        let chained: HashSet<u32> = problem.fixed_edges.iter()
+            .filter(|(a, b)| *b == a + 1)
+            .map(|(a, _)| *a)
+            .collect();
+
+        let mut chains = Vec::new();
+        let mut tsp_node = 1;
+        while tsp_node <= merges.len() as u32 {
+            let last = if chained.contains(&tsp_node) { tsp_node + 1 } else { tsp_node };
+            let nodes = (merges[&tsp_node], merges[&last]);
+            let length = (nodes.0..nodes.1)
+                .fold(0.0, |length, i| length + distance_3d(layer.nodes[i as usize - 1], layer.nodes[i as usize]));
+            chains.push(MergedChain { section: 0, tsp_nodes: (tsp_node, last), nodes, length });
+            tsp_node = last + 1;
+        }
+
+        chains
+    }
+
+    // Writes the chains of a layer to <dir>/layer_<n>.merges.csv, a chain being cut when it ends where
+    // the next one starts, for being longer than max_merge_length
+    fn write_merge_report(dir: &str, current_layer: u32, chains: &[MergedChain]) -> Result<(), String> {
+        let path = Path::new(dir).join(format!("layer_{}.merges.csv", current_layer));
+
+        let mut contents = String::from("section,first_tsp_node,last_tsp_node,first_node,last_node,nodes,length,cut\n");
+        for (i, chain) in chains.iter().enumerate() {
+            let cut = chains.get(i + 1)
+                .is_some_and(|next| next.section == chain.section && chain.tsp_nodes.0 != chain.tsp_nodes.1 && next.nodes.0 == chain.nodes.1);
+            contents.push_str(&format!("{},{},{},{},{},{},{:.3},{}\n",
+                chain.section, chain.tsp_nodes.0, chain.tsp_nodes.1, chain.nodes.0, chain.nodes.1,
+                chain.nodes.1 - chain.nodes.0 + 1, chain.length, cut));
+        }
+
+        fs::write(&path, contents)
+            .map_err(|e| format!("Unable to write file {}: {}", path.display(), e))
+    }
+
+    // Writes a problem and its tour to <dir>/layer_<n>/section_<k>.tsp and .tour in TSPLIB format,
+    // sections of a layer being numbered from 0, for other TSP tools to compare against
+    fn export_tour(dir: &str, current_layer: u32, problem: &solver::TspProblem, tour: &[i32], comment: &str) -> Result<(), String> {
+        let layer_dir = Path::new(dir).join(format!("layer_{}", current_layer));
+        fs::create_dir_all(&layer_dir)
+            .map_err(|e| format!("Unable to create directory {}: {}", layer_dir.display(), e))?;
+        let section = (0..).find(|section| !layer_dir.join(format!("section_{}.tour", section)).exists()).unwrap();
+        let name = format!("layer_{}.section_{}", current_layer, section);
+
+        let tsp_path = layer_dir.join(format!("section_{}.tsp", section)).display().to_string();
+        Optimizer::write_tsp_file(&tsp_path, problem, current_layer)?;
+
+        let mut contents = format!(
+            "NAME: {}.tour\n\
+            COMMENT: {}, length {:.3}\n\
+            TYPE: TOUR\n\
+            DIMENSION: {}\n\
+            TOUR_SECTION\n",
+            name, comment, problem.tour_length(tour), tour.len()
+        );
+        for node in tour {
+            contents.push_str(&format!("{}\n", node));
+        }
+        contents.push_str("-1\nEOF\n");
+
+        let tour_path = layer_dir.join(format!("section_{}.tour", section));
+        fs::write(&tour_path, contents)
+            .map_err(|e| format!("Unable to write file {}: {}", tour_path.display(), e))
+    }
+
+    fn write_tsp_file(path: &str, problem: &solver::TspProblem, current_layer: u32) -> Result<(), String> {
+        fs::write(path, Optimizer::tsp_problem(problem, current_layer))
+            .map_err(|e| format!("Unable to write file {}: {}", path, e))
+    }
+
+    // TSPLIB problem of a layer, nodes and the edges its chains fix
+    fn tsp_problem(problem: &solver::TspProblem, current_layer: u32) -> String {
+        let mut tsp = format!(
+            "NAME: {}\n\
+            COMMENT: {}\n\
+            TYPE: TSP\n\
+            DIMENSION: {}\n",
+            format_args!("Layer {}", current_layer),
+            format_args!("Print optimization for current_layer {}", current_layer),
+            problem.nodes.len()
+        );
+
+        if problem.cost.is_euclidean() {
+            // Write nodes
+            tsp.push_str("EDGE_WEIGHT_TYPE: EUC_3D\nNODE_COORD_SECTION\n");
+            for (i, node) in problem.nodes.iter().enumerate() {
+                tsp.push_str(&format!("{} {:.3} {:.3} {:.3}\n", i + 1, node.0, node.1, node.2));
+            }
+        } else {
+            // Other costs are scaled to integer weights above the diagonal, one row per node
+            tsp.push_str("EDGE_WEIGHT_TYPE: EXPLICIT\nEDGE_WEIGHT_FORMAT: UPPER_ROW\nEDGE_WEIGHT_SECTION\n");
+            let n = problem.nodes.len() as i32;
+            for a in 1..n {
+                let row: Vec<String> = (a + 1..=n)
+                    .map(|b| format!("{}", (problem.distance(a, b) * problem.weight_scale()).round().max(0.0)))
+                    .collect();
+                tsp.push_str(&row.join(" "));
+                tsp.push('\n');
+            }
+        }
+
+        // Write mandatory edges
+        tsp.push_str("FIXED_EDGES_SECTION\n");
+        for (a, b) in problem.fixed_edges.iter() {
+            tsp.push_str(&format!("{} {}\n", a, b));
+        }
+        tsp.push_str("-1\nEOF\n");
+
+        tsp
+    }
+
+    // Node sequence of a solver result, if its tour section is complete, checked against the problem apart
+    #[cfg(feature = "lkh")]
+    fn read_tour(result: &str) -> Option<Vec<i32>> {
+        let mut tour = Vec::new();
+        let mut process = false;
+        let mut complete = false;
+
+        for line in result.lines() {
+            if process {
+                let node = line.trim().parse::<i32>().ok()?;
+                if node == -1 {
+                    complete = true;
+                    break;
+                }
+                tour.push(node);
+            } else {
+                process = line.starts_with("TOUR_SECTION");
+            }
+        }
+
+        complete.then_some(tour)
+    }
+
+    // Converts a tour over merged nodes into moves between original nodes
+    fn tour_moves(tour: &[i32], merges: &HashMap<u32, u32>) -> Vec<(i32, i32)> {
+        let mut moves = Vec::new();
+        let mut prev_node: i32 = 1;
+
+        // The last node is fixed next to node 1, it must close the tour instead of opening it
+        let mut tour = tour.to_vec();
+        if tour.len() > 2 && tour[1] == tour.len() as i32 {
+            tour[1..].reverse();
+        }
+
+        for node in tour {
+            let from = *merges.get(&(prev_node as u32)).unwrap() as i32;
+            let to = *merges.get(&(node as u32)).unwrap() as i32;
+
+            if node - prev_node == 1 {
+                for i in from..to {
+                    moves.push((i, i + 1));
+                }
+            } else if node - prev_node == -1 {
+                for i in (to..from).rev() {
+                    moves.push((i + 1, i));
+                }
+            } else {
+                moves.push((from, to));
+            }
+
+            // Update previous node
+            prev_node = node;
+        }
+
+        moves
+    }
+
+    // Moves following the order of the original G-code
+    fn original_moves(layer: &gcode::GCodeLayer) -> Vec<(i32, i32)> {
+        let mut moves = vec![(1, 1)];
+        for i in 2..=layer.nodes.len() as i32 {
+            moves.push((i - 1, i));
+        }
+        moves
+    }
+
+    // Tour of the solver runs of a problem with the shortest estimated time, none if every run failed
+    fn fastest_tour(layer: &gcode::GCodeLayer, merges: &HashMap<u32, u32>, tours: Vec<Vec<i32>>, current_layer: u32, config: &config::Config) -> Option<Vec<i32>> {
+        if tours.len() < 2 {
+            return tours.into_iter().next();
+        }
+
+        let times: Vec<f64> = tours.iter()
+            .map(|tour| Optimizer::layer_time(layer, &Optimizer::tour_moves(tour, merges), config))
+            .collect();
+        let fastest = (0..tours.len()).min_by(|&a, &b| times[a].total_cmp(&times[b])).unwrap();
+        info!("Layer {}: run {} of {} fastest, estimated {:.3} s (slowest {:.3} s)",
+            current_layer, fastest + 1, tours.len(), times[fastest], times.iter().copied().fold(0.0, f64::max));
+        tours.into_iter().nth(fastest)
+    }
+
+    // Keeps the original order of a layer when the optimized tour is not faster
+    fn select_moves(&mut self, layer: &gcode::GCodeLayer, moves: Vec<(i32, i32)>) -> (Vec<(i32, i32)>, Option<report::SkipReason>) {
+        if self.config.objective != config::Objective::Time {
+            return (moves, None);
+        }
+
+        let original = Optimizer::original_moves(layer);
+        let original_time = Optimizer::layer_time(layer, &original, &self.config);
+        let optimized_time = Optimizer::layer_time(layer, &moves, &self.config);
+        let saved = (original_time - optimized_time) / 60.0;
+
+        if saved > 0.0 {
+            status!("Layer {}: {:.3} min saved", self.current_layer, saved);
+            info!("Layer {}: estimated {:.3} min saved", self.current_layer, saved);
+            self.time_saved += saved;
+            (moves, None)
+        } else {
+            status!("Layer {}: no time saved, keeping original order", self.current_layer);
+            self.warnings.add(warnings::WarningKind::SlowerLayer, self.current_layer);
+            info!("Layer {}: optimized tour is {:.3} min slower, keeping original order", self.current_layer, -saved);
+            (original, Some(report::SkipReason::Slower))
+        }
+    }
+
+    // Travel distance of a sequence of moves, starting from the given position
+    fn travel(layer: &gcode::GCodeLayer, mut position: (f64, f64, f64), moves: &[(i32, i32)]) -> f64 {
+        let mut travel = 0.0;
+        for &(origin, destination) in moves {
+            let node = layer.nodes[destination as usize - 1];
+            let extrusion = if destination - origin == 1 { layer.extrusion(origin as u32) }
+                else if destination - origin == -1 { layer.extrusion(destination as u32) }
+                else { None };
+            if extrusion.is_none() {
+                travel += distance_3d(position, node);
+            }
+            position = node;
+        }
+        travel
+    }
+
+    // Times a travel crosses a path extruded before it in the same layer, each crossing risking a string
+    // or a scar on the print. Starts from the given position like travel.
+    fn crossings(layer: &gcode::GCodeLayer, mut position: (f64, f64, f64), moves: &[(i32, i32)]) -> usize {
+        let mut printed = Vec::new();
+        let mut crossings = 0;
+        for &(origin, destination) in moves {
+            let node = layer.nodes[destination as usize - 1];
+            let extrusion = if destination - origin == 1 { layer.extrusion(origin as u32) }
+                else if destination - origin == -1 { layer.extrusion(destination as u32) }
+                else { None };
+            if extrusion.is_some() {
+                printed.push((position, node));
+            } else {
+                crossings += printed.iter().filter(|&&(from, to)| segments_cross(position, node, from, to)).count();
+            }
+            position = node;
+        }
+        crossings
+    }
+
+    // Walks a sequence of moves from the given position, giving each extrusion with the node it leaves
+    // and the travel leading to it, then the travel ending the sequence with the last node extruded from
+    fn split_travels(layer: &gcode::GCodeLayer, mut position: (f64, f64, f64), moves: &[(i32, i32)], mut add: impl FnMut(Option<u32>, f64, f64)) {
+        let mut travel = 0.0;
+        let mut last = None;
+        for &(origin, destination) in moves {
+            let node = layer.nodes[destination as usize - 1];
+            let key = if destination - origin == 1 { origin as u32 }
+                else if destination - origin == -1 { destination as u32 }
+                else { 0 };
+            if key > 0 && layer.extrusion(key).is_some() {
+                add(Some(key), distance_3d(position, node), travel);
+                travel = 0.0;
+                last = Some(key);
+            } else {
+                travel += distance_3d(position, node);
+            }
+            position = node;
+        }
+
+        if travel > 0.0 {
+            add(last, 0.0, travel);
+        }
+    }
+
+    // Adds the distances of a sequence of moves to the report of their feature, starting from the given
+    // position. Extrusions are counted with the original moves, travels to the optimized ones when told so.
+    // Travels ending the layer lead nowhere, they count for the last feature extruded.
+    fn add_features(features: &mut Vec<report::FeatureReport>, layer: &gcode::GCodeLayer, position: (f64, f64, f64), moves: &[(i32, i32)], optimized: bool) {
+        Optimizer::split_travels(layer, position, moves, |key, extrusion, travel| {
+            let feature = report::feature(features, key.map_or("", |key| layer.feature(key)));
+            if optimized {
+                feature.optimized_travel += travel;
+            } else {
+                feature.extrusion += extrusion;
+                feature.base_travel += travel;
+            }
+        });
+    }
+
+    // Same as add_features for the tools extruding
+    fn add_tools(tools: &mut Vec<report::ToolReport>, layer: &gcode::GCodeLayer, position: (f64, f64, f64), moves: &[(i32, i32)], optimized: bool) {
+        Optimizer::split_travels(layer, position, moves, |key, extrusion, travel| {
+            let tool = report::tool(tools, key.map_or(0, |key| layer.tool(key)));
+            if optimized {
+                tool.optimized_travel += travel;
+            } else {
+                tool.extrusion += extrusion;
+                tool.base_travel += travel;
+            }
+        });
+    }
+
+    // Distance moved while extruding in a sequence of moves
+    fn extrusion(layer: &gcode::GCodeLayer, moves: &[(i32, i32)]) -> f64 {
+        let mut extrusion = 0.0;
+        for &(origin, destination) in moves {
+            let extrudes = if destination - origin == 1 { layer.extrusion(origin as u32).is_some() }
+                else if destination - origin == -1 { layer.extrusion(destination as u32).is_some() }
+                else { false };
+            if extrudes {
+                extrusion += distance_3d(layer.nodes[origin as usize - 1], layer.nodes[destination as usize - 1]);
+            }
+        }
+        extrusion
+    }
+
+    // Estimates the time needed to perform a sequence of moves
+    fn layer_time(layer: &gcode::GCodeLayer, moves: &[(i32, i32)], config: &config::Config) -> f64 {
+        let mut time = 0.0;
+        let mut feedrate = 0.0;
+
+        for &(origin, destination) in moves {
+            if let Some(f) = Optimizer::move_feedrate(layer, origin, destination) {
+                feedrate = f;
+            }
+
+            let distance = distance_3d(layer.nodes[origin as usize - 1], layer.nodes[destination as usize - 1]);
+            time += simulator::move_time(distance, feedrate, config.acceleration);
+        }
+
+        time
+    }
+
+    // Feedrate of a move, if one was recorded
+    fn move_feedrate(layer: &gcode::GCodeLayer, origin: i32, destination: i32) -> Option<f64> {
+        let key = if destination - origin == 1 { origin as u32 }
+            else if destination - origin == -1 { destination as u32 }
+            else { 0 }; // Will give default travel feedrate, this is used for new travel movements
+
+        layer.feedrate(key)
+    }
+
+    fn write_position(contents: &mut String, (x, y, z): (f64, f64, f64), config: &config::Config) {
+        gcode::write_value(contents, " X", x, config.position_decimals);
+        gcode::write_value(contents, " Y", y, config.position_decimals);
+        gcode::write_value(contents, " Z", z, config.position_decimals);
+    }
+
+    // Move of the original order, the first node of a layer being reached from the layer before
+    fn is_original_move(origin: i32, destination: i32) -> bool {
+        destination - origin == 1 || (origin, destination) == (1, 1)
+    }
+
+    // Comments of the nodes no longer reached by their original move, written at the start of the layer in order
+    fn add_moved_comments(&mut self, layer: &gcode::GCodeLayer, moves: &[(i32, i32)]) {
+        if layer.comments.is_empty() {
+            return;
+        }
+        let kept: HashSet<u32> = moves.iter()
+            .filter(|&&(origin, destination)| Optimizer::is_original_move(origin, destination))
+            .map(|&(_, destination)| destination as u32)
+            .collect();
+        let mut moved: Vec<(&u32, &String)> = layer.comments.iter().filter(|(node, _)| !kept.contains(node)).collect();
+        moved.sort();
+        for (_, comments) in moved {
+            self.optimized_gcode.contents.push_str(comments);
+        }
+    }
+
+    // Layer change at the height of the first extrusion, viewers starting each layer with its first ;TYPE:
+    fn add_layer_markers(&mut self, layer: &gcode::GCodeLayer) {
+        self.marked_feature.clear();
+        let Some(first) = (1..layer.nodes.len() as u32).find(|&node| layer.extrusion(node).is_some()) else {
+            return;
+        };
+        let contents = &mut self.optimized_gcode.contents;
+        contents.push_str(";LAYER_CHANGE");
+        gcode::write_value(contents, "\n;Z:", layer.nodes[first as usize].2, self.config.position_decimals);
+        contents.push('\n');
+    }
+
+    // Feedrate of an extrusion keeping the filament melted under the maximum volumetric flow
+    fn limit_flow(&self, extrusion: f64, distance: f64, feedrate: f64) -> f64 {
+        let radius = self.config.filament_diameter / 2.0;
+        let volume = extrusion * std::f64::consts::PI * radius * radius;
+        if distance <= 0.0 {
+            return feedrate;
+        }
+        // Volume per mm moved times mm/s
+        let flow = volume / distance * feedrate / 60.0;
+        if flow <= self.config.max_volumetric_flow {
+            feedrate
+        } else {
+            self.config.max_volumetric_flow * distance / volume * 60.0
+        }
+    }
+
+    // Writes a sequence of moves, each run of extrusions starting primed and ending with the retraction
+    // that followed it in the original, whichever way it is run
+    fn add_moves(&mut self, layer: &gcode::GCodeLayer, moves: &[(i32, i32)]) {
+        let mut i = 0;
+        while i < moves.len() {
+            let run = moves[i..].iter()
+                .take_while(|&&(origin, destination)| Optimizer::move_extrusion(layer, origin, destination).is_some())
+                .count();
+            if run == 0 {
+                let (origin, destination) = moves[i];
+                self.add_line(layer, origin, destination, None);
+                // Nodes reached by an extrusion retract at the end of its run instead
+                if destination == 1 || layer.extrusion(destination as u32 - 1).is_none() {
+                    self.add_retraction(layer, destination as u32);
+                }
+                i += 1;
+                continue;
+            }
+
+            let chain = &moves[i..i + run];
+            i += run;
+            let (first, last) = (chain[0].0, chain[run - 1].1);
+            if last > first {
+                for &(origin, destination) in chain {
+                    self.add_line(layer, origin, destination, Optimizer::move_extrusion(layer, origin, destination));
+                }
+                self.add_retraction(layer, last as u32);
+                continue;
+            }
+
+            // Run backwards, the primes in place it started with would come last, they are pushed before it
+            let primes = chain.iter().rev()
+                .take_while(|&&(origin, destination)| Optimizer::in_place(layer, origin, destination))
+                .count();
+            let (extrusions, primes) = chain.split_at(run - primes);
+            for &(_, destination) in primes.iter().rev() {
+                self.add_prime(layer, destination as u32);
+            }
+            for &(origin, destination) in extrusions {
+                self.add_line(layer, origin, destination, Optimizer::move_extrusion(layer, origin, destination));
+            }
+            // The head still ends where the primes started, they may have moved in Z
+            for &(origin, destination) in primes {
+                if layer.nodes[origin as usize - 1] != layer.nodes[destination as usize - 1] {
+                    self.add_line(layer, origin, destination, None);
+                }
+            }
+            self.add_retraction(layer, first as u32);
+        }
+    }
+
+    // Extrusion of a move, if it runs an extruding move of the original either way
+    fn move_extrusion(layer: &gcode::GCodeLayer, origin: i32, destination: i32) -> Option<f64> {
+        if destination - origin == 1 { layer.extrusion(origin as u32) }
+        else if destination - origin == -1 { layer.extrusion(destination as u32) }
+        else { None }
+    }
+
+    // Staying in place in XY, like the prime following the travel to a chain
+    fn in_place(layer: &gcode::GCodeLayer, origin: i32, destination: i32) -> bool {
+        let (from, to) = (layer.nodes[origin as usize - 1], layer.nodes[destination as usize - 1]);
+        (from.0, from.1) == (to.0, to.1)
+    }
+
+    // Extrusion in place leaving a node, written where the head is along with its fan commands
+    fn add_prime(&mut self, layer: &gcode::GCodeLayer, node: u32) {
+        if let Some(fans) = layer.fans.get(&node) {
+            self.optimized_gcode.contents.push_str(fans);
+        }
+        if let Some(e) = layer.extrusion(node) {
+            self.add_extruder_move(e, layer.feedrate(node));
+        }
+    }
+
+    // Filament retracted once a node is reached in the original, if any
+    fn add_retraction(&mut self, layer: &gcode::GCodeLayer, node: u32) {
+        if let Some(&(retraction, feedrate)) = layer.retractions.get(&node) {
+            self.add_extruder_move(-retraction, Some(feedrate));
+        }
+    }
+
+    // Filament pushed, or pulled back when negative, without moving the head
+    fn add_extruder_move(&mut self, mut e: f64, feedrate: Option<f64>) {
+        // Counted like the moves they were read as, primes extruding and retractions travelling nowhere
+        if e > 0.0 {
+            self.optimized_gcode.stats.increment_extrusion(0.0);
+        } else {
+            self.optimized_gcode.stats.increment_travel(0.0);
+        }
+        self.optimized_gcode.stats.increment_filament(e);
+        if self.optimized_gcode.extruder_mode == gcode::CoordinatesMode::Absolute {
+            e += self.last_extrusion;
+        }
+        self.last_extrusion = e;
+
+        let contents = &mut self.optimized_gcode.contents;
+        contents.push_str("G1");
+        gcode::write_value(contents, " E", e, self.config.extrusion_decimals);
+        if let Some(f) = feedrate {
+            gcode::write_value(contents, " F", f, self.config.feedrate_decimals);
+        }
+        contents.push('\n');
+    }
+
+    fn add_line(&mut self, layer: &gcode::GCodeLayer, origin: i32, destination: i32, extrusion: Option<f64>) {
+        let pno = origin as u32;
+        let no = destination as u32;
+        
+        let n = layer.nodes[destination as usize - 1];
+
+        let mut x = n.0;
+        let mut y = n.1;
+        let mut z = n.2;
+
+        if self.optimized_gcode.position_mode == gcode::CoordinatesMode::Relative {
+            let p = layer.nodes[origin as usize - 1];
+
+            x -= p.0;
+            y -= p.1;
+            z -= p.2;
+        }
+
+        // Feedrates of the slicer may ask for more flow than the hotend melts
+        let mut feedrate = Optimizer::move_feedrate(layer, origin, destination);
+        if let (Some(e), Some(f)) = (extrusion, feedrate) {
+            if self.config.max_volumetric_flow > 0.0 {
+                let limited = self.limit_flow(e, distance_3d(self.last_position, n), f);
+                if limited < f {
+                    self.flow_limited += 1;
+                    self.warnings.add(warnings::WarningKind::FlowLimited, self.current_layer);
+                    feedrate = Some(limited);
+                }
+            }
+        }
+
+        // Feedrates of merged nodes and the default of new travels may be more than the axes of the printer take
+        if let (Some(f), Some(printer)) = (feedrate, &self.config.printer) {
+            let max = &printer.max_feedrate;
+            let delta = [n.0 - self.last_position.0, n.1 - self.last_position.1, n.2 - self.last_position.2];
+            let capped = simulator::capped_feedrate(f, delta, extrusion.unwrap_or(0.0), &[max.x, max.y, max.z, max.e]);
+            if capped < f {
+                self.feedrate_capped += 1;
+                self.warnings.add(warnings::WarningKind::FeedrateCapped, self.current_layer);
+                feedrate = Some(capped);
+            }
+        }
+
+        // Write the line straight into the output buffer, it keeps its capacity between flushes
+        let contents = &mut self.optimized_gcode.contents;
+
+        // Extrusions of another feature than the last one marked start with its marker
+        if self.config.viewer_markers && extrusion.is_some() {
+            let feature = layer.feature(if destination > origin { pno } else { no });
+            if !feature.is_empty() && feature != self.marked_feature {
+                contents.push_str(";TYPE:");
+                contents.push_str(feature);
+                contents.push('\n');
+                self.marked_feature = feature.to_string();
+            }
+        }
+
+        // Fan commands go before the extrusion they came before, whichever way it is run
+        if extrusion.is_some() {
+            if let Some(fans) = layer.fans.get(if destination > origin { &pno } else { &no }) {
+                contents.push_str(fans);
+            }
+        }
+
+        if let Some(mut e) = extrusion {
+            self.optimized_gcode.stats.increment_filament(e);
+            if self.optimized_gcode.extruder_mode == gcode::CoordinatesMode::Absolute {
+                e += self.last_extrusion;
+            }
+            
+            self.last_extrusion = e;
+
+            contents.push_str("G1");
+            Optimizer::write_position(contents, (x, y, z), &self.config);
+            gcode::write_value(contents, " E", e, self.config.extrusion_decimals);
+            self.optimized_gcode.stats.increment_extrusion(distance_3d(self.last_position, n));
+        } else {
+            contents.push_str("G0");
+            Optimizer::write_position(contents, (x, y, z), &self.config);
+            self.optimized_gcode.stats.increment_travel(distance_3d(self.last_position, n));
+        }
+
+        // Add feedrate if needed
+        if let Some(f) = feedrate {
+            gcode::write_value(contents, " F", f, self.config.feedrate_decimals);
+        }
+        contents.push('\n');
+
+        // Comments of the original move, the ones of moves that changed being written ahead of the layer
+        if Optimizer::is_original_move(origin, destination) {
+            if let Some(comments) = layer.comments.get(&no) {
+                contents.push_str(comments);
+            }
+        }
+
+        // Replay commands attached to the destination
+        if let Some(commands) = layer.commands.get(&no) {
+            contents.push_str(commands);
+        }
+
+        // Update previous node
+        self.last_position = n;
+    }
+}
+
+// Checks that a G-code file exists and is not empty, before anything is written for it
+pub fn check_gcode_file(gcode_path: &str) -> Result<(), String> {
+    let path_gcode = Path::new(gcode_path);
+
+    // Check that the G-code file exists
+    if !path_gcode.exists() {
+        return Err(format!("File {} does not exist", gcode_path));
+    }
+
+    // Check that file has a .gcode extension
+    if path_gcode.extension().unwrap_or_default() != "gcode" {
+        return Err(format!("File {} does not have a .gcode extension", gcode_path));
+    }
+
+    // Check that G-code file is not empty, without loading it
+    let size = fs::metadata(gcode_path)
+        .map_err(|_| format!("Unable to read file {}", gcode_path))?
+        .len();
+
+    if size == 0 {
+        return Err(format!("File {} is empty", gcode_path));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    // Lines along X on two layers, the middle one of each shorter to reach backwards, every line primed
+    // after the travel leading to it and retracted once extruded
+    fn retracting_lines() -> String {
+        let mut gcode = String::from("G90\nM83\nG28\n");
+        for z in [0.2, 0.4] {
+            gcode.push_str(&format!("G1 Z{} F720\n", z));
+            for (from, to) in [(0, 10), (30, 20), (40, 50)] {
+                gcode.push_str(&format!("G0 X{} Y0 F9000\nG1 E0.8 F2100\nG1 X{} Y0 E0.4 F1800\nG1 E-0.8 F2100\n", from, to));
+            }
+        }
+        gcode
+    }
+
+    #[test]
+    fn retractions_stay_with_their_chains() {
+        let gcode = retracting_lines();
+        let (output, _) = Optimizer::optimize_str(&gcode, &config::Config::default()).unwrap();
+        // The middle line is run backwards, primed before and retracted after all the same
+        assert!(output.contains("G0 X20 Y0 Z0.2 F9000\nG1 E0.8 F2100\nG1 X30 Y0 Z0.2 E0.4 F1800\nG1 E-0.8 F2100\n"));
+        assert_eq!(output.matches("G1 E-0.8 F2100").count(), 6);
+
+        let dir = TempDir::new().unwrap();
+        let gcode_path = dir.path().join("lines.gcode").display().to_string();
+        let optimized_path = dir.path().join("lines_optimized.gcode").display().to_string();
+        fs::write(&gcode_path, &gcode).unwrap();
+        fs::write(&optimized_path, &output).unwrap();
+        assert!(verify::verify(&gcode_path, &optimized_path, 0.0001, 3, commands::Modes::default()).is_ok());
+    }
+
+    #[test]
+    fn unreadable_gcode_fails_without_panicking() {
+        let config = config::Config { strict_modes: true, ..Default::default() };
+        // Moves before any positioning mode is set cannot be read in strict mode
+        let failure = Optimizer::optimize_str("G1 X10 Y0 E1\n", &config).unwrap_err();
+        assert_eq!(failure.kind, ErrorKind::Parse);
+
+        assert_eq!(Optimizer::optimize_str("\n", &config::Config::default()).unwrap_err().kind, ErrorKind::Parse);
+    }
+
+    #[test]
+    fn optimize_returns_its_failures() {
+        let dir = TempDir::new().unwrap();
+        let optimizer = |name: &str, gcode: &str, config| {
+            let gcode_path = dir.path().join(name).display().to_string();
+            fs::write(&gcode_path, gcode).unwrap();
+            Optimizer::builder().input(&gcode_path).config(config).csv(None).build().unwrap()
+        };
+
+        let report = optimizer("lines.gcode", &retracting_lines(), config::Config::default()).optimize().unwrap();
+        assert_eq!(report.layers, 3);
+
+        // Moves before any positioning mode cannot be read in strict mode
+        let config = config::Config { strict_modes: true, ..Default::default() };
+        let failure = optimizer("unset.gcode", "G1 X10 Y0 E1\nG1 X20 Y0 E1\n", config).optimize().unwrap_err();
+        assert_eq!(failure.kind, ErrorKind::Parse);
+    }
+
+    #[test]
+    fn unwritable_merge_report_fails_before_solving() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        // A directory cannot be created under a file
+        let config = config::Config { merge_report: file.join("merges").display().to_string(), ..Default::default() };
+
+        let failure = Optimizer::optimize_str(&retracting_lines(), &config).unwrap_err();
+        assert_eq!(failure.kind, ErrorKind::Error);
+        assert!(failure.message.starts_with("Unable to create directory"));
+
+        let layers: Vec<gcode::GCodeLayer> = gcode::GCodeReader::new("", retracting_lines().as_bytes()).collect();
+        assert!(Optimizer::optimize_layers(layers, &config).is_err());
+
+        let merge_report = dir.path().join("merges").display().to_string();
+        let config = config::Config { merge_report: merge_report.clone(), ..Default::default() };
+        Optimizer::optimize_str(&retracting_lines(), &config).unwrap();
+        assert!(Path::new(&merge_report).join("layer_1.merges.csv").is_file());
+    }
+
+    #[test]
+    fn time_objective_keeps_the_fastest_run() {
+        let layer = gcode::GCodeLayer {
+            nodes: vec![(0.0, 0.0, 0.2), (10.0, 0.0, 0.2), (10.0, 10.0, 0.2), (0.0, 10.0, 0.2)],
+            travel_feedrate: 9000.0,
+            ..Default::default()
+        };
+        let merges: HashMap<u32, u32> = (1..=4).map(|node| (node, node)).collect();
+        let config = config::Config { objective: config::Objective::Time, num_runs: 3, ..Default::default() };
+        // Runs crossing the square are slower, whichever of them came first
+        let tours = vec![vec![1, 3, 2, 4], vec![1, 2, 3, 4], vec![1, 2, 4, 3]];
+        assert_eq!(Optimizer::fastest_tour(&layer, &merges, tours, 1, &config), Some(vec![1, 2, 3, 4]));
+        assert_eq!(Optimizer::fastest_tour(&layer, &merges, Vec::new(), 1, &config), None);
+    }
+}
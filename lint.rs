@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::BufReader;
+use serde::Serialize;
+use crate::commands::{self, Command, Visitor};
+
+// Issues listed when some are found
+const LISTED: usize = 10;
+
+// Structural problem of a written G-code file, none of them changing what is extruded
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintKind {
+    // Filament pushed without moving in XY while nothing was retracted
+    ExtrusionInPlace,
+    ZeroFeedrate,
+    // Move written twice in a row, the second one going nowhere
+    DuplicateMove,
+    // Layer with no M106 or M107 where the original sets the fan
+    MissingFan,
+}
+
+impl LintKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            LintKind::ExtrusionInPlace => "extrusion in place",
+            LintKind::ZeroFeedrate => "feedrate of 0",
+            LintKind::DuplicateMove => "duplicate move",
+            LintKind::MissingFan => "missing fan command",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LintIssue {
+    pub kind: LintKind,
+    // Line of the written file
+    pub line_num: u32,
+    pub message: String,
+}
+
+pub fn display(issues: &[LintIssue]) {
+    for issue in issues.iter().take(LISTED) {
+        status!("  line {}: {}, {}", issue.line_num, issue.kind.name(), issue.message);
+    }
+    if issues.len() > LISTED {
+        status!("  and {} more", issues.len() - LISTED);
+    }
+}
+
+// Follows the extruder and the layers while visiting commands, the extruder being absolute until
+// told otherwise. Layers start on the first extrusion at a new height, as the optimizer splits them.
+#[derive(Default)]
+struct Linter {
+    issues: Vec<LintIssue>,
+    relative_extrusion: bool,
+    extruder_position: f64,
+    // Filament pulled back and not pushed again yet
+    retracted: f64,
+    // Last move with its line, forgotten once another command comes between
+    last_move: Option<(u32, Command)>,
+    z: Option<f64>,
+    // First line of each layer and whether it sets the fan
+    layers: Vec<(u32, bool)>,
+}
+
+impl Linter {
+    fn issue(&mut self, kind: LintKind, line_num: u32, message: String) {
+        self.issues.push(LintIssue { kind, line_num, message });
+    }
+
+    fn check_feedrate(&mut self, line_num: u32, command: &Command) {
+        if command.value('F') == Some(0.0) {
+            self.issue(LintKind::ZeroFeedrate, line_num, format!("{} stops the head", command.name));
+        }
+    }
+}
+
+impl Visitor for Linter {
+    fn visit_move(&mut self, line_num: u32, command: &Command, from: (f64, f64, f64), to: (f64, f64, f64)) {
+        self.check_feedrate(line_num, command);
+        // Relative E words extrude again when repeated
+        let repeats = from == to && (command.value('E').is_none() || !self.relative_extrusion);
+        if let Some((last_line, _)) = self.last_move.as_ref().filter(|(_, last)| repeats && last == command) {
+            let message = format!("same as line {}", last_line);
+            self.issue(LintKind::DuplicateMove, line_num, message);
+        }
+        self.last_move = Some((line_num, command.clone()));
+        if command.name == "G28" {
+            return;
+        }
+
+        let extrusion = match command.value('E') {
+            Some(e) if self.relative_extrusion => e,
+            Some(e) => {
+                let extrusion = e - self.extruder_position;
+                self.extruder_position = e;
+                extrusion
+            },
+            None => 0.0,
+        };
+        let in_place = (from.0, from.1) == (to.0, to.1);
+        if extrusion < 0.0 {
+            self.retracted -= extrusion;
+        } else if extrusion > 0.0 && in_place {
+            // Pushing back what was retracted primes the nozzle, anything more is a blob
+            if extrusion > self.retracted + 1e-6 {
+                self.issue(LintKind::ExtrusionInPlace, line_num, format!("E{:.5} with nothing retracted", extrusion - self.retracted));
+            }
+            self.retracted = (self.retracted - extrusion).max(0.0);
+        } else if extrusion > 0.0 {
+            self.retracted = 0.0;
+            if self.z != Some(to.2) {
+                self.z = Some(to.2);
+                self.layers.push((line_num, false));
+            }
+        }
+    }
+
+    fn visit_command(&mut self, line_num: u32, command: &Command) {
+        self.check_feedrate(line_num, command);
+        self.last_move = None;
+        match command.name.as_str() {
+            "M82" => self.relative_extrusion = false,
+            "M83" => self.relative_extrusion = true,
+            "G92" => if let Some(e) = command.value('E') {
+                self.extruder_position = e;
+            },
+            "M106" | "M107" => self.layers.last_mut().unwrap().1 = true,
+            _ => (),
+        }
+    }
+}
+
+fn read(gcode_path: &str) -> Linter {
+    let file = File::open(gcode_path)
+        .unwrap_or_else(|_| panic!("Unable to read file {}", gcode_path));
+    // Commands before the first extrusion make layer 0
+    let mut linter = Linter { layers: vec![(1, false)], ..Default::default() };
+    commands::visit(BufReader::new(file), &mut linter);
+    linter
+}
+
+// Lints an optimized G-code file, the original telling which layers set the fan
+pub fn lint(gcode_path: &str, optimized_path: &str) -> Vec<LintIssue> {
+    let base = read(gcode_path);
+    let mut optimized = read(optimized_path);
+
+    let missing: Vec<(usize, u32)> = base.layers.iter().zip(&optimized.layers).enumerate()
+        .filter(|(_, (base, optimized))| base.1 && !optimized.1)
+        .map(|(i, (_, optimized))| (i, optimized.0))
+        .collect();
+    for (layer, line_num) in missing {
+        optimized.issue(LintKind::MissingFan, line_num, format!("layer {} sets the fan in the original", layer));
+    }
+
+    optimized.issues.sort_by_key(|issue| issue.line_num);
+    optimized.issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+    use crate::{config, Optimizer};
+
+    // Purge line retracted at its end, then three lines on each of two layers, the middle one drawn right to
+    // left, every line primed after the travel leading to it and retracted once extruded
+    fn retracting_lines() -> String {
+        let mut gcode = String::from("G90\nM83\nG28\nG1 Z0.2 F720\nG1 X5 Y5 F9000\nG1 X60 Y5 E3 F1200\nG1 E-0.8 F2100\n");
+        for z in [0.2, 0.4] {
+            gcode.push_str(&format!("G1 Z{} F720\n", z));
+            for (from, to) in [(0, 10), (30, 20), (40, 50)] {
+                gcode.push_str(&format!("G0 X{} Y0 F9000\nG1 E0.8 F2100\nG1 X{} Y0 E0.4 F1800\nG1 E-0.8 F2100\n", from, to));
+            }
+        }
+        gcode
+    }
+
+    #[test]
+    fn primes_after_retractions() {
+        let gcode = retracting_lines();
+        let (output, _) = Optimizer::optimize_str(&gcode, &config::Config::default()).unwrap();
+        // Some line is run backwards, its prime being written before it
+        assert!(output.lines().any(|line| line.starts_with("G1 E0.8")));
+
+        let dir = TempDir::new().unwrap();
+        let gcode_path = dir.path().join("lines.gcode").display().to_string();
+        let optimized_path = dir.path().join("lines_optimized.gcode").display().to_string();
+        std::fs::write(&gcode_path, &gcode).unwrap();
+        std::fs::write(&optimized_path, &output).unwrap();
+        let issues = lint(&gcode_path, &optimized_path);
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::commands::Command;
+
+// Commands whose parameters are text, kept as they are
+const TEXT_COMMANDS: &[&str] = &["M23", "M28", "M30", "M32", "M117", "M118", "M928"];
+
+// Rewrites G-code into the fewest bytes the firmware reads the same way: comments and blank lines go,
+// modes already set are not set again, axes and feedrates keeping their value are left out of moves
+// and numbers lose their trailing zeros and the zero before the point.
+// Positions are only followed across moves and mode commands, any other command may move the head.
+#[derive(Default)]
+pub struct Minifier {
+    relative: Option<bool>,
+    relative_extrusion: Option<bool>,
+    inches: Option<bool>,
+    // Last X, Y, Z and F written, as written
+    axes: [Option<String>; 4],
+    // Last E set by G92 or an absolute extrusion
+    extruder: Option<String>,
+}
+
+impl Minifier {
+    // Minifies whole lines, the state being kept for the next ones
+    pub fn minify(&mut self, contents: &str) -> String {
+        let mut minified = String::with_capacity(contents.len());
+        for line in contents.lines() {
+            let command = Command::parse(line);
+            if command.name.is_empty() {
+                continue;
+            }
+            if let Some(line) = self.minify_command(command) {
+                minified.push_str(&line);
+                minified.push('\n');
+            }
+        }
+        minified
+    }
+
+    fn minify_command(&mut self, command: Command) -> Option<String> {
+        let name = command.name.to_uppercase();
+        if TEXT_COMMANDS.contains(&name.as_str()) {
+            self.forget();
+            return Some(format!("{} {}", command.name, command.params.join(" ")).trim_end().to_string());
+        }
+        let params: Vec<String> = command.params.iter().map(|param| shorten(param)).collect();
+
+        match name.as_str() {
+            // Positions written before the mode changed no longer compare
+            "G90" | "G91" => set_mode(&mut self.relative, name == "G91").then(|| {
+                self.forget();
+                name
+            }),
+            "M82" | "M83" => set_mode(&mut self.relative_extrusion, name == "M83").then_some(name),
+            "G20" | "G21" => set_mode(&mut self.inches, name == "G20").then_some(name),
+            "G0" | "G1" => self.minify_move(name, params),
+            "G92" => {
+                let e = params.iter().find(|param| param.starts_with('E')).map(|param| param[1..].to_string());
+                // Setting the extruder where it already is does nothing
+                if params.len() == 1 && e.is_some() && e == self.extruder {
+                    return None;
+                }
+                if params.iter().any(|param| !param.starts_with('E')) {
+                    self.forget();
+                }
+                self.extruder = e;
+                Some(join(name, &params))
+            },
+            _ => {
+                self.forget();
+                Some(join(name, &params))
+            },
+        }
+    }
+
+    // Move without the words keeping their value, none when nothing is left to do
+    fn minify_move(&mut self, name: String, params: Vec<String>) -> Option<String> {
+        let mut kept = Vec::with_capacity(params.len());
+        for param in params {
+            let letter = param.chars().next().unwrap_or_default();
+            let Some(axis) = "XYZF".find(letter) else {
+                if letter == 'E' {
+                    self.extruder = (self.relative_extrusion == Some(false)).then(|| param[1..].to_string());
+                }
+                kept.push(param);
+                continue;
+            };
+            let value = param[1..].to_string();
+            // Relative moves of 0 go nowhere, repeated ones still move
+            let unchanged = if axis < 3 && self.relative != Some(false) {
+                value == "0" && self.relative == Some(true)
+            } else {
+                self.axes[axis].as_ref() == Some(&value)
+            };
+            if axis < 3 && self.relative == Some(true) {
+                self.axes[axis] = None;
+            } else {
+                self.axes[axis] = Some(value);
+            }
+            if !unchanged {
+                kept.push(param);
+            }
+        }
+        (!kept.is_empty()).then(|| join(name, &kept))
+    }
+
+    fn forget(&mut self) {
+        self.axes = Default::default();
+        self.extruder = None;
+    }
+}
+
+// Whether setting a mode changes it, remembering it
+fn set_mode(mode: &mut Option<bool>, value: bool) -> bool {
+    mode.replace(value) != Some(value)
+}
+
+fn join(name: String, params: &[String]) -> String {
+    let mut line = name;
+    for param in params {
+        line.push(' ');
+        line.push_str(param);
+    }
+    line
+}
+
+// Word with its number written in the fewest characters, words without a number left as they are
+fn shorten(param: &str) -> String {
+    let mut chars = param.chars();
+    let Some(letter) = chars.next().filter(|letter| letter.is_ascii_alphabetic()) else {
+        return param.to_string();
+    };
+    let number = chars.as_str();
+    if number.parse::<f64>().is_err() || !number.bytes().all(|c| c.is_ascii_digit() || c == b'.' || c == b'-') {
+        return param.to_string();
+    }
+
+    let (sign, digits) = number.strip_prefix('-').map_or(("", number), |digits| ("-", digits));
+    let digits = if digits.contains('.') { digits.trim_end_matches('0').trim_end_matches('.') } else { digits };
+    let digits = digits.trim_start_matches('0');
+    match digits {
+        "" => format!("{}0", letter.to_ascii_uppercase()),
+        _ => format!("{}{}{}", letter.to_ascii_uppercase(), sign, digits),
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::Scope;
+
+// Threads can be started, browsers run everything on the calling thread
+pub const THREADED: bool = cfg!(not(target_arch = "wasm32"));
+
+// Fixed set of worker threads pulling jobs from a shared queue.
+// Without threads, jobs are run by the caller as their result is waited for.
+pub struct WorkerPool<'scope, R> {
+    results: Receiver<(u32, R)>,
+    pending: HashMap<u32, R>,
+    inline: Option<Box<dyn FnMut(u32) -> Option<R> + 'scope>>,
+}
+
+// Jobs waiting for a worker with their priority, closed once no more will be added
+struct Pending<J> {
+    jobs: VecDeque<(u32, J, usize)>,
+    closed: bool,
+}
+
+struct JobQueue<J> {
+    pending: Mutex<Pending<J>>,
+    added: Condvar,
+}
+
+// Adds jobs to a running pool, workers stop once it is dropped and the queue is empty
+pub struct JobSender<J> {
+    queue: Arc<JobQueue<J>>,
+}
+
+impl<J> JobSender<J> {
+    pub fn send(&self, id: u32, job: J) {
+        self.send_with_priority(id, job, 0);
+    }
+
+    // Jobs of higher priority are started first, equal ones in the order they were sent
+    pub fn send_with_priority(&self, id: u32, job: J, priority: usize) {
+        self.queue.pending.lock().unwrap().jobs.push_back((id, job, priority));
+        self.queue.added.notify_one();
+    }
+}
+
+impl<J> Drop for JobSender<J> {
+    fn drop(&mut self) {
+        self.queue.pending.lock().unwrap().closed = true;
+        self.queue.added.notify_all();
+    }
+}
+
+impl<'scope, R: Send + 'scope> WorkerPool<'scope, R> {
+    // Starts `threads` threads of a scope running the jobs given to the returned sender
+    pub fn run<J, F>(scope: &'scope Scope<'scope, '_>, threads: usize, work: F) -> (WorkerPool<'scope, R>, JobSender<J>)
+    where
+        J: Send + 'scope,
+        F: Fn(u32, J) -> R + Send + Sync + 'scope,
+    {
+        let queue = Arc::new(JobQueue {
+            pending: Mutex::new(Pending { jobs: VecDeque::new(), closed: false }),
+            added: Condvar::new(),
+        });
+        let work = Arc::new(work);
+        let (sender, results) = channel();
+
+        if !THREADED {
+            let jobs = Arc::clone(&queue);
+            let inline = move |id: u32| {
+                let mut pending = jobs.pending.lock().unwrap();
+                let i = pending.jobs.iter().position(|(job_id, _, _)| *job_id == id)?;
+                let (_, job, _) = pending.jobs.remove(i)?;
+                drop(pending);
+                Some(work(id, job))
+            };
+            let pool = WorkerPool { results, pending: HashMap::new(), inline: Some(Box::new(inline)) };
+            return (pool, JobSender { queue });
+        }
+
+        for _ in 0..threads.max(1) {
+            let queue = Arc::clone(&queue);
+            let work = Arc::clone(&work);
+            let sender = sender.clone();
+
+            scope.spawn(move || loop {
+                let job = {
+                    let mut pending = queue.pending.lock().unwrap();
+                    while pending.jobs.is_empty() && !pending.closed {
+                        pending = queue.added.wait(pending).unwrap();
+                    }
+                    let next = pending.jobs.iter()
+                        .enumerate()
+                        .max_by_key(|(i, (_, _, priority))| (*priority, std::cmp::Reverse(*i)))
+                        .map(|(i, _)| i);
+                    next.and_then(|i| pending.jobs.remove(i))
+                };
+                match job {
+                    Some((id, job, _)) => {
+                        if sender.send((id, work(id, job))).is_err() {
+                            break;
+                        }
+                    },
+                    None => break,
+                }
+            });
+        }
+
+        let pool = WorkerPool {
+            results,
+            pending: HashMap::new(),
+            inline: None,
+        };
+
+        (pool, JobSender { queue })
+    }
+
+    // Waits for the result of a job, keeping the ones finishing earlier for later
+    pub fn wait(&mut self, id: u32) -> R {
+        loop {
+            if let Some(result) = self.pending.remove(&id) {
+                return result;
+            }
+            if let Some(inline) = self.inline.as_mut() {
+                return inline(id).unwrap_or_else(|| panic!("Job {} was never sent", id));
+            }
+
+            let (done, result) = self.results.recv()
+                .unwrap_or_else(|_| panic!("Worker threads stopped before finishing job {}", id));
+            self.pending.insert(done, result);
+        }
+    }
+}
+
+// Counts the holders running at the same time, each waiting until fewer run than its own limit
+#[cfg(feature = "lkh")]
+pub struct Semaphore {
+    running: Mutex<usize>,
+    released: Condvar,
+}
+
+// Slot of a semaphore, given back when dropped
+#[cfg(feature = "lkh")]
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+#[cfg(feature = "lkh")]
+impl Semaphore {
+    pub const fn new() -> Semaphore {
+        Semaphore {
+            running: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    // Blocks until fewer than limit holders run
+    pub fn acquire(&self, limit: usize) -> SemaphoreGuard<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= limit.max(1) {
+            running = self.released.wait(running).unwrap();
+        }
+        *running += 1;
+
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+#[cfg(feature = "lkh")]
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.running.lock().unwrap() -= 1;
+        // Waiters have different limits, so each checks its own
+        self.semaphore.released.notify_all();
+    }
+}
+
+#[cfg(all(test, feature = "lkh"))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn each_holder_waits_on_its_own_limit() {
+        let semaphore = Semaphore::new();
+        let first = semaphore.acquire(2);
+        // A larger limit than the first holder's still lets more run
+        let second = semaphore.acquire(3);
+
+        let acquired = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _slot = semaphore.acquire(2);
+                acquired.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+
+            drop(second);
+        });
+        assert!(acquired.load(Ordering::SeqCst));
+        drop(first);
+    }
+}
@@ -0,0 +1,162 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+// Machine a G-code file is printed on, read from a TOML profile such as
+//   bed_size = [250, 210]
+//   bed_shape = "circular"
+//   acceleration = 4000
+//   [max_feedrate]
+//   x = 12000
+//   [jerk]
+//   x = 8
+//   [retraction]
+//   length = 0.8
+// Distances are in mm, feedrates in mm/min, jerks in mm/s and accelerations in mm/s².
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PrinterProfile {
+    // File name of the profile without its extension
+    #[serde(skip_deserializing)]
+    pub name: String,
+    // Printable area along X and Y, starting at the origin
+    pub bed_size: [f64; 2],
+    #[serde(default)]
+    pub origin: [f64; 2],
+    #[serde(default)]
+    pub bed_shape: BedShape,
+    #[serde(default)]
+    pub max_feedrate: MaxFeedrate,
+    // Replaces the acceleration of the configuration unless the configuration sets it
+    pub acceleration: Option<f64>,
+    #[serde(default)]
+    pub jerk: Jerk,
+    #[serde(default)]
+    pub retraction: Retraction,
+}
+
+// Round beds of deltas fill the area of the bed size, their center at the origin plus half the size
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BedShape {
+    #[default]
+    Rectangular,
+    Circular,
+}
+
+// Fastest moves of each axis, 0 when unknown
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MaxFeedrate {
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    #[serde(default)]
+    pub z: f64,
+    #[serde(default)]
+    pub e: f64,
+}
+
+// Change of speed of each axis taken without slowing down at a corner, 0 stopping at every corner
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Jerk {
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    #[serde(default)]
+    pub z: f64,
+    #[serde(default)]
+    pub e: f64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Retraction {
+    #[serde(default)]
+    pub length: f64,
+    #[serde(default)]
+    pub speed: f64,
+    #[serde(default)]
+    pub z_hop: f64,
+}
+
+impl PrinterProfile {
+    // Whether a position lies on the bed, within the given margin
+    pub fn on_bed(&self, x: f64, y: f64, margin: f64) -> bool {
+        let (width, depth) = (self.bed_size[0], self.bed_size[1]);
+        let (x, y) = (x - self.origin[0], y - self.origin[1]);
+        match self.bed_shape {
+            BedShape::Rectangular => -margin <= x && x <= width + margin && -margin <= y && y <= depth + margin,
+            BedShape::Circular => {
+                // Ellipse when the sizes differ, a circle otherwise
+                let (dx, dy) = ((x - width / 2.0) / (width / 2.0 + margin), (y - depth / 2.0) / (depth / 2.0 + margin));
+                dx * dx + dy * dy <= 1.0
+            }
+        }
+    }
+
+    // Settings of the configuration the profile gives, as configuration fields
+    pub fn settings(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut settings = serde_json::Map::new();
+        if let Some(acceleration) = self.acceleration {
+            settings.insert(String::from("acceleration"), acceleration.into());
+        }
+        settings
+    }
+}
+
+// Profiles of the user, in tsp-gcode-optimizer/printers of the user's configuration directory
+// (~/.config on Linux and macOS unless XDG_CONFIG_HOME is set, %APPDATA% on Windows)
+pub fn profiles_dir() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+        .or_else(|| if cfg!(windows) { env::var_os("APPDATA").map(PathBuf::from) } else { None })
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("tsp-gcode-optimizer").join("printers"))
+}
+
+// Names of the profiles found in the profiles directory, in order
+pub fn list() -> Vec<String> {
+    let Some(dir) = profiles_dir() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = fs::read_dir(dir).into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().unwrap_or_default() == "toml")
+        .filter_map(|path| path.file_stem().map(|name| name.to_string_lossy().to_string()))
+        .collect();
+    names.sort();
+    names
+}
+
+// Profile named like prusa-mk4 in the profiles directory, or the TOML file at a path
+pub fn load(name: &str) -> Result<PrinterProfile, String> {
+    let path = if name.ends_with(".toml") || name.contains(['/', '\\']) {
+        PathBuf::from(name)
+    } else {
+        let dir = profiles_dir().ok_or("No configuration directory to find printer profiles in")?;
+        dir.join(format!("{}.toml", name))
+    };
+
+    let toml = fs::read_to_string(&path).map_err(|_| {
+        let known = list();
+        if known.is_empty() {
+            format!("Printer profile {} not found", path.display())
+        } else {
+            format!("Printer profile {} not found, known printers: {}", path.display(), known.join(", "))
+        }
+    })?;
+    let mut profile: PrinterProfile = toml::from_str(&toml)
+        .map_err(|e| format!("Invalid printer profile {}: {}", path.display(), e.message()))?;
+    if profile.bed_size.iter().any(|size| *size <= 0.0) || profile.acceleration.is_some_and(|acceleration| acceleration <= 0.0) {
+        return Err(format!("Invalid printer profile {}: bed size and acceleration must be above 0", path.display()));
+    }
+
+    profile.name = path.file_stem().map_or(name.to_string(), |stem| stem.to_string_lossy().to_string());
+    Ok(profile)
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use tracing::info;
+use crate::segments;
+use crate::simulator::{self, Limits};
+
+// Rewrites a G-code file with M73 P<percent> R<minutes left> commands, one each time the estimated
+// print time crosses another percent, so printers show the progress of the order actually printed.
+// M73 commands already in the file are dropped.
+pub fn add_progress(gcode_path: &str, limits: &Limits) -> Result<(), String> {
+    let segments = segments::read(gcode_path);
+    let times = simulator::move_times(&segments, limits);
+    let total: f64 = times.iter().sum();
+
+    // Time elapsed once each move line is done
+    let mut elapsed = 0.0;
+    let mut done: HashMap<u32, f64> = HashMap::new();
+    for (segment, time) in segments.iter().zip(&times) {
+        elapsed += time;
+        done.insert(segment.line_num, elapsed);
+    }
+
+    let progress_path = format!("{}.progress", gcode_path);
+    let input = File::open(gcode_path)
+        .map_err(|e| format!("Unable to read file {}: {}", gcode_path, e))?;
+    let output = File::create(&progress_path)
+        .map_err(|e| format!("Unable to write to file {}: {}", progress_path, e))?;
+    let mut writer = BufWriter::new(output);
+    let write_error = |e: io::Error| format!("Unable to write to file {}: {}", progress_path, e);
+
+    let mut percent = None;
+    let mut elapsed = 0.0;
+    let mut commands = 0;
+    for (i, line) in BufReader::new(input).lines().enumerate() {
+        let line = line.map_err(|e| format!("Unable to read file {}: {}", gcode_path, e))?;
+        if line.split_whitespace().next() == Some("M73") {
+            continue;
+        }
+
+        // Progress when the line starts, given before the first move and each time it changes
+        if let Some(&after) = done.get(&(i as u32 + 1)) {
+            let current = if total > 0.0 { (elapsed / total * 100.0).floor() as u32 } else { 0 };
+            if percent != Some(current) {
+                writeln!(writer, "M73 P{} R{}", current, ((total - elapsed) / 60.0).round() as u32).map_err(write_error)?;
+                percent = Some(current);
+                commands += 1;
+            }
+            elapsed = after;
+        }
+        writeln!(writer, "{}", line).map_err(write_error)?;
+    }
+    writeln!(writer, "M73 P100 R0").map_err(write_error)?;
+    writer.flush().map_err(write_error)?;
+    drop(writer);
+
+    fs::rename(&progress_path, gcode_path)
+        .map_err(|e| format!("Unable to replace file {} with {}: {}", gcode_path, progress_path, e))?;
+    info!("Added {} progress command-s to {}, estimated print time {:.2} min", commands + 1, gcode_path, total / 60.0);
+    Ok(())
+}
@@ -1,15 +1,20 @@
-// Get position from a line of G-code
-pub fn get_position(line: &str, current_position: (f64, f64, f64)) -> (f64, f64, f64) {
+// Get position from a line of G-code, failing on the first axis without a number
+pub fn get_position(line: &str, current_position: (f64, f64, f64)) -> Result<(f64, f64, f64), String> {
     let mut position = current_position;
     for part in line.split_whitespace() {
         match part.chars().next() {
-            Some('X') => position.0 = part[1..].parse().unwrap(),
-            Some('Y') => position.1 = part[1..].parse().unwrap(),
-            Some('Z') => position.2 = part[1..].parse().unwrap(),
+            Some('X') => position.0 = number(part)?,
+            Some('Y') => position.1 = number(part)?,
+            Some('Z') => position.2 = number(part)?,
             _ => (),
         }
     }
-    position
+    Ok(position)
+}
+
+// Number of a word such as X12.5
+pub fn number(part: &str) -> Result<f64, String> {
+    part[1..].parse().map_err(|_| format!("Invalid number {}", part))
 }
 
 // Calculate distance between two points in 3D space
@@ -17,7 +22,90 @@ pub fn distance_3d(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
     ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt()
 }
 
+// Whether two segments cross in the XY plane, segments only touching at an end not crossing
+pub fn segments_cross(a: (f64, f64, f64), b: (f64, f64, f64), c: (f64, f64, f64), d: (f64, f64, f64)) -> bool {
+    let side = |p: (f64, f64, f64), q: (f64, f64, f64), r: (f64, f64, f64)| (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0);
+    side(a, b, c) * side(a, b, d) < 0.0 && side(c, d, a) * side(c, d, b) < 0.0
+}
+
 // Calculate distance between a point and the origin in 3D space
 pub fn distance_to_origin(a: (f64, f64, f64)) -> f64 {
     (a.0.powi(2) + a.1.powi(2) + a.2.powi(2)).sqrt()
-}
\ No newline at end of file
+}
+
+// Coordinates of a list of points split into one array per axis, so distances to all of them
+// are computed in a single loop the compiler can vectorize
+pub struct Coordinates {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+    zs: Vec<f64>,
+}
+
+impl Coordinates {
+    pub fn new(points: &[(f64, f64, f64)]) -> Coordinates {
+        Coordinates {
+            xs: points.iter().map(|p| p.0).collect(),
+            ys: points.iter().map(|p| p.1).collect(),
+            zs: points.iter().map(|p| p.2).collect(),
+        }
+    }
+
+    // Distances from a point to every point, same values as distance_3d
+    pub fn distances_from(&self, a: (f64, f64, f64), out: &mut [f64]) {
+        // Equal lengths let the bounds checks out of the loop
+        let n = out.len();
+        let (xs, ys, zs) = (&self.xs[..n], &self.ys[..n], &self.zs[..n]);
+        for i in 0..n {
+            let dx = a.0 - xs[i];
+            let dy = a.1 - ys[i];
+            let dz = a.2 - zs[i];
+            out[i] = (dx * dx + dy * dy + dz * dz).sqrt();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points spread over a print bed and its height, from a fixed seed
+    fn random_points(count: usize, seed: u64) -> Vec<(f64, f64, f64)> {
+        let mut state = seed;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64 * 300.0 - 50.0
+        };
+        (0..count).map(|_| (next(), next(), next())).collect()
+    }
+
+    #[test]
+    fn distances_from_matches_distance_3d() {
+        // Lengths around the widths of vector registers, most of them leaving a remainder
+        for (i, count) in [0, 1, 2, 3, 4, 5, 7, 8, 9, 15, 16, 17, 31, 33, 1001].into_iter().enumerate() {
+            let points = random_points(count, 0x9E37_79B9_7F4A_7C15 + i as u64);
+            let coordinates = Coordinates::new(&points);
+            let from = random_points(1, i as u64 + 1)[0];
+
+            let mut distances = vec![f64::NAN; count];
+            coordinates.distances_from(from, &mut distances);
+            for (point, distance) in points.iter().zip(&distances) {
+                let expected = distance_3d(from, *point);
+                assert!((distance - expected).abs() <= 1e-9 * expected.max(1.0), "{} instead of {} for {} points", distance, expected, count);
+            }
+        }
+    }
+
+    #[test]
+    fn distances_from_fills_shorter_outputs_only() {
+        let points = random_points(10, 42);
+        let coordinates = Coordinates::new(&points);
+        let mut distances = vec![-1.0; 7];
+        coordinates.distances_from(points[0], &mut distances);
+        assert_eq!(distances[0], 0.0);
+        for (point, distance) in points.iter().zip(&distances) {
+            assert!((distance - distance_3d(points[0], *point)).abs() <= 1e-9);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::fs;
+use serde::Serialize;
+use tracing::info;
+use crate::config::Config;
+use crate::console;
+use crate::gcode::{GCodeLayer, UnknownCommand};
+use crate::warnings::Warnings;
+
+// Outcome of an optimization, distances in the units of the G-code
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OptimizationReport {
+    pub layers: u32,
+    pub base_travel: f64,
+    pub optimized_travel: f64,
+    pub base_extrusion: f64,
+    pub optimized_extrusion: f64,
+    pub base_travel_moves: usize,
+    pub optimized_travel_moves: usize,
+    pub base_extrusion_moves: usize,
+    pub optimized_extrusion_moves: usize,
+    // Filament pushed, the optimization leaving it unchanged
+    pub base_filament: f64,
+    pub optimized_filament: f64,
+    // Estimated minutes saved, only computed with the time objective
+    pub time_saved: f64,
+    // Problems the solver failed on, written in their original order
+    pub solver_failures: usize,
+    // Extrusions slowed down to keep under the maximum volumetric flow
+    pub flow_limited: usize,
+    // Moves slowed down to the maximum feedrates of the printer profile
+    pub feedrate_capped: usize,
+    // Seconds spent solving, summed over the solver threads
+    pub solve_time: f64,
+    // Layers left once cancelled were written in their original order
+    pub cancelled: bool,
+    pub per_layer: Vec<LayerReport>,
+    pub features: Vec<FeatureReport>,
+    pub tools: Vec<ToolReport>,
+    pub warnings: Warnings,
+    pub unknown_commands: Vec<UnknownCommand>,
+}
+
+// Travel and estimated move time of a layer before and after optimizing it, times in seconds
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LayerReport {
+    pub layer: u32,
+    pub nodes: usize,
+    // Nodes of the problem the layer was merged into, 0 when it kept its order
+    pub merged: usize,
+    pub status: LayerStatus,
+    pub solve_time: f64,
+    pub base_travel: f64,
+    pub optimized_travel: f64,
+    pub base_extrusion: f64,
+    pub optimized_extrusion: f64,
+    pub base_time: f64,
+    pub optimized_time: f64,
+    // Travels crossing a path already extruded in the layer, a proxy for stringing and scarring
+    pub base_crossings: usize,
+    pub optimized_crossings: usize,
+    // Why the layer, or a section of it, kept its original order
+    pub skip_reason: Option<SkipReason>,
+}
+
+// Distances of a feature named by the slicer, perimeters or infill for instance. Each travel
+// counts for the feature it leads to, those ending a layer for the last feature extruded.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FeatureReport {
+    pub feature: String,
+    pub extrusion: f64,
+    pub base_travel: f64,
+    pub optimized_travel: f64,
+}
+
+// Report of a feature, added the first time it is met. Moves before any ;TYPE: comment are of the Unknown feature.
+pub fn feature<'a>(features: &'a mut Vec<FeatureReport>, name: &str) -> &'a mut FeatureReport {
+    let name = if name.is_empty() { "Unknown" } else { name };
+    match features.iter().position(|feature| feature.feature == name) {
+        Some(i) => &mut features[i],
+        None => {
+            features.push(FeatureReport { feature: name.to_string(), ..Default::default() });
+            features.last_mut().unwrap()
+        }
+    }
+}
+
+// Distances of a tool of multi-extruder printers, travels counting for the tool they lead to like
+// for features, and the times the tool was selected
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ToolReport {
+    pub tool: u32,
+    pub extrusion: f64,
+    pub base_travel: f64,
+    pub optimized_travel: f64,
+    pub tool_changes: usize,
+}
+
+// Report of a tool, added the first time it is met and kept sorted by tool
+pub fn tool(tools: &mut Vec<ToolReport>, tool: u32) -> &mut ToolReport {
+    match tools.binary_search_by_key(&tool, |report| report.tool) {
+        Ok(i) => &mut tools[i],
+        Err(i) => {
+            tools.insert(i, ToolReport { tool, ..Default::default() });
+            &mut tools[i]
+        }
+    }
+}
+
+// Table of the distances of each tool, left out for single tool jobs
+pub fn display_tools(tools: &[ToolReport]) {
+    if tools.len() < 2 {
+        return;
+    }
+    status!("\n{:<6}  {:>12}  {:>12}  {:>12}  {:>12}  {:>8}  {:>8}", "Tool", "Extrusion", "Travel", "Optimized", "Saved", "Saved %", "Changes");
+    for tool in tools {
+        let saved = tool.base_travel - tool.optimized_travel;
+        status!("{:<6}  {:>12.2}  {:>12.2}  {:>12.2}  {:>12.2}  {:>8.2}  {:>8}",
+            format!("T{}", tool.tool), tool.extrusion, tool.base_travel, tool.optimized_travel, saved,
+            percent(saved, tool.base_travel), tool.tool_changes);
+    }
+}
+
+// Table of the distances of each feature, showing where the travel saved comes from
+pub fn display_features(features: &[FeatureReport]) {
+    if features.is_empty() {
+        return;
+    }
+    let width = features.iter().map(|feature| feature.feature.len()).max().unwrap_or(0).max(7);
+    status!("\n{:<width$}  {:>12}  {:>12}  {:>12}  {:>12}  {:>8}", "Feature", "Extrusion", "Travel", "Optimized", "Saved", "Saved %", width = width);
+    for feature in features {
+        let saved = feature.base_travel - feature.optimized_travel;
+        status!("{:<width$}  {:>12.2}  {:>12.2}  {:>12.2}  {:>12.2}  {:>8.2}",
+            feature.feature, feature.extrusion, feature.base_travel, feature.optimized_travel, saved,
+            percent(saved, feature.base_travel), width = width);
+    }
+}
+
+// Value before and after optimizing, the change being the optimized value minus the original one
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Change {
+    pub base: f64,
+    pub optimized: f64,
+    pub change: f64,
+    pub percent: f64,
+}
+
+impl Change {
+    pub fn new(base: f64, optimized: f64) -> Change {
+        Change { base, optimized, change: optimized - base, percent: percent(optimized - base, base) }
+    }
+}
+
+// What an optimization changed, times in seconds. Less is better for every one of them.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Comparison {
+    pub travel: Change,
+    pub travel_moves: Change,
+    pub extrusion_moves: Change,
+    pub time: Change,
+    pub crossings: Change,
+}
+
+impl Comparison {
+    // Times are the estimates of the layers, to be replaced by finer ones when available
+    pub fn new(report: &OptimizationReport) -> Comparison {
+        Comparison {
+            travel: Change::new(report.base_travel, report.optimized_travel),
+            travel_moves: Change::new(report.base_travel_moves as f64, report.optimized_travel_moves as f64),
+            extrusion_moves: Change::new(report.base_extrusion_moves as f64, report.optimized_extrusion_moves as f64),
+            time: Change::new(
+                report.per_layer.iter().map(|layer| layer.base_time).sum(),
+                report.per_layer.iter().map(|layer| layer.optimized_time).sum(),
+            ),
+            crossings: Change::new(
+                report.per_layer.iter().map(|layer| layer.base_crossings as f64).sum(),
+                report.per_layer.iter().map(|layer| layer.optimized_crossings as f64).sum(),
+            ),
+        }
+    }
+
+    pub fn display(&self) {
+        status!("\n{:<20}  {:>12}  {:>12}  {:>22}", "Comparison", "Original", "Optimized", "Change");
+        let rows = [
+            ("Travel distance", self.travel, 2),
+            ("Travel moves", self.travel_moves, 0),
+            ("Extrusion moves", self.extrusion_moves, 0),
+            ("Estimated time (s)", self.time, 2),
+            ("Travel crossings", self.crossings, 0),
+        ];
+        for (name, change, decimals) in rows {
+            let text = format!("{:+.decimals$} ({:+.2}%)", change.change, change.percent, decimals = decimals);
+            let text = if change.change == 0.0 { text } else { console::highlight(&text, change.change < 0.0) };
+            status!("{:<20}  {:>12.decimals$}  {:>12.decimals$}  {:>22}", name, change.base, change.optimized, text, decimals = decimals);
+        }
+    }
+
+    pub fn log(&self) {
+        info!("Travel distance {:.2} -> {:.2} ({:+.2}%), travel moves {} -> {}, estimated time {:.2} s -> {:.2} s ({:+.2}%), travel crossings {} -> {}",
+            self.travel.base, self.travel.optimized, self.travel.percent, self.travel_moves.base, self.travel_moves.optimized,
+            self.time.base, self.time.optimized, self.time.percent, self.crossings.base, self.crossings.optimized);
+    }
+}
+
+// Whether a layer went through the solver, was taken from a checkpoint or kept its order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerStatus {
+    #[default]
+    Skipped,
+    Solved,
+    Reused,
+}
+
+impl LayerStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LayerStatus::Skipped => "skipped",
+            LayerStatus::Solved => "solved",
+            LayerStatus::Reused => "reused",
+        }
+    }
+}
+
+// Why a layer was written in its original order
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    // Travels only, like the homing and priming moves before the first extrusion
+    NoExtrusion,
+    // 3 nodes or less, like the layers of vase mode prints rising at every move
+    TooFewNodes,
+    // Outside the layers to optimize
+    NotSelected,
+    // Optimized order estimated slower with the time objective
+    Slower,
+    SolverFailed,
+    Cancelled,
+}
+
+impl SkipReason {
+    // Reason of a layer not sent to the solver
+    pub fn unsolved(layer: &GCodeLayer, number: u32, config: &Config) -> SkipReason {
+        if !layer.extrudes() {
+            SkipReason::NoExtrusion
+        } else if layer.nodes.len() <= 3 {
+            SkipReason::TooFewNodes
+        } else if !config.optimizes_layer(number) {
+            SkipReason::NotSelected
+        } else {
+            SkipReason::Cancelled
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::NoExtrusion => "no extrusion",
+            SkipReason::TooFewNodes => "too few nodes",
+            SkipReason::NotSelected => "not selected",
+            SkipReason::Slower => "slower once optimized",
+            SkipReason::SolverFailed => "solver failed",
+            SkipReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+// Report of a job for farms aggregating results across jobs, times in seconds
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    input: &'a str,
+    output: &'a str,
+    layers: u32,
+    nodes: usize,
+    merged: usize,
+    base_travel: f64,
+    optimized_travel: f64,
+    travel_saved_percent: f64,
+    base_extrusion: f64,
+    optimized_extrusion: f64,
+    base_filament: f64,
+    optimized_filament: f64,
+    solve_time: f64,
+    // Estimated print time saved over every layer, whatever the objective
+    time_saved: f64,
+    comparison: &'a Comparison,
+    solver_failures: usize,
+    cancelled: bool,
+    features: &'a [FeatureReport],
+    tools: &'a [ToolReport],
+    warnings: &'a Warnings,
+    unknown_commands: &'a [UnknownCommand],
+    per_layer: Vec<JsonLayer<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonLayer<'a> {
+    #[serde(flatten)]
+    layer: &'a LayerReport,
+    time_saved: f64,
+}
+
+impl<'a> JsonReport<'a> {
+    fn new(report: &'a OptimizationReport, comparison: &'a Comparison, input: &'a str, output: &'a str) -> JsonReport<'a> {
+        JsonReport {
+            input,
+            output,
+            layers: report.layers,
+            nodes: report.per_layer.iter().map(|layer| layer.nodes).sum(),
+            merged: report.per_layer.iter().map(|layer| layer.merged).sum(),
+            base_travel: report.base_travel,
+            optimized_travel: report.optimized_travel,
+            travel_saved_percent: percent(report.base_travel - report.optimized_travel, report.base_travel),
+            base_extrusion: report.base_extrusion,
+            optimized_extrusion: report.optimized_extrusion,
+            base_filament: report.base_filament,
+            optimized_filament: report.optimized_filament,
+            solve_time: report.solve_time,
+            time_saved: report.per_layer.iter().map(|layer| layer.base_time - layer.optimized_time).sum(),
+            comparison,
+            solver_failures: report.solver_failures,
+            cancelled: report.cancelled,
+            features: &report.features,
+            tools: &report.tools,
+            warnings: &report.warnings,
+            unknown_commands: &report.unknown_commands,
+            per_layer: report.per_layer.iter()
+                .map(|layer| JsonLayer { layer, time_saved: layer.base_time - layer.optimized_time })
+                .collect(),
+        }
+    }
+}
+
+// Writes the overall and per-layer metrics of an optimization as JSON
+pub fn write_json(report: &OptimizationReport, comparison: &Comparison, input: &str, output: &str, path: &str) -> Result<(), String> {
+    let json = JsonReport::new(report, comparison, input, output);
+    fs::write(path, serde_json::to_string_pretty(&json).unwrap())
+        .map_err(|e| format!("Unable to write file {}: {}", path, e))
+}
+
+// Writes the nodes, travels and solve time of each layer as CSV
+pub fn write_csv(layers: &[LayerReport], path: &str) -> Result<(), String> {
+    let mut csv = String::from("Layer,Nodes,Merged,Base travel,Optimized travel,Saved %,Solve time,Status,Base crossings,Optimized crossings\n");
+    for layer in layers {
+        csv.push_str(&format!("{},{},{},{:.3},{:.3},{:.2},{:.3},{},{},{}\n",
+            layer.layer, layer.nodes, layer.merged, layer.base_travel, layer.optimized_travel,
+            percent(layer.base_travel - layer.optimized_travel, layer.base_travel), layer.solve_time, layer.status.name(),
+            layer.base_crossings, layer.optimized_crossings));
+    }
+
+    fs::write(path, csv)
+        .map_err(|e| format!("Unable to write file {}: {}", path, e))
+}
+
+// CSV of the layers kept in their original order, next to the CSV of the layers
+pub fn skipped_csv_path(csv_path: &str) -> String {
+    match csv_path.strip_suffix(".csv") {
+        Some(stem) => format!("{}.skipped.csv", stem),
+        None => format!("{}.skipped", csv_path),
+    }
+}
+
+pub fn write_skipped_csv(layers: &[LayerReport], path: &str) -> Result<(), String> {
+    let mut csv = String::from("Layer,Nodes,Status,Reason\n");
+    for layer in layers {
+        if let Some(reason) = layer.skip_reason {
+            csv.push_str(&format!("{},{},{},{}\n", layer.layer, layer.nodes, layer.status.name(), reason.name()));
+        }
+    }
+
+    fs::write(path, csv)
+        .map_err(|e| format!("Unable to write file {}: {}", path, e))
+}
+
+// Page showing the report to operators, its data filled in where the template has /*REPORT*/
+const HTML_TEMPLATE: &str = include_str!("report.template.html");
+
+// Writes the report as a single HTML page with summary cards, a chart of the travel saved
+// on each layer and a sortable table of the layers, usable without network access
+pub fn write_html(report: &OptimizationReport, comparison: &Comparison, input: &str, output: &str, path: &str) -> Result<(), String> {
+    // A script element ends at the first </ found in it
+    let json = serde_json::to_string(&JsonReport::new(report, comparison, input, output)).unwrap().replace("</", "<\\/");
+    fs::write(path, HTML_TEMPLATE.replace("/*REPORT*/", &json))
+        .map_err(|e| format!("Unable to write file {}: {}", path, e))
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 { part / total * 100.0 } else { 0.0 }
+}
+
+// Layers saving the most travel, the ones made longer last
+pub fn display_top_layers(layers: &[LayerReport], count: usize) {
+    let mut top: Vec<&LayerReport> = layers.iter().filter(|layer| layer.base_travel != layer.optimized_travel).collect();
+    if top.is_empty() {
+        return;
+    }
+    top.sort_by(|a, b| (b.base_travel - b.optimized_travel).total_cmp(&(a.base_travel - a.optimized_travel)));
+    top.truncate(count);
+
+    let saved: f64 = layers.iter().map(|layer| layer.base_travel - layer.optimized_travel).sum();
+    status!("\nTop {} layer-s by travel saved:", top.len());
+    status!("{:>6}  {:>7}  {:>12}  {:>12}  {:>12}  {:>8}  {:>9}", "Layer", "Nodes", "Travel", "Optimized", "Saved", "Of total", "Solve (s)");
+    for layer in top {
+        let layer_saved = layer.base_travel - layer.optimized_travel;
+        status!("{:>6}  {:>7}  {:>12.2}  {:>12.2}  {:>12.2}  {:>7.1}%  {:>9.3}",
+            layer.layer, layer.nodes, layer.base_travel, layer.optimized_travel, layer_saved,
+            percent(layer_saved, saved), layer.solve_time);
+    }
+}
+
+// Table of the layers, followed by the projected savings over the whole file
+pub fn display_layers(layers: &[LayerReport]) {
+    status!("\n{:>6}  {:>7}  {:>12}  {:>12}  {:>12}  {:>10}", "Layer", "Nodes", "Travel", "Optimized", "Saved", "Saved (s)");
+    for layer in layers {
+        status!("{:>6}  {:>7}  {:>12.2}  {:>12.2}  {:>12.2}  {:>10.2}",
+            layer.layer, layer.nodes, layer.base_travel, layer.optimized_travel,
+            layer.base_travel - layer.optimized_travel, layer.base_time - layer.optimized_time);
+    }
+
+    let base_travel: f64 = layers.iter().map(|layer| layer.base_travel).sum();
+    let optimized_travel: f64 = layers.iter().map(|layer| layer.optimized_travel).sum();
+    let time_saved: f64 = layers.iter().map(|layer| layer.base_time - layer.optimized_time).sum();
+    status!("\nProjected travel saved: {:.2} ({:.2}%)", base_travel - optimized_travel, percent(base_travel - optimized_travel, base_travel));
+    status!("Projected time saved: {:.2} min", time_saved / 60.0);
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use serde::Serialize;
+use crate::commands::{self, Command, Modes, Visitor};
+
+// Endpoints closer than this are the same point, in the units of the G-code
+pub const POSITION_STEP: f64 = 0.001;
+
+// Straight move of a G-code file between absolute positions, extruding when its E amount is positive
+#[derive(Clone, Debug, Serialize)]
+pub struct Segment {
+    pub line_num: u32,
+    pub from: (f64, f64, f64),
+    pub to: (f64, f64, f64),
+    // Filament pushed by the move, whatever the extruder mode
+    pub extrusion: f64,
+    pub feedrate: f64,
+    // Feature named by the last ;TYPE: comment of the slicer, empty before the first one
+    pub feature: String,
+}
+
+// Endpoints of a segment rounded to a grid, in the same order whichever way it is run
+pub type SegmentKey = ((i64, i64, i64), (i64, i64, i64));
+
+impl Segment {
+    pub fn extrudes(&self) -> bool {
+        self.extrusion > 0.0
+    }
+
+    // Staying in place in XY, like primes and z-hops
+    pub fn in_place(&self) -> bool {
+        (self.from.0, self.from.1) == (self.to.0, self.to.1)
+    }
+
+    pub fn key(&self, step: f64) -> SegmentKey {
+        let (from, to) = (round(self.from, step), round(self.to, step));
+        if from <= to { (from, to) } else { (to, from) }
+    }
+}
+
+// Position on a grid of the given step
+pub fn round(position: (f64, f64, f64), step: f64) -> (i64, i64, i64) {
+    ((position.0 / step).round() as i64, (position.1 / step).round() as i64, (position.2 / step).round() as i64)
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({:.3}, {:.3}, {:.3}) -> ({:.3}, {:.3}, {:.3}) E{:.5}",
+            self.from.0, self.from.1, self.from.2, self.to.0, self.to.1, self.to.2, self.extrusion)
+    }
+}
+
+// Follows the extruder mode, feedrate and feature while visiting moves
+struct SegmentReader {
+    segments: Vec<Segment>,
+    relative_extrusion: bool,
+    extruder_position: f64,
+    feedrate: f64,
+    feature: String,
+}
+
+impl Visitor for SegmentReader {
+    fn visit_move(&mut self, line_num: u32, command: &Command, from: (f64, f64, f64), to: (f64, f64, f64)) {
+        // Homing moves nothing the print depends on
+        if command.name == "G28" {
+            return;
+        }
+
+        let extrusion = match command.value('E') {
+            Some(e) if self.relative_extrusion => e,
+            Some(e) => {
+                let extrusion = e - self.extruder_position;
+                self.extruder_position = e;
+                extrusion
+            },
+            None => 0.0,
+        };
+        if let Some(feedrate) = command.value('F') {
+            self.feedrate = feedrate;
+        }
+
+        self.segments.push(Segment { line_num, from, to, extrusion, feedrate: self.feedrate, feature: self.feature.clone() });
+    }
+
+    fn visit_command(&mut self, _line_num: u32, command: &Command) {
+        match command.name.as_str() {
+            "M82" => self.relative_extrusion = false,
+            "M83" => self.relative_extrusion = true,
+            "G92" => if let Some(e) = command.value('E') {
+                self.extruder_position = e;
+            },
+            _ => (),
+        }
+    }
+
+    fn visit_comment(&mut self, _line_num: u32, comment: &str) {
+        if let Some(feature) = comment.strip_prefix("TYPE:") {
+            self.feature = feature.trim().to_string();
+        }
+    }
+}
+
+// Index of the first segment of each layer but the first, as the parser of the optimizer splits them:
+// on the first extrusion moving in XY at a new height, or the prime in place at that height right before
+pub fn layer_starts(segments: &[Segment]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut z = 0.0;
+    let mut prime = None;
+    for (i, segment) in segments.iter().enumerate().filter(|(_, segment)| segment.extrudes()) {
+        if segment.in_place() {
+            if segment.to.2 != z && prime.is_none() {
+                prime = Some((i, segment.to.2));
+            }
+            continue;
+        }
+        let prime = prime.take().filter(|&(_, prime_z)| prime_z == segment.to.2);
+        if segment.to.2 != z {
+            starts.push(prime.map_or(i, |(first, _)| first));
+            z = segment.to.2;
+        }
+    }
+    starts
+}
+
+// Every move of a G-code file in order, the extruder being absolute until told otherwise
+// and the feedrate the parser's default until one is given
+pub fn read(gcode_path: &str) -> Vec<Segment> {
+    read_in(gcode_path, Modes::default())
+}
+
+// Same as read, the file starting in the modes given
+pub fn read_in(gcode_path: &str, modes: Modes) -> Vec<Segment> {
+    let file = File::open(gcode_path)
+        .unwrap_or_else(|_| panic!("Unable to read file {}", gcode_path));
+    let mut reader = SegmentReader {
+        segments: Vec::new(),
+        relative_extrusion: modes.relative_extrusion,
+        extruder_position: 0.0,
+        feedrate: 1500.0,
+        feature: String::new(),
+    };
+    commands::visit_in(BufReader::new(file), modes, &mut reader);
+    reader.segments
+}
@@ -0,0 +1,73 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use tracing::info;
+
+// Numbers lines as N<line> <command>*<checksum> for hosts streaming G-code over serial, the firmware
+// asking again for any line arriving with another checksum. Numbering starts from an M110 reset,
+// comments and blank lines being left out as hosts do.
+#[derive(Default)]
+pub struct LineNumbers {
+    next: u32,
+}
+
+impl LineNumbers {
+    // Numbers whole lines, the numbering going on with the next ones
+    pub fn number(&mut self, contents: &str) -> String {
+        let mut numbered = String::with_capacity(contents.len() + contents.len() / 4);
+        if self.next == 0 {
+            self.push(&mut numbered, "M110 N0");
+        }
+        for line in contents.lines() {
+            let command = strip(line);
+            if !command.is_empty() {
+                self.push(&mut numbered, command);
+            }
+        }
+        numbered
+    }
+
+    fn push(&mut self, numbered: &mut String, command: &str) {
+        let start = numbered.len();
+        write!(numbered, "N{} {}", self.next, command).unwrap();
+        let checksum = numbered[start..].bytes().fold(0, |checksum, byte| checksum ^ byte);
+        writeln!(numbered, "*{}", checksum).unwrap();
+        self.next += 1;
+    }
+}
+
+// Command of a line without its comment, nor the number and checksum it may already have
+fn strip(line: &str) -> &str {
+    let command = line.split_once(';').map_or(line, |(command, _)| command).trim();
+    let command = command.rsplit_once('*')
+        .filter(|(_, checksum)| !checksum.is_empty() && checksum.bytes().all(|c| c.is_ascii_digit()))
+        .map_or(command, |(command, _)| command.trim_end());
+    match command.split_once(char::is_whitespace) {
+        Some((number, rest)) if number.len() > 1 && number.starts_with(['N', 'n']) && number[1..].bytes().all(|c| c.is_ascii_digit()) => rest.trim_start(),
+        _ => command,
+    }
+}
+
+// Rewrites a G-code file with line numbers and checksums
+pub fn number_file(gcode_path: &str) -> Result<(), String> {
+    let numbered_path = format!("{}.numbered", gcode_path);
+    let input = File::open(gcode_path)
+        .map_err(|e| format!("Unable to read file {}: {}", gcode_path, e))?;
+    let output = File::create(&numbered_path)
+        .map_err(|e| format!("Unable to write to file {}: {}", numbered_path, e))?;
+    let mut writer = BufWriter::new(output);
+    let write_error = |e: io::Error| format!("Unable to write to file {}: {}", numbered_path, e);
+
+    let mut numbers = LineNumbers::default();
+    for line in BufReader::new(input).lines() {
+        let line = line.map_err(|e| format!("Unable to read file {}: {}", gcode_path, e))?;
+        writer.write_all(numbers.number(&line).as_bytes()).map_err(write_error)?;
+    }
+    writer.flush().map_err(write_error)?;
+    drop(writer);
+
+    fs::rename(&numbered_path, gcode_path)
+        .map_err(|e| format!("Unable to replace file {} with {}: {}", gcode_path, numbered_path, e))?;
+    info!("Numbered {} line-s of {} with checksums", numbers.next, gcode_path);
+    Ok(())
+}
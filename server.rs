@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response};
+use crate::error::Failure;
+use crate::{config, OptimizationReport, Optimizer};
+
+// Largest G-code accepted, whole files being held in memory until downloaded or deleted
+const MAX_UPLOAD: usize = 512 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+struct JobState {
+    status: Status,
+    // Taken by the worker once it starts the job
+    input: Option<(String, config::Config)>,
+    // Progress events as JSON, kept for the clients following the job late
+    events: Vec<String>,
+    gcode: Option<String>,
+    report: Option<OptimizationReport>,
+    error: Option<String>,
+}
+
+// Optimization uploaded to the server, followed by any number of clients
+struct Job {
+    state: Mutex<JobState>,
+    changed: Condvar,
+}
+
+impl Job {
+    fn lock(&self) -> MutexGuard<'_, JobState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, state: &mut JobState, event: String) {
+        state.events.push(event);
+        self.changed.notify_all();
+    }
+}
+
+// Body of a JSON upload, the configuration defaulting to the server's
+#[derive(Deserialize)]
+struct Upload {
+    gcode: String,
+    #[serde(default)]
+    config: Option<serde_json::Value>,
+}
+
+struct Server {
+    // Configuration of the server, whose machine settings every job keeps
+    config: config::Config,
+    jobs: Mutex<HashMap<u32, Arc<Job>>>,
+    // Jobs waiting for the worker, in upload order
+    queue: Mutex<mpsc::Sender<u32>>,
+    waiting: Mutex<Vec<u32>>,
+    next_id: AtomicU32,
+}
+
+impl Server {
+    fn job(&self, id: u32) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner()).get(&id).cloned()
+    }
+}
+
+// Serves optimizations over HTTP until the process is stopped. Uploaded files are queued and
+// optimized one at a time, each optimization using every solver thread of the configuration.
+//   POST   /jobs             G-code as the body, configuration JSON in an X-Config header,
+//                            or JSON {"gcode": ..., "config": {...}}
+//   GET    /jobs             Jobs and their status
+//   GET    /jobs/<id>        Status, report or error of a job
+//   GET    /jobs/<id>/events Progress as server-sent events, ending with done or failed
+//   GET    /jobs/<id>/gcode  Optimized G-code
+//   DELETE /jobs/<id>        Forgets a job that is not running
+pub fn serve(port: u16, config: config::Config) -> Result<(), Failure> {
+    let http = tiny_http::Server::http(("0.0.0.0", port))
+        .map_err(|e| Failure::error(format!("Unable to listen on port {}: {}", port, e)))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let server = Arc::new(Server {
+        config,
+        jobs: Mutex::new(HashMap::new()),
+        queue: Mutex::new(sender),
+        waiting: Mutex::new(Vec::new()),
+        next_id: AtomicU32::new(1),
+    });
+
+    let worker = Arc::clone(&server);
+    thread::spawn(move || {
+        for id in receiver {
+            run_job(&worker, id);
+        }
+    });
+
+    status!("Serving optimizations on port {}", port);
+    info!("Serving on port {}", port);
+    for request in http.incoming_requests() {
+        let server = Arc::clone(&server);
+        // Event streams last as long as their job, every request gets its own thread
+        thread::spawn(move || handle(&server, request));
+    }
+    Ok(())
+}
+
+fn run_job(server: &Server, id: u32) {
+    server.waiting.lock().unwrap_or_else(|e| e.into_inner()).retain(|&waiting| waiting != id);
+    // Deleted while queued
+    let Some(job) = server.job(id) else {
+        return;
+    };
+    let Some((gcode, config)) = ({
+        let mut state = job.lock();
+        state.status = Status::Running;
+        job.push(&mut state, json!({ "event": "started" }).to_string());
+        state.input.take()
+    }) else {
+        return;
+    };
+    info!("Job {} started", id);
+
+    // Events are forwarded as they come, until the optimization drops its sender
+    let (sender, receiver) = mpsc::channel();
+    let forwarder = {
+        let job = Arc::clone(&job);
+        thread::spawn(move || {
+            for event in receiver {
+                let event = serde_json::to_string(&event).unwrap();
+                let mut state = job.lock();
+                job.push(&mut state, event);
+            }
+        })
+    };
+    let optimized = Optimizer::optimize_str_with_events(&gcode, &config, Some(sender));
+    let _ = forwarder.join();
+
+    let mut state = job.lock();
+    match optimized {
+        Ok((gcode, report)) => {
+            info!("Job {} done, travel {:.2} -> {:.2}", id, report.base_travel, report.optimized_travel);
+            job.push(&mut state, json!({ "event": "done", "report": report }).to_string());
+            state.status = Status::Done;
+            state.gcode = Some(gcode);
+            state.report = Some(report);
+        }
+        Err(e) => {
+            warn!("Job {} failed: {}", id, e);
+            job.push(&mut state, json!({ "event": "failed", "error": e.message }).to_string());
+            state.status = Status::Failed;
+            state.error = Some(e.message);
+        }
+    }
+}
+
+fn handle(server: &Server, mut request: Request) {
+    let path = request.url().split('?').next().unwrap_or_default().trim_matches('/').to_string();
+    let parts: Vec<&str> = path.split('/').collect();
+    let method = request.method().clone();
+
+    let response = match (&method, parts.as_slice()) {
+        (Method::Post, ["jobs"]) => upload(server, &mut request),
+        (Method::Get, ["jobs"]) => list(server),
+        (_, ["jobs", id, ..]) => match id.parse().ok().and_then(|id| server.job(id).map(|job| (id, job))) {
+            None => error(404, format!("No job {}", id)),
+            Some((id, job)) => match (&method, &parts[2..]) {
+                (Method::Get, []) => describe(server, id, &job),
+                (Method::Get, ["events"]) => return stream_events(request, &job),
+                (Method::Get, ["gcode"]) => download(id, &job),
+                (Method::Delete, []) => delete(server, id, &job),
+                _ => error(404, format!("No route {} {}", method, request.url())),
+            },
+        },
+        _ => error(404, format!("No route {} {}", method, request.url())),
+    };
+
+    if let Err(e) = request.respond(response) {
+        warn!("Unable to answer {} /{}: {}", method, path, e);
+    }
+}
+
+fn upload(server: &Server, request: &mut Request) -> Response<Cursor<Vec<u8>>> {
+    if request.body_length().is_some_and(|length| length > MAX_UPLOAD) {
+        return error(413, format!("G-code larger than {} bytes", MAX_UPLOAD));
+    }
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().take(MAX_UPLOAD as u64 + 1).read_to_string(&mut body) {
+        return error(400, format!("Unable to read the upload: {}", e));
+    }
+    if body.len() > MAX_UPLOAD {
+        return error(413, format!("G-code larger than {} bytes", MAX_UPLOAD));
+    }
+
+    let is_json = header(request, "Content-Type").is_some_and(|value| value.starts_with("application/json"));
+    let (gcode, config) = if is_json {
+        match serde_json::from_str::<Upload>(&body) {
+            Ok(upload) => (upload.gcode, upload.config.map(|config| config.to_string())),
+            Err(e) => return error(400, format!("Unable to parse the upload: {}", e)),
+        }
+    } else {
+        (body, header(request, "X-Config"))
+    };
+    if gcode.trim().is_empty() {
+        return error(400, String::from("G-code is empty"));
+    }
+    let config = match job_config(&server.config, config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => return error(400, e),
+    };
+
+    let id = server.next_id.fetch_add(1, Ordering::Relaxed);
+    let job = Arc::new(Job {
+        state: Mutex::new(JobState {
+            status: Status::Queued,
+            input: Some((gcode, config)),
+            events: Vec::new(),
+            gcode: None,
+            report: None,
+            error: None,
+        }),
+        changed: Condvar::new(),
+    });
+    server.jobs.lock().unwrap_or_else(|e| e.into_inner()).insert(id, job);
+    server.waiting.lock().unwrap_or_else(|e| e.into_inner()).push(id);
+    server.queue.lock().unwrap_or_else(|e| e.into_inner()).send(id)
+        .unwrap_or_else(|_| panic!("Optimization worker stopped"));
+    info!("Job {} queued", id);
+
+    respond_json(202, json!({ "id": id, "status": Status::Queued }))
+}
+
+// Configuration of a job, keeping the solver program, the threads and the directories written of the server's.
+// Clients choose how their file is optimized, never what runs on the machine, how much of it nor where it writes.
+fn job_config(base: &config::Config, json: Option<&str>) -> Result<config::Config, String> {
+    let Some(json) = json else {
+        return Ok(base.clone());
+    };
+    let mut config = config::parse_config_str(json)?;
+    config.program = base.program.clone();
+    config.threads = base.threads;
+    config.max_solver_processes = base.max_solver_processes;
+    config.solver_memory_limit = base.solver_memory_limit;
+    config.keep_intermediates = base.keep_intermediates.clone();
+    config.merge_report = base.merge_report.clone();
+
+    config::complete(config).map_err(|e| e.message)
+}
+
+fn list(server: &Server) -> Response<Cursor<Vec<u8>>> {
+    let jobs = server.jobs.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut ids: Vec<u32> = jobs.keys().copied().collect();
+    ids.sort_unstable();
+    let jobs: Vec<serde_json::Value> = ids.iter()
+        .map(|&id| json!({ "id": id, "status": jobs[&id].lock().status }))
+        .collect();
+    respond_json(200, json!(jobs))
+}
+
+fn describe(server: &Server, id: u32, job: &Job) -> Response<Cursor<Vec<u8>>> {
+    let position = server.waiting.lock().unwrap_or_else(|e| e.into_inner()).iter().position(|&waiting| waiting == id);
+    let state = job.lock();
+    respond_json(200, json!({
+        "id": id,
+        "status": state.status,
+        "position": position,
+        "report": state.report,
+        "error": state.error,
+    }))
+}
+
+fn download(id: u32, job: &Job) -> Response<Cursor<Vec<u8>>> {
+    let state = job.lock();
+    match (&state.gcode, state.status) {
+        (Some(gcode), _) => Response::from_data(gcode.as_bytes().to_vec())
+            .with_header(content_type("text/plain"))
+            .with_header(Header::from_bytes("Content-Disposition", format!("attachment; filename=\"job_{}_optimized.gcode\"", id)).unwrap()),
+        (None, Status::Failed) => error(409, format!("Job {} failed: {}", id, state.error.as_deref().unwrap_or_default())),
+        (None, _) => error(409, format!("Job {} is not done", id)),
+    }
+}
+
+fn delete(server: &Server, id: u32, job: &Job) -> Response<Cursor<Vec<u8>>> {
+    if job.lock().status == Status::Running {
+        return error(409, format!("Job {} is running", id));
+    }
+    server.jobs.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+    server.waiting.lock().unwrap_or_else(|e| e.into_inner()).retain(|&waiting| waiting != id);
+    info!("Job {} deleted", id);
+    respond_json(200, json!({ "id": id, "deleted": true }))
+}
+
+// Writes the events of a job as they come, the response ending once it is done or failed.
+// Chunks are encoded here so each event is flushed as soon as it is pushed.
+fn stream_events(request: Request, job: &Job) {
+    let mut writer = request.into_writer();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\n\r\n";
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+
+    let mut sent = 0;
+    loop {
+        let (events, finished) = {
+            let mut state = job.lock();
+            while state.events.len() == sent && !matches!(state.status, Status::Done | Status::Failed) {
+                state = job.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            (state.events[sent..].to_vec(), matches!(state.status, Status::Done | Status::Failed))
+        };
+        sent += events.len();
+
+        let chunk: String = events.iter().map(|event| format!("data: {}\n\n", event)).collect();
+        let mut written = write!(writer, "{:x}\r\n{}\r\n", chunk.len(), chunk);
+        if finished {
+            written = written.and_then(|_| writer.write_all(b"0\r\n\r\n"));
+        }
+        // Client gone
+        if written.and_then(|_| writer.flush()).is_err() || finished {
+            return;
+        }
+    }
+}
+
+fn header(request: &Request, name: &'static str) -> Option<String> {
+    request.headers().iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().to_string())
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).unwrap()
+}
+
+fn respond_json(status: u16, value: serde_json::Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(content_type("application/json"))
+}
+
+fn error(status: u16, message: String) -> Response<Cursor<Vec<u8>>> {
+    respond_json(status, json!({ "error": message }))
+}
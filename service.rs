@@ -0,0 +1,84 @@
+use std::panic;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::task;
+use crate::cancel::CancellationToken;
+use crate::error::{ErrorKind, Failure};
+use crate::{Optimizer, OptimizerBuilder};
+
+// Longest wait for the solver before checking its time limit and for cancellation
+const POLL: Duration = Duration::from_millis(20);
+
+// Optimizes a G-code file from async code without stalling the runtime's workers. This is a blocking
+// wrapper: the whole optimization runs on one of the runtime's blocking threads and its solver threads
+// still wait on each solver process, only their timeouts and kills going through the runtime, which
+// needs its time driver enabled. Files optimized at once take as many threads as they would without it.
+pub async fn optimize(builder: OptimizerBuilder) -> Result<Optimizer, Failure> {
+    task::spawn_blocking(move || {
+        let mut optimizer = builder.build().map_err(|e| Failure::new(ErrorKind::Usage, e))?;
+        optimizer.optimize()?;
+        Ok(optimizer)
+    })
+    .await
+    .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+// Async counterpart of Optimizer::run_process, none if the solver failed, ran out of time
+// or was cancelled. A solver given up on is killed when its handle is dropped.
+pub async fn run_process(mut command: Command, input: Option<&str>, current_layer: u32, timeout: f64, cancel: &CancellationToken) -> Option<String> {
+    let piped = || if input.is_some() { Stdio::piped() } else { Stdio::null() };
+    let mut child = command
+        .stdin(piped())
+        .stdout(piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| warn!("Failed to run TSP solver on layer {}: {}", current_layer, e))
+        .ok()?;
+
+    // Output is drained on its own task so a full pipe never stalls the solver
+    let reader = child.stdout.take().map(|mut stdout| tokio::spawn(async move {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output).await;
+        output
+    }));
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        // A solver dying early shows in its exit status
+        let _ = stdin.write_all(input.as_bytes()).await;
+    }
+
+    let start = Instant::now();
+    let status = loop {
+        match tokio::time::timeout(POLL, child.wait()).await {
+            Ok(Ok(status)) => break status,
+            Ok(Err(e)) => {
+                warn!("Lost TSP solver on layer {}: {}", current_layer, e);
+                return None;
+            }
+            Err(_) => (),
+        }
+
+        if timeout > 0.0 && start.elapsed().as_secs_f64() >= timeout {
+            warn!("TSP solver killed after {} s on layer {}", timeout, current_layer);
+            return None;
+        }
+        if cancel.is_cancelled() {
+            warn!("TSP solver stopped on layer {}, optimization cancelled", current_layer);
+            return None;
+        }
+    };
+
+    // Crashed or killed, by the memory limit for instance
+    if !status.success() {
+        warn!("TSP solver failed on layer {} ({})", current_layer, status);
+        return None;
+    }
+
+    match reader {
+        Some(reader) => reader.await.ok(),
+        None => Some(String::new()),
+    }
+}
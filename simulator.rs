@@ -0,0 +1,162 @@
+use crate::config;
+use crate::segments::Segment;
+
+// Estimate the time of a single move with a trapezoidal velocity profile,
+// starting and ending at rest
+pub fn move_time(distance: f64, feedrate: f64, acceleration: f64) -> f64 {
+    if distance <= 0.0 || feedrate <= 0.0 {
+        return 0.0;
+    }
+
+    let speed = feedrate / 60.0;
+    if acceleration <= 0.0 {
+        return distance / speed;
+    }
+
+    // Distance needed to reach full speed and brake back to rest
+    let ramp = speed * speed / acceleration;
+    if distance >= ramp {
+        2.0 * speed / acceleration + (distance - ramp) / speed
+    } else {
+        // Full speed is never reached, triangular profile
+        2.0 * (distance / acceleration).sqrt()
+    }
+}
+
+// Limits of the machine moves are planned with, feedrates in mm/min and jerks in mm/s.
+// Axes are X, Y, Z and E, a limit of 0 leaving the axis unbounded (or stopping at corners for jerks).
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub acceleration: f64,
+    pub max_feedrate: [f64; 4],
+    pub jerk: [f64; 4],
+}
+
+impl Limits {
+    // Acceleration of the configuration, feedrates and jerks of its printer profile if it has one
+    pub fn new(config: &config::Config) -> Limits {
+        let mut limits = Limits { acceleration: config.acceleration, ..Default::default() };
+        if let Some(printer) = &config.printer {
+            let (feedrate, jerk) = (&printer.max_feedrate, &printer.jerk);
+            limits.max_feedrate = [feedrate.x, feedrate.y, feedrate.z, feedrate.e];
+            limits.jerk = [jerk.x, jerk.y, jerk.z, jerk.e];
+        }
+        limits
+    }
+}
+
+// Move as the planner sees it, speeds in mm/s
+struct PlannedMove {
+    // Segment the move comes from
+    index: usize,
+    distance: f64,
+    // Unit vector of the move along X, Y, Z and E (E per mm moved)
+    direction: [f64; 4],
+    speed: f64,
+    entry: f64,
+}
+
+// Estimated time of a sequence of moves in seconds, see move_times
+pub fn print_time(segments: &[Segment], limits: &Limits) -> f64 {
+    move_times(segments, limits).iter().sum()
+}
+
+// Estimated time of each move of a sequence in seconds. Each move follows a trapezoidal velocity profile
+// whose cruise speed is capped by the axis feedrates, moves chaining at the speed the jerks allow
+// at their corner as long as they can brake in time. Moves without XYZ motion, like retractions,
+// start and end at rest.
+pub fn move_times(segments: &[Segment], limits: &Limits) -> Vec<f64> {
+    let mut times = vec![0.0; segments.len()];
+    let mut planned = Vec::new();
+    for (index, segment) in segments.iter().enumerate() {
+        let delta = [segment.to.0 - segment.from.0, segment.to.1 - segment.from.1, segment.to.2 - segment.from.2];
+        let distance = (delta[0] * delta[0] + delta[1] * delta[1] + delta[2] * delta[2]).sqrt();
+        if distance <= 0.0 {
+            plan(&mut planned, limits, &mut times);
+            times[index] = move_time(segment.extrusion.abs(), segment.feedrate.min(limit(limits.max_feedrate[3])), limits.acceleration);
+            continue;
+        }
+
+        let direction = [delta[0] / distance, delta[1] / distance, delta[2] / distance, segment.extrusion / distance];
+        let speed = capped_feedrate(segment.feedrate, delta, segment.extrusion, &limits.max_feedrate) / 60.0;
+        planned.push(PlannedMove { index, distance, direction, speed, entry: 0.0 });
+    }
+
+    plan(&mut planned, limits, &mut times);
+    times
+}
+
+// Feedrate of a move once each axis is kept under its maximum feedrate, moves without XYZ motion
+// being capped by the E axis alone
+pub fn capped_feedrate(feedrate: f64, delta: [f64; 3], extrusion: f64, max_feedrate: &[f64; 4]) -> f64 {
+    let distance = (delta[0] * delta[0] + delta[1] * delta[1] + delta[2] * delta[2]).sqrt();
+    if distance <= 0.0 {
+        return feedrate.min(limit(max_feedrate[3]));
+    }
+
+    let direction = [delta[0] / distance, delta[1] / distance, delta[2] / distance, extrusion / distance];
+    (0..4).fold(feedrate, |feedrate, axis| match direction[axis].abs() {
+        component if max_feedrate[axis] > 0.0 && component > 0.0 => feedrate.min(max_feedrate[axis] / component),
+        _ => feedrate,
+    })
+}
+
+fn limit(max_feedrate: f64) -> f64 {
+    if max_feedrate > 0.0 { max_feedrate } else { f64::INFINITY }
+}
+
+// Times of a chain of moves starting and ending at rest, the chain being emptied
+fn plan(moves: &mut Vec<PlannedMove>, limits: &Limits, times: &mut [f64]) {
+    let acceleration = limits.acceleration;
+
+    // Corner speeds the jerks allow, then lowered until every move can brake for the next one
+    for i in 1..moves.len() {
+        let (previous, next) = (&moves[i - 1], &moves[i]);
+        moves[i].entry = (0..4).fold(previous.speed.min(next.speed), |speed, axis| {
+            let change = (previous.direction[axis] - next.direction[axis]).abs();
+            if change > 0.0 { speed.min(limits.jerk[axis] / change) } else { speed }
+        });
+    }
+    if acceleration > 0.0 {
+        let mut exit = 0.0;
+        for planned in moves.iter_mut().rev() {
+            planned.entry = planned.entry.min((exit * exit + 2.0 * acceleration * planned.distance).sqrt());
+            exit = planned.entry;
+        }
+    }
+
+    for i in 0..moves.len() {
+        let PlannedMove { index, distance, speed, entry, .. } = moves[i];
+        // Exit lowered to the speed reachable from the entry when the move is too short to get there
+        let mut exit = moves.get(i + 1).map_or(0.0, |next| next.entry);
+        if acceleration > 0.0 {
+            exit = exit.min((entry * entry + 2.0 * acceleration * distance).sqrt());
+        }
+        if let Some(next) = moves.get_mut(i + 1) {
+            next.entry = exit;
+        }
+        times[index] = trapezoid_time(distance, entry, speed, exit, acceleration);
+    }
+
+    moves.clear();
+}
+
+// Time of a move accelerating from its entry speed to its cruise speed and braking to its exit speed
+fn trapezoid_time(distance: f64, entry: f64, speed: f64, exit: f64, acceleration: f64) -> f64 {
+    if speed <= 0.0 {
+        return 0.0;
+    }
+    if acceleration <= 0.0 {
+        return distance / speed;
+    }
+
+    let accelerating = (speed * speed - entry * entry) / (2.0 * acceleration);
+    let braking = (speed * speed - exit * exit) / (2.0 * acceleration);
+    if accelerating + braking <= distance {
+        (speed - entry) / acceleration + (speed - exit) / acceleration + (distance - accelerating - braking) / speed
+    } else {
+        // Cruise speed is never reached, triangular profile
+        let peak = ((2.0 * acceleration * distance + entry * entry + exit * exit) / 2.0).sqrt();
+        (peak - entry) / acceleration + (peak - exit) / acceleration
+    }
+}
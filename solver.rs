@@ -0,0 +1,444 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::cancel::CancellationToken;
+use crate::cost::CostModel;
+use crate::quick_math::Coordinates;
+use crate::timing::Instant;
+
+// Scale of the integer weights written to TSPLIB files when costs are not euclidean distances
+const WEIGHT_SCALE: f64 = 1000.0;
+
+pub struct TspProblem {
+    pub nodes: Vec<(f64, f64, f64)>,
+    pub fixed_edges: Vec<(u32, u32)>,
+    pub cost: Arc<dyn CostModel>,
+}
+
+impl TspProblem {
+    // Cost between two nodes, numbered from 1, their distance unless another model is given
+    pub fn distance(&self, a: i32, b: i32) -> f64 {
+        self.cost.cost(self.nodes[a as usize - 1], self.nodes[b as usize - 1])
+    }
+
+    // Costs from a node to every node, distances being computed all at once
+    fn costs_from(&self, coordinates: &Coordinates, a: (f64, f64, f64), out: &mut [f64]) {
+        if self.cost.is_euclidean() {
+            coordinates.distances_from(a, out);
+        } else {
+            for (cost, &b) in out.iter_mut().zip(&self.nodes) {
+                *cost = self.cost.cost(a, b);
+            }
+        }
+    }
+
+    // Factor from costs to the weights written to TSPLIB files
+    pub fn weight_scale(&self) -> f64 {
+        if self.cost.is_euclidean() { 1.0 } else { WEIGHT_SCALE }
+    }
+
+    // Length of a closed tour
+    pub fn tour_length(&self, tour: &[i32]) -> f64 {
+        let mut length = 0.0;
+        for i in 0..tour.len() {
+            length += self.distance(tour[i], tour[(i + 1) % tour.len()]);
+        }
+        length
+    }
+
+    // Why a tour cannot be used, if it does not visit every node once or leaves a fixed edge out
+    pub fn check_tour(&self, tour: &[i32]) -> Result<(), String> {
+        let n = self.nodes.len();
+        if tour.len() != n {
+            return Err(format!("{} nodes instead of {}", tour.len(), n));
+        }
+        let mut visited = vec![false; n + 1];
+        for &node in tour {
+            if node < 1 || node as usize > n {
+                return Err(format!("node {} out of 1..{}", node, n));
+            }
+            if std::mem::replace(&mut visited[node as usize], true) {
+                return Err(format!("node {} visited twice", node));
+            }
+        }
+
+        let edges: HashSet<(i32, i32)> = (0..n)
+            .map(|i| (tour[i], tour[(i + 1) % n]))
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect();
+        let mut fixed = self.fixed_edges.iter().map(|&(a, b)| (a.min(b) as i32, a.max(b) as i32));
+        match fixed.find(|edge| !edges.contains(edge)) {
+            Some((a, b)) if n > 1 => Err(format!("fixed edge {}-{} left out", a, b)),
+            _ => Ok(()),
+        }
+    }
+
+    // Fixed edges as unordered pairs
+    fn fixed_set(&self) -> HashSet<(i32, i32)> {
+        self.fixed_edges.iter()
+            .map(|&(a, b)| (a.min(b) as i32, a.max(b) as i32))
+            .collect()
+    }
+}
+
+// Small xorshift generator, good enough for randomized search
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Lower bound on the tour length: a spanning tree over nodes 2..n keeping the fixed edges,
+// plus the two cheapest edges allowed at node 1
+pub fn one_tree_bound(problem: &TspProblem) -> f64 {
+    let n = problem.nodes.len();
+    if n < 3 {
+        return problem.tour_length(&(1..=n as i32).collect::<Vec<i32>>());
+    }
+
+    let fixed = problem.fixed_set();
+    let key = |a: i32, b: i32, distance: f64| {
+        // Fixed edges are picked first, but still count with their real length
+        if fixed.contains(&(a.min(b), a.max(b))) { (0.0, distance) } else { (distance, distance) }
+    };
+
+    // Prim's algorithm over nodes 2..n, distances from the last node added computed all at once
+    let coordinates = Coordinates::new(&problem.nodes);
+    let mut distances = vec![0.0; n];
+    let mut bound = 0.0;
+    let mut in_tree = vec![false; n + 1];
+    let mut best = vec![(f64::INFINITY, 0.0); n + 1];
+    let mut current = 2;
+    in_tree[current as usize] = true;
+    for _ in 3..=n {
+        problem.costs_from(&coordinates, problem.nodes[current as usize - 1], &mut distances);
+        let mut next = 0;
+        for node in 2..=n as i32 {
+            if in_tree[node as usize] {
+                continue;
+            }
+
+            let candidate = key(current, node, distances[node as usize - 1]);
+            if candidate < best[node as usize] {
+                best[node as usize] = candidate;
+            }
+            if next == 0 || best[node as usize] < best[next as usize] {
+                next = node;
+            }
+        }
+
+        bound += best[next as usize].1;
+        in_tree[next as usize] = true;
+        current = next;
+    }
+
+    // Two cheapest edges at node 1
+    problem.costs_from(&coordinates, problem.nodes[0], &mut distances);
+    let mut edges: Vec<(f64, f64)> = (2..=n as i32).map(|node| key(1, node, distances[node as usize - 1])).collect();
+    edges.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    bound += edges[0].1 + edges[1].1;
+
+    bound
+}
+
+// Improves a tour with 2-opt moves accepted by simulated annealing, never breaking fixed edges,
+// stopping early once the tour is no longer than the target or the optimization is cancelled
+pub fn simulated_annealing(problem: &TspProblem, tour: Vec<i32>, duration: f64, target: f64, cancel: &CancellationToken) -> Vec<i32> {
+    let n = tour.len();
+    if n < 4 || duration <= 0.0 {
+        return tour;
+    }
+
+    let fixed = problem.fixed_set();
+    let is_fixed = |a: i32, b: i32| fixed.contains(&(a.min(b), a.max(b)));
+
+    let mut current = tour;
+    let mut current_length = problem.tour_length(&current);
+    let mut best = current.clone();
+    let mut best_length = current_length;
+
+    // Start around a tenth of the average edge length and cool down over the time budget
+    let initial_temperature = current_length / n as f64 * 0.1;
+    let mut temperature = initial_temperature;
+    let budget = Duration::from_secs_f64(duration);
+    let start = Instant::now();
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    let mut iteration: u64 = 0;
+
+    loop {
+        if iteration.is_multiple_of(1000) {
+            let elapsed = start.elapsed();
+            if elapsed >= budget || best_length <= target || cancel.is_cancelled() {
+                break;
+            }
+            temperature = initial_temperature * (1.0 - elapsed.as_secs_f64() / duration);
+        }
+        iteration += 1;
+
+        // Reverse the segment between positions i and j, node 1 stays first
+        let mut i = 1 + rng.below(n - 1);
+        let mut j = 1 + rng.below(n - 1);
+        if i == j {
+            continue;
+        }
+        if i > j {
+            std::mem::swap(&mut i, &mut j);
+        }
+
+        let a = current[i - 1];
+        let b = current[i];
+        let c = current[j];
+        let d = current[(j + 1) % n];
+        if is_fixed(a, b) || is_fixed(c, d) {
+            continue;
+        }
+
+        let delta = problem.distance(a, c) + problem.distance(b, d)
+            - problem.distance(a, b) - problem.distance(c, d);
+
+        if delta < 0.0 || (temperature > 0.0 && rng.unit() < (-delta / temperature).exp()) {
+            current[i..=j].reverse();
+            current_length += delta;
+
+            if current_length < best_length - 1e-9 {
+                best.clone_from(&current);
+                best_length = current_length;
+            }
+        }
+    }
+
+    best
+}
+
+// Greedy tour keeping every fixed edge: chains of fixed edges are taken whole,
+// always moving on to the chain with the end closest to the current node
+pub fn nearest_neighbour(problem: &TspProblem) -> Vec<i32> {
+    let n = problem.nodes.len();
+    let mut links: Vec<Vec<i32>> = vec![Vec::new(); n + 1];
+    for &(a, b) in problem.fixed_edges.iter() {
+        if a != b {
+            links[a as usize].push(b as i32);
+            links[b as usize].push(a as i32);
+        }
+    }
+
+    let mut visited = vec![false; n + 1];
+    let walk = |start: i32, visited: &mut Vec<bool>| {
+        let mut chain = vec![start];
+        visited[start as usize] = true;
+        let mut current = start;
+        while let Some(&next) = links[current as usize].iter().find(|next| !visited[**next as usize]) {
+            visited[next as usize] = true;
+            chain.push(next);
+            current = next;
+        }
+        chain
+    };
+
+    // Chain through node 1: one side starts the tour, the other one ends it
+    let mut tour = walk(1, &mut visited);
+    let mut tail = match links[1].iter().find(|next| !visited[**next as usize]) {
+        Some(&other) => walk(other, &mut visited),
+        None => Vec::new(),
+    };
+    tail.reverse();
+
+    while tour.len() + tail.len() < n {
+        let current = tour[tour.len() - 1];
+
+        // Closest chain end, any node left if only closed loops remain
+        let next = (1..=n as i32)
+            .filter(|node| !visited[*node as usize] && links[*node as usize].len() < 2)
+            .min_by(|a, b| problem.distance(current, *a).partial_cmp(&problem.distance(current, *b)).unwrap())
+            .or_else(|| (1..=n as i32).find(|node| !visited[*node as usize]))
+            .unwrap();
+
+        tour.extend(walk(next, &mut visited));
+    }
+
+    tour.extend(tail);
+    tour
+}
+
+// Solves a small problem exactly with the Held-Karp dynamic programming algorithm
+pub fn held_karp(problem: &TspProblem) -> Vec<i32> {
+    let n = problem.nodes.len();
+    if n <= 3 {
+        return (1..=n as i32).collect();
+    }
+
+    // Fixed edges are made cheaper than any possible tour so they are always kept
+    let coordinates = Coordinates::new(&problem.nodes);
+    let mut cost: Vec<Vec<f64>> = problem.nodes.iter()
+        .map(|&a| {
+            let mut row = vec![0.0; n];
+            problem.costs_from(&coordinates, a, &mut row);
+            row
+        })
+        .collect();
+    let longest = cost.iter().flatten().fold(0.0, |acc: f64, d| acc.max(*d));
+    let bonus = longest * n as f64 + 1.0;
+    for &(a, b) in problem.fixed_edges.iter() {
+        let (a, b) = (a as usize - 1, b as usize - 1);
+        if a != b {
+            cost[a][b] -= bonus;
+            cost[b][a] -= bonus;
+        }
+    }
+
+    // Shortest paths from node 1 through every subset of the other nodes
+    let m = n - 1;
+    let size = 1usize << m;
+    let mut dp = vec![f64::INFINITY; size * m];
+    let mut parent = vec![usize::MAX; size * m];
+    for j in 0..m {
+        dp[(1 << j) * m + j] = cost[0][j + 1];
+    }
+
+    for mask in 1..size {
+        for j in 0..m {
+            let current = dp[mask * m + j];
+            if mask & (1 << j) == 0 || current == f64::INFINITY {
+                continue;
+            }
+
+            for k in 0..m {
+                if mask & (1 << k) != 0 {
+                    continue;
+                }
+
+                let next = (mask | (1 << k)) * m + k;
+                let value = current + cost[j + 1][k + 1];
+                if value < dp[next] {
+                    dp[next] = value;
+                    parent[next] = j;
+                }
+            }
+        }
+    }
+
+    // Close the tour back to node 1
+    let full = size - 1;
+    let mut last = 0;
+    let mut best = f64::INFINITY;
+    for j in 0..m {
+        let value = dp[full * m + j] + cost[j + 1][0];
+        if value < best {
+            best = value;
+            last = j;
+        }
+    }
+
+    // Walk back through the parents
+    let mut tour = Vec::with_capacity(n);
+    let mut mask = full;
+    let mut j = last;
+    loop {
+        tour.push(j as i32 + 2);
+        let p = parent[mask * m + j];
+        mask ^= 1 << j;
+        if p == usize::MAX {
+            break;
+        }
+        j = p;
+    }
+    tour.push(1);
+    tour.reverse();
+
+    tour
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::Euclidean;
+
+    // Random nodes on a 100 mm square, with disjoint pairs of them fixed like merged extrusions
+    fn random_problem(rng: &mut Rng, n: usize, fixed: usize) -> TspProblem {
+        let nodes = (0..n)
+            .map(|_| (rng.unit() * 100.0, rng.unit() * 100.0, 0.2))
+            .collect();
+        let mut order: Vec<u32> = (1..=n as u32).collect();
+        for i in (1..n).rev() {
+            order.swap(i, rng.below(i + 1));
+        }
+        let fixed_edges = order.chunks_exact(2).take(fixed).map(|pair| (pair[0], pair[1])).collect();
+
+        TspProblem { nodes, fixed_edges, cost: Arc::new(Euclidean) }
+    }
+
+    // Shortest tour keeping the fixed edges, trying every order of the nodes after node 1
+    fn brute_force(problem: &TspProblem) -> f64 {
+        fn permute(problem: &TspProblem, tour: &mut Vec<i32>, k: usize, best: &mut f64) {
+            if k == tour.len() {
+                if problem.check_tour(tour).is_ok() {
+                    *best = best.min(problem.tour_length(tour));
+                }
+                return;
+            }
+            for i in k..tour.len() {
+                tour.swap(k, i);
+                permute(problem, tour, k + 1, best);
+                tour.swap(k, i);
+            }
+        }
+
+        let mut tour: Vec<i32> = (1..=problem.nodes.len() as i32).collect();
+        let mut best = f64::INFINITY;
+        permute(problem, &mut tour, 1, &mut best);
+        best
+    }
+
+    #[test]
+    fn held_karp_matches_brute_force() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for n in 4..=8 {
+            for fixed in 0..=n / 2 {
+                let problem = random_problem(&mut rng, n, fixed);
+                let tour = held_karp(&problem);
+                assert_eq!(problem.check_tour(&tour), Ok(()));
+                assert!((problem.tour_length(&tour) - brute_force(&problem)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_annealing_keeps_a_valid_tour() {
+        let mut rng = Rng(0x853C_49E6_748F_EA9B);
+        for fixed in [0, 5, 20] {
+            let problem = random_problem(&mut rng, 40, fixed);
+            let start = nearest_neighbour(&problem);
+            assert_eq!(problem.check_tour(&start), Ok(()));
+
+            let tour = simulated_annealing(&problem, start.clone(), 0.05, 0.0, &CancellationToken::new());
+            assert_eq!(problem.check_tour(&tour), Ok(()));
+            assert_eq!(tour[0], 1);
+            assert!(problem.tour_length(&tour) <= problem.tour_length(&start) + 1e-9);
+        }
+    }
+
+    #[test]
+    fn one_tree_bound_is_below_the_optimum() {
+        let mut rng = Rng(0xDA94_2042_E4DD_58B5);
+        for n in 3..=11 {
+            for fixed in [0, 1, n / 2] {
+                let problem = random_problem(&mut rng, n, fixed);
+                let optimum = problem.tour_length(&held_karp(&problem));
+                assert!(one_tree_bound(&problem) <= optimum + 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// Directories to remove if the process is interrupted
+static ACTIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+// Directories created by the process, so that two created in the same instant get different names
+static CREATED: AtomicU64 = AtomicU64::new(0);
+
+// Unique directory, in the OS temp directory by default, removed with its contents when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<TempDir, String> {
+        TempDir::new_in(&std::env::temp_dir())
+    }
+
+    // Same as new, inside the given directory
+    pub fn new_in(base: &Path) -> Result<TempDir, String> {
+        fs::create_dir_all(base)
+            .map_err(|e| format!("Unable to create directory {}: {}", base.display(), e))?;
+        // Names left by another process are skipped rather than shared
+        let path = loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let count = CREATED.fetch_add(1, Ordering::Relaxed);
+            let path = base
+                .join(format!("tsp-gcode-optimizer-{}-{}-{}", std::process::id(), nanos, count));
+            match fs::create_dir(&path) {
+                Ok(()) => break path,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(format!("Unable to create directory {}: {}", path.display(), e)),
+            }
+        };
+        ACTIVE_DIRS.lock().unwrap().push(path.clone());
+
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Unable to remove directory {}: {}", self.path.display(), e);
+        }
+        ACTIVE_DIRS.lock().unwrap().retain(|dir| dir != &self.path);
+    }
+}
+
+// Removes every temp directory still in use, when quitting on Ctrl-C
+pub fn remove_active_dirs() {
+    // The handler runs on its own thread, a poisoned lock still holds valid paths
+    let dirs = ACTIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    for dir in dirs.iter() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
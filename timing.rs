@@ -0,0 +1,49 @@
+use tracing::info;
+
+// Clock of the optimization, browsers have no system clock std can read
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+pub use web_time::Instant;
+
+// Time spent in each phase of an optimization, in seconds.
+// Phases of different layers overlap, totals are summed over every thread.
+#[derive(Clone, Copy, Default)]
+pub struct PhaseTimes {
+    pub parse: f64,
+    pub merge: f64,
+    pub write_problem: f64,
+    pub solve: f64,
+    pub read_tour: f64,
+    pub write_output: f64,
+}
+
+impl PhaseTimes {
+    pub fn add(&mut self, other: &PhaseTimes) {
+        self.parse += other.parse;
+        self.merge += other.merge;
+        self.write_problem += other.write_problem;
+        self.solve += other.solve;
+        self.read_tour += other.read_tour;
+        self.write_output += other.write_output;
+    }
+
+    pub fn display(&self) {
+        status!("Parsing: {:.3} s", self.parse);
+        status!("Merging: {:.3} s", self.merge);
+        status!("Writing TSP files: {:.3} s", self.write_problem);
+        status!("Solving: {:.3} s", self.solve);
+        status!("Reading tours: {:.3} s", self.read_tour);
+        status!("Writing output: {:.3} s", self.write_output);
+    }
+
+    pub fn log(&self, info: String) {
+        info!("{}, parsing {:.3} s, merging {:.3} s, writing TSP files {:.3} s, solving {:.3} s, reading tours {:.3} s, writing output {:.3} s",
+            info, self.parse, self.merge, self.write_problem, self.solve, self.read_tour, self.write_output);
+    }
+}
+
+// Seconds elapsed since the given instant
+pub fn seconds_since(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64()
+}
@@ -0,0 +1,33 @@
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use indicatif::{ProgressBar, ProgressStyle};
+use crate::events::ProgressEvent;
+
+// Draws the progress of an optimization on stderr until it finishes. Layers are counted as they
+// are parsed, so the length of the bar and its ETA settle once the whole file has been read.
+pub fn show(events: Receiver<ProgressEvent>) {
+    let bar = ProgressBar::new(0);
+    bar.set_style(ProgressStyle::with_template("{spinner} [{elapsed_precise}] {wide_bar} {pos}/{len} layers, ETA {eta}\n  {msg}")
+        .unwrap());
+    bar.enable_steady_tick(Duration::from_millis(100));
+
+    let mut solving: usize = 0;
+    let mut saved = 0.0;
+    for event in events {
+        match event {
+            ProgressEvent::Parsed { .. } => bar.inc_length(1),
+            ProgressEvent::Solving { .. } => solving += 1,
+            ProgressEvent::Solved { .. } => solving = solving.saturating_sub(1),
+            ProgressEvent::Written { saved: layer_saved, .. } => {
+                saved += layer_saved;
+                bar.inc(1);
+            }
+            ProgressEvent::Finished { layers, cancelled, .. } => {
+                let outcome = if cancelled { "cancelled" } else { "optimized" };
+                bar.finish_with_message(format!("{} layer-s {}, travel saved: {:.2}", layers, outcome, saved));
+                break;
+            }
+        }
+        bar.set_message(format!("Travel saved: {:.2}, {} layer-s solving", saved, solving));
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+use crate::config::{Printer, Upload};
+
+// Longest wait for the host to accept the connection, the transfer itself is not limited
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sends a G-code file to an OctoPrint or Moonraker host, both taking it as a multipart form
+// whose file field keeps the name of the file
+pub fn upload(target: &Upload, gcode_path: &str) -> Result<(), String> {
+    let contents = fs::read(gcode_path)
+        .map_err(|_| format!("Unable to read file {}", gcode_path))?;
+    let name = Path::new(gcode_path).file_name().map_or(gcode_path.to_string(), |name| name.to_string_lossy().to_string());
+
+    let endpoint = match target.printer {
+        Printer::OctoPrint => format!("{}/api/files/local", target.url),
+        Printer::Moonraker => format!("{}/server/files/upload", target.url),
+    };
+    status!("\nUploading {} to {}", name, target.url);
+
+    let boundary = format!("tsp-gcode-optimizer-{}", chrono::Local::now().timestamp_nanos_opt().unwrap_or_default());
+    let mut body = Vec::with_capacity(contents.len() + 512);
+    body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        boundary, name.replace('"', "_")).as_bytes());
+    body.extend(contents);
+    if target.print {
+        body.extend(format!("\r\n--{}\r\nContent-Disposition: form-data; name=\"print\"\r\n\r\ntrue", boundary).as_bytes());
+    }
+    body.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let agent = ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).build();
+    let mut request = agent.post(&endpoint)
+        .set("Content-Type", &format!("multipart/form-data; boundary={}", boundary));
+    if !target.api_key.is_empty() {
+        request = request.set("X-Api-Key", &target.api_key);
+    }
+
+    match request.send_bytes(&body) {
+        Ok(_) => {
+            status!("Uploaded {}{}", name, if target.print { ", printing" } else { "" });
+            info!("Uploaded {} to {}", gcode_path, endpoint);
+            Ok(())
+        }
+        Err(ureq::Error::Status(code, response)) => {
+            let reason = response.into_string().unwrap_or_default();
+            Err(format!("Upload of {} to {} refused ({}): {}", name, endpoint, code, reason.trim()))
+        }
+        Err(e) => Err(format!("Unable to upload {} to {}: {}", name, endpoint, e)),
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use tracing::{info, warn};
+use crate::commands::Modes;
+use crate::segments::{self, Segment, SegmentKey, POSITION_STEP};
+
+// Segments listed for each kind of difference
+const LISTED: usize = 10;
+
+// Outcome of checking that an optimized G-code extrudes exactly what the original one does
+pub struct Verification {
+    pub segments: usize,
+    // Extrusions of the original found nowhere in the output
+    pub missing: Vec<Segment>,
+    // Extrusions of the output found nowhere in the original, or once too many
+    pub extra: Vec<Segment>,
+    // Same endpoints, with amounts further apart than the tolerance
+    pub mismatched: Vec<(Segment, Segment)>,
+    // Layers pushing another net amount of filament, with the original and the output amounts
+    pub layers: Vec<(usize, f64, f64)>,
+    pub base_extrusion: f64,
+    pub optimized_extrusion: f64,
+    pub tolerance: f64,
+}
+
+impl Verification {
+    // Totals are allowed the tolerance once per segment, as rounding adds up
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty() && self.layers.is_empty()
+            && (self.base_extrusion - self.optimized_extrusion).abs() <= self.tolerance * self.segments.max(1) as f64
+    }
+
+    pub fn display(&self) {
+        status!("{} extrusion segment-s checked", self.segments);
+
+        status!("Missing from the output: {}", self.missing.len());
+        for segment in self.missing.iter().take(LISTED) {
+            status!("  line {}: {}", segment.line_num, segment);
+        }
+        status!("Not in the original: {}", self.extra.len());
+        for segment in self.extra.iter().take(LISTED) {
+            status!("  line {}: {}", segment.line_num, segment);
+        }
+        status!("Different extrusion: {}", self.mismatched.len());
+        for (base, optimized) in self.mismatched.iter().take(LISTED) {
+            status!("  lines {} and {}: {}, E{:.5} instead of E{:.5}", base.line_num, optimized.line_num, base, optimized.extrusion, base.extrusion);
+        }
+        status!("Different net extrusion: {} layer-s", self.layers.len());
+        for (layer, base, optimized) in self.layers.iter().take(LISTED) {
+            status!("  layer {}: E{:.5} instead of E{:.5}", layer, optimized, base);
+        }
+        status!("Net extrusion: {:.5} -> {:.5}", self.base_extrusion, self.optimized_extrusion);
+
+        if self.is_ok() {
+            status!("\nVerification passed");
+        } else {
+            status!("\nVerification failed");
+        }
+    }
+}
+
+// Endpoints of a segment of the original as the output writes them with the given decimals
+fn written_key(segment: &Segment, decimals: usize, step: f64) -> SegmentKey {
+    let written = |value: f64| format!("{:.*}", decimals, value).parse().unwrap();
+    let (from, to) = (segment.from, segment.to);
+    Segment {
+        from: (written(from.0), written(from.1), written(from.2)),
+        to: (written(to.0), written(to.1), written(to.2)),
+        ..segment.clone()
+    }.key(step)
+}
+
+// Net filament of each layer as the parser of the optimizer splits them, retractions taking back
+// what they pull, with the number of moves turning the extruder
+fn layer_extrusions(segments: &[Segment]) -> Vec<(f64, usize)> {
+    let mut layers = vec![(0.0, 0)];
+    let mut starts = segments::layer_starts(segments).into_iter().peekable();
+    for (i, segment) in segments.iter().enumerate() {
+        if starts.next_if_eq(&i).is_some() {
+            layers.push((0.0, 0));
+        }
+        if segment.extrusion != 0.0 {
+            let layer = layers.last_mut().unwrap();
+            layer.0 += segment.extrusion;
+            layer.1 += 1;
+        }
+    }
+    layers
+}
+
+// Matches every extrusion segment of the original with one of the output, run either way,
+// extruding the same amount within the tolerance, and checks that each layer pushes the same net
+// amount of filament. Primes and other extrusions in place go wherever their chain starts, only their
+// amount counts. The output is written with the given position decimals and the original is read in
+// the modes given until it sets its own.
+pub fn verify(gcode_path: &str, optimized_path: &str, tolerance: f64, decimals: usize, modes: Modes) -> Verification {
+    let base_moves = segments::read_in(gcode_path, modes);
+    let optimized_moves = segments::read(optimized_path);
+    let base_layers = layer_extrusions(&base_moves);
+    let optimized_layers = layer_extrusions(&optimized_moves);
+    let base: Vec<Segment> = base_moves.into_iter().filter(|segment| segment.extrudes() && !segment.in_place()).collect();
+    let optimized: Vec<Segment> = optimized_moves.into_iter().filter(|segment| segment.extrudes() && !segment.in_place()).collect();
+
+    let mut verification = Verification {
+        segments: base.len(),
+        missing: Vec::new(),
+        extra: Vec::new(),
+        mismatched: Vec::new(),
+        layers: Vec::new(),
+        base_extrusion: base_layers.iter().map(|layer| layer.0).sum(),
+        optimized_extrusion: optimized_layers.iter().map(|layer| layer.0).sum(),
+        tolerance,
+    };
+
+    // Layers are allowed the tolerance once per move of the extruder, as rounding adds up
+    for i in 0..base_layers.len().max(optimized_layers.len()) {
+        let (base, moves) = base_layers.get(i).copied().unwrap_or_default();
+        let (optimized, _) = optimized_layers.get(i).copied().unwrap_or_default();
+        if (base - optimized).abs() > tolerance * moves.max(1) as f64 {
+            verification.layers.push((i, base, optimized));
+        }
+    }
+
+    // Grid no finer than the positions written
+    let step = POSITION_STEP.max(10f64.powi(-(decimals as i32)));
+    let mut remaining: HashMap<SegmentKey, Vec<Segment>> = HashMap::new();
+    for segment in optimized {
+        remaining.entry(segment.key(step)).or_default().push(segment);
+    }
+
+    for segment in base {
+        let Some(candidates) = remaining.get_mut(&written_key(&segment, decimals, step)).filter(|candidates| !candidates.is_empty()) else {
+            verification.missing.push(segment);
+            continue;
+        };
+
+        match candidates.iter().position(|candidate| (candidate.extrusion - segment.extrusion).abs() <= tolerance) {
+            Some(i) => {
+                candidates.swap_remove(i);
+            },
+            None => {
+                let candidate = candidates.swap_remove(0);
+                verification.mismatched.push((segment, candidate));
+            },
+        }
+    }
+
+    verification.extra = remaining.into_values().flatten().collect();
+    verification.extra.sort_by_key(|segment| segment.line_num);
+
+    if verification.is_ok() {
+        info!("Verified {} against {}, {} extrusion segment-s", optimized_path, gcode_path, verification.segments);
+    } else {
+        warn!("Verification of {} against {} failed: {} missing, {} extra, {} different segment-s, {} layer-s with another net extrusion",
+            optimized_path, gcode_path, verification.missing.len(), verification.extra.len(), verification.mismatched.len(), verification.layers.len());
+    }
+
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    // Two layers of lines, each one primed after the travel leading to it and retracted once extruded
+    const RETRACTING: &str = "\
+G90
+M83
+G28
+G1 Z0.2 F720
+G0 X10 Y10 F9000
+G1 E0.8 F2100
+G1 X20 Y10 E0.5 F1800
+G1 X20 Y20 E0.5
+G1 E-0.8 F2100
+G0 X50 Y50 F9000
+G1 E0.8 F2100
+G1 X60 Y50 E0.5 F1800
+G1 X60 Y60 E0.5
+G1 E-0.8 F2100
+G1 Z0.4 F720
+G0 X10 Y10 F9000
+G1 E0.8 F2100
+G1 X20 Y10 E0.5 F1800
+G1 E-0.8 F2100
+";
+
+    fn verify_output(output: &str) -> Verification {
+        let dir = TempDir::new().unwrap();
+        let gcode_path = dir.path().join("original.gcode").display().to_string();
+        let optimized_path = dir.path().join("optimized.gcode").display().to_string();
+        std::fs::write(&gcode_path, RETRACTING).unwrap();
+        std::fs::write(&optimized_path, output).unwrap();
+        verify(&gcode_path, &optimized_path, 0.0001, 3, Modes::default())
+    }
+
+    #[test]
+    fn reordered_lines_keeping_their_retractions() {
+        let lines: Vec<&str> = RETRACTING.lines().collect();
+        // Second line of the first layer printed first, backwards, primed and retracted the same
+        let mut output = lines[..4].to_vec();
+        output.extend(["G0 X60 Y60 F9000", "G1 E0.8 F2100", "G1 X60 Y50 E0.5 F1800", "G1 X50 Y50 E0.5", "G1 E-0.8 F2100"]);
+        output.extend(&lines[4..9]);
+        output.extend(&lines[14..]);
+
+        let verification = verify_output(&output.join("\n"));
+        assert!(verification.is_ok());
+        assert_eq!(verification.segments, 5);
+        assert!((verification.optimized_extrusion - 2.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lost_retractions() {
+        let output: Vec<&str> = RETRACTING.lines().filter(|line| !line.contains("E-")).collect();
+
+        let verification = verify_output(&output.join("\n"));
+        assert!(!verification.is_ok());
+        assert!(verification.missing.is_empty() && verification.extra.is_empty() && verification.mismatched.is_empty());
+        // Layer 0 holds what comes before the first extrusion
+        assert_eq!(verification.layers.len(), 2);
+        let (layer, base, optimized) = verification.layers[0];
+        assert_eq!(layer, 1);
+        assert!((base - 2.0).abs() < 1e-9 && (optimized - 3.6).abs() < 1e-9);
+        assert!((verification.optimized_extrusion - 4.9).abs() < 1e-9);
+    }
+}
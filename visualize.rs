@@ -0,0 +1,414 @@
+use std::fmt::Write as _;
+use std::fs;
+use tracing::info;
+use crate::quick_math::distance_3d;
+use crate::segments::{self, Segment};
+
+// Width of each side of a drawing, in pixels
+const SIDE: f64 = 500.0;
+const MARGIN: f64 = 20.0;
+const TITLE: f64 = 30.0;
+// Seconds an animated layer takes to play, its moves sharing them by their time on the printer
+const PLAYBACK: f64 = 20.0;
+
+// Moves of a G-code file split into layers the way the optimizer splits them
+pub fn layers(gcode_path: &str) -> Vec<Vec<Segment>> {
+    let mut segments = segments::read(gcode_path);
+    let mut layers: Vec<Vec<Segment>> = segments::layer_starts(&segments).iter().rev()
+        .map(|&start| segments.split_off(start))
+        .collect();
+    layers.push(segments);
+    layers.reverse();
+    layers
+}
+
+// Draws the selected layers of the original and optimized G-code side by side, one SVG per layer
+// written to <original>.layer<n>.svg. Returns the paths written.
+pub fn write_svgs(gcode_path: &str, optimized_path: &str, selected: impl Fn(u32) -> bool) -> Result<Vec<String>, String> {
+    let base = layers(gcode_path);
+    let optimized = layers(optimized_path);
+
+    let mut paths = Vec::new();
+    for (i, (base, optimized)) in base.iter().zip(&optimized).enumerate() {
+        let layer = i as u32;
+        if !selected(layer) || !base.iter().any(Segment::extrudes) {
+            continue;
+        }
+
+        let path = format!("{}.layer{}.svg", gcode_path, layer);
+        fs::write(&path, svg(layer, base, optimized))
+            .map_err(|e| format!("Unable to write file {}: {}", path, e))?;
+        paths.push(path);
+    }
+
+    info!("Drew {} layer-s of {} and {}", paths.len(), gcode_path, optimized_path);
+    Ok(paths)
+}
+
+// Same as write_svgs for the optimized G-code alone, each layer drawn move by move as an animated SVG
+// written to <original>.layer<n>.animated.svg
+pub fn write_animations(gcode_path: &str, optimized_path: &str, selected: impl Fn(u32) -> bool) -> Result<Vec<String>, String> {
+    let optimized = layers(optimized_path);
+
+    let mut paths = Vec::new();
+    for (i, segments) in optimized.iter().enumerate() {
+        let layer = i as u32;
+        if !selected(layer) || !segments.iter().any(Segment::extrudes) {
+            continue;
+        }
+
+        let path = format!("{}.layer{}.animated.svg", gcode_path, layer);
+        fs::write(&path, animated_svg(layer, segments))
+            .map_err(|e| format!("Unable to write file {}: {}", path, e))?;
+        paths.push(path);
+    }
+
+    info!("Animated {} layer-s of {}", paths.len(), optimized_path);
+    Ok(paths)
+}
+
+// Smallest and largest X and Y reached by the extrusions, travels to and from the layer may go past them
+fn extrusion_bounds(segments: &[Segment]) -> ((f64, f64), (f64, f64)) {
+    let (mut min, mut max) = ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY));
+    for segment in segments.iter().filter(|segment| segment.extrudes()) {
+        for point in [segment.from, segment.to] {
+            min = (min.0.min(point.0), min.1.min(point.1));
+            max = (max.0.max(point.0), max.1.max(point.1));
+        }
+    }
+    (min, max)
+}
+
+// Extrusions in blue and travels in dashed red, the original on the left
+fn svg(layer: u32, base: &[Segment], optimized: &[Segment]) -> String {
+    // Both sides share the bounds of the extrusions of the original
+    let (min, max) = extrusion_bounds(base);
+    let scale = (SIDE - 2.0 * MARGIN) / (max.0 - min.0).max(max.1 - min.1).max(1.0);
+
+    let width = 2.0 * SIDE;
+    let height = SIDE + TITLE;
+    let mut svg = String::new();
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#, width, height, width, height).unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+
+    for (side, (name, segments)) in [("Original", base), ("Optimized", optimized)].into_iter().enumerate() {
+        let offset = side as f64 * SIDE;
+        let travel: f64 = segments.iter()
+            .filter(|segment| !segment.extrudes())
+            .map(|segment| distance_3d(segment.from, segment.to))
+            .sum();
+        writeln!(svg, r#"<text x="{}" y="20" font-family="sans-serif" font-size="14">{} layer {}, travel {:.2}</text>"#,
+            offset + MARGIN, name, layer, travel).unwrap();
+
+        // Flipped so Y points up as it does on the bed
+        let point = |(x, y, _): (f64, f64, f64)| (offset + MARGIN + (x - min.0) * scale, TITLE + SIDE - MARGIN - (y - min.1) * scale);
+        let mut extrusions = String::new();
+        let mut travels = String::new();
+        for segment in segments {
+            let (from, to) = (point(segment.from), point(segment.to));
+            let path = if segment.extrudes() { &mut extrusions } else { &mut travels };
+            write!(path, "M{:.2} {:.2}L{:.2} {:.2}", from.0, from.1, to.0, to.1).unwrap();
+        }
+        writeln!(svg, r#"<path d="{}" fill="none" stroke="steelblue" stroke-width="1.2"/>"#, extrusions).unwrap();
+        writeln!(svg, r#"<path d="{}" fill="none" stroke="crimson" stroke-width="0.6" stroke-dasharray="3 2"/>"#, travels).unwrap();
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// Moves drawn one after the other in the order the head runs them, extrusions in blue and travels in
+// red, each taking its share of the playback by its time at its feedrate. The drawing stays once done.
+fn animated_svg(layer: u32, segments: &[Segment]) -> String {
+    let (min, max) = extrusion_bounds(segments);
+    let scale = (SIDE - 2.0 * MARGIN) / (max.0 - min.0).max(max.1 - min.1).max(1.0);
+    let point = |(x, y, _): (f64, f64, f64)| (MARGIN + (x - min.0) * scale, TITLE + SIDE - MARGIN - (y - min.1) * scale);
+
+    // Moves with no feedrate known take the time of the slowest one
+    let slowest = segments.iter().map(|segment| segment.feedrate).filter(|&f| f > 0.0).fold(f64::INFINITY, f64::min);
+    let slowest = if slowest.is_finite() { slowest } else { 1.0 };
+    let times: Vec<f64> = segments.iter()
+        .map(|segment| distance_3d(segment.from, segment.to) / if segment.feedrate > 0.0 { segment.feedrate } else { slowest })
+        .collect();
+    let total: f64 = times.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+
+    let height = SIDE + TITLE;
+    let mut svg = String::new();
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#, SIDE, height, SIDE, height).unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    writeln!(svg, r#"<text x="{}" y="20" font-family="sans-serif" font-size="14">Optimized layer {}, {} move-s</text>"#,
+        MARGIN, layer, segments.len()).unwrap();
+
+    // Each line is hidden behind a dash offset as long as itself, brought to 0 while it is run
+    let mut begin = 0.0;
+    for (segment, time) in segments.iter().zip(times) {
+        let duration = time / total * PLAYBACK;
+        let (from, to) = (point(segment.from), point(segment.to));
+        let length = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+        if length > 0.0 {
+            let style = if segment.extrudes() { r#"stroke="steelblue" stroke-width="1.2""# } else { r#"stroke="crimson" stroke-width="0.6""# };
+            writeln!(svg, r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" {} stroke-dasharray="{:.2}" stroke-dashoffset="{:.2}"><animate attributeName="stroke-dashoffset" from="{:.2}" to="0" begin="{:.3}s" dur="{:.3}s" fill="freeze"/></line>"#,
+                from.0, from.1, to.0, to.1, style, length, length, length, begin, duration.max(0.001)).unwrap();
+        }
+        begin += duration;
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// Draws where the travels of the original (left) and optimized (right) G-code pass over the bed,
+// seen from above, to a PNG. Pixels get hotter the more travels cross them, over the footprint
+// of the extrusions in grey, so travels concentrated over the part stand out.
+pub fn write_heatmap(gcode_path: &str, optimized_path: &str, heatmap_path: &str) -> Result<(), String> {
+    let base = segments::read(gcode_path);
+    let optimized = segments::read(optimized_path);
+
+    // Both sides share the bounds of every extrusion of the print
+    let (mut min, mut max) = ((f64::INFINITY, f64::INFINITY), (f64::NEG_INFINITY, f64::NEG_INFINITY));
+    for segment in base.iter().filter(|segment| segment.extrudes()) {
+        for point in [segment.from, segment.to] {
+            min = (min.0.min(point.0), min.1.min(point.1));
+            max = (max.0.max(point.0), max.1.max(point.1));
+        }
+    }
+    if min.0 > max.0 {
+        (min, max) = ((0.0, 0.0), (1.0, 1.0));
+    }
+    let side = SIDE as usize;
+    let scale = (SIDE - 2.0 * MARGIN) / (max.0 - min.0).max(max.1 - min.1).max(1.0);
+    // Flipped so Y points up as it does on the bed
+    let pixel = |(x, y, _): (f64, f64, f64)| (MARGIN + (x - min.0) * scale, SIDE - MARGIN - (y - min.1) * scale);
+
+    let grids: Vec<(Vec<u32>, Vec<u32>)> = [&base, &optimized].into_iter()
+        .map(|segments| {
+            let mut parts = vec![0; side * side];
+            let mut travels = vec![0; side * side];
+            for segment in segments {
+                let grid = if segment.extrudes() { &mut parts } else { &mut travels };
+                let (from, to) = (pixel(segment.from), pixel(segment.to));
+                let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil() as usize + 1;
+                let mut last = None;
+                for step in 0..=steps {
+                    let t = step as f64 / steps as f64;
+                    let (x, y) = (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+                    if x < 0.0 || y < 0.0 || x >= SIDE || y >= SIDE {
+                        continue;
+                    }
+                    // Each move heats a pixel once
+                    let cell = y as usize * side + x as usize;
+                    if last != Some(cell) {
+                        grid[cell] += 1;
+                        last = Some(cell);
+                    }
+                }
+            }
+            (parts, travels)
+        })
+        .collect();
+
+    // Both sides share the same color scale, logarithmic for the few pixels every travel crosses
+    let hottest = grids.iter().flat_map(|(_, travels)| travels.iter()).copied().max().unwrap_or(0).max(1);
+    let width = 2 * side;
+    let mut image = vec![255u8; width * side * 3];
+    for (i, (parts, travels)) in grids.iter().enumerate() {
+        for cell in 0..side * side {
+            let color = if travels[cell] > 0 {
+                heat((travels[cell] as f64).ln_1p() / (hottest as f64).ln_1p())
+            } else if parts[cell] > 0 {
+                [200, 200, 200]
+            } else {
+                continue;
+            };
+            let offset = ((cell / side) * width + i * side + cell % side) * 3;
+            image[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+    // Line between the two sides
+    for y in 0..side {
+        let offset = (y * width + side) * 3;
+        image[offset..offset + 3].copy_from_slice(&[0, 0, 0]);
+    }
+
+    let file = fs::File::create(heatmap_path)
+        .map_err(|e| format!("Unable to create file {}: {}", heatmap_path, e))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width as u32, side as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(&image))
+        .map_err(|e| format!("Unable to write file {}: {}", heatmap_path, e))?;
+
+    info!("Drew the travels of {} and {} to {}", gcode_path, optimized_path, heatmap_path);
+    Ok(())
+}
+
+// Color of a heat between 0 and 1, from yellow to dark red
+fn heat(heat: f64) -> [u8; 3] {
+    let heat = heat.clamp(0.0, 1.0);
+    if heat < 0.5 {
+        [255, (230.0 - 330.0 * heat) as u8, 0]
+    } else {
+        [(255.0 - 230.0 * (heat - 0.5)) as u8, (65.0 - 130.0 * (heat - 0.5)) as u8, 0]
+    }
+}
+
+// Moves of a toolpath export, in millimeters of the G-code
+struct PathSegment {
+    from: (f64, f64, f64),
+    to: (f64, f64, f64),
+    layer: u32,
+    extrudes: bool,
+}
+
+// Formats a toolpath can be exported to
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToolpathFormat {
+    Obj,
+    Gltf,
+}
+
+// Format of a toolpath export, given by the extension of its path
+pub fn toolpath_format(export_path: &str) -> Result<ToolpathFormat, String> {
+    if export_path.ends_with(".obj") {
+        Ok(ToolpathFormat::Obj)
+    } else if export_path.ends_with(".gltf") {
+        Ok(ToolpathFormat::Gltf)
+    } else {
+        Err(format!("Unknown toolpath format for {}, expected a .obj or .gltf file", export_path))
+    }
+}
+
+// Writes every move of a G-code file as line segments, to OBJ or glTF depending on the extension
+// of the export path. Extrusions and travels are kept apart and each move carries its layer index.
+pub fn export_toolpath(gcode_path: &str, export_path: &str) -> Result<(), String> {
+    let format = toolpath_format(export_path)?;
+    let segments: Vec<PathSegment> = layers(gcode_path).into_iter().enumerate()
+        .flat_map(|(layer, segments)| segments.into_iter().map(move |segment| PathSegment {
+            from: segment.from,
+            to: segment.to,
+            layer: layer as u32,
+            extrudes: segment.extrudes(),
+        }))
+        .filter(|segment| segment.from != segment.to)
+        .collect();
+
+    let contents = match format {
+        ToolpathFormat::Obj => obj(&segments),
+        ToolpathFormat::Gltf => gltf(&segments),
+    };
+
+    fs::write(export_path, contents)
+        .map_err(|e| format!("Unable to write file {}: {}", export_path, e))?;
+    info!("Exported {} move-s of {} to {}", segments.len(), gcode_path, export_path);
+    Ok(())
+}
+
+// One object per layer with an extrusion and a travel group of polylines, in printer coordinates (Z up)
+fn obj(segments: &[PathSegment]) -> String {
+    let mut obj = String::from("# Toolpath exported by TSP G-code optimizer\n");
+    let mut vertices = 0;
+    let mut last: Option<(u32, bool)> = None;
+    let mut position = None;
+    for segment in segments {
+        if last.is_none_or(|(layer, _)| layer != segment.layer) {
+            writeln!(obj, "o layer_{}", segment.layer).unwrap();
+        }
+        if last != Some((segment.layer, segment.extrudes)) {
+            writeln!(obj, "g {}", if segment.extrudes { "extrusion" } else { "travel" }).unwrap();
+            position = None;
+        }
+        last = Some((segment.layer, segment.extrudes));
+
+        // Moves following each other share their vertex
+        if position != Some(segment.from) {
+            writeln!(obj, "v {:.4} {:.4} {:.4}", segment.from.0, segment.from.1, segment.from.2).unwrap();
+            vertices += 1;
+        }
+        writeln!(obj, "v {:.4} {:.4} {:.4}", segment.to.0, segment.to.1, segment.to.2).unwrap();
+        vertices += 1;
+        writeln!(obj, "l {} {}", vertices - 1, vertices).unwrap();
+        position = Some(segment.to);
+    }
+    obj
+}
+
+// Line primitives for extrusions and travels, each vertex carrying its layer index in a _LAYER attribute.
+// glTF has Y up, the bed lies in its XZ plane.
+fn gltf(segments: &[PathSegment]) -> String {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut buffer_views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut primitives = Vec::new();
+
+    for (material, extrudes) in [(0, true), (1, false)] {
+        let lines: Vec<&PathSegment> = segments.iter().filter(|segment| segment.extrudes == extrudes).collect();
+        if lines.is_empty() {
+            continue;
+        }
+
+        let points: Vec<[f32; 3]> = lines.iter()
+            .flat_map(|segment| [segment.from, segment.to])
+            .map(|(x, y, z)| [x as f32, z as f32, -y as f32])
+            .collect();
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for point in &points {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(point[axis]);
+                max[axis] = max[axis].max(point[axis]);
+            }
+        }
+
+        let offset = buffer.len();
+        buffer.extend(points.iter().flatten().flat_map(|value| value.to_le_bytes()));
+        buffer_views.push(serde_json::json!({ "buffer": 0, "byteOffset": offset, "byteLength": points.len() * 12, "target": 34962 }));
+        accessors.push(serde_json::json!({
+            "bufferView": buffer_views.len() - 1, "componentType": 5126, "count": points.len(), "type": "VEC3", "min": min, "max": max,
+        }));
+
+        let offset = buffer.len();
+        buffer.extend(lines.iter().flat_map(|segment| [segment.layer as f32; 2]).flat_map(|layer| layer.to_le_bytes()));
+        buffer_views.push(serde_json::json!({ "buffer": 0, "byteOffset": offset, "byteLength": points.len() * 4, "target": 34962 }));
+        accessors.push(serde_json::json!({ "bufferView": buffer_views.len() - 1, "componentType": 5126, "count": points.len(), "type": "SCALAR" }));
+
+        primitives.push(serde_json::json!({
+            "attributes": { "POSITION": accessors.len() - 2, "_LAYER": accessors.len() - 1 },
+            "material": material,
+            "mode": 1,
+        }));
+    }
+
+    let gltf = serde_json::json!({
+        "asset": { "version": "2.0", "generator": "TSP G-code optimizer" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0, "name": "toolpath" }],
+        "meshes": [{ "name": "toolpath", "primitives": primitives }],
+        "materials": [
+            { "name": "extrusion", "pbrMetallicRoughness": { "baseColorFactor": [0.27, 0.51, 0.71, 1.0] } },
+            { "name": "travel", "pbrMetallicRoughness": { "baseColorFactor": [0.86, 0.08, 0.24, 1.0] } },
+        ],
+        "buffers": [{ "byteLength": buffer.len(), "uri": format!("data:application/octet-stream;base64,{}", base64(&buffer)) }],
+        "bufferViews": buffer_views,
+        "accessors": accessors,
+    });
+    serde_json::to_string(&gltf).unwrap()
+}
+
+// Standard base64 with padding, for the buffer embedded in a glTF file
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let value = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(value >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,127 @@
+use serde::Serialize;
+
+// Locations listed for each kind of warning
+const EXAMPLES: usize = 5;
+
+// What went wrong without stopping the optimization, summed up at the end of the run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    UnknownCommand,
+    // G20/G21, G90/G91 or M82/M83 given again once the mode was set
+    RepeatedMode,
+    // Moves read in the position or extruder mode of the configuration, the file not setting it first
+    AssumedMode,
+    // Layers kept in their original order because the solver failed on them
+    SolverFailure,
+    // Layers kept in their original order because their tour takes longer
+    SlowerLayer,
+    // Moves off the bed of the printer, already off it in the original
+    OffBed,
+    // Extrusions slowed down to the maximum volumetric flow
+    FlowLimited,
+    // Moves slowed down to the maximum feedrates of the printer
+    FeedrateCapped,
+    // Structural problems of the written file, at its lines
+    Lint,
+    // Commands of the input missing from the output, or written more often than in the input
+    LostCommand,
+    AddedCommand,
+}
+
+impl WarningKind {
+    pub fn description(self) -> &'static str {
+        match self {
+            WarningKind::UnknownCommand => "Unknown commands",
+            WarningKind::RepeatedMode => "Modes set again",
+            WarningKind::AssumedMode => "Modes assumed",
+            WarningKind::SolverFailure => "Solver failures",
+            WarningKind::SlowerLayer => "Layers slower once optimized",
+            WarningKind::OffBed => "Moves off the bed",
+            WarningKind::FlowLimited => "Extrusions slowed down",
+            WarningKind::FeedrateCapped => "Moves over the printer feedrates",
+            WarningKind::Lint => "Lint issues in the output",
+            WarningKind::LostCommand => "Commands lost",
+            WarningKind::AddedCommand => "Commands added",
+        }
+    }
+
+    // Examples are line numbers of the input, or layer numbers
+    fn location(self) -> &'static str {
+        match self {
+            WarningKind::UnknownCommand | WarningKind::RepeatedMode | WarningKind::AssumedMode | WarningKind::OffBed | WarningKind::LostCommand => "line",
+            WarningKind::Lint | WarningKind::AddedCommand => "output line",
+            WarningKind::SolverFailure | WarningKind::SlowerLayer | WarningKind::FlowLimited | WarningKind::FeedrateCapped => "layer",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WarningGroup {
+    pub kind: WarningKind,
+    pub description: &'static str,
+    pub location: &'static str,
+    pub count: usize,
+    // First lines or layers the warning was given for
+    pub examples: Vec<u32>,
+}
+
+// Warnings grouped by kind, in the order they were first given
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct Warnings {
+    groups: Vec<WarningGroup>,
+}
+
+impl Warnings {
+    pub fn add(&mut self, kind: WarningKind, location: u32) {
+        let group = self.group(kind);
+        group.count += 1;
+        if group.examples.len() < EXAMPLES && !group.examples.contains(&location) {
+            group.examples.push(location);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Warnings) {
+        for other in &other.groups {
+            let group = self.group(other.kind);
+            group.count += other.count;
+            for &location in &other.examples {
+                if group.examples.len() < EXAMPLES && !group.examples.contains(&location) {
+                    group.examples.push(location);
+                }
+            }
+        }
+    }
+
+    pub fn groups(&self) -> &[WarningGroup] {
+        &self.groups
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn display(&self) {
+        if self.is_empty() {
+            return;
+        }
+        status!("\nWarnings:");
+        for group in &self.groups {
+            let examples: Vec<String> = group.examples.iter().map(|location| location.to_string()).collect();
+            let more = if group.count > examples.len() { ", ..." } else { "" };
+            let plural = if examples.len() > 1 { "s" } else { "" };
+            status!("  {:<30} {:>7}  {}{} {}{}", group.description, group.count, group.location, plural, examples.join(", "), more);
+        }
+    }
+
+    fn group(&mut self, kind: WarningKind) -> &mut WarningGroup {
+        match self.groups.iter().position(|group| group.kind == kind) {
+            Some(i) => &mut self.groups[i],
+            None => {
+                self.groups.push(WarningGroup { kind, description: kind.description(), location: kind.location(), count: 0, examples: Vec::new() });
+                self.groups.last_mut().unwrap()
+            }
+        }
+    }
+}
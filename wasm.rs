@@ -0,0 +1,31 @@
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use crate::{config, Optimizer, OptimizationReport};
+
+// Optimized G-code and its report, handed to JavaScript as JSON
+#[derive(Serialize)]
+struct Optimized {
+    gcode: String,
+    report: OptimizationReport,
+}
+
+// Optimizes G-code text in the browser with the builtin solver, the configuration being JSON text
+// (its defaults when empty). Only the builtin solver runs here, there are no processes to start.
+// Returns the optimized G-code and its report as JSON.
+#[wasm_bindgen]
+pub fn optimize(gcode: &str, config: &str) -> Result<String, JsError> {
+    let config = if config.trim().is_empty() {
+        config::defaults()
+    } else {
+        config::parse_config_str(config).map_err(|e| JsError::new(&e))?
+    };
+    if config.solver != config::Solver::Builtin {
+        return Err(JsError::new("Only the builtin solver runs in the browser"));
+    }
+
+    let (gcode, report) = config::complete(config)
+        .and_then(|config| Optimizer::optimize_str(gcode, &config))
+        .map_err(|e| JsError::new(&e.message))?;
+    serde_json::to_string(&Optimized { gcode, report })
+        .map_err(|e| JsError::new(&e.to_string()))
+}